@@ -0,0 +1,77 @@
+//! Times patch generation for inputs that spend most of their time in the suffix array search.
+//!
+//! There's no benchmark framework among the dependencies, so this is a plain binary:
+//! `cargo bench --bench search`. Compare the numbers between builds to see the effect of a change.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+/// Pseudo-random bytes, so the matches are found by the search instead of the prefix trimming.
+fn noise(len: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed;
+    (0..len)
+        .map(|_| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 56) as u8
+        })
+        .collect()
+}
+
+fn bench(name: &str, old: &[u8], new: &[u8]) {
+    const RUNS: u32 = 5;
+    let mut patch = Vec::new();
+    let mut best = Duration::MAX;
+    for _ in 0..RUNS {
+        patch.clear();
+        let start = Instant::now();
+        ddelta::generate(black_box(old), black_box(new), &mut patch, |_| {}).unwrap();
+        best = best.min(start.elapsed());
+    }
+    println!("{name:<24} {:>10.2?} (best of {RUNS})", best);
+}
+
+fn main() {
+    let old = noise(4 << 20, 1);
+
+    // Every few bytes are changed, so the search runs at almost every position of the new file
+    let mut scattered = old.clone();
+    for i in (0..scattered.len()).step_by(64) {
+        scattered[i] ^= 0xff;
+    }
+    scattered.reverse();
+    bench("scattered changes", &old, &scattered);
+
+    // Blocks of the old file in a different order
+    let blocks: Vec<&[u8]> = old.chunks(4096).collect();
+    let shuffled: Vec<u8> = blocks
+        .iter()
+        .rev()
+        .flat_map(|b| b.iter().copied())
+        .collect();
+    bench("shuffled blocks", &old, &shuffled);
+
+    // Data that isn't in the old file at all
+    bench("unrelated", &old, &noise(1 << 20, 2));
+
+    // Long runs make the suffix array full of long common prefixes
+    let runs: Vec<u8> = (0..4 << 20).map(|i: u32| (i >> 12) as u8).collect();
+    let mut changed = runs.clone();
+    changed[1 << 21] = 1;
+    changed.rotate_left(12345);
+    bench("long runs", &runs, &changed);
+
+    // A block repeated over and over with a few changes, so most suffixes share long prefixes
+    let block = noise(1000, 3);
+    let mut periodic: Vec<u8> = block.iter().cycle().take(2 << 20).copied().collect();
+    for i in (0..periodic.len()).step_by(9973) {
+        periodic[i] ^= 0x55;
+    }
+    let mut changed = periodic.clone();
+    for i in (0..changed.len()).step_by(7919) {
+        changed[i] ^= 0x33;
+    }
+    changed.rotate_left(123_457);
+    bench("repeated block", &periodic, &changed);
+}
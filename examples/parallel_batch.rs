@@ -0,0 +1,78 @@
+//! Generates and applies a batch of patches on several threads, with one `Differ` and one
+//! `Patcher` per thread, so their buffers are reused for every job that thread picks up.
+//!
+//! Run with `cargo run --release --example parallel_batch [jobs] [threads]`.
+
+use std::io::Cursor;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Instant;
+
+use ddelta::{DiffOptions, Differ, Patcher};
+
+/// An old and a new version of a file, made up from `seed`.
+fn versions(seed: usize) -> (Vec<u8>, Vec<u8>) {
+    let mut state = seed as u64 * 0x9e37_79b9_7f4a_7c15 + 1;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    let old: Vec<u8> = (0..256 * 1024).map(|_| next() as u8).collect();
+    let mut new = old.clone();
+    for _ in 0..100 {
+        let at = next() as usize % new.len();
+        new[at] = next() as u8;
+    }
+    let at = next() as usize % new.len();
+    new.splice(at..at, b"inserted".iter().copied());
+    (old, new)
+}
+
+fn main() {
+    let mut args = std::env::args()
+        .skip(1)
+        .map(|arg| arg.parse().expect("not a number"));
+    let jobs = args.next().unwrap_or(64);
+    let threads = args
+        .next()
+        .or_else(|| thread::available_parallelism().ok().map(usize::from))
+        .unwrap_or(4);
+
+    let next_job = AtomicUsize::new(0);
+    let patch_bytes = Mutex::new(0);
+    let start = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                let mut differ = Differ::new(DiffOptions::new().chunk_size(64 * 1024));
+                let mut patcher = Patcher::new();
+                let (mut patch, mut out) = (Vec::new(), Vec::new());
+                loop {
+                    let job = next_job.fetch_add(1, Ordering::Relaxed);
+                    if job >= jobs {
+                        break;
+                    }
+                    let (old, new) = versions(job);
+                    patch.clear();
+                    differ
+                        .run_chunked(&mut &old[..], &mut &new[..], &mut patch)
+                        .unwrap();
+                    out.clear();
+                    patcher
+                        .run_chunked(&mut Cursor::new(&old), &mut out, &mut &patch[..])
+                        .unwrap();
+                    assert_eq!(out, new, "job {job} didn't round trip");
+                    *patch_bytes.lock().unwrap() += patch.len();
+                }
+            });
+        }
+    });
+    println!(
+        "{jobs} patches on {threads} threads in {:.2?}, {} bytes in total",
+        start.elapsed(),
+        patch_bytes.into_inner().unwrap(),
+    );
+}
@@ -0,0 +1,40 @@
+//! Big-endian integers as they're stored in patches, which can be read and written in place.
+//!
+//! These are what zerocopy's `byteorder` types do, without depending on the byteorder crate.
+
+use std::fmt;
+use std::mem::size_of;
+
+use zerocopy::{AsBytes, FromBytes, FromZeroes, Unaligned};
+
+macro_rules! be_int {
+    ($name: ident, $int: ty) => {
+        #[derive(
+            Copy, Clone, Default, PartialEq, Eq, FromZeroes, FromBytes, AsBytes, Unaligned,
+        )]
+        #[repr(transparent)]
+        pub(crate) struct $name([u8; size_of::<$int>()]);
+
+        impl $name {
+            // Without the `diff` and `apply` features, only some of the integers are written
+            #[allow(dead_code)]
+            pub(crate) const fn new(n: $int) -> Self {
+                $name(n.to_be_bytes())
+            }
+
+            pub(crate) const fn get(self) -> $int {
+                <$int>::from_be_bytes(self.0)
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.debug_tuple(stringify!($name)).field(&self.get()).finish()
+            }
+        }
+    };
+}
+
+be_int!(U32, u32);
+be_int!(U64, u64);
+be_int!(I64, i64);
@@ -0,0 +1,267 @@
+//! The raw patch format of the `bsdiff` crate, and conversion from and to it.
+//!
+//! A `bsdiff` patch is a sequence of entries like those of ddelta, without any header or
+//! terminator: the length of the diff data, the length of the extra data and the seek, followed by
+//! the data itself. The numbers are 8 bytes each, little endian, with the sign in the top bit
+//! instead of in two's complement. The patch ends where the next entry would start. Diff data
+//! works the same way in both formats, so patches convert without the old or new file.
+
+use std::io::{self, Read, Write};
+
+use zerocopy::AsBytes;
+
+use crate::be::{I64, U64};
+use crate::entries::{Event, PatchEntries};
+use crate::io::read_up_to;
+use crate::patch::{apply_diff, checked_offset, copy_bytes, Buffers, PatchError, Result};
+use crate::spec::Terminator;
+use crate::units::{Len, NewOffset, OldOffset};
+use crate::{EntryHeader, OldSource, PatchHeader, WouldBlock, COPY_FLAG, DDELTA_MAGIC};
+
+/// The size of an entry header.
+const CONTROL_LEN: usize = 24;
+
+/// Decodes a number of a `bsdiff` patch.
+fn offtin(buf: [u8; 8]) -> i64 {
+    let y = u64::from_le_bytes(buf);
+    let magnitude = (y & !(1 << 63)) as i64;
+    if y & (1 << 63) != 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+/// Encodes a number for a `bsdiff` patch.
+fn offtout(x: i64) -> [u8; 8] {
+    let y = match x < 0 {
+        true => x.unsigned_abs() | 1 << 63,
+        false => x as u64,
+    };
+    y.to_le_bytes()
+}
+
+/// Reads the next entry header of a `bsdiff` patch, or returns [`None`] at its end. The lengths
+/// are checked to not be negative.
+fn read_control(patch: &mut impl Read, would_block: WouldBlock) -> Result<Option<(u64, u64, i64)>> {
+    let mut buf = [0; CONTROL_LEN];
+    match read_up_to(patch, &mut buf, would_block)? {
+        0 => return Ok(None),
+        CONTROL_LEN => {}
+        _ => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+    }
+    let [diff, extra, seek] = [0, 8, 16].map(|i| offtin(buf[i..i + 8].try_into().unwrap()));
+    if diff < 0 || extra < 0 {
+        return Err(PatchError::Internal(
+            "Negative length in bsdiff patch".into(),
+        ));
+    }
+    Ok(Some((diff as u64, extra as u64, seek)))
+}
+
+fn write_control(out: &mut impl Write, diff: u64, extra: u64, seek: i64) -> io::Result<()> {
+    out.write_all(&offtout(diff as i64))?;
+    out.write_all(&offtout(extra as i64))?;
+    out.write_all(&offtout(seek))
+}
+
+/// Copies `len` bytes from `src` to `dst`, failing if `src` ends before that.
+fn pass_through(src: &mut impl Read, dst: &mut impl Write, len: u64) -> Result<()> {
+    if io::copy(&mut src.take(len), dst)? != len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(())
+}
+
+/// Apply a patch in the raw format of the `bsdiff` crate, as its `patch` function does.
+pub fn apply_bsdiff(
+    old: &mut impl OldSource,
+    new: &mut impl Write,
+    patch: &mut impl Read,
+) -> Result<()> {
+    let mut bufs = Buffers::default();
+    let mut written = NewOffset::default();
+    while let Some((diff, extra, seek)) = read_control(patch, WouldBlock::Fail)? {
+        written = checked_offset(written, diff, extra)?;
+        apply_diff(patch, old, new, diff, false, &mut bufs)?;
+        copy_bytes(patch, new, extra, &mut bufs)?;
+        old.seek_by(seek)?;
+    }
+    new.flush()?;
+    Ok(())
+}
+
+/// Convert a ddelta patch, plain or chunked, to the raw format of the `bsdiff` crate.
+///
+/// Patches that copy from the new file (see
+/// [`DiffOptions::copy_from_new`][crate::DiffOptions::copy_from_new]) or have word diffs (see
+/// [`DiffOptions::word_diff`][crate::DiffOptions::word_diff]) can't be converted.
+pub fn to_bsdiff(patch: &mut impl Read, out: &mut impl Write) -> Result<()> {
+    let mut entries = PatchEntries::new(patch, true, WouldBlock::Fail);
+    // Where the old file is at for the applier, which doesn't know about chunks
+    let mut old_pos = OldOffset::default();
+    while let Some(event) = entries.next()? {
+        match event {
+            Event::Header(_) => {
+                if entries.words() {
+                    return Err(PatchError::Internal("Word diffs can't be converted".into()));
+                }
+                // Each chunk starts at its own offset in the old file
+                let start = entries.chunk_start().in_old();
+                if start != old_pos {
+                    let seek = old_pos.seek_to(start).ok_or(PatchError::OffsetOverflow)?;
+                    write_control(out, 0, 0, seek)?;
+                    old_pos = start;
+                }
+            }
+            Event::Entry(entry) => {
+                let (diff, extra, seek) = (entry.diff.get(), entry.extra.get(), entry.seek.get());
+                if entries.copies() && diff & COPY_FLAG != 0 {
+                    return Err(PatchError::Internal(
+                        "Copies from the new file can't be converted".into(),
+                    ));
+                }
+                write_control(out, diff, extra, seek)?;
+                pass_through(entries.patch(), out, diff + extra)?;
+                old_pos = old_pos
+                    .checked_add(Len::new(diff))
+                    .ok_or(PatchError::OffsetOverflow)?
+                    .seek(seek)?;
+            }
+            Event::End => {}
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Convert a patch in the raw format of the `bsdiff` crate to a plain ddelta patch.
+///
+/// The ddelta header needs the size of the new file up front, so the whole patch is passed in
+/// memory.
+pub fn from_bsdiff(bsdiff: &[u8], out: &mut impl Write) -> Result<()> {
+    // Once to add up the size of the new file, and once to write the entries
+    let mut new_size = NewOffset::default();
+    let mut rest = bsdiff;
+    while let Some((diff, extra, _)) = read_control(&mut rest, WouldBlock::Fail)? {
+        new_size = checked_offset(new_size, diff, extra)?;
+        let len = usize::try_from(diff + extra)
+            .ok()
+            .filter(|&len| len <= rest.len())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        rest = &rest[len..];
+    }
+    out.write_all(
+        PatchHeader {
+            magic: *DDELTA_MAGIC,
+            new_file_size: U64::new(new_size.get()),
+        }
+        .as_bytes(),
+    )?;
+    let mut rest = bsdiff;
+    while let Some((diff, extra, seek)) = read_control(&mut rest, WouldBlock::Fail)? {
+        // An empty entry would be read as the end of the patch, and does nothing anyway
+        if Terminator::matches(diff, extra, seek) {
+            continue;
+        }
+        out.write_all(
+            EntryHeader {
+                diff: U64::new(diff),
+                extra: U64::new(extra),
+                seek: I64::new(seek),
+            }
+            .as_bytes(),
+        )?;
+        let (data, tail) = rest.split_at((diff + extra) as usize);
+        out.write_all(data)?;
+        rest = tail;
+    }
+    out.write_all(&[0; CONTROL_LEN])?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Generate a patch in the raw format of the `bsdiff` crate, which its `patch` function and
+/// [`apply_bsdiff`] apply. See [`generate`][crate::generate].
+#[cfg(feature = "diff")]
+pub fn generate_bsdiff(
+    old: &[u8],
+    new: &[u8],
+    patch: &mut impl Write,
+    progress: impl FnMut(crate::State),
+) -> crate::diff::Result<()> {
+    use crate::DiffError;
+
+    let mut ddelta = Vec::new();
+    crate::generate(old, new, &mut ddelta, progress)?;
+    to_bsdiff(&mut &ddelta[..], patch).map_err(|e| match e {
+        PatchError::Io(e) | PatchError::Write { error: e, .. } => DiffError::Io(e),
+        e => DiffError::Internal(e.to_string().into()),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::{apply_bsdiff, from_bsdiff, offtin, offtout, to_bsdiff};
+    use crate::apply_chunked;
+
+    #[test]
+    fn numbers() {
+        for x in [0, 1, -1, 1000, -1000, i64::MAX, -i64::MAX] {
+            assert_eq!(offtin(offtout(x)), x);
+        }
+        assert_eq!(offtout(-2), [2, 0, 0, 0, 0, 0, 0, 0x80]);
+    }
+
+    #[test]
+    fn convert() {
+        let old = b"hello world, hello moon";
+        // "hello world" from the old file with one byte changed, "!!" as extra data, a seek past
+        // ", ", and "hello" from the old file
+        let mut bsdiff = Vec::new();
+        bsdiff.extend([11, 2, 2].map(offtout).concat());
+        bsdiff.extend([0, 0, 0, 0, 0, 0, b'W'.wrapping_sub(b'w'), 0, 0, 0, 0]);
+        bsdiff.extend(b"!!");
+        bsdiff.extend([5, 0, 0].map(offtout).concat());
+        bsdiff.extend([0; 5]);
+        let expected = b"hello World!!hello";
+
+        let mut new = Vec::new();
+        apply_bsdiff(&mut Cursor::new(old), &mut new, &mut &bsdiff[..]).unwrap();
+        assert_eq!(new, expected);
+
+        let mut ddelta = Vec::new();
+        from_bsdiff(&bsdiff, &mut ddelta).unwrap();
+        let mut new = Vec::new();
+        apply_chunked(&mut Cursor::new(old), &mut new, &mut &ddelta[..]).unwrap();
+        assert_eq!(new, expected);
+
+        let mut back = Vec::new();
+        to_bsdiff(&mut &ddelta[..], &mut back).unwrap();
+        assert_eq!(back, bsdiff);
+
+        assert!(from_bsdiff(&bsdiff[..bsdiff.len() - 1], &mut Vec::new()).is_err());
+    }
+
+    #[cfg(feature = "diff")]
+    #[test]
+    fn generate_chunked() {
+        let old: Vec<u8> = (0..20_000u32).map(|i| (i * i / 7) as u8).collect();
+        let new = [&old[..5000], b"inserted", &old[5000..]].concat();
+        let mut ddelta = Vec::new();
+        crate::generate_chunked(&mut &old[..], &mut &new[..], &mut ddelta, 4096, |_| {}).unwrap();
+        let mut bsdiff = Vec::new();
+        to_bsdiff(&mut &ddelta[..], &mut bsdiff).unwrap();
+        let mut out = Vec::new();
+        apply_bsdiff(&mut Cursor::new(&old), &mut out, &mut &bsdiff[..]).unwrap();
+        assert_eq!(out, new);
+
+        let mut bsdiff = Vec::new();
+        super::generate_bsdiff(&old, &new, &mut bsdiff, |_| {}).unwrap();
+        let mut out = Vec::new();
+        apply_bsdiff(&mut Cursor::new(&old), &mut out, &mut &bsdiff[..]).unwrap();
+        assert_eq!(out, new);
+    }
+}
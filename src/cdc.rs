@@ -0,0 +1,280 @@
+//! Content-defined chunking for [`generate_chunked_with_options`][crate::generate_chunked_with_options].
+//!
+//! Chunk boundaries are picked with the FastCDC gear hash, so that both files get cut at the same
+//! content rather than at the same offsets. Old and new chunks are then paired up by the
+//! fingerprint of their cut point, which keeps the pairing in sync across insertions and deletions.
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+
+use crate::diff::{generate_chunk, read_into, write_ending, write_header, Result, Sorted};
+use crate::{DiffOptions, State, WouldBlock};
+
+pub(crate) const GEAR: [u64; 256] = gear_table();
+
+/// Generates the gear table with splitmix64, so it doesn't have to be spelled out.
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut state: u64 = 0x6464_656c_7461_2d72;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Params {
+    min: usize,
+    avg: usize,
+    max: usize,
+    mask_s: u64,
+    mask_l: u64,
+}
+
+impl Params {
+    /// Derives the chunk size limits from the maximum chunk size: chunks average a quarter of it,
+    /// and are at least a sixteenth of it.
+    fn new(max: usize) -> Self {
+        let max = max.max(64);
+        let avg = max / 4;
+        let bits = avg.ilog2();
+        Params {
+            min: max / 16,
+            avg,
+            max,
+            // Normalized chunking: be stricter before the average size, and more lenient after it
+            mask_s: u64::MAX << (64 - (bits + 1)),
+            mask_l: u64::MAX << (64 - (bits - 1)),
+        }
+    }
+
+    /// Finds the first cut point in `data`, returning the chunk length and the hash at the cut.
+    ///
+    /// The hash only depends on the 64 bytes preceding the cut, so it identifies the cut point by
+    /// content, independent of where the chunk started.
+    fn cut(&self, data: &[u8]) -> (usize, u64) {
+        let end = data.len().min(self.max);
+        let mut hash = 0u64;
+        let mut i = self.min.saturating_sub(64).min(end);
+        while i < end {
+            hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+            i += 1;
+            if i < self.min {
+                continue;
+            }
+            let mask = if i < self.avg {
+                self.mask_s
+            } else {
+                self.mask_l
+            };
+            if hash & mask == 0 {
+                return (i, hash);
+            }
+        }
+        (end, hash)
+    }
+}
+
+struct Chunk {
+    data: Vec<u8>,
+    offset: u64,
+    fingerprint: u64,
+}
+
+impl Chunk {
+    fn end(&self) -> u64 {
+        self.offset + self.data.len() as u64
+    }
+}
+
+/// Splits a reader into content-defined chunks.
+struct Chunker<'a, R> {
+    reader: &'a mut R,
+    would_block: WouldBlock,
+    params: Params,
+    /// What has been read, but isn't part of a chunk yet.
+    buf: Vec<u8>,
+    offset: u64,
+}
+
+impl<'a, R: Read> Chunker<'a, R> {
+    fn new(reader: &'a mut R, would_block: WouldBlock, params: Params) -> Result<Self> {
+        let mut buf = Vec::new();
+        buf.try_reserve(params.max)?;
+        Ok(Chunker {
+            reader,
+            would_block,
+            params,
+            buf,
+            offset: 0,
+        })
+    }
+
+    fn next_chunk(&mut self) -> Result<Option<Chunk>> {
+        read_into(
+            self.reader,
+            &mut self.buf,
+            self.params.max,
+            self.would_block,
+        )?;
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        let (len, fingerprint) = self.params.cut(&self.buf);
+        let mut data = Vec::new();
+        data.try_reserve_exact(len)?;
+        data.extend_from_slice(&self.buf[..len]);
+        let chunk = Chunk {
+            data,
+            offset: self.offset,
+            fingerprint,
+        };
+        self.buf.drain(..len);
+        self.offset += len as u64;
+        Ok(Some(chunk))
+    }
+}
+
+/// Chunked generation with content-defined boundaries.
+///
+/// Each new chunk is diffed against the old chunks leading up to the old cut point with the same
+/// fingerprint. Since old and new chunks don't start at the same offset anymore, every chunk begins
+/// with a seek entry moving the old file to the right place, which keeps the output compatible with
+/// [`apply_chunked`][crate::apply_chunked].
+pub(crate) fn generate_chunked(
+    old_f: &mut impl Read,
+    new_f: &mut impl Read,
+    patch_f: &mut impl Write,
+    options: &DiffOptions,
+    sorted: &mut Sorted,
+    mut progress: impl FnMut(State),
+) -> Result<()> {
+    let params = Params::new(options.max_chunk_size());
+    // How far ahead of the new file the old file is read while looking for matching cut points
+    let lookahead = params.max as u64 * 2;
+    let mut new_chunks = Chunker::new(new_f, options.would_block, params)?;
+    let mut old_chunks = Chunker::new(old_f, options.would_block, params)?;
+    let mut queue: VecDeque<Chunk> = VecDeque::new();
+    let mut old_eof = false;
+    // Difference between matching offsets in the old and new file, as of the last matched chunk
+    let mut drift: i64 = 0;
+    let mut bytes_completed = 0;
+    loop {
+        progress(State::Reading);
+        let Some(new) = new_chunks.next_chunk()? else {
+            if bytes_completed == 0 {
+                write_header(patch_f, 0)?;
+                write_ending(patch_f)?;
+            }
+            break;
+        };
+        let new_end = new.end() as i64;
+        while !old_eof && queue.back().map_or(0, Chunk::end) < new_end as u64 + lookahead {
+            match old_chunks.next_chunk()? {
+                Some(chunk) => {
+                    queue.try_reserve(1)?;
+                    queue.push_back(chunk);
+                }
+                None => old_eof = true,
+            }
+        }
+
+        // Old chunks that are too far behind the current position will never be matched again
+        while queue.len() > 1
+            && (queue[0].end() as i64) < new_end + drift - params.max as i64
+            && queue[0].fingerprint != new.fingerprint
+        {
+            queue.pop_front();
+        }
+
+        let matched = queue.iter().position(|c| c.fingerprint == new.fingerprint);
+        let window = match matched {
+            Some(i) => i + 1,
+            None => queue
+                .iter()
+                .scan(0, |len, c| {
+                    let fits = *len < new.data.len();
+                    *len += c.data.len();
+                    Some(fits)
+                })
+                .take_while(|&fits| fits)
+                .count(),
+        };
+        let mut old = Vec::new();
+        old.try_reserve_exact(queue.iter().take(window).map(|c| c.data.len()).sum())?;
+        for chunk in queue.iter().take(window) {
+            old.extend_from_slice(&chunk.data);
+        }
+        let old_start = queue.front().map_or(old_chunks.offset, |c| c.offset);
+        // Keep the end of the window, which is where the matched cut point is
+        let skip = old.len().saturating_sub(params.max);
+        let old = &old[skip..];
+        let old_start = old_start + skip as u64;
+
+        generate_chunk(
+            old,
+            &new.data,
+            patch_f,
+            old_start as i64 - new.offset as i64,
+            new.offset,
+            options,
+            sorted,
+            |d| match d {
+                State::Working(bytes) => progress(State::Working(bytes + bytes_completed)),
+                other => progress(other),
+            },
+        )?;
+        bytes_completed += new.data.len() as u64;
+
+        if let Some(i) = matched {
+            let matched_end = queue[i].end();
+            queue.drain(..=i);
+            drift = matched_end as i64 - new_end;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, feature = "apply"))]
+mod test {
+    use std::io::Cursor;
+
+    use crate::test_util::Rng;
+    use crate::{apply_chunked, generate_chunked, generate_chunked_with_options, DiffOptions};
+
+    #[test]
+    fn insertion_stays_in_sync() {
+        let old = Rng::new(1).bytes(64 * 1024);
+        let mut new = old.clone();
+        new.splice(1000..1000, Rng::new(2).bytes(777));
+        new.splice(40_000..40_100, Rng::new(3).bytes(10));
+
+        let mut cdc = Vec::new();
+        let options = DiffOptions::new()
+            .chunk_size(4096)
+            .content_defined_chunking(true);
+        generate_chunked_with_options(&mut &old[..], &mut &new[..], &mut cdc, &options, |_| {})
+            .unwrap();
+        let mut fixed = Vec::new();
+        generate_chunked(&mut &old[..], &mut &new[..], &mut fixed, 4096, |_| {}).unwrap();
+
+        let mut out = Vec::new();
+        apply_chunked(&mut Cursor::new(&old), &mut out, &mut &cdc[..]).unwrap();
+        assert_eq!(out, new);
+        // Fixed chunking misaligns every chunk after the insertion. Patches are meant to be
+        // compressed, so compare what's left once runs of zeros are squeezed out.
+        let nonzero = |patch: &[u8]| patch.iter().filter(|&&b| b != 0).count();
+        assert!(
+            nonzero(&cdc) * 2 < nonzero(&fixed),
+            "{} vs {}",
+            nonzero(&cdc),
+            nonzero(&fixed)
+        );
+    }
+}
@@ -0,0 +1,269 @@
+//! Signing patches with a chain of certificates, so they can be checked against a few trusted root
+//! keys while the keys that sign patches change.
+//!
+//! The trailer is the magic number, the number of certificates (u8), the certificates, the ID of
+//! the key that signed the patch, the signature, and the size of the patch (u64). Every byte string
+//! in it is prefixed with its length as a u16. A certificate is the ID of the key it was issued by,
+//! the ID and public key it certifies, and the issuer's signature over `DDELTACT` followed by the
+//! first three of those. Patches are signed as they are, and can't start with `DDELTACT`, so a
+//! signature for one can't be passed off as the other.
+//!
+//! No particular signature scheme is used; signing and verifying are done by callbacks, e.g. with
+//! Ed25519.
+
+use std::io::Write;
+
+use crate::patch::{PatchError, Result};
+use crate::{apply_chunked, OldSource};
+
+/// Magic number at the start of the trailer.
+const SIGNATURE_MAGIC: &[u8; 8] = b"DDELTACS";
+/// What the signed part of a certificate starts with.
+const CERTIFICATE_MAGIC: &[u8; 8] = b"DDELTACT";
+
+/// A public key, and the ID it is referred to by.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct PublicKey {
+    pub id: Vec<u8>,
+    pub key: Vec<u8>,
+}
+
+/// A key, signed by the key with the ID `issuer`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Certificate {
+    pub key: PublicKey,
+    pub issuer: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl Certificate {
+    /// Certifies `key` with the key `issuer`, whose signature over the bytes it is given is
+    /// returned by `sign`.
+    pub fn issue(
+        key: PublicKey,
+        issuer: impl Into<Vec<u8>>,
+        sign: impl FnOnce(&[u8]) -> Vec<u8>,
+    ) -> Result<Self> {
+        let mut certificate = Certificate {
+            key,
+            issuer: issuer.into(),
+            signature: Vec::new(),
+        };
+        certificate.signature = sign(&certificate.signed_bytes()?);
+        Ok(certificate)
+    }
+
+    /// What the issuer signs.
+    fn signed_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = CERTIFICATE_MAGIC.to_vec();
+        for part in [&self.issuer, &self.key.id, &self.key.key] {
+            put(&mut bytes, part)?;
+        }
+        Ok(bytes)
+    }
+}
+
+/// Appends `bytes`, prefixed with their length.
+fn put(out: &mut Vec<u8>, bytes: &[u8]) -> Result<()> {
+    let len = u16::try_from(bytes.len()).map_err(|_| {
+        PatchError::Internal("Keys and signatures must be shorter than 64KiB".into())
+    })?;
+    out.extend_from_slice(&len.to_be_bytes());
+    out.extend_from_slice(bytes);
+    Ok(())
+}
+
+/// Takes the next byte string from the trailer.
+fn take<'a>(data: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = u16::from_be_bytes(data.get(..2)?.try_into().unwrap()) as usize;
+    let bytes = data.get(2..2 + len)?;
+    *data = &data[2 + len..];
+    Some(bytes)
+}
+
+fn bad(reason: &str) -> PatchError {
+    PatchError::BadSignature(reason.into())
+}
+
+/// Write a signature trailer for `patch`, to be appended to it, so [`verify_chain`] can check it.
+///
+/// `key_id` is the ID of the key that `sign` signs with, and `chain` the certificates that lead
+/// to it from a trusted root key, starting with the one a root key issued. It may be empty if the
+/// patch is signed with a root key. The trailer starts with the magic number `DDELTACS`, which
+/// chunked appliers only skip with [`Trailing::Block`][crate::Trailing::Block], so apply the patch
+/// with [`apply_verified_chain`].
+pub fn sign_chain(
+    patch: &[u8],
+    chain: &[Certificate],
+    key_id: &[u8],
+    sign: impl FnOnce(&[u8]) -> Vec<u8>,
+    out: &mut impl Write,
+) -> Result<()> {
+    let count = u8::try_from(chain.len())
+        .map_err(|_| PatchError::Internal("A chain can have at most 255 certificates".into()))?;
+    let mut trailer = SIGNATURE_MAGIC.to_vec();
+    trailer.push(count);
+    for certificate in chain {
+        for part in [
+            &certificate.issuer,
+            &certificate.key.id,
+            &certificate.key.key,
+            &certificate.signature,
+        ] {
+            put(&mut trailer, part)?;
+        }
+    }
+    put(&mut trailer, key_id)?;
+    put(&mut trailer, &sign(patch))?;
+    trailer.extend_from_slice(&(patch.len() as u64).to_be_bytes());
+    out.write_all(&trailer)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Check a patch followed by a trailer from [`sign_chain`], and return the size of the patch
+/// without the trailer.
+///
+/// The first certificate has to be issued by one of `trust_roots`, each one after that by the key
+/// of the one before, and the patch signed by the key of the last one, or by a root key if there
+/// are no certificates. `verify` is given a public key, the signed bytes and the signature.
+/// Certificates don't expire, so a compromised key is dealt with by no longer trusting the root
+/// that (indirectly) certified it.
+pub fn verify_chain(
+    data: &[u8],
+    trust_roots: &[PublicKey],
+    verify: impl Fn(&[u8], &[u8], &[u8]) -> bool,
+) -> Result<usize> {
+    let footer = data.len().checked_sub(8).ok_or_else(|| bad("missing"))?;
+    let len = u64::from_be_bytes(data[footer..].try_into().unwrap());
+    let len = usize::try_from(len)
+        .ok()
+        .filter(|&len| len <= footer && data[len..].starts_with(SIGNATURE_MAGIC))
+        .ok_or_else(|| bad("missing"))?;
+    let (patch, mut trailer) = (&data[..len], &data[len + SIGNATURE_MAGIC.len()..footer]);
+    let truncated = || bad("truncated");
+    let (&count, rest) = trailer.split_first().ok_or_else(truncated)?;
+    trailer = rest;
+    let mut chain = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let mut next = || take(&mut trailer).map(<[u8]>::to_vec).ok_or_else(truncated);
+        chain.push(Certificate {
+            issuer: next()?,
+            key: PublicKey {
+                id: next()?,
+                key: next()?,
+            },
+            signature: next()?,
+        });
+    }
+    let key_id = take(&mut trailer).ok_or_else(truncated)?;
+    let signature = take(&mut trailer).ok_or_else(truncated)?;
+    if !trailer.is_empty() {
+        return Err(bad("trailing data"));
+    }
+
+    let first_issuer = chain.first().map_or(key_id, |first| &first.issuer[..]);
+    let mut current = trust_roots
+        .iter()
+        .find(|root| root.id == first_issuer)
+        .ok_or_else(|| bad("not issued by a trusted root"))?;
+    for certificate in &chain {
+        if certificate.issuer != current.id
+            || !verify(
+                &current.key,
+                &certificate.signed_bytes()?,
+                &certificate.signature,
+            )
+        {
+            return Err(bad("broken certificate chain"));
+        }
+        current = &certificate.key;
+    }
+    if current.id != key_id || !verify(&current.key, patch, signature) {
+        return Err(bad("doesn't match the patch"));
+    }
+    Ok(len)
+}
+
+/// Apply a patch that is followed by a trailer from [`sign_chain`], after checking it with
+/// [`verify_chain`]. This accepts the same patches as [`apply_chunked`].
+pub fn apply_verified_chain(
+    trust_roots: &[PublicKey],
+    verify: impl Fn(&[u8], &[u8], &[u8]) -> bool,
+    old: &mut impl OldSource,
+    new: &mut impl Write,
+    data: &[u8],
+) -> Result<()> {
+    let len = verify_chain(data, trust_roots, verify)?;
+    apply_chunked(old, new, &mut &data[..len])
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::{apply_verified_chain, sign_chain, verify_chain, Certificate, PublicKey};
+    use crate::spec::{encode_entry, encode_header, Terminator};
+    use crate::PatchError;
+
+    /// Not a real signature scheme: the public key is the secret, and signatures depend on both it
+    /// and the content
+    fn sign(key: &[u8], data: &[u8]) -> Vec<u8> {
+        vec![data
+            .iter()
+            .fold(key[0], |a, b| a.wrapping_mul(31).wrapping_add(*b))]
+    }
+
+    fn verify(key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+        sign(key, data) == signature
+    }
+
+    #[test]
+    fn chain() {
+        let key = |id: &[u8], secret| PublicKey {
+            id: id.to_vec(),
+            key: vec![secret],
+        };
+        let (root, intermediate, signer) = (key(b"root", 1), key(b"2026", 2), key(b"build", 3));
+        let chain = [
+            Certificate::issue(intermediate.clone(), "root", |d| sign(&[1], d)).unwrap(),
+            Certificate::issue(signer, "2026", |d| sign(&[2], d)).unwrap(),
+        ];
+        // "hi" as extra data
+        let patch = [
+            encode_header(2),
+            encode_entry(b"", b"hi", 0),
+            Terminator::BYTES.to_vec(),
+        ]
+        .concat();
+        let mut signed = patch.clone();
+        sign_chain(&patch, &chain, b"build", |d| sign(&[3], d), &mut signed).unwrap();
+
+        let mut new = Vec::new();
+        let roots = [root.clone()];
+        apply_verified_chain(&roots, verify, &mut Cursor::new(b""), &mut new, &signed).unwrap();
+        assert_eq!(new, b"hi");
+
+        let rejected = |data: &[u8], roots: &[PublicKey]| {
+            matches!(
+                verify_chain(data, roots, verify),
+                Err(PatchError::BadSignature(_))
+            )
+        };
+        assert!(rejected(&signed, &[intermediate.clone(), key(b"root", 9)]));
+        let mut tampered = signed.clone();
+        tampered[patch.len() - 25] = b'o';
+        assert!(rejected(&tampered, &roots));
+        // The intermediate key can't be swapped for another one
+        let mut tampered = signed.clone();
+        let at = signed.windows(3).position(|w| w == [0, 1, 2]).unwrap();
+        tampered[at + 2] = 9;
+        assert!(rejected(&tampered, &roots));
+        assert!(rejected(&patch, &roots));
+
+        // Signed with the root key directly
+        let mut signed = patch.clone();
+        sign_chain(&patch, &[], b"root", |d| sign(&[1], d), &mut signed).unwrap();
+        assert_eq!(verify_chain(&signed, &roots, verify).unwrap(), patch.len());
+    }
+}
@@ -0,0 +1,257 @@
+//! Checksums of old and new files, with a choice of algorithm.
+
+use std::fmt;
+use std::io::{self, Write};
+
+/// A checksum algorithm, identified by a single byte when stored.
+///
+/// All algorithms are known regardless of the enabled features, so stored checksums can always be
+/// parsed (and skipped). Computing them requires the corresponding feature: `crc32`, `xxhash64` or
+/// `blake3`. CRC32 is the cheapest, and fine for catching corruption on small devices; xxHash64 is
+/// faster on 64-bit machines and less likely to collide; BLAKE3 is cryptographic, so it also
+/// protects against deliberately modified files (as long as the checksum itself can be trusted).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ChecksumAlgorithm {
+    Crc32,
+    XxHash64,
+    Blake3,
+}
+
+impl ChecksumAlgorithm {
+    /// The byte identifying this algorithm.
+    pub fn id(self) -> u8 {
+        match self {
+            ChecksumAlgorithm::Crc32 => 1,
+            ChecksumAlgorithm::XxHash64 => 2,
+            ChecksumAlgorithm::Blake3 => 3,
+        }
+    }
+
+    /// The algorithm identified by `id`, if there is one.
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(ChecksumAlgorithm::Crc32),
+            2 => Some(ChecksumAlgorithm::XxHash64),
+            3 => Some(ChecksumAlgorithm::Blake3),
+            _ => None,
+        }
+    }
+
+    /// The name of this algorithm in the text form of a [`Checksum`].
+    pub fn name(self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Crc32 => "crc32",
+            ChecksumAlgorithm::XxHash64 => "xxhash64",
+            ChecksumAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    /// The length of the checksum in bytes.
+    pub fn digest_len(self) -> usize {
+        match self {
+            ChecksumAlgorithm::Crc32 => 4,
+            ChecksumAlgorithm::XxHash64 => 8,
+            ChecksumAlgorithm::Blake3 => 32,
+        }
+    }
+
+    /// Whether this algorithm was compiled in, see [`ChecksumHasher::new`].
+    pub fn is_supported(self) -> bool {
+        match self {
+            ChecksumAlgorithm::Crc32 => cfg!(feature = "crc32"),
+            ChecksumAlgorithm::XxHash64 => cfg!(feature = "xxhash64"),
+            ChecksumAlgorithm::Blake3 => cfg!(feature = "blake3"),
+        }
+    }
+}
+
+/// A checksum together with the algorithm that created it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Checksum {
+    pub algorithm: ChecksumAlgorithm,
+    pub digest: Box<[u8]>,
+}
+
+impl Checksum {
+    /// Computes the checksum of `data`, or returns [`None`] if the algorithm isn't supported.
+    pub fn of(algorithm: ChecksumAlgorithm, data: &[u8]) -> Option<Self> {
+        let mut hasher = ChecksumHasher::new(algorithm)?;
+        hasher.update(data);
+        Some(hasher.finish())
+    }
+
+    /// The stored form: the algorithm's id, followed by the digest.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(1 + self.digest.len());
+        out.push(self.algorithm.id());
+        out.extend_from_slice(&self.digest);
+        out
+    }
+
+    /// Parses the stored form from the start of `input`, returning the checksum and the rest of the
+    /// input. Fails if the algorithm is unknown, or `input` is too short.
+    pub fn from_bytes(input: &[u8]) -> Option<(Self, &[u8])> {
+        let (&id, rest) = input.split_first()?;
+        let algorithm = ChecksumAlgorithm::from_id(id)?;
+        if rest.len() < algorithm.digest_len() {
+            return None;
+        }
+        let (digest, rest) = rest.split_at(algorithm.digest_len());
+        let digest = digest.into();
+        Some((Checksum { algorithm, digest }, rest))
+    }
+
+    /// Parses the text form, as written by [`Display`][fmt::Display]: the algorithm's name, a colon,
+    /// and the digest in hexadecimal, e.g. `crc32:0d4a1185`.
+    pub fn parse(text: &str) -> Option<Self> {
+        let (name, hex) = text.split_once(':')?;
+        let algorithm = [
+            ChecksumAlgorithm::Crc32,
+            ChecksumAlgorithm::XxHash64,
+            ChecksumAlgorithm::Blake3,
+        ]
+        .into_iter()
+        .find(|a| a.name() == name)?;
+        if !hex.bytes().all(|b| b.is_ascii_hexdigit()) || hex.len() != algorithm.digest_len() * 2 {
+            return None;
+        }
+        let digest = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+            .collect::<Option<_>>()?;
+        Some(Checksum { algorithm, digest })
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:", self.algorithm.name())?;
+        self.digest.iter().try_for_each(|b| write!(f, "{:02x}", b))
+    }
+}
+
+enum State {
+    #[cfg(feature = "crc32")]
+    Crc32(crc32fast::Hasher),
+    #[cfg(feature = "xxhash64")]
+    XxHash64(Box<xxhash_rust::xxh64::Xxh64>),
+    #[cfg(feature = "blake3")]
+    Blake3(Box<blake3::Hasher>),
+}
+
+/// Computes a [`Checksum`] incrementally.
+///
+/// This implements [`Write`], so it can be fed with [`io::copy`], or combined with the file being
+/// written with a tee-like writer.
+pub struct ChecksumHasher {
+    state: State,
+}
+
+impl ChecksumHasher {
+    /// Starts a checksum, or returns [`None`] if the algorithm isn't supported.
+    pub fn new(algorithm: ChecksumAlgorithm) -> Option<Self> {
+        let state = match algorithm {
+            #[cfg(feature = "crc32")]
+            ChecksumAlgorithm::Crc32 => Some(State::Crc32(crc32fast::Hasher::new())),
+            #[cfg(feature = "xxhash64")]
+            ChecksumAlgorithm::XxHash64 => {
+                Some(State::XxHash64(Box::new(xxhash_rust::xxh64::Xxh64::new(0))))
+            }
+            #[cfg(feature = "blake3")]
+            ChecksumAlgorithm::Blake3 => Some(State::Blake3(Box::new(blake3::Hasher::new()))),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        };
+        state.map(|state| ChecksumHasher { state })
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match &mut self.state {
+            #[cfg(feature = "crc32")]
+            State::Crc32(h) => h.update(data),
+            #[cfg(feature = "xxhash64")]
+            State::XxHash64(h) => h.update(data),
+            #[cfg(feature = "blake3")]
+            State::Blake3(h) => {
+                h.update(data);
+            }
+            #[allow(unreachable_patterns)]
+            _ => {
+                let _ = data;
+                unreachable!()
+            }
+        }
+    }
+
+    pub fn finish(self) -> Checksum {
+        match self.state {
+            #[cfg(feature = "crc32")]
+            State::Crc32(h) => Checksum {
+                algorithm: ChecksumAlgorithm::Crc32,
+                digest: h.finalize().to_be_bytes().into(),
+            },
+            #[cfg(feature = "xxhash64")]
+            State::XxHash64(h) => Checksum {
+                algorithm: ChecksumAlgorithm::XxHash64,
+                digest: h.digest().to_be_bytes().into(),
+            },
+            #[cfg(feature = "blake3")]
+            State::Blake3(h) => Checksum {
+                algorithm: ChecksumAlgorithm::Blake3,
+                digest: h.finalize().as_bytes()[..].into(),
+            },
+            #[allow(unreachable_patterns)]
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Write for ChecksumHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Checksum, ChecksumAlgorithm};
+
+    #[test]
+    fn checksums() {
+        for algorithm in [
+            ChecksumAlgorithm::Crc32,
+            ChecksumAlgorithm::XxHash64,
+            ChecksumAlgorithm::Blake3,
+        ] {
+            assert_eq!(ChecksumAlgorithm::from_id(algorithm.id()), Some(algorithm));
+            let Some(checksum) = Checksum::of(algorithm, b"hello world") else {
+                assert!(!algorithm.is_supported());
+                continue;
+            };
+            assert_eq!(checksum.digest.len(), algorithm.digest_len());
+            assert_ne!(
+                Checksum::of(algorithm, b"hello world!"),
+                Some(checksum.clone())
+            );
+            assert_eq!(
+                Checksum::parse(&checksum.to_string()),
+                Some(checksum.clone())
+            );
+            let bytes = [checksum.to_bytes(), b"rest".to_vec()].concat();
+            assert_eq!(Checksum::from_bytes(&bytes), Some((checksum, &b"rest"[..])));
+        }
+        assert_eq!(Checksum::from_bytes(&[1, 0, 0]), None);
+        assert_eq!(Checksum::from_bytes(&[0, 0, 0, 0, 0]), None);
+        assert_eq!(
+            Checksum::parse("crc32:0d4a1185").unwrap().digest[..],
+            [13, 74, 17, 133]
+        );
+        assert_eq!(Checksum::parse("crc32:0d4a118"), None);
+        assert_eq!(Checksum::parse("crc32:0d4a11+5"), None);
+    }
+}
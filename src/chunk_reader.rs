@@ -0,0 +1,208 @@
+//! Applying a chunked patch one chunk at a time, with the old and new file picked by the
+//! application for each chunk.
+
+use std::io::{self, Read, Write};
+
+use crate::entries::{Event, PatchEntries};
+use crate::patch::{apply_chunk, copy_bytes, Buffers, Result};
+use crate::units::{Len, NewOffset};
+use crate::{OldSource, Trailing, WouldBlock, COPY_FLAG};
+
+/// Reads a chunked patch, and hands out its chunks to be applied one at a time.
+///
+/// [`apply_chunked`][crate::apply_chunked] writes the whole new file to a single writer. When the
+/// application manages its own storage, e.g. an A/B slot that is written in erase blocks, or a
+/// flash layout that isn't a single file, it can instead ask for each chunk with
+/// [`next_chunk`][Self::next_chunk], see where it goes in the new file and how long it is, and
+/// apply it to wherever that is. Chunks that are already there, e.g. after an interrupted update,
+/// can be skipped. This is the counterpart of [`ChunkedPatchWriter`][crate::ChunkedPatchWriter],
+/// but reads any patch that `apply_chunked` does.
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use ddelta::{ChunkedPatchReader, ChunkedPatchWriter, DiffOptions};
+///
+/// let (old, new) = (b"first block second block", b"second block, first block");
+/// let mut writer = ChunkedPatchWriter::new(Vec::new(), DiffOptions::new());
+/// writer.push(&old[12..], 12, &new[..13])?;
+/// writer.push(&old[..12], 0, &new[13..])?;
+/// let patch = writer.finish()?;
+///
+/// // Each chunk into a buffer of its own
+/// let mut reader = ChunkedPatchReader::new(&patch[..]);
+/// let mut blocks = Vec::new();
+/// while let Some(chunk) = reader.next_chunk()? {
+///     let mut block = Vec::new();
+///     chunk.apply(&mut std::io::Cursor::new(old), &mut block)?;
+///     blocks.push(block);
+/// }
+/// assert_eq!(blocks, [&new[..13], &new[13..]]);
+/// # Ok(())
+/// # }
+/// ```
+pub struct ChunkedPatchReader<R> {
+    entries: PatchEntries<R>,
+    bufs: Buffers,
+    /// Whether the entries of the last chunk are yet to be read.
+    pending: bool,
+}
+
+impl<R: Read> ChunkedPatchReader<R> {
+    /// Creates a reader of the chunked (or plain) patch `patch`.
+    pub fn new(patch: R) -> Self {
+        ChunkedPatchReader {
+            entries: PatchEntries::new(patch, true, WouldBlock::default()),
+            bufs: Buffers::default(),
+            pending: false,
+        }
+    }
+
+    /// Sets what may follow the last chunk, see [`Patcher::trailing`][crate::Patcher::trailing].
+    pub fn trailing(mut self, trailing: Trailing) -> Self {
+        self.entries = self.entries.trailing(trailing);
+        self
+    }
+
+    /// Limits the buffers used while applying chunks, see
+    /// [`Patcher::max_memory`][crate::Patcher::max_memory].
+    pub fn max_memory(mut self, bytes: impl Into<Option<u64>>) -> Self {
+        self.bufs.set_limit(bytes.into());
+        self
+    }
+
+    /// Reads the header of the next chunk, or returns [`None`] at the end of the patch. If the
+    /// previous chunk wasn't applied, it is skipped first.
+    ///
+    /// After an error, the position in the patch is lost, and the reader can't be used any more.
+    pub fn next_chunk(&mut self) -> Result<Option<PatchChunk<'_, R>>> {
+        if self.pending {
+            self.skip_entries()?;
+        }
+        let Some(Event::Header(header)) = self.entries.next()? else {
+            return Ok(None);
+        };
+        self.pending = true;
+        Ok(Some(PatchChunk {
+            start: self.entries.chunk_start(),
+            len: Len::new(header.new_file_size.get()),
+            reader: self,
+        }))
+    }
+
+    /// Reads the rest of the current chunk without applying it.
+    fn skip_entries(&mut self) -> Result<()> {
+        let copies = self.entries.copies();
+        while let Some(Event::Entry(entry)) = self.entries.next()? {
+            if !(copies && entry.diff.get() & COPY_FLAG != 0) {
+                for len in [entry.diff.get(), entry.extra.get()] {
+                    copy_bytes(self.entries.patch(), &mut io::sink(), len, &mut self.bufs)?;
+                }
+            }
+        }
+        self.pending = false;
+        Ok(())
+    }
+}
+
+/// A chunk of a patch, returned by [`ChunkedPatchReader::next_chunk`].
+pub struct PatchChunk<'a, R> {
+    start: NewOffset,
+    len: Len,
+    reader: &'a mut ChunkedPatchReader<R>,
+}
+
+impl<R: Read> PatchChunk<'_, R> {
+    /// Where the chunk starts in the new file. The chunk was made to read the old file from the
+    /// same offset.
+    pub fn start(&self) -> NewOffset {
+        self.start
+    }
+
+    /// How much of the new file the chunk writes.
+    pub fn len(&self) -> Len {
+        self.len
+    }
+
+    /// Whether the chunk writes nothing, which only happens in a patch of an empty file.
+    pub fn is_empty(&self) -> bool {
+        self.len.get() == 0
+    }
+
+    /// Applies the chunk, writing [`len`][Self::len] bytes to `new`. `old` is the whole old file,
+    /// which is read from [`start`][Self::start] on.
+    pub fn apply(self, old: &mut impl OldSource, new: &mut impl Write) -> Result<()> {
+        let reader = self.reader;
+        old.seek_to(self.start.in_old().get())?;
+        apply_chunk(
+            old,
+            new,
+            &mut reader.entries,
+            &mut reader.bufs,
+            &mut |_, _| Ok(()),
+        )?;
+        reader.pending = false;
+        Ok(())
+    }
+
+    /// Reads past the chunk without applying it. This also happens if the chunk is dropped, but
+    /// then the patch is only read by the next call to
+    /// [`next_chunk`][ChunkedPatchReader::next_chunk].
+    pub fn skip(self) -> Result<()> {
+        self.reader.skip_entries()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::ChunkedPatchReader;
+    use crate::spec::{encode_entry, encode_header, Terminator};
+    use crate::{Len, NewOffset};
+
+    fn chunk(new: &[u8], diff: usize, seek: i64) -> Vec<u8> {
+        [
+            encode_header(new.len() as u64),
+            encode_entry(&vec![0; diff], &new[diff..], seek),
+            Terminator::BYTES.to_vec(),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn chunks() {
+        let old = b"abcdefgh";
+        // "ab" from the old file and "XY", then "efgh" from where the chunk starts, then "ij"
+        let patch = [
+            chunk(b"abXY", 2, 2),
+            chunk(b"efgh", 4, 0),
+            chunk(b"ij", 0, 0),
+        ]
+        .concat();
+        let mut reader = ChunkedPatchReader::new(&patch[..]);
+        let mut new = Vec::new();
+        let first = reader.next_chunk().unwrap().unwrap();
+        assert_eq!(
+            (first.start(), first.len()),
+            (NewOffset::new(0), Len::new(4))
+        );
+        first.apply(&mut Cursor::new(old), &mut new).unwrap();
+        assert_eq!(new, b"abXY");
+        // Dropped, and skipped by the next call
+        let second = reader.next_chunk().unwrap().unwrap();
+        assert_eq!(second.start(), NewOffset::new(4));
+        let third = reader.next_chunk().unwrap().unwrap();
+        assert_eq!(third.start(), NewOffset::new(8));
+        third.apply(&mut Cursor::new(old), &mut new).unwrap();
+        assert_eq!(new, b"abXYij");
+        assert!(reader.next_chunk().unwrap().is_none());
+
+        let mut reader = ChunkedPatchReader::new(&patch[..]);
+        reader.next_chunk().unwrap().unwrap().skip().unwrap();
+        let mut new = Vec::new();
+        let second = reader.next_chunk().unwrap().unwrap();
+        second.apply(&mut Cursor::new(old), &mut new).unwrap();
+        // The seek at the end of the first chunk doesn't carry over
+        assert_eq!(new, b"efgh");
+    }
+}
@@ -0,0 +1,187 @@
+//! Generating a chunked patch from chunks that the application pairs up itself.
+
+use std::io::Write;
+
+use crate::diff::{generate_chunk, write_ending, write_header, Budget, DiffError, Result, Sorted};
+use crate::split::Splitter;
+use crate::summary::Recorder;
+use crate::{DiffOptions, State, Summary};
+
+/// Writes a chunked patch, one chunk of the new file at a time, each diffed against whichever part
+/// of the old file the application picks for it.
+///
+/// [`generate_chunked`][crate::generate_chunked] reads both files in order and diffs each chunk of
+/// the new file against the same range of the old one. When the application knows better where a
+/// chunk's data comes from, e.g. because both files are stored in its own layout of extents or
+/// pages, it can [`push`][Self::push] the pairs itself. The result is applied with
+/// [`apply_chunked`][crate::apply_chunked] like any other chunked patch.
+///
+/// Each chunk is limited to 2^31-1 bytes of old and new data. Of the [`DiffOptions`], the ones
+/// that affect single patches are used, as well as [`max_patch_size`][DiffOptions::max_patch_size]
+/// and [`max_run`][DiffOptions::max_run]. After an error, the patch is incomplete.
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use ddelta::{ChunkedPatchWriter, DiffOptions};
+///
+/// let (old, new) = (b"first block second block", b"second block, first block");
+/// let mut writer = ChunkedPatchWriter::new(Vec::new(), DiffOptions::new());
+/// writer.push(&old[12..], 12, &new[..13])?;
+/// writer.push(&old[..12], 0, &new[13..])?;
+/// let patch = writer.finish()?;
+///
+/// let mut patched = Vec::new();
+/// ddelta::apply_chunked(&mut std::io::Cursor::new(old), &mut patched, &mut &patch[..])?;
+/// assert_eq!(patched, new);
+/// # Ok(())
+/// # }
+/// ```
+pub struct ChunkedPatchWriter<W, P = fn(State)> {
+    patch: Splitter<Budget<W>>,
+    options: DiffOptions,
+    sorted: Sorted,
+    recorder: Recorder,
+    /// How much of the new file has been pushed.
+    new_len: u64,
+    progress: P,
+}
+
+impl<W: Write> ChunkedPatchWriter<W> {
+    /// Creates a writer of a patch to `patch`, which doesn't report progress.
+    pub fn new(patch: W, options: DiffOptions) -> Self {
+        ChunkedPatchWriter {
+            patch: Splitter::new(Budget::new(patch, options.max_patch_size), options.max_run),
+            options,
+            sorted: Sorted::default(),
+            recorder: Recorder::new(),
+            new_len: 0,
+            progress: |_| {},
+        }
+    }
+}
+
+impl<W: Write, P: FnMut(State)> ChunkedPatchWriter<W, P> {
+    /// Sets the function that will be called periodically with progress updates. The progress
+    /// counts from the start of the new file, and [`State::Done`] is reported by
+    /// [`finish`][Self::finish].
+    pub fn with_progress<Q: FnMut(State)>(self, progress: Q) -> ChunkedPatchWriter<W, Q> {
+        ChunkedPatchWriter {
+            patch: self.patch,
+            options: self.options,
+            sorted: self.sorted,
+            recorder: self.recorder,
+            new_len: self.new_len,
+            progress,
+        }
+    }
+
+    /// Appends `new` to the new file, diffed against `old`, which is at `old_offset` in the old
+    /// file. Empty chunks are skipped.
+    ///
+    /// If `old` is the same as in the last call, its suffix array is reused.
+    pub fn push(&mut self, old: &[u8], old_offset: u64, new: &[u8]) -> Result<()> {
+        if new.is_empty() {
+            return Ok(());
+        }
+        // The applier has the old file at the start of the chunk in the new file
+        let seek = i64::try_from(old_offset)
+            .ok()
+            .and_then(|offset| offset.checked_sub(i64::try_from(self.new_len).ok()?))
+            .ok_or_else(|| DiffError::Internal("The offset must be below 2^63".into()))?;
+        self.recorder.inputs(old, new);
+        let (recorder, progress, completed) = (&self.recorder, &mut self.progress, self.new_len);
+        let result = generate_chunk(
+            old,
+            new,
+            &mut self.patch,
+            seek,
+            self.new_len,
+            &self.options,
+            &mut self.sorted,
+            |state| {
+                recorder.observe(state);
+                match state {
+                    State::Working(bytes) => progress(State::Working(bytes + completed)),
+                    other => progress(other),
+                }
+            },
+        );
+        self.patch.inner.finish(result)?;
+        self.new_len += new.len() as u64;
+        Ok(())
+    }
+
+    /// Appends a chunk of an existing patch as it is, which writes `new_len` bytes of the new file.
+    /// The chunk has to have been made for where the new file is now.
+    #[cfg(feature = "apply")]
+    pub(crate) fn push_raw(&mut self, chunk: &[u8], new_len: u64) -> Result<()> {
+        let result = self.patch.write_all(chunk).map_err(DiffError::from);
+        self.patch.inner.finish(result)?;
+        self.new_len += new_len;
+        Ok(())
+    }
+
+    /// How much of the new file has been pushed so far.
+    pub fn new_len(&self) -> u64 {
+        self.new_len
+    }
+
+    /// Ends the patch, and returns the writer it was written to.
+    pub fn finish(mut self) -> Result<W> {
+        if self.new_len == 0 {
+            // An empty new file still needs a chunk
+            let result =
+                write_header(&mut self.patch, 0).and_then(|()| write_ending(&mut self.patch));
+            self.patch.inner.finish(result)?;
+        }
+        self.patch.flush()?;
+        let summary = self.recorder.finish();
+        (self.progress)(State::Done(Summary {
+            patch_bytes: self.patch.inner.written,
+            ..summary
+        }));
+        Ok(self.patch.inner.inner)
+    }
+}
+
+#[cfg(all(test, feature = "apply"))]
+mod test {
+    use std::io::Cursor;
+
+    use crate::{apply_chunked, histogram, ChunkedPatchWriter, DiffOptions, State};
+
+    #[test]
+    fn pushed() {
+        let old: Vec<u8> = (0..4000u32).map(|i| (i * 7 % 251) as u8).collect();
+        // The two halves of the old file, swapped and with some changes
+        let mut new = [&old[2000..], &old[..2000]].concat();
+        new[100] ^= 1;
+        new[3000] ^= 1;
+        let mut done = None;
+        let mut writer =
+            ChunkedPatchWriter::new(Vec::new(), DiffOptions::new()).with_progress(|state| {
+                if let State::Done(summary) = state {
+                    done = Some(summary);
+                }
+            });
+        writer.push(&old[2000..], 2000, &new[..2000]).unwrap();
+        writer.push(&old[..2000], 0, &new[2000..]).unwrap();
+        writer.push(&old, 0, &[]).unwrap();
+        assert_eq!(writer.new_len(), 4000);
+        let patch = writer.finish().unwrap();
+        assert_eq!(done.unwrap().patch_bytes, patch.len() as u64);
+        // Both chunks were found in the old file
+        assert!(histogram(&patch).unwrap().extra_bytes < 10);
+
+        let mut patched = Vec::new();
+        apply_chunked(&mut Cursor::new(&old), &mut patched, &mut &patch[..]).unwrap();
+        assert_eq!(patched, new);
+
+        let patch = ChunkedPatchWriter::new(Vec::new(), DiffOptions::new())
+            .finish()
+            .unwrap();
+        let mut patched = Vec::new();
+        apply_chunked(&mut Cursor::new(&old), &mut patched, &mut &patch[..]).unwrap();
+        assert!(patched.is_empty());
+    }
+}
@@ -0,0 +1,121 @@
+//! Copies from earlier in the new file, for data that the new file repeats, but the old file
+//! doesn't have.
+//!
+//! Whatever the differ would store as-is is looked up in a hash index of the new file written so
+//! far, and repeated parts are replaced with copy entries, like LZ77 does. Only
+//! [`DDELTA_MAGIC_V2`][crate::DDELTA_MAGIC_V2] patches may contain those.
+
+use std::io::Write;
+use std::ops::Range;
+
+use zerocopy::AsBytes;
+
+use crate::be::{I64, U64};
+use crate::diff::{match_len, Result};
+use crate::lowmem::{hash, Index, MIN_MATCH};
+use crate::{EntryHeader, COPY_FLAG, COPY_WINDOW};
+
+/// Copies shorter than this save less than the entries they need.
+const MIN_COPY: usize = 64;
+/// Only every this many positions of the new file are indexed.
+const STEP: usize = 4;
+
+/// Finds repeated data in the new file, and writes entries using it.
+pub(crate) struct Copies {
+    index: Index,
+    /// Everything before this has been added to the index.
+    indexed: usize,
+}
+
+impl Copies {
+    pub(crate) fn new(new_len: usize) -> Result<Self> {
+        // Only the window can be copied from, so there's no point in indexing more than that
+        let positions = new_len.min(COPY_WINDOW as usize) / STEP;
+        Ok(Copies {
+            // Keep the table at most half full
+            index: Index::new(positions * 2 * 4)?,
+            indexed: 0,
+        })
+    }
+
+    /// Returns the distance and length of the longest copy found for `new[pos..end]`.
+    fn find(&mut self, new: &[u8], pos: usize, end: usize) -> Option<(usize, usize)> {
+        let indexable = new.len().saturating_sub(MIN_MATCH - 1);
+        let start = self.indexed.next_multiple_of(STEP);
+        for i in (start..pos.min(indexable)).step_by(STEP) {
+            self.index.insert(hash(&new[i..]), i);
+        }
+        self.indexed = self.indexed.max(pos.min(indexable));
+        if pos >= indexable {
+            return None;
+        }
+        let source = self
+            .index
+            .get(hash(&new[pos..]))
+            .filter(|&source| source < pos && pos - source <= COPY_WINDOW as usize)?;
+        // Copies may overlap with what they write, so this compares within `new` directly
+        let len = match_len(&new[source..], &new[pos..end]);
+        (len >= MIN_COPY).then_some((pos - source, len))
+    }
+
+    /// Writes an entry that adds `diff_new` to `diff_old`, then stores `new[extra]`, then seeks by
+    /// `seek`. The stored data is split up into several entries wherever it can be copied instead.
+    pub(crate) fn write_entry(
+        &mut self,
+        patch: &mut impl Write,
+        diff_new: &[u8],
+        diff_old: &[u8],
+        new: &[u8],
+        extra: Range<usize>,
+        seek: i64,
+    ) -> Result<()> {
+        // Each literal is followed by a copy, except for the last one
+        let mut pieces = Vec::new();
+        let mut literal = extra.start;
+        let mut pos = extra.start;
+        while pos < extra.end {
+            match self.find(new, pos, extra.end) {
+                Some((distance, len)) => {
+                    pieces.push((literal..pos, Some((distance, len))));
+                    pos += len;
+                    literal = pos;
+                }
+                None => pos += 1,
+            }
+        }
+        pieces.push((literal..extra.end, None));
+
+        for (i, (literal, copy)) in pieces.into_iter().enumerate() {
+            let diff = if i == 0 { diff_new.len() } else { 0 };
+            let seek = if copy.is_none() { seek } else { 0 };
+            // An empty entry would be read as the end of the patch, and does nothing anyway
+            if diff != 0 || !literal.is_empty() || seek != 0 {
+                patch.write_all(
+                    EntryHeader {
+                        diff: U64::new(diff as u64),
+                        extra: U64::new(literal.len() as u64),
+                        seek: I64::new(seek),
+                    }
+                    .as_bytes(),
+                )?;
+            }
+            if i == 0 {
+                for (n, o) in diff_new.iter().zip(diff_old) {
+                    patch.write_all(&[n.wrapping_sub(*o)])?;
+                }
+            }
+            patch.write_all(&new[literal])?;
+            if let Some((distance, len)) = copy {
+                patch.write_all(
+                    EntryHeader {
+                        diff: U64::new(COPY_FLAG | len as u64),
+                        extra: U64::new(distance as u64),
+                        seek: I64::new(0),
+                    }
+                    .as_bytes(),
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,242 @@
+//! Broken variants of a valid patch, as a corpus of inputs that appliers have to reject.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::spec::{self, SpecError, Terminator, ENTRY_HEADER_SIZE, PATCH_HEADER_SIZE};
+use crate::{ExtendedHeader, COPY_FLAG, DDELTA_MAGIC, DDELTA_MAGIC_V2};
+
+/// A variant of a patch, made by [`mutations`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mutation {
+    /// What was changed, e.g. `chunk0-entry1-extra-max`. Unique within one call.
+    pub name: String,
+    pub patch: Vec<u8>,
+}
+
+/// Where the parts of a patch are.
+struct Layout {
+    ext: bool,
+    chunks: Vec<ChunkLayout>,
+}
+
+struct ChunkLayout {
+    header: usize,
+    new_file_size: u64,
+    v2: bool,
+    /// The offset of each entry, and whether it's a copy.
+    entries: Vec<(usize, bool)>,
+    terminator: usize,
+}
+
+fn u64_at(patch: &[u8], at: usize) -> u64 {
+    u64::from_be_bytes(patch[at..at + 8].try_into().unwrap())
+}
+
+/// Finds the parts of a patch that [`spec::parse_chunked`] accepted.
+fn layout(patch: &[u8]) -> Layout {
+    let ext = ExtendedHeader::matches(patch);
+    let mut pos = if ext { PATCH_HEADER_SIZE } else { 0 };
+    let mut chunks = Vec::new();
+    while pos < patch.len() {
+        let header = pos;
+        let v2 = patch[pos..].starts_with(DDELTA_MAGIC_V2);
+        let new_file_size = u64_at(patch, pos + 8);
+        pos += PATCH_HEADER_SIZE;
+        let mut entries = Vec::new();
+        loop {
+            let (diff, extra) = (u64_at(patch, pos), u64_at(patch, pos + 8));
+            let seek = u64_at(patch, pos + 16) as i64;
+            if Terminator::matches(diff, extra, seek) {
+                break;
+            }
+            let copy = v2 && diff & COPY_FLAG != 0;
+            entries.push((pos, copy));
+            pos += ENTRY_HEADER_SIZE;
+            if !copy {
+                pos += (diff + extra) as usize;
+            }
+        }
+        chunks.push(ChunkLayout {
+            header,
+            new_file_size,
+            v2,
+            entries,
+            terminator: pos,
+        });
+        pos += ENTRY_HEADER_SIZE;
+    }
+    Layout { ext, chunks }
+}
+
+/// Makes broken variants of `patch`, a valid chunked or plain patch without anything after it.
+///
+/// The variants are cut off in each kind of field, have their magic numbers changed, or have sizes,
+/// lengths and seeks that are off by one or as large as they can be. To keep the corpus small, only
+/// the first and last chunk, and in those only the first and last entry, are changed, so a patch
+/// with a few of each is as good as a large one. Each variant is rejected by
+/// [`apply_chunked`][crate::apply_chunked] with the old file the patch was made for. Plain
+/// appliers, and the original ddelta tool, ignore data after the first chunk, so they may accept
+/// variants that only change later chunks.
+pub fn mutations(patch: &[u8]) -> Result<Vec<Mutation>, SpecError> {
+    spec::parse_chunked(patch)?;
+    let layout = layout(patch);
+    let mut out = Vec::new();
+    let mut push = |name: String, patch: Vec<u8>| out.push(Mutation { name, patch });
+    let truncated = |at: usize| patch[..at].to_vec();
+    let with = |at: usize, bytes: &[u8]| {
+        let mut patch = patch.to_vec();
+        patch[at..at + bytes.len()].copy_from_slice(bytes);
+        patch
+    };
+
+    if layout.ext {
+        push("ext-magic-flipped".into(), with(7, b"x"));
+        push("ext-truncated".into(), truncated(PATCH_HEADER_SIZE / 2));
+    }
+    let last_chunk = layout.chunks.len().saturating_sub(1);
+    for (c, chunk) in layout.chunks.iter().enumerate() {
+        if c != 0 && c != last_chunk {
+            continue;
+        }
+        let name = |what: &str| format!("chunk{c}-{what}");
+        let at = chunk.header;
+        push(name("magic-flipped"), with(at + 7, b"x"));
+        if chunk.v2 && chunk.entries.iter().any(|&(_, copy)| copy) {
+            // Copies are read as entries of enormous length
+            push(name("magic-v1"), with(at, DDELTA_MAGIC));
+        }
+        push(
+            name("header-truncated"),
+            truncated(at + PATCH_HEADER_SIZE / 2),
+        );
+        let size = chunk.new_file_size;
+        push(name("size-max"), with(at + 8, &u64::MAX.to_be_bytes()));
+        push(
+            name("size-plus-one"),
+            with(at + 8, &(size + 1).to_be_bytes()),
+        );
+        if size > 0 {
+            push(
+                name("size-minus-one"),
+                with(at + 8, &(size - 1).to_be_bytes()),
+            );
+        }
+        push(name("terminator-missing"), truncated(chunk.terminator));
+        push(
+            name("terminator-truncated"),
+            truncated(chunk.terminator + ENTRY_HEADER_SIZE / 2),
+        );
+
+        let last_entry = chunk.entries.len().saturating_sub(1);
+        for (e, &(at, copy)) in chunk.entries.iter().enumerate() {
+            if e != 0 && e != last_entry {
+                continue;
+            }
+            let name = |what: &str| format!("chunk{c}-entry{e}-{what}");
+            push(
+                name("header-truncated"),
+                truncated(at + ENTRY_HEADER_SIZE / 2),
+            );
+            let (diff, extra) = (u64_at(patch, at), u64_at(patch, at + 8));
+            if copy {
+                let len = diff & !COPY_FLAG;
+                push(name("distance-zero"), with(at + 8, &0u64.to_be_bytes()));
+                push(name("distance-max"), with(at + 8, &u64::MAX.to_be_bytes()));
+                push(
+                    name("len-plus-one"),
+                    with(at, &(COPY_FLAG | (len + 1)).to_be_bytes()),
+                );
+            } else {
+                if diff + extra > 0 {
+                    let data = at + ENTRY_HEADER_SIZE;
+                    push(
+                        name("data-truncated"),
+                        truncated(data + ((diff + extra) / 2) as usize),
+                    );
+                }
+                push(name("diff-max"), with(at, &(u64::MAX >> 1).to_be_bytes()));
+                push(name("diff-plus-one"), with(at, &(diff + 1).to_be_bytes()));
+                push(name("extra-max"), with(at + 8, &u64::MAX.to_be_bytes()));
+                push(
+                    name("extra-plus-one"),
+                    with(at + 8, &(extra + 1).to_be_bytes()),
+                );
+            }
+            push(name("seek-min"), with(at + 16, &i64::MIN.to_be_bytes()));
+            push(name("seek-max"), with(at + 16, &i64::MAX.to_be_bytes()));
+        }
+    }
+    if !layout.chunks.is_empty() {
+        push("trailing-garbage".into(), [patch, b"garbage"].concat());
+    }
+    Ok(out)
+}
+
+/// Writes the [`mutations`] of `patch` to `dir`, one file each, named after the mutation, along with
+/// `patch` itself as `valid`. Returns how many files were written.
+///
+/// This makes a ready-made corpus for fuzzers, and a set of negative tests for other
+/// implementations of the format.
+pub fn write_corpus(patch: &[u8], dir: &Path) -> io::Result<usize> {
+    let mutations = mutations(patch).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    fs::create_dir_all(dir)?;
+    fs::write(dir.join("valid"), patch)?;
+    for mutation in &mutations {
+        fs::write(dir.join(&mutation.name), &mutation.patch)?;
+    }
+    Ok(mutations.len() + 1)
+}
+
+#[cfg(all(test, feature = "apply"))]
+mod test {
+    use std::collections::HashSet;
+    use std::fs;
+    use std::io::Cursor;
+
+    use super::{mutations, write_corpus};
+    use crate::apply_chunked;
+    use crate::spec::{
+        encode_copy, encode_entry, encode_ext_header, encode_header, encode_header_v2, Terminator,
+    };
+
+    #[test]
+    fn corpus() {
+        let old = b"abcdef";
+        // "ab!" and a seek back, then a chunk with "de" and a copy of it
+        let patch = [
+            encode_ext_header(*b"test", 0),
+            encode_header(3),
+            encode_entry(&[0, 0], b"!", -2),
+            Terminator::BYTES.to_vec(),
+            encode_header_v2(4),
+            encode_entry(&[0, 0], b"", 0),
+            encode_copy(2, 2, 0),
+            Terminator::BYTES.to_vec(),
+        ]
+        .concat();
+        let mut new = Vec::new();
+        apply_chunked(&mut Cursor::new(old), &mut new, &mut &patch[..]).unwrap();
+        assert_eq!(new, b"ab!dede");
+
+        let mutations = mutations(&patch).unwrap();
+        let names: HashSet<_> = mutations.iter().map(|m| &m.name).collect();
+        assert_eq!(names.len(), mutations.len());
+        for mutation in &mutations {
+            let result = apply_chunked(
+                &mut Cursor::new(old),
+                &mut Vec::new(),
+                &mut &mutation.patch[..],
+            );
+            assert!(result.is_err(), "{} was accepted", mutation.name);
+        }
+        assert!(super::mutations(&patch[..20]).is_err());
+
+        let dir = std::env::temp_dir().join(format!("ddelta-corpus-{}", std::process::id()));
+        let written = write_corpus(&patch, &dir).unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), written);
+        assert_eq!(fs::read(dir.join("valid")).unwrap(), patch);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,41 +1,601 @@
 use std::cmp::Ordering;
-use std::i32;
-use std::io::{ErrorKind, Read, Write};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::TryReserveError;
+use std::fmt;
+use std::hash::Hasher;
+use std::io::{self, Read, Write};
+use std::mem::size_of;
+use std::ops::Range;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use byteorder::WriteBytesExt;
-#[cfg(not(feature = "c"))]
-use divsufsort as cdivsufsort;
-use thiserror::Error;
-use zerocopy::{AsBytes, I64, U64};
+use zerocopy::AsBytes;
 
-use crate::{EntryHeader, PatchHeader, State, DDELTA_MAGIC};
+use crate::be::{I64, U64};
+use crate::copy::Copies;
+use crate::entry_writer::{Entry, EntryWriter};
+use crate::io::read_up_to;
+use crate::spec::Terminator;
+use crate::split::Splitter;
+use crate::summary::Recorder;
+use crate::{
+    cdc, estimate_similarity, EntryHeader, PatchHeader, State, WouldBlock, COPY_FLAG, DDELTA_MAGIC,
+    DDELTA_MAGIC_V2, DDELTA_MAGIC_WORDS,
+};
 
 type Str = Box<str>;
-type Result<T> = std::result::Result<T, DiffError>;
+pub(crate) type Result<T> = std::result::Result<T, DiffError>;
 
-#[derive(Error, Debug)]
+#[derive(Debug)]
 pub enum DiffError {
-    #[error("io error while generating patch {0}")]
-    Io(#[from] std::io::Error),
-    #[error("patch generation failed: {0}")]
+    Io(std::io::Error),
     Internal(Str),
+    PatchTooLarge {
+        written: u64,
+    },
+    /// A buffer couldn't be allocated. Nothing is allocated in a way that aborts the process when
+    /// the memory isn't available, so this can be handled, e.g. by retrying with a smaller chunk
+    /// size, or see [`DiffOptions::shrink_on_oom`].
+    OutOfMemory(TryReserveError),
 }
 
-const FUZZ: isize = 8;
+impl fmt::Display for DiffError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiffError::Io(e) => write!(f, "io error while generating patch {e}"),
+            DiffError::Internal(e) => write!(f, "patch generation failed: {e}"),
+            DiffError::PatchTooLarge { written } => write!(
+                f,
+                "patch would exceed the maximum size after {written} bytes"
+            ),
+            DiffError::OutOfMemory(_) => f.write_str("ran out of memory while generating patch"),
+        }
+    }
+}
+
+impl std::error::Error for DiffError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DiffError::Io(e) => Some(e),
+            DiffError::OutOfMemory(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for DiffError {
+    fn from(e: std::io::Error) -> Self {
+        DiffError::Io(e)
+    }
+}
+
+impl From<TryReserveError> for DiffError {
+    fn from(e: TryReserveError) -> Self {
+        DiffError::OutOfMemory(e)
+    }
+}
+
+const FUZZ: i64 = 8;
+/// Diff data shorter than this is always kept with [`DiffOptions::prefer_literals`].
+const MIN_ENTROPY_LEN: usize = 256;
+/// How much [`read_into`] reads first, before doubling.
+const READ_STEP: usize = 64 * 1024;
+/// Chunks aren't made any smaller than this when retrying after running out of memory.
+const MIN_RETRY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Options for [`generate_chunked_with_options`] and [`Differ`].
+#[derive(Clone, Debug, Default)]
+pub struct DiffOptions {
+    chunk_size: Option<usize>,
+    pub(crate) old_window: Option<usize>,
+    new_window: Option<usize>,
+    content_defined: bool,
+    pub(crate) max_patch_size: Option<u64>,
+    min_similarity: Option<f32>,
+    shrink_on_oom: bool,
+    copy_from_new: bool,
+    align: Option<usize>,
+    words: bool,
+    pub(crate) max_run: Option<u64>,
+    max_work: Option<u64>,
+    prefer_literals: bool,
+    backend: SortBackend,
+    pipelined: bool,
+    pub(crate) would_block: WouldBlock,
+    hints: Vec<Hint>,
+    masks: Vec<Range<u64>>,
+}
+
+/// A part of the new file that the caller knows to have come from the old file, for
+/// [`DiffOptions::hints`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Hint {
+    /// Where the part starts in the old file.
+    pub old: u64,
+    /// Where the part starts in the new file.
+    pub new: u64,
+    /// How long the part is in the new file.
+    pub len: u64,
+}
+
+impl DiffOptions {
+    /// The default options: no chunk size limit, and fixed-size chunks.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the maximum chunk size, see the `chunk_sizes` parameter of [`generate_chunked`].
+    pub fn chunk_size(mut self, chunk_size: impl Into<Option<usize>>) -> Self {
+        self.chunk_size = chunk_size.into();
+        self
+    }
+
+    /// Sets the size of the chunks the new file is split into, instead of the chunk size.
+    ///
+    /// Together with [`old_window`][Self::old_window], this allows diffing each chunk of the new
+    /// file against a larger (or smaller) part of the old file. Ignored with
+    /// [`content_defined_chunking`][Self::content_defined_chunking].
+    pub fn new_window(mut self, size: impl Into<Option<usize>>) -> Self {
+        self.new_window = size.into();
+        self
+    }
+
+    /// Sets how much of the old file each chunk of the new file is diffed against, instead of the
+    /// chunk size.
+    ///
+    /// The window is centered on the chunk of the new file, so data that moved by up to half the
+    /// difference between the windows is still found. When diffing a small new file against a
+    /// large old one, a large old window finds matches a same-sized chunk would miss, without
+    /// sorting the whole old file at once. The applier doesn't need to know about the windows, as
+    /// the patch seeks to the start of each one. Memory use is about 5 times the old window plus
+    /// the new window. Ignored with [`content_defined_chunking`][Self::content_defined_chunking],
+    /// and [`shrink_on_oom`][Self::shrink_on_oom] has no effect if the windows differ.
+    pub fn old_window(mut self, size: impl Into<Option<usize>>) -> Self {
+        self.old_window = size.into();
+        self
+    }
+
+    /// Chooses chunk boundaries by content (using FastCDC) instead of at fixed offsets.
+    ///
+    /// With fixed-size chunks, a single insertion or deletion shifts the contents of every
+    /// following chunk, and matches crossing a chunk boundary are lost. Content-defined boundaries
+    /// are found at the same data in both files, so the chunks stay aligned. Chunks will then
+    /// average a quarter of the chunk size, and up to twice the chunk size of the old file is kept
+    /// in memory while looking for matching boundaries.
+    pub fn content_defined_chunking(mut self, enabled: bool) -> Self {
+        self.content_defined = enabled;
+        self
+    }
+
+    /// Aborts generation with [`DiffError::PatchTooLarge`] once the patch would grow beyond `bytes`.
+    ///
+    /// This is meant for callers that fall back to distributing the whole new file if the patch
+    /// isn't small enough to be worth it, so they don't spend time finishing a useless patch.
+    /// Whatever was written to the patch up to that point should be discarded.
+    pub fn max_patch_size(mut self, bytes: impl Into<Option<u64>>) -> Self {
+        self.max_patch_size = bytes.into();
+        self
+    }
+
+    /// Stores chunks as-is, without diffing them, if their estimated similarity to the old data is
+    /// below `threshold`.
+    ///
+    /// See [`estimate_similarity`][crate::estimate_similarity]. Diffing unrelated data is slow, and
+    /// produces a patch that's no better than the new data itself. A threshold around 0.05 skips
+    /// those chunks while keeping anything that shares a noticeable amount of content.
+    pub fn min_similarity(mut self, threshold: impl Into<Option<f32>>) -> Self {
+        self.min_similarity = threshold.into();
+        self
+    }
+
+    /// Retries with half the chunk size when running out of memory, instead of failing.
+    ///
+    /// The buffers and the suffix array are allocated fallibly, and if that fails, the chunk that
+    /// was being worked on is split in half, down to a minimum of 64 KiB. The smaller size is kept
+    /// for the rest of the patch. This keeps long-running generations from failing near the end
+    /// when memory is tight, at the cost of a somewhat larger patch. Only fixed-size chunks are
+    /// retried; with [`content_defined_chunking`][Self::content_defined_chunking] the error is
+    /// returned as-is.
+    pub fn shrink_on_oom(mut self, enabled: bool) -> Self {
+        self.shrink_on_oom = enabled;
+        self
+    }
+
+    /// Lets the patch copy data from earlier in the new file, where the new file repeats itself.
+    ///
+    /// Without this, data that's in the new file more than once, but not in the old file, is
+    /// stored in the patch every time. With it, repeats up to 4 MiB back are replaced by references
+    /// to the earlier copy. Compressing the patch achieves a similar effect for repeats that are
+    /// close together, but not beyond the window of the compressor. This uses a newer version of the
+    /// format, which the original ddelta tool and older versions of this crate can't apply.
+    pub fn copy_from_new(mut self, enabled: bool) -> Self {
+        self.copy_from_new = enabled;
+        self
+    }
+
+    /// Makes every entry start, and switch from diff to extra data, at a multiple of `bytes` in the
+    /// new file.
+    ///
+    /// Appliers writing to flash memory or doing DMA can then write whole pages, e.g. by buffering
+    /// the diff and extra data of an entry separately. Boundaries are moved by storing the bytes
+    /// around them as extra data, so the patch grows by up to `bytes` per entry. Offsets count from
+    /// the start of each chunk, so the chunk size should be a multiple of `bytes`, and
+    /// [`content_defined_chunking`][Self::content_defined_chunking] should be off. This disables
+    /// [`copy_from_new`][Self::copy_from_new].
+    pub fn align(mut self, bytes: impl Into<Option<usize>>) -> Self {
+        self.align = bytes.into().filter(|&bytes| bytes > 1);
+        self
+    }
+
+    /// Stores the diff data as the difference of 32-bit words instead of single bytes.
+    ///
+    /// Machine code is full of relative addresses, which all change by the same amount when code
+    /// is inserted between them and their target. Subtracting whole little-endian words turns each
+    /// of them into the same 4 bytes of diff data, where subtracting bytes gives different ones
+    /// depending on the carries, so the patch compresses better. For other data it makes little
+    /// difference either way. Words are counted from the start of each entry's diff data. This uses
+    /// a newer version of the format, which the original ddelta tool and older versions of this
+    /// crate can't apply, and disables [`copy_from_new`][Self::copy_from_new].
+    pub fn word_diff(mut self, enabled: bool) -> Self {
+        self.words = enabled;
+        self
+    }
+
+    /// Splits entries so none has more than `bytes` of diff or extra data.
+    ///
+    /// Appliers that handle an entry at a time, e.g. with fixed-size buffers on embedded devices,
+    /// then never have to deal with a multi-gigabyte entry. Each split adds an entry header of 24
+    /// bytes to the patch. Copies from the new file are split as well.
+    pub fn max_run(mut self, bytes: impl Into<Option<u64>>) -> Self {
+        self.max_run = bytes.into().filter(|&bytes| bytes > 0);
+        self
+    }
+
+    /// Stores the rest of a chunk as-is once searching it has compared more than `bytes` bytes.
+    ///
+    /// Some inputs, e.g. long runs that match in many places, make the search slow down to
+    /// quadratic time in the size of a chunk. When diffing untrusted files, this bounds the time
+    /// spent on each chunk, at the cost of a larger patch for the inputs that hit the limit.
+    /// Ordinary inputs take a few times the chunk size, so a budget of 100 times the chunk size
+    /// only affects pathological ones. Sorting isn't counted, as it takes about the same time for
+    /// any input of the same size.
+    pub fn max_work(mut self, bytes: impl Into<Option<u64>>) -> Self {
+        self.max_work = bytes.into();
+        self
+    }
+
+    /// Stores the new data as extra data instead of diff data, where the diff data wouldn't
+    /// compress any better.
+    ///
+    /// Diff data is usually mostly zeros, but where the old data only roughly matches, it can end
+    /// up more random than the new data itself, e.g. text compared to slightly different text.
+    /// This estimates how well both would compress from how often each byte value occurs, and
+    /// stores whichever is better, which also saves the applier from reading the old data there.
+    /// Only regions of at least 256 bytes are checked, as the estimate isn't reliable below that.
+    pub fn prefer_literals(mut self, enabled: bool) -> Self {
+        self.prefer_literals = enabled;
+        self
+    }
+
+    /// Sets which implementation of divsufsort builds the suffix arrays.
+    pub fn backend(mut self, backend: SortBackend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Reads the next chunk while the current one is diffed on another thread.
+    ///
+    /// Without this, reading and diffing take turns, which leaves the CPU idle while reading from
+    /// slow storage such as spinning disks or network mounts. The inputs, the patch and the
+    /// progress callback are still only used from the calling thread. This keeps a second chunk of
+    /// both files in memory, and up to 1 MiB of the patch. Only fixed-size chunks are pipelined,
+    /// and [`shrink_on_oom`][Self::shrink_on_oom] has no effect then.
+    pub fn pipelined(mut self, enabled: bool) -> Self {
+        self.pipelined = enabled;
+        self
+    }
+
+    /// Tells the search where parts of the new file came from, e.g. from packaging metadata that
+    /// says a file in an image moved from one offset to another.
+    ///
+    /// Within the new range of a hint, its place in the old file is tried along with the best
+    /// match the suffix array finds, and used if it matches at least as many bytes. Data that
+    /// occurs many times, e.g. padding, or files that were in the old image more than once, then
+    /// continues where the hint says instead of wherever the search lands, which keeps entries
+    /// long and the diff data zero. A chunk starting inside a hint also starts out expecting that
+    /// offset. Offsets are into the whole files, so hints work with any chunking, but only help
+    /// where the old data they point to is part of the chunk being diffed, see
+    /// [`old_window`][Self::old_window] and [`ChunkedPatchWriter`][crate::ChunkedPatchWriter].
+    /// Wrong hints only cost a bit of time.
+    pub fn hints(mut self, hints: impl Into<Vec<Hint>>) -> Self {
+        self.hints = hints.into();
+        self
+    }
+
+    /// Marks parts of the new file whose bytes don't matter, because the caller overwrites them
+    /// after applying, e.g. checksums and timestamps in firmware images.
+    ///
+    /// Such fields differ in every build, and their random bytes break up the matches around them,
+    /// which costs more than the few bytes themselves. Here they're searched as if they had the
+    /// bytes of the old file at the same offset, and stored as zeros in the diff or extra data,
+    /// which compress to next to nothing. The new file then has unspecified bytes in these ranges
+    /// until the caller fills them in. Offsets are into the whole new file, as with
+    /// [`hints`][Self::hints]. This disables [`copy_from_new`][Self::copy_from_new].
+    pub fn mask(mut self, ranges: impl Into<Vec<Range<u64>>>) -> Self {
+        self.masks = ranges.into();
+        self
+    }
+
+    /// Sets what to do when the inputs are non-blocking and not ready yet.
+    pub fn would_block(mut self, would_block: WouldBlock) -> Self {
+        self.would_block = would_block;
+        self
+    }
+
+    pub(crate) fn max_chunk_size(&self) -> usize {
+        self.chunk_size
+            .unwrap_or(i32::MAX as usize - 1)
+            .min(i32::MAX as usize - 1)
+    }
+
+    /// The sizes of the old and new windows, which default to the chunk size.
+    fn windows(&self) -> (usize, usize) {
+        let size = |window: Option<usize>| {
+            window.map_or(self.max_chunk_size(), |size| {
+                size.clamp(1, i32::MAX as usize - 1)
+            })
+        };
+        (size(self.old_window), size(self.new_window))
+    }
+}
+
+/// Counts the bytes written to the patch, and refuses to write more than the budget allows.
+pub(crate) struct Budget<W> {
+    pub(crate) inner: W,
+    pub(crate) written: u64,
+    limit: u64,
+    exceeded: bool,
+}
+
+impl<W: Write> Budget<W> {
+    pub(crate) fn new(inner: W, limit: Option<u64>) -> Self {
+        Budget {
+            inner,
+            written: 0,
+            limit: limit.unwrap_or(u64::MAX),
+            exceeded: false,
+        }
+    }
+
+    /// Turns the I/O error caused by running out of budget into [`DiffError::PatchTooLarge`].
+    pub(crate) fn finish<T>(&self, result: Result<T>) -> Result<T> {
+        match result {
+            Err(DiffError::Io(_)) if self.exceeded => Err(DiffError::PatchTooLarge {
+                written: self.written,
+            }),
+            other => other,
+        }
+    }
+}
+
+impl<W: Write> Write for Budget<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written.saturating_add(buf.len() as u64) > self.limit {
+            self.exceeded = true;
+            return Err(io::Error::other("patch size limit exceeded"));
+        }
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Buffers that can be kept around between generations.
+#[derive(Default)]
+pub(crate) struct Scratch {
+    old: Vec<u8>,
+    new: Vec<u8>,
+    pub(crate) sorted: Sorted,
+}
+
+/// A suffix array, and the old data it was built for.
+#[derive(Default)]
+pub(crate) struct Sorted {
+    array: Vec<i32>,
+    /// The length and hash of the old data, if `array` is its suffix array.
+    built_for: Option<(usize, u64)>,
+    /// Whether `array` is for the old data of every chunk until this is reset, so it isn't hashed
+    /// again for each of them.
+    pinned: bool,
+}
+
+/// A patch generator that keeps its options, progress callback and buffers between runs.
+///
+/// The free functions set all of these up again for every call. When generating many patches, for
+/// example in a long-running service, a `Differ` avoids reallocating the (potentially multiple
+/// gigabytes of) buffers each time. The suffix array of the last old data is kept as well, so
+/// diffing against the same old data again (e.g. when retrying, or when creating patches to several
+/// new versions) skips sorting it. Whether the old data is the same is checked by its hash.
+pub struct Differ<P = fn(State)> {
+    options: DiffOptions,
+    progress: P,
+    scratch: Scratch,
+}
+
+impl Differ {
+    /// Creates a differ with the given options, which doesn't report progress.
+    pub fn new(options: DiffOptions) -> Self {
+        Differ {
+            options,
+            progress: |_| {},
+            scratch: Scratch::default(),
+        }
+    }
+}
+
+impl<P: FnMut(State)> Differ<P> {
+    /// Sets the function that will be called periodically with progress updates.
+    pub fn with_progress<Q: FnMut(State)>(self, progress: Q) -> Differ<Q> {
+        Differ {
+            options: self.options,
+            progress,
+            scratch: self.scratch,
+        }
+    }
+
+    /// The options this differ was created with.
+    pub fn options(&self) -> &DiffOptions {
+        &self.options
+    }
+
+    /// Generate a ddelta patch, see [`generate`].
+    pub fn run(&mut self, old: &[u8], new: &[u8], patch: &mut impl Write) -> Result<()> {
+        let recorder = Recorder::new();
+        recorder.inputs(old, new);
+        let mut patch = Splitter::new(
+            Budget::new(recorder.patch_writer(patch), self.options.max_patch_size),
+            self.options.max_run,
+        );
+        let result = generate_chunk(
+            old,
+            new,
+            &mut patch,
+            0,
+            0,
+            &self.options,
+            &mut self.scratch.sorted,
+            |state| {
+                recorder.observe(state);
+                (self.progress)(state)
+            },
+        );
+        patch.inner.finish(result)?;
+        (self.progress)(State::Done(recorder.finish()));
+        Ok(())
+    }
+
+    /// Generate a chunked ddelta patch, see [`generate_chunked_with_options`].
+    pub fn run_chunked(
+        &mut self,
+        old: &mut impl Read,
+        new: &mut impl Read,
+        patch: &mut impl Write,
+    ) -> Result<()> {
+        chunked(
+            old,
+            new,
+            patch,
+            &self.options,
+            &mut self.scratch,
+            &mut self.progress,
+        )
+    }
+
+    /// Generate a chunked ddelta patch from a new file that's read as a stream, see
+    /// [`generate_streaming`].
+    pub fn run_streaming(
+        &mut self,
+        old: &[u8],
+        new: &mut impl Read,
+        patch: &mut impl Write,
+    ) -> Result<()> {
+        streaming(
+            old,
+            new,
+            patch,
+            &self.options,
+            &mut self.scratch,
+            &mut self.progress,
+        )
+    }
+
+    /// Writes the suffix array of `data` to `index`, so a later differ can
+    /// [load][Self::load_index] it instead of sorting `data` again.
+    ///
+    /// This is meant for release trains: after diffing to a new version, save the index of the new
+    /// version, and load it when that version is the old one of the next patch. If the suffix array
+    /// kept from the last run was built for `data`, it's written as is, otherwise `data` is sorted
+    /// now, and the result is kept for the next run. The index takes four bytes per byte of `data`.
+    pub fn save_index(&mut self, data: &[u8], index: &mut impl Write) -> Result<()> {
+        if data.len() > i32::MAX as usize {
+            return Err(DiffError::Internal(
+                format!("The filesize must not be larger than {} bytes", i32::MAX).into(),
+            ));
+        }
+        let sorted = &mut self.scratch.sorted;
+        let key = sort_key(data);
+        if sorted.built_for != Some(key) {
+            sorted.built_for = None;
+            sorted.array.clear();
+            try_resize(&mut sorted.array, data.len() + 1)?;
+            sort(
+                data,
+                &mut sorted.array[..data.len()],
+                self.options.backend,
+                &mut self.progress,
+            );
+            sorted.built_for = Some(key);
+        }
+        index.write_all(INDEX_MAGIC)?;
+        index.write_all(U64::new(data.len() as u64).as_bytes())?;
+        index.write_all(U64::new(index_hash(data)).as_bytes())?;
+        let mut buf = Vec::with_capacity(64 * 1024);
+        for chunk in sorted.array[..data.len()].chunks(16 * 1024) {
+            buf.clear();
+            buf.extend(chunk.iter().flat_map(|i| i.to_be_bytes()));
+            index.write_all(&buf)?;
+        }
+        Ok(())
+    }
 
-fn read_up_to(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
-    let mut bytes_read = 0;
-    while bytes_read < buf.len() {
-        match reader.read(&mut buf[bytes_read..]) {
-            Ok(0) => break,
-            Ok(n) => {
-                bytes_read += n;
+    /// Reads an index written by [`save_index`][Self::save_index], so the next run against `data`
+    /// as the old file doesn't sort it.
+    ///
+    /// Fails if the index was saved for different data, which is checked by its length and hash. A
+    /// corrupted index can make patches larger, but never wrong, as every match is compared with
+    /// the data itself.
+    pub fn load_index(&mut self, data: &[u8], index: &mut impl Read) -> Result<()> {
+        let mut header = [0; INDEX_MAGIC.len() + 16];
+        index.read_exact(&mut header)?;
+        if !header.starts_with(INDEX_MAGIC) {
+            return Err(DiffError::Internal("Invalid index magic number".into()));
+        }
+        let len = u64::from_be_bytes(header[8..16].try_into().unwrap());
+        let hash = u64::from_be_bytes(header[16..].try_into().unwrap());
+        if len != data.len() as u64 || hash != index_hash(data) {
+            return Err(DiffError::Internal(
+                "The index was saved for different data".into(),
+            ));
+        }
+        let sorted = &mut self.scratch.sorted;
+        sorted.built_for = None;
+        sorted.array.clear();
+        try_resize(&mut sorted.array, data.len() + 1)?;
+        let mut buf = vec![0; 64 * 1024];
+        for chunk in sorted.array[..data.len()].chunks_mut(buf.len() / 4) {
+            let buf = &mut buf[..chunk.len() * 4];
+            index.read_exact(buf)?;
+            for (i, bytes) in chunk.iter_mut().zip(buf.chunks_exact(4)) {
+                *i = i32::from_be_bytes(bytes.try_into().unwrap());
+                // Out of range positions would panic when searching
+                if *i < 0 || *i as usize >= data.len() {
+                    return Err(DiffError::Internal("Invalid position in the index".into()));
+                }
             }
-            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
-            Err(e) => return Err(e.into()),
         }
+        sorted.built_for = Some(sort_key(data));
+        Ok(())
+    }
+
+    /// Frees the buffers kept around from previous runs.
+    pub fn shrink(&mut self) {
+        self.scratch = Scratch::default();
     }
-    Ok(bytes_read)
 }
 
 /// Generate a ddelta patch. This does **not** have a limit of 2^31-1 bytes, unlike [`generate`].
@@ -57,19 +617,109 @@ pub fn generate_chunked(
     new_f: &mut impl Read,
     patch_f: &mut impl Write,
     chunk_sizes: impl Into<Option<usize>>,
-    mut progress: impl FnMut(State) -> (),
+    progress: impl FnMut(State),
+) -> Result<()> {
+    let options = DiffOptions::new().chunk_size(chunk_sizes);
+    generate_chunked_with_options(old_f, new_f, patch_f, &options, progress)
+}
+
+/// Generate a chunked ddelta patch, as [`generate_chunked`] does, configured by [`DiffOptions`].
+///
+/// The output must be applied with [`apply_chunked`][crate::apply_chunked].
+pub fn generate_chunked_with_options(
+    old_f: &mut impl Read,
+    new_f: &mut impl Read,
+    patch_f: &mut impl Write,
+    options: &DiffOptions,
+    progress: impl FnMut(State),
+) -> Result<()> {
+    chunked(
+        old_f,
+        new_f,
+        patch_f,
+        options,
+        &mut Scratch::default(),
+        progress,
+    )
+}
+
+fn chunked(
+    old_f: &mut impl Read,
+    new_f: &mut impl Read,
+    patch_f: &mut impl Write,
+    options: &DiffOptions,
+    scratch: &mut Scratch,
+    mut progress: impl FnMut(State),
+) -> Result<()> {
+    let recorder = Recorder::new();
+    let mut patch_f = Splitter::new(
+        Budget::new(recorder.patch_writer(patch_f), options.max_patch_size),
+        options.max_run,
+    );
+    let result = chunked_unlimited(
+        &mut recorder.old_reader(old_f),
+        &mut recorder.new_reader(new_f),
+        &mut patch_f,
+        options,
+        scratch,
+        |state| {
+            recorder.observe(state);
+            progress(state)
+        },
+    );
+    patch_f.inner.finish(result)?;
+    progress(State::Done(recorder.finish()));
+    Ok(())
+}
+
+fn chunked_unlimited(
+    old_f: &mut impl Read,
+    new_f: &mut impl Read,
+    patch_f: &mut impl Write,
+    options: &DiffOptions,
+    scratch: &mut Scratch,
+    mut progress: impl FnMut(State),
 ) -> Result<()> {
-    let chunk_sizes = chunk_sizes
-        .into()
-        .unwrap_or(i32::MAX as usize - 1)
-        .min(i32::MAX as usize - 1);
-    let mut old_buf = vec![0; chunk_sizes];
-    let mut new_buf = vec![0; chunk_sizes];
+    if options.content_defined {
+        return cdc::generate_chunked(
+            old_f,
+            new_f,
+            patch_f,
+            options,
+            &mut scratch.sorted,
+            progress,
+        );
+    }
+    let (old_window, new_window) = options.windows();
+    if old_window != new_window {
+        return windowed(
+            old_f, new_f, patch_f, options, scratch, old_window, new_window, progress,
+        );
+    }
+    if options.pipelined {
+        return pipelined(old_f, new_f, patch_f, options, scratch, progress);
+    }
+    let Scratch {
+        old: old_buf,
+        new: new_buf,
+        sorted,
+    } = scratch;
+    let mut chunk_sizes = new_window;
+    old_buf.clear();
+    new_buf.clear();
+    while let Err(e) =
+        try_reserve(old_buf, chunk_sizes).and_then(|()| try_reserve(new_buf, chunk_sizes))
+    {
+        chunk_sizes = shrink(options, chunk_sizes, e)?;
+    }
+    // How much is read at a time, which stays the same when the chunks shrink
+    let buf_len = chunk_sizes;
     let mut bytes_completed = 0;
     loop {
         progress(State::Reading);
-        let new_bytes_read = read_up_to(new_f, &mut new_buf)?;
-        let new_buf = &new_buf[..new_bytes_read];
+        new_buf.clear();
+        read_into(new_f, new_buf, buf_len, options.would_block)?;
+        let new_buf = &new_buf[..];
         // Nothing left in new file, so no need to read any more
         if new_buf.is_empty() {
             if bytes_completed == 0 {
@@ -79,267 +729,1569 @@ pub fn generate_chunked(
             break;
         }
 
-        let old_bytes_read = read_up_to(old_f, &mut old_buf)?;
-        let old_buf = &old_buf[..old_bytes_read];
+        old_buf.clear();
+        read_into(old_f, old_buf, buf_len, options.would_block)?;
+        let old_buf = &old_buf[..];
 
-        generate(old_buf, new_buf, patch_f, |d| match d {
-            State::Working(bytes) => progress(State::Working(bytes + bytes_completed)),
-            other => progress(other),
-        })?;
-        bytes_completed += new_bytes_read as u64;
+        // The buffers might be larger than the chunk size after running out of memory. Both files
+        // are split at the same offsets, which keeps the chunks aligned for the applier.
+        let mut start = 0;
+        while start < new_buf.len() {
+            let end = start.saturating_add(chunk_sizes);
+            let new = &new_buf[start..new_buf.len().min(end)];
+            let old = &old_buf[old_buf.len().min(start)..old_buf.len().min(end)];
+            let completed = bytes_completed;
+            let result =
+                generate_chunk(
+                    old,
+                    new,
+                    patch_f,
+                    0,
+                    completed,
+                    options,
+                    sorted,
+                    |d| match d {
+                        State::Working(bytes) => progress(State::Working(bytes + completed)),
+                        other => progress(other),
+                    },
+                );
+            match result {
+                Err(DiffError::OutOfMemory(e)) => chunk_sizes = shrink(options, chunk_sizes, e)?,
+                other => {
+                    other?;
+                    start += new.len();
+                    bytes_completed += new.len() as u64;
+                }
+            }
+        }
     }
     Ok(())
 }
 
-fn write_header(patch: &mut impl Write, len: u64) -> Result<()> {
-    patch
-        .write_all(
-            PatchHeader {
-                magic: *DDELTA_MAGIC,
-                new_file_size: U64::new(len),
+/// Generates a chunked patch where each chunk of `new_window` bytes of the new file is diffed
+/// against `old_window` bytes of the old file around it.
+#[allow(clippy::too_many_arguments)]
+fn windowed(
+    old_f: &mut impl Read,
+    new_f: &mut impl Read,
+    patch_f: &mut impl Write,
+    options: &DiffOptions,
+    scratch: &mut Scratch,
+    old_window: usize,
+    new_window: usize,
+    mut progress: impl FnMut(State),
+) -> Result<()> {
+    let Scratch {
+        old: old_buf,
+        new: new_buf,
+        sorted,
+    } = scratch;
+    new_buf.clear();
+    new_buf.try_reserve(new_window)?;
+    old_buf.clear();
+    // The offset in the old file that `old_buf` starts at
+    let mut old_start = 0;
+    let mut old_eof = false;
+    let mut bytes_completed = 0;
+    loop {
+        progress(State::Reading);
+        new_buf.clear();
+        read_into(new_f, new_buf, new_window, options.would_block)?;
+        let new = &new_buf[..];
+        if new.is_empty() {
+            if bytes_completed == 0 {
+                write_header(patch_f, 0)?;
+                write_ending(patch_f)?;
             }
-            .as_bytes(),
-        )
-        .map_err(|e| e.into())
-}
+            break;
+        }
 
-fn write_ending(patch: &mut impl Write) -> Result<()> {
-    patch
-        .write_all(
-            EntryHeader {
-                diff: Default::default(),
-                extra: Default::default(),
-                seek: Default::default(),
+        let center = bytes_completed + new.len() as u64 / 2;
+        let window_start = center.saturating_sub(old_window as u64 / 2);
+        let window_end = window_start + old_window as u64;
+        // Drop what's before the window, and read up to its end
+        loop {
+            let skip = window_start.saturating_sub(old_start);
+            let skip = skip.min(old_buf.len() as u64) as usize;
+            old_buf.drain(..skip);
+            old_start += skip as u64;
+            let end = old_start + old_buf.len() as u64;
+            if old_eof || end >= window_end {
+                break;
             }
-            .as_bytes(),
-        )
-        .map_err(|e| e.into())
-}
+            let len = old_buf.len();
+            let more = usize::try_from(window_end - end)
+                .unwrap_or(usize::MAX)
+                .min(old_window - len);
+            old_buf.try_reserve(more)?;
+            let read = read_into(old_f, old_buf, len + more, options.would_block)?;
+            old_eof = read < more;
+        }
 
-/// Generate a ddelta patch. This has a limit of 2^31-1 bytes.
-///
-/// Beyond this, use [`generate_chunked`]
-/// to create a patch file with multiple patches. The output is compatible with the original ddelta
-/// tool, but not with bsdiff. Call [`apply`][crate::apply] or
-/// [`apply_chunked`][crate::apply_chunked] to use the created patch file. `progress` is a function
-/// that will be called periodically with progress updates.
-pub fn generate(
-    old: &[u8],
-    new: &[u8],
-    patch: &mut impl Write,
-    mut progress: impl FnMut(State) -> (),
-) -> Result<()> {
-    if !old.len().max(new.len()) < i32::MAX as usize {
-        return Err(DiffError::Internal(
-            format!("The filesize must not be larger than {} bytes", i32::MAX).into(),
-        ));
+        let completed = bytes_completed;
+        generate_chunk(
+            old_buf,
+            new,
+            patch_f,
+            old_start as i64 - bytes_completed as i64,
+            completed,
+            options,
+            sorted,
+            |d| match d {
+                State::Working(bytes) => progress(State::Working(bytes + completed)),
+                other => progress(other),
+            },
+        )?;
+        bytes_completed += new.len() as u64;
     }
-    progress(State::Sorting);
-    write_header(patch, new.len() as u64)?;
-    let mut sorted = cdivsufsort::sort(old).into_parts().1;
-    sorted.push(0);
-    let mut scan = 0;
-    let mut len = 0;
-    let mut pos = 0;
-    let mut lastoffset = 0;
-    let mut lastscan = 0;
-    let mut lastpos = 0;
-    while scan < new.len() as isize {
-        let mut num_less_than_eight = 0;
-        let mut oldscore: isize = 0;
-        scan += len;
-        let mut scsc = scan;
-        // If we come across a large block of data that only differs
-        // by less than 8 bytes, this loop will take a long time to
-        // go past that block of data. We need to track the number of
-        // times we're stuck in the block and break out of it.
-        while scan < new.len() as isize {
-            if scan % 100_00 == 0 {
-                progress(State::Working(scan as u64));
-            }
-            let prev_len = len;
-            let prev_oldscore = oldscore;
-            let prev_pos = pos;
-
-            len = search(
-                &sorted,
-                &old[..old.len().wrapping_sub(1).min(old.len())],
-                &new[scan as usize..],
-                0,
-                old.len(),
-                &mut pos,
-            );
-
-            while scsc < scan + len {
-                if (scsc + lastoffset < old.len() as isize)
-                    && (old[(scsc + lastoffset) as usize] == new[scsc as usize])
-                {
-                    oldscore += 1;
-                }
-                scsc += 1;
-            }
+    Ok(())
+}
 
-            if ((len as isize == oldscore) && (len != 0)) || (len as isize > oldscore + 8) {
-                break;
-            }
+/// How many pieces of the patch the diffing thread may be ahead of the writing one.
+const PIPELINE_DEPTH: usize = 16;
+/// The size of the pieces the patch is passed between threads in.
+const PIPELINE_PIECE: usize = 64 * 1024;
 
-            if (scan + lastoffset < old.len() as isize)
-                && (old[(scan + lastoffset) as usize] == new[scan as usize])
-            {
-                oldscore -= 1;
-            }
+/// What the diffing thread of [`pipelined`] passes back.
+enum Output {
+    Patch(Vec<u8>),
+    State(State),
+    /// The chunk is done, and its buffers can be reused.
+    Done(Result<()>, Vec<u8>, Vec<u8>),
+}
 
-            if prev_len as isize - FUZZ <= len as isize
-                && len <= prev_len
-                && prev_oldscore - FUZZ <= oldscore
-                && oldscore <= prev_oldscore
-                && prev_pos <= pos
-                && pos as isize <= prev_pos as isize + FUZZ
-                && oldscore <= len as isize
-                && len as isize <= oldscore + FUZZ
-            {
-                num_less_than_eight += 1;
-            } else {
-                num_less_than_eight = 0;
-            }
+/// Passes the patch to the writing thread in pieces.
+struct PipeWriter<'a> {
+    tx: &'a mpsc::SyncSender<Output>,
+    buf: Vec<u8>,
+}
 
-            if num_less_than_eight > 100 {
-                break;
-            }
+impl Write for PipeWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= PIPELINE_PIECE {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
 
-            scan += 1;
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            let piece = std::mem::take(&mut self.buf);
+            self.tx
+                .send(Output::Patch(piece))
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
         }
+        Ok(())
+    }
+}
 
-        if (len != oldscore) || (scan == new.len() as isize) {
-            let mut s = 0;
-            let mut s_f = 0;
-            let mut lenf = 0;
-            let mut i = 0;
-            while (lastscan + i < scan) && (lastpos + i < old.len() as isize) {
-                if old[(lastpos + i) as usize] == new[(lastscan + i) as usize] {
-                    s += 1;
-                }
-                i += 1;
-                if s * 2 - i > s_f * 2 - lenf {
-                    s_f = s;
-                    lenf = i;
-                }
-            }
-            let mut lenb = 0;
-            if scan < new.len() as isize {
-                let mut s = 0;
-                let mut s_b = 0;
-                i = 1;
-                while (scan >= lastscan + i) && (pos >= i) {
-                    if old[(pos - i) as usize] == new[(scan - i) as usize] {
-                        s += 1;
-                    }
-                    if s * 2 - i > s_b * 2 - lenb {
-                        s_b = s;
-                        lenb = i;
-                    }
-                    i += 1;
+/// Generates fixed-size chunks like [`chunked_unlimited`], but diffs each chunk on another thread
+/// while the next one is read. The inputs, the patch and `progress` stay on the calling thread.
+fn pipelined(
+    old_f: &mut impl Read,
+    new_f: &mut impl Read,
+    patch_f: &mut impl Write,
+    options: &DiffOptions,
+    scratch: &mut Scratch,
+    mut progress: impl FnMut(State),
+) -> Result<()> {
+    let (_, chunk_size) = options.windows();
+    let Scratch {
+        old: old_buf,
+        new: new_buf,
+        sorted,
+    } = scratch;
+    // One pair of buffers is diffed while the other is read into
+    let mut current = (std::mem::take(old_buf), std::mem::take(new_buf));
+    let mut next = (Vec::new(), Vec::new());
+    for buf in [&mut current.0, &mut current.1, &mut next.0, &mut next.1] {
+        buf.clear();
+        buf.try_reserve(chunk_size)?;
+    }
+    let read_chunk = |(old, new): &mut (Vec<u8>, Vec<u8>),
+                      old_f: &mut _,
+                      new_f: &mut _,
+                      progress: &mut dyn FnMut(State)|
+     -> Result<(usize, usize)> {
+        progress(State::Reading);
+        old.clear();
+        new.clear();
+        let new_len = read_into(new_f, new, chunk_size, options.would_block)?;
+        let old_len = match new_len {
+            0 => 0,
+            _ => read_into(old_f, old, chunk_size, options.would_block)?,
+        };
+        Ok((old_len, new_len))
+    };
+
+    let mut lens = read_chunk(&mut current, old_f, new_f, &mut progress)?;
+    if lens.1 == 0 {
+        write_header(patch_f, 0)?;
+        write_ending(patch_f)?;
+    }
+    let result = thread::scope(|s| {
+        let (job_tx, job_rx) = mpsc::sync_channel::<((Vec<u8>, Vec<u8>), (usize, usize), u64)>(0);
+        let (out_tx, out_rx) = mpsc::sync_channel(PIPELINE_DEPTH);
+        s.spawn(move || {
+            for ((old, new), (old_len, new_len), new_start) in job_rx {
+                let mut patch = PipeWriter {
+                    tx: &out_tx,
+                    buf: Vec::new(),
+                };
+                let result = generate_chunk(
+                    &old[..old_len],
+                    &new[..new_len],
+                    &mut patch,
+                    0,
+                    new_start,
+                    options,
+                    sorted,
+                    |state| {
+                        let _ = out_tx.send(Output::State(state));
+                    },
+                )
+                .and_then(|()| patch.flush().map_err(DiffError::from));
+                if out_tx.send(Output::Done(result, old, new)).is_err() {
+                    break;
                 }
             }
-            if lastscan + lenf > scan - lenb {
-                let overlap = (lastscan + lenf) - (scan - lenb);
-                let mut s = 0;
-                let mut s_s = 0;
-                let mut lens = 0;
-                for i in 0..overlap {
-                    if new[(lastscan + lenf - overlap + i) as usize]
-                        == old[(lastpos + lenf - overlap + i) as usize]
-                    {
-                        s += 1;
-                    }
-                    if new[(scan - lenb + i) as usize] == old[(pos - lenb + i) as usize] {
-                        s -= 1;
+        });
+
+        let mut bytes_completed = 0;
+        while lens.1 > 0 {
+            let job = (std::mem::take(&mut current), lens, bytes_completed);
+            job_tx.send(job).expect("the diffing thread stopped");
+            let next_lens = read_chunk(&mut next, old_f, new_f, &mut progress)?;
+            current = loop {
+                match out_rx.recv().expect("the diffing thread stopped") {
+                    Output::Patch(piece) => patch_f.write_all(&piece)?,
+                    Output::State(State::Working(bytes)) => {
+                        progress(State::Working(bytes + bytes_completed))
                     }
-                    if s > s_s {
-                        s_s = s;
-                        lens = i + 1;
+                    Output::State(state) => progress(state),
+                    Output::Done(result, old, new) => {
+                        result?;
+                        break std::mem::replace(&mut next, (old, new));
                     }
                 }
-                lenf += lens - overlap;
-                lenb -= lens;
-            }
-            if lenf < 0 || (scan - lenb) - (lastscan + lenf) < 0 {
-                return Err(DiffError::Internal(
-                    "invalid state while creating patch".into(),
-                ));
-            }
-            patch.write_all(
-                EntryHeader {
-                    diff: U64::new(lenf as u64),
-                    extra: U64::new(((scan - lenb) - (lastscan + lenf)) as u64),
-                    seek: I64::new(((pos - lenb) - (lastpos + lenf)) as i64),
-                }
-                .as_bytes(),
-            )?;
-            for i in 0..lenf {
-                patch.write_u8(
-                    new[(lastscan + i) as usize].wrapping_sub(old[(lastpos + i) as usize]),
-                )?;
-            }
-            if (scan - lenb) - (lastscan + lenf) != 0 {
-                patch.write_all(&new[(lastscan + lenf) as usize..(scan - lenb) as usize])?;
-            }
-
-            lastscan = scan - lenb;
-            lastpos = pos - lenb;
-            lastoffset = pos - scan;
+            };
+            bytes_completed += lens.1 as u64;
+            lens = next_lens;
         }
-    }
-    write_ending(patch)?;
-    patch.flush()?;
-    Ok(())
+        Ok(())
+    });
+    (*old_buf, *new_buf) = current;
+    result
 }
 
-fn match_len(a: &[u8], b: &[u8]) -> usize {
-    a.iter()
-        .zip(b.iter())
-        .enumerate()
-        .take_while(|(_, (old, new))| old == new)
-        .last()
-        .map_or(0, |(i, _)| i + 1)
+/// Generate a chunked ddelta patch from an old file that's in memory to a new file that's read as
+/// a stream.
+///
+/// This is meant for pipelines where the new file is still being produced, e.g. by a build step.
+/// The new file is read `new_window` bytes at a time (see [`DiffOptions::new_window`], this
+/// defaults to the chunk size), and each window is diffed against the whole old file and written
+/// as a chunk of the patch as soon as it has been read. So only the window of the new file is kept
+/// in memory, and matches are found anywhere in the old file. The suffix array of the old file is
+/// built once, up front, which makes the memory use about 5 times the old file plus the window.
+/// The old file must not be larger than 2^31-2 bytes.
+///
+/// [`content_defined_chunking`][DiffOptions::content_defined_chunking],
+/// [`old_window`][DiffOptions::old_window] and [`shrink_on_oom`][DiffOptions::shrink_on_oom] are
+/// ignored. The output must be applied with [`apply_chunked`][crate::apply_chunked].
+pub fn generate_streaming(
+    old: &[u8],
+    new_f: &mut impl Read,
+    patch_f: &mut impl Write,
+    options: &DiffOptions,
+    progress: impl FnMut(State),
+) -> Result<()> {
+    streaming(
+        old,
+        new_f,
+        patch_f,
+        options,
+        &mut Scratch::default(),
+        progress,
+    )
 }
 
-/// Compares lexicographically the common part of these slices, i.e. takes the smallest length and
-/// compares within that.
-fn min_memcmp(a: &[u8], b: &[u8]) -> Ordering {
-    let len = a.len().min(b.len());
-    a[..len].cmp(&b[..len])
+fn streaming(
+    old: &[u8],
+    new_f: &mut impl Read,
+    patch_f: &mut impl Write,
+    options: &DiffOptions,
+    scratch: &mut Scratch,
+    mut progress: impl FnMut(State),
+) -> Result<()> {
+    let recorder = Recorder::new();
+    recorder.inputs(old, &[]);
+    let mut patch_f = Splitter::new(
+        Budget::new(recorder.patch_writer(patch_f), options.max_patch_size),
+        options.max_run,
+    );
+    let result = streaming_unlimited(
+        old,
+        &mut recorder.new_reader(new_f),
+        &mut patch_f,
+        options,
+        scratch,
+        |state| {
+            recorder.observe(state);
+            progress(state)
+        },
+    );
+    patch_f.inner.finish(result)?;
+    progress(State::Done(recorder.finish()));
+    Ok(())
 }
 
-/// This is a binary search of the string `new` in the `old` string using the suffix array
-/// `sorted`. `st` and `en` is the start and end of the search range (inclusive).
-/// Returns the length of the longest prefix found and stores the position of the
-/// string found in `*pos`.
-fn search(sorted: &[i32], old: &[u8], new: &[u8], st: usize, en: usize, pos: &mut isize) -> isize {
-    if en - st < 2 {
-        let x = match_len(&old[(sorted[st] as usize)..], new) as isize;
-        let y = match_len(&old[(sorted[en] as usize)..], new) as isize;
-
-        if x > y {
-            *pos = sorted[st] as isize;
-            x
+fn streaming_unlimited(
+    old: &[u8],
+    new_f: &mut impl Read,
+    patch_f: &mut impl Write,
+    options: &DiffOptions,
+    scratch: &mut Scratch,
+    mut progress: impl FnMut(State),
+) -> Result<()> {
+    if old.len() >= i32::MAX as usize {
+        return Err(DiffError::Internal(
+            format!("The filesize must not be larger than {} bytes", i32::MAX).into(),
+        ));
+    }
+    let (_, new_window) = options.windows();
+    let Scratch {
+        new: new_buf,
+        sorted,
+        ..
+    } = scratch;
+    new_buf.clear();
+    new_buf.try_reserve(new_window)?;
+    // Sorted as a whole, so every chunk finds the suffix array ready instead of sorting whatever
+    // part of the old file it doesn't share its start and end with
+    let key = sort_key(old);
+    if sorted.built_for != Some(key) {
+        sorted.built_for = None;
+        sorted.array.clear();
+        try_resize(&mut sorted.array, old.len() + 1)?;
+        sort(
+            old,
+            &mut sorted.array[..old.len()],
+            options.backend,
+            &mut progress,
+        );
+        sorted.built_for = Some(key);
+    }
+    sorted.pinned = true;
+    let result = streaming_chunks(old, new_f, patch_f, options, new_buf, sorted, progress);
+    sorted.pinned = false;
+    result
+}
+
+/// The chunks of [`streaming_unlimited`], once the whole old file is sorted.
+fn streaming_chunks(
+    old: &[u8],
+    new_f: &mut impl Read,
+    patch_f: &mut impl Write,
+    options: &DiffOptions,
+    new_buf: &mut Vec<u8>,
+    sorted: &mut Sorted,
+    mut progress: impl FnMut(State),
+) -> Result<()> {
+    let (_, new_window) = options.windows();
+    let mut bytes_completed = 0;
+    loop {
+        progress(State::Reading);
+        new_buf.clear();
+        read_into(new_f, new_buf, new_window, options.would_block)?;
+        let new = &new_buf[..];
+        if new.is_empty() {
+            if bytes_completed == 0 {
+                write_header(patch_f, 0)?;
+                write_ending(patch_f)?;
+            }
+            break;
+        }
+
+        // The applier starts each chunk at the same offset in the old file as in the new one
+        let completed = bytes_completed;
+        generate_chunk(
+            old,
+            new,
+            patch_f,
+            -(completed as i64),
+            completed,
+            options,
+            sorted,
+            |d| match d {
+                State::Working(bytes) => progress(State::Working(bytes + completed)),
+                other => progress(other),
+            },
+        )?;
+        bytes_completed += new.len() as u64;
+    }
+    Ok(())
+}
+
+/// Resizes `buf` to `len`, failing instead of aborting if that isn't possible.
+pub(crate) fn try_resize<T: Clone + Default>(
+    buf: &mut Vec<T>,
+    len: usize,
+) -> std::result::Result<(), TryReserveError> {
+    buf.try_reserve(len.saturating_sub(buf.len()))?;
+    buf.resize(len, T::default());
+    Ok(())
+}
+
+#[cfg(test)]
+thread_local! {
+    /// The most [`try_reserve`] reserves in tests, as allocations that fail are hard to come by.
+    static RESERVE_LIMIT: std::cell::Cell<usize> = const { std::cell::Cell::new(usize::MAX) };
+}
+
+/// Reserves room for `len` more bytes in `buf`, failing instead of aborting if that isn't possible.
+fn try_reserve(buf: &mut Vec<u8>, len: usize) -> std::result::Result<(), TryReserveError> {
+    #[cfg(test)]
+    if len > RESERVE_LIMIT.get() {
+        return Vec::<u8>::new().try_reserve(usize::MAX);
+    }
+    buf.try_reserve(len)
+}
+
+/// Reads into `buf` until it holds `len` bytes or the reader reaches its end, returning how much
+/// was read.
+///
+/// The buffers for whole chunks are only reserved, which doesn't use any memory yet. Only what is
+/// read into is zeroed first, in steps that double, so a small input in a buffer for a large chunk
+/// stays small. `buf` should have the capacity for `len` bytes already.
+pub(crate) fn read_into(
+    reader: &mut (impl Read + ?Sized),
+    buf: &mut Vec<u8>,
+    len: usize,
+    would_block: WouldBlock,
+) -> io::Result<usize> {
+    let start = buf.len();
+    let mut step = READ_STEP;
+    while buf.len() < len {
+        let filled = buf.len();
+        let end = len.min(filled.saturating_add(step));
+        buf.resize(end, 0);
+        let read = read_up_to(reader, &mut buf[filled..], would_block).inspect_err(|_| {
+            buf.truncate(filled);
+        });
+        buf.truncate(filled + read?);
+        if buf.len() < end {
+            break;
+        }
+        step = step.saturating_mul(2);
+    }
+    Ok(buf.len() - start)
+}
+
+/// Returns the chunk size to retry with after running out of memory, or the error if the options
+/// don't allow retrying.
+fn shrink(options: &DiffOptions, chunk_size: usize, e: TryReserveError) -> Result<usize> {
+    if options.shrink_on_oom && chunk_size > MIN_RETRY_CHUNK_SIZE {
+        Ok((chunk_size / 2).max(MIN_RETRY_CHUNK_SIZE))
+    } else {
+        Err(e.into())
+    }
+}
+
+/// Generates a single patch with [`generate_from`], or stores `new` as-is if the options say it
+/// isn't worth diffing.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn generate_chunk(
+    old: &[u8],
+    new: &[u8],
+    patch: &mut impl Write,
+    old_offset: i64,
+    new_start: u64,
+    options: &DiffOptions,
+    sorted: &mut Sorted,
+    progress: impl FnMut(State),
+) -> Result<()> {
+    span!(DEBUG, "chunk", old = old.len(), new = new.len(), old_offset);
+    match options.min_similarity {
+        Some(threshold) if !new.is_empty() && estimate_similarity(old, new) < threshold => {
+            write_literal(patch, new)
+        }
+        _ => generate_from(
+            old, new, patch, old_offset, new_start, options, sorted, progress,
+        ),
+    }
+}
+
+/// Writes a patch for a new file of `len` bytes that is the same as the old file, starting
+/// `old_offset` bytes after where the applier has it.
+fn write_contained(
+    patch: &mut impl Write,
+    len: usize,
+    old_offset: i64,
+    mut progress: impl FnMut(State),
+) -> Result<()> {
+    write_header(patch, len as u64)?;
+    write_unchanged(patch, old_offset, len)?;
+    progress(State::Working(len as u64));
+    write_ending(patch)
+}
+
+/// Writes a patch for a new file that is the `old_len` bytes of the old file starting `old_offset`
+/// bytes after where the applier has it, followed by `appended`.
+fn write_appended(
+    patch: &mut impl Write,
+    old_len: usize,
+    appended: &[u8],
+    old_offset: i64,
+    mut progress: impl FnMut(State),
+) -> Result<()> {
+    write_header(patch, (old_len + appended.len()) as u64)?;
+    write_unchanged(patch, old_offset, old_len)?;
+    patch.write_all(
+        EntryHeader {
+            diff: Default::default(),
+            extra: U64::new(appended.len() as u64),
+            seek: Default::default(),
+        }
+        .as_bytes(),
+    )?;
+    patch.write_all(appended)?;
+    progress(State::Working((old_len + appended.len()) as u64));
+    write_ending(patch)
+}
+
+/// Writes entries that seek by `seek`, then take `len` bytes from the old file unchanged.
+fn write_unchanged(patch: &mut impl Write, seek: i64, len: usize) -> Result<()> {
+    for (diff, seek) in [(0, seek), (len as u64, 0)] {
+        if Terminator::matches(diff, 0, seek) {
+            continue;
+        }
+        patch.write_all(
+            EntryHeader {
+                diff: U64::new(diff),
+                extra: Default::default(),
+                seek: I64::new(seek),
+            }
+            .as_bytes(),
+        )?;
+    }
+    io::copy(&mut io::repeat(0).take(len as u64), patch)?;
+    Ok(())
+}
+
+/// Writes a patch that contains all of `new`, without referencing the old file.
+fn write_literal(patch: &mut impl Write, new: &[u8]) -> Result<()> {
+    write_header(patch, new.len() as u64)?;
+    patch.write_all(
+        EntryHeader {
+            diff: Default::default(),
+            extra: U64::new(new.len() as u64),
+            seek: Default::default(),
+        }
+        .as_bytes(),
+    )?;
+    patch.write_all(new)?;
+    write_ending(patch)
+}
+
+pub(crate) fn write_header(patch: &mut impl Write, len: u64) -> Result<()> {
+    write_header_with(patch, DDELTA_MAGIC, len)
+}
+
+fn write_header_with(patch: &mut impl Write, magic: &[u8; 8], len: u64) -> Result<()> {
+    patch
+        .write_all(
+            PatchHeader {
+                magic: *magic,
+                new_file_size: U64::new(len),
+            }
+            .as_bytes(),
+        )
+        .map_err(|e| e.into())
+}
+
+pub(crate) fn write_ending(patch: &mut impl Write) -> Result<()> {
+    Terminator::write(patch).map_err(|e| e.into())
+}
+
+/// Generate a ddelta patch. This has a limit of 2^31-1 bytes.
+///
+/// Beyond this, use [`generate_chunked`]
+/// to create a patch file with multiple patches. The output is compatible with the original ddelta
+/// tool, but not with bsdiff. Call [`apply`][crate::apply] or
+/// [`apply_chunked`][crate::apply_chunked] to use the created patch file. `progress` is a function
+/// that will be called periodically with progress updates.
+pub fn generate(
+    old: &[u8],
+    new: &[u8],
+    patch: &mut impl Write,
+    mut progress: impl FnMut(State),
+) -> Result<()> {
+    let recorder = Recorder::new();
+    recorder.inputs(old, new);
+    generate_from(
+        old,
+        new,
+        &mut recorder.patch_writer(patch),
+        0,
+        0,
+        &DiffOptions::new(),
+        &mut Sorted::default(),
+        |state| {
+            recorder.observe(state);
+            progress(state)
+        },
+    )?;
+    progress(State::Done(recorder.finish()));
+    Ok(())
+}
+
+/// What [`generate_with_report`] wrote.
+#[derive(Eq, PartialEq, Copy, Clone, Hash, Debug, Default)]
+pub struct GenerateReport {
+    /// The size of the patch.
+    pub patch_bytes: u64,
+    /// The number of entries in the patch, not counting the one that ends it.
+    pub entries: u64,
+    /// The size of the new file.
+    pub new_len: u64,
+}
+
+/// [`generate`], returning the size of the patch and how many entries it has, e.g. to log the
+/// compression ratio without wrapping `patch` in a counting writer.
+pub fn generate_with_report(
+    old: &[u8],
+    new: &[u8],
+    patch: &mut impl Write,
+    progress: impl FnMut(State),
+) -> Result<GenerateReport> {
+    let mut counter = EntryCounter::new(patch);
+    generate(old, new, &mut counter, progress)?;
+    Ok(GenerateReport {
+        patch_bytes: counter.bytes,
+        entries: counter.entries,
+        new_len: new.len() as u64,
+    })
+}
+
+/// Like [`generate`], but `old` starts `old_offset` bytes after the position the applier has the
+/// old file at when starting this patch. A seek entry is emitted first to get there. `new` starts
+/// at `new_start` in the new file, which places the [hints][DiffOptions::hints]. Of the `options`,
+/// only the ones that affect a single patch are used. `sorted` holds the suffix array, which is
+/// reused if it was built for the same old data before.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn generate_from(
+    old: &[u8],
+    new: &[u8],
+    patch: &mut impl Write,
+    old_offset: i64,
+    new_start: u64,
+    options: &DiffOptions,
+    sorted: &mut Sorted,
+    mut progress: impl FnMut(State),
+) -> Result<()> {
+    if old.len().max(new.len()) >= i32::MAX as usize {
+        return Err(DiffError::Internal(
+            format!("The filesize must not be larger than {} bytes", i32::MAX).into(),
+        ));
+    }
+    let masks = local_masks(&options.masks, new_start, new.len());
+    let filled;
+    let new = match masks.is_empty() {
+        true => new,
+        false => {
+            filled = fill_masks(old, new, &masks, old_offset);
+            &filled[..]
+        }
+    };
+    // A truncated old file, or its end, doesn't need to be searched for
+    let contained = if new.is_empty() {
+        None
+    } else if old.starts_with(new) {
+        Some(0)
+    } else if old.ends_with(new) {
+        Some(old.len() - new.len())
+    } else {
+        None
+    };
+    if let Some(start) = contained {
+        return write_contained(patch, new.len(), old_offset + start as i64, progress);
+    }
+    // Neither does data appended to the old file, e.g. to a log. Copies and aligned entries would
+    // need to look at the appended data, so those still search.
+    if !old.is_empty() && options.align.is_none() && !options.copy_from_new && new.starts_with(old)
+    {
+        return write_appended(patch, old.len(), &new[old.len()..], old_offset, progress);
+    }
+    // Copies write their own entries, unless the entries have to be aligned, word diffs or masked
+    let mut writer =
+        (!options.copy_from_new || options.align.is_some() || options.words || !masks.is_empty())
+            .then(|| {
+                EntryWriter::new(
+                    old,
+                    new,
+                    options.align.unwrap_or(1),
+                    options.words,
+                    old_offset,
+                )
+                .masked(&masks)
+            });
+    // Only the part between what the start and end of both files have in common needs to be
+    // searched. Sorting just that part of the old file is faster too, unless the whole old file is
+    // already sorted from a previous run.
+    let new_len = new.len();
+    let prefix = match_len(old, new);
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(old, new)| old == new)
+        .count();
+    let new = &new[prefix..new.len() - suffix];
+    let mut key = match (sorted.pinned, sorted.built_for) {
+        (true, Some(key)) => key,
+        _ => sort_key(old),
+    };
+    // Where the common start and end are in the part of the old file that's searched
+    let (old, start, end) = if sorted.built_for == Some(key) {
+        (old, prefix, old.len() - suffix)
+    } else {
+        let trimmed = &old[prefix..old.len() - suffix];
+        if trimmed.len() != old.len() {
+            key = sort_key(trimmed);
+        }
+        (trimmed, 0, trimmed.len())
+    };
+    let reuse = sorted.built_for == Some(key);
+    if !reuse {
+        // Allocated before writing anything, so a chunk can be retried if this fails
+        sorted.built_for = None;
+        sorted.array.clear();
+        try_resize(&mut sorted.array, old.len() + 1)?;
+    }
+    let mut copies = writer
+        .is_none()
+        .then(|| Copies::new(new.len()))
+        .transpose()?;
+    let magic = match copies {
+        Some(_) => DDELTA_MAGIC_V2,
+        None if options.words => DDELTA_MAGIC_WORDS,
+        None => DDELTA_MAGIC,
+    };
+    write_header_with(patch, magic, new_len as u64)?;
+    // Positions in the searched part of the files are this far from the same positions in all of
+    // them, for the entry writer
+    let old_base = (prefix - start) as i64;
+    match &mut writer {
+        Some(writer) => writer.push(
+            patch,
+            Entry {
+                new: 0,
+                diff: prefix,
+                extra: 0,
+                old: 0,
+            },
+        )?,
+        None => write_unchanged(patch, old_offset, prefix)?,
+    }
+    if reuse {
+        let total = old.len() as u64;
+        progress(State::Sorting { done: total, total });
+    } else {
+        sort(
+            old,
+            &mut sorted.array[..old.len()],
+            options.backend,
+            &mut progress,
+        );
+        sorted.built_for = Some(key);
+    }
+    let sorted = &sorted.array;
+    // The applier has the old file at the same offset as the new one when `new` starts, so the
+    // searched part of the old file is `old_offset - start` bytes ahead of that of the new file
+    let hints = local_hints(
+        &options.hints,
+        new_start + prefix as u64,
+        new.len(),
+        old_offset - start as i64,
+    );
+    let s = scan_matches(
+        sorted,
+        old,
+        new,
+        &hints,
+        start,
+        options.max_work.unwrap_or(u64::MAX),
+        &mut |scan| progress(State::Working((prefix as i64 + scan) as u64)),
+        |s, lenf, lenb| {
+            let diff_new = &new[s.lastscan as usize..(s.lastscan + lenf) as usize];
+            let diff_old = &old[s.lastpos as usize..(s.lastpos + lenf) as usize];
+            // How much of the match is stored as diff data, the rest of it goes into the extra data
+            let diff = match options.prefer_literals
+                && diff_new.len() >= MIN_ENTROPY_LEN
+                && entropy(
+                    diff_new
+                        .iter()
+                        .zip(diff_old)
+                        .map(|(n, o)| n.wrapping_sub(*o)),
+                ) >= entropy(diff_new.iter().copied())
+            {
+                true => 0,
+                false => lenf,
+            };
+            if let Some(writer) = &mut writer {
+                writer.push(
+                    patch,
+                    Entry {
+                        new: prefix + s.lastscan as usize,
+                        diff: diff as usize,
+                        extra: ((s.scan - lenb) - (s.lastscan + diff)) as usize,
+                        old: old_base + s.lastpos,
+                    },
+                )?;
+            } else if let Some(copies) = &mut copies {
+                copies.write_entry(
+                    patch,
+                    &diff_new[..diff as usize],
+                    &diff_old[..diff as usize],
+                    new,
+                    (s.lastscan + diff) as usize..(s.scan - lenb) as usize,
+                    (s.pos - lenb) - (s.lastpos + diff),
+                )?;
+            }
+            Ok(())
+        },
+    )?;
+    match writer {
+        Some(mut writer) => {
+            writer.push(
+                patch,
+                Entry {
+                    new: new_len - suffix,
+                    diff: suffix,
+                    extra: 0,
+                    old: old_base + end as i64,
+                },
+            )?;
+            writer.finish(patch)?;
+        }
+        None if suffix > 0 => write_unchanged(patch, end as i64 - s.lastpos, suffix)?,
+        None => {}
+    }
+    write_ending(patch)?;
+    patch.flush()?;
+    Ok(())
+}
+
+/// Runs the scan loop of [`generate_from`] over the searched parts of the files, where the common
+/// start of the old part is at `start`. `entry` is given the state at each match worth starting a
+/// new entry for, with how far the last entry's diff data goes forward from `lastscan` and
+/// backward from `scan`. Returns the state at the end, after the last entry.
+#[allow(clippy::too_many_arguments)]
+fn scan_matches(
+    sorted: &[i32],
+    old: &[u8],
+    new: &[u8],
+    hints: &[Hinted],
+    start: usize,
+    max_work: u64,
+    progress: &mut impl FnMut(i64),
+    mut entry: impl FnMut(&Scan, i64, i64) -> Result<()>,
+) -> Result<Scan> {
+    // Positions are i64, so the arithmetic on them can't overflow on 32-bit targets. They're all
+    // below i32::MAX, checked by the callers, so they're indexed with plain casts to usize.
+    let mut s = Scan {
+        // Hints may point before the old data of this chunk, like the ones in `find_match`
+        lastoffset: hint_at(hints, 0)
+            .filter(|offset| (0..old.len() as i64).contains(offset))
+            .unwrap_or(start as i64),
+        lastpos: start as i64,
+        ..Scan::default()
+    };
+    span!(DEBUG, "scan", old = old.len(), new = new.len());
+    while s.scan < new.len() as i64 {
+        let oldscore = find_match(sorted, old, new, hints, &mut s, max_work, progress);
+        if s.len == oldscore && s.scan != new.len() as i64 {
+            continue;
+        }
+        let (lenf, lenb) = resolve_overlap(
+            old,
+            new,
+            &s,
+            extend_forward(old, new, &s),
+            extend_backward(old, new, &s),
+        );
+        if lenf < 0 || (s.scan - lenb) - (s.lastscan + lenf) < 0 {
+            return Err(DiffError::Internal(
+                "invalid state while creating patch".into(),
+            ));
+        }
+        entry(&s, lenf, lenb)?;
+        s.lastscan = s.scan - lenb;
+        s.lastpos = s.pos - lenb;
+        s.lastoffset = s.pos - s.scan;
+    }
+    Ok(s)
+}
+
+/// Where the scan loop of [`generate_from`] is in the searched parts of the files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Scan {
+    /// Where the current match starts in the new file, how long it is, and where it is in the old
+    /// file.
+    scan: i64,
+    len: i64,
+    pos: i64,
+    /// Where the last entry ends in the new file, and where its seek goes in the old file.
+    lastscan: i64,
+    lastpos: i64,
+    /// How far the old file is ahead of the new file after the last match.
+    lastoffset: i64,
+    /// How many bytes have been compared, for [`DiffOptions::max_work`].
+    work: u64,
+}
+
+/// A [`Hint`] in the searched parts of the files: where it starts and ends in the new part, and how
+/// far the old part is ahead of the new part there.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Hinted {
+    start: i64,
+    end: i64,
+    offset: i64,
+}
+
+/// The parts of `hints` that overlap the `new_len` bytes at `new_start` in the new file, sorted and
+/// without overlaps, where earlier hints win. The searched part of the old file starts `shift`
+/// bytes after that of the new file, which starts at `new_start`.
+fn local_hints(hints: &[Hint], new_start: u64, new_len: usize, shift: i64) -> Vec<Hinted> {
+    let mut local: Vec<Hinted> = hints
+        .iter()
+        .filter_map(|hint| {
+            let start = hint.new.max(new_start);
+            let end = hint
+                .new
+                .saturating_add(hint.len)
+                .min(new_start + new_len as u64);
+            let offset = i64::try_from(hint.old)
+                .ok()?
+                .checked_sub(i64::try_from(hint.new).ok()?)?
+                .checked_sub(shift)?;
+            (start < end).then(|| Hinted {
+                start: (start - new_start) as i64,
+                end: (end - new_start) as i64,
+                offset,
+            })
+        })
+        .collect();
+    local.sort_by_key(|hint| hint.start);
+    let mut covered = 0;
+    local.retain_mut(|hint| {
+        hint.start = hint.start.max(covered);
+        covered = covered.max(hint.end);
+        hint.start < hint.end
+    });
+    local
+}
+
+/// The parts of `masks` that overlap the `new_len` bytes at `new_start` in the new file, relative to
+/// `new_start`, sorted and merged.
+fn local_masks(masks: &[Range<u64>], new_start: u64, new_len: usize) -> Vec<Range<usize>> {
+    let new_end = new_start + new_len as u64;
+    let mut local: Vec<Range<usize>> = masks
+        .iter()
+        .filter(|mask| mask.start < new_end && new_start < mask.end)
+        .map(|mask| {
+            (mask.start.max(new_start) - new_start) as usize
+                ..(mask.end.min(new_end) - new_start) as usize
+        })
+        .collect();
+    local.sort_by_key(|mask| mask.start);
+    local.dedup_by(|next, prev| {
+        let merged = next.start <= prev.end;
+        if merged {
+            prev.end = prev.end.max(next.end);
+        }
+        merged
+    });
+    local
+}
+
+/// A copy of `new` with the bytes in `masks` replaced by those of the old file at the same offset,
+/// or zeros past its end, so they match wherever the data around them does. `old` starts
+/// `old_offset` bytes after the start of `new`.
+fn fill_masks(old: &[u8], new: &[u8], masks: &[Range<usize>], old_offset: i64) -> Vec<u8> {
+    let mut filled = new.to_vec();
+    for mask in masks {
+        for i in mask.clone() {
+            filled[i] = usize::try_from(i as i64 - old_offset)
+                .ok()
+                .and_then(|at| old.get(at).copied())
+                .unwrap_or(0);
+        }
+    }
+    filled
+}
+
+/// How far the old part is ahead of the new one at `scan` according to `hints`, if a hint covers it.
+fn hint_at(hints: &[Hinted], scan: i64) -> Option<i64> {
+    let i = hints.partition_point(|hint| hint.start <= scan);
+    let hint = hints[..i].last()?;
+    (scan < hint.end).then_some(hint.offset)
+}
+
+/// Moves past the current match to the next one that's worth starting a new entry for, and returns
+/// how many of its bytes also match at the last match's offset.
+///
+/// A match is worth it if continuing at the last offset matches fewer of its bytes, or none of them
+/// differ. Where a hint covers `scan`, the hinted offset is used instead of the searched match if
+/// it's at least as long. If `max_work` is exceeded, `scan` is moved to the end of `new`, so the
+/// rest is stored as extra data. `progress` is given the new position every 10000 bytes.
+fn find_match(
+    sorted: &[i32],
+    old: &[u8],
+    new: &[u8],
+    hints: &[Hinted],
+    s: &mut Scan,
+    max_work: u64,
+    progress: &mut impl FnMut(i64),
+) -> i64 {
+    let mut num_less_than_eight = 0;
+    let mut oldscore: i64 = 0;
+    s.scan += s.len;
+    let mut scsc = s.scan;
+    // If we come across a large block of data that only differs
+    // by less than 8 bytes, this loop will take a long time to
+    // go past that block of data. We need to track the number of
+    // times we're stuck in the block and break out of it.
+    while s.scan < new.len() as i64 {
+        if s.scan % 10_000 == 0 {
+            progress(s.scan);
+        }
+        let prev_len = s.len;
+        let prev_oldscore = oldscore;
+        let prev_pos = s.pos;
+
+        s.len = search(
+            sorted,
+            &old[..old.len().saturating_sub(1)],
+            &new[s.scan as usize..],
+            0,
+            old.len(),
+            &mut s.pos,
+        );
+        if let Some(offset) = hint_at(hints, s.scan) {
+            let at = s.scan + offset;
+            if (0..old.len() as i64).contains(&at) {
+                let len = match_len(&old[at as usize..], &new[s.scan as usize..]) as i64;
+                s.work = s.work.saturating_add(len as u64);
+                if len >= s.len {
+                    (s.len, s.pos) = (len, at);
+                }
+            }
+        }
+        s.work = s
+            .work
+            .saturating_add((s.len + (s.scan + s.len - scsc).max(0)) as u64 + 1);
+        if s.work > max_work {
+            // Give up on finding matches, so the rest is stored as extra data
+            s.scan = new.len() as i64;
+            break;
+        }
+
+        while scsc < s.scan + s.len {
+            if (0..old.len() as i64).contains(&(scsc + s.lastoffset))
+                && (old[(scsc + s.lastoffset) as usize] == new[scsc as usize])
+            {
+                oldscore += 1;
+            }
+            scsc += 1;
+        }
+
+        if ((s.len == oldscore) && (s.len != 0)) || (s.len > oldscore + 8) {
+            break;
+        }
+
+        if (0..old.len() as i64).contains(&(s.scan + s.lastoffset))
+            && (old[(s.scan + s.lastoffset) as usize] == new[s.scan as usize])
+        {
+            oldscore -= 1;
+        }
+
+        if prev_len - FUZZ <= s.len
+            && s.len <= prev_len
+            && prev_oldscore - FUZZ <= oldscore
+            && oldscore <= prev_oldscore
+            && prev_pos <= s.pos
+            && s.pos <= prev_pos + FUZZ
+            && oldscore <= s.len
+            && s.len <= oldscore + FUZZ
+        {
+            num_less_than_eight += 1;
         } else {
-            *pos = sorted[en] as isize;
-            y
+            num_less_than_eight = 0;
+        }
+
+        if num_less_than_eight > 100 {
+            break;
+        }
+
+        s.scan += 1;
+    }
+    oldscore
+}
+
+/// How far the last entry's diff data goes: as far past `lastscan` as gives the most bytes that
+/// match more often than not.
+fn extend_forward(old: &[u8], new: &[u8], s: &Scan) -> i64 {
+    let mut score = 0;
+    let mut best = 0;
+    let mut lenf = 0;
+    let mut i = 0;
+    while (s.lastscan + i < s.scan) && (s.lastpos + i < old.len() as i64) {
+        if old[(s.lastpos + i) as usize] == new[(s.lastscan + i) as usize] {
+            score += 1;
         }
+        i += 1;
+        if score * 2 - i > best * 2 - lenf {
+            best = score;
+            lenf = i;
+        }
+    }
+    lenf
+}
+
+/// How far the current match can be extended backwards from `scan` by the same measure as
+/// [`extend_forward`], without going past `lastscan`. Nothing at the end of `new`, where there's
+/// no match.
+fn extend_backward(old: &[u8], new: &[u8], s: &Scan) -> i64 {
+    let mut lenb = 0;
+    if s.scan < new.len() as i64 {
+        let mut score = 0;
+        let mut best = 0;
+        let mut i = 1;
+        while (s.scan >= s.lastscan + i) && (s.pos >= i) {
+            if old[(s.pos - i) as usize] == new[(s.scan - i) as usize] {
+                score += 1;
+            }
+            if score * 2 - i > best * 2 - lenb {
+                best = score;
+                lenb = i;
+            }
+            i += 1;
+        }
+    }
+    lenb
+}
+
+/// Shortens the forward and backward extensions so they don't overlap, splitting the overlap where
+/// the most bytes match on the side they're given to.
+fn resolve_overlap(old: &[u8], new: &[u8], s: &Scan, mut lenf: i64, mut lenb: i64) -> (i64, i64) {
+    if s.lastscan + lenf > s.scan - lenb {
+        let overlap = (s.lastscan + lenf) - (s.scan - lenb);
+        let mut score = 0;
+        let mut best = 0;
+        let mut lens = 0;
+        for i in 0..overlap {
+            if new[(s.lastscan + lenf - overlap + i) as usize]
+                == old[(s.lastpos + lenf - overlap + i) as usize]
+            {
+                score += 1;
+            }
+            if new[(s.scan - lenb + i) as usize] == old[(s.pos - lenb + i) as usize] {
+                score -= 1;
+            }
+            if score > best {
+                best = score;
+                lens = i + 1;
+            }
+        }
+        lenf += lens - overlap;
+        lenb -= lens;
+    }
+    (lenf, lenb)
+}
+
+/// The length and hash of old data, to tell whether a suffix array was built for it.
+fn sort_key(old: &[u8]) -> (usize, u64) {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(old);
+    (old.len(), hasher.finish())
+}
+
+/// Magic number of the files written by [`Differ::save_index`].
+const INDEX_MAGIC: &[u8; 8] = b"DDELTASA";
+
+/// A hash of the data an index was saved for. Unlike [`sort_key`], this is written to files, so it
+/// must be the same across Rust versions, which the hasher of the standard library isn't.
+fn index_hash(data: &[u8]) -> u64 {
+    // FNV-1a
+    data.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// [`generate`], taking trait objects.
+///
+/// Every reader and writer type used with the generic functions gets its own copy of the generator.
+/// This function is compiled once, which keeps binaries small when many types are involved, and is
+/// easier to call through FFI or plugin layers.
+pub fn generate_dyn(
+    old: &[u8],
+    new: &[u8],
+    patch: &mut dyn Write,
+    progress: &mut dyn FnMut(State),
+) -> Result<()> {
+    generate(old, new, &mut { patch }, progress)
+}
+
+/// [`generate_chunked_with_options`], taking trait objects. See [`generate_dyn`].
+pub fn generate_chunked_dyn(
+    old: &mut dyn Read,
+    new: &mut dyn Read,
+    patch: &mut dyn Write,
+    options: &DiffOptions,
+    progress: &mut dyn FnMut(State),
+) -> Result<()> {
+    generate_chunked_with_options(
+        &mut { old },
+        &mut { new },
+        &mut { patch },
+        options,
+        progress,
+    )
+}
+
+/// Counts the bytes and entries of a (plain or chunked) patch that is written through it.
+struct EntryCounter<W> {
+    inner: W,
+    bytes: u64,
+    entries: u64,
+    /// The header being written, which is a chunk header outside of chunks and an entry header
+    /// inside of them.
+    header: Vec<u8>,
+    /// Whether the current chunk may contain copies, if there is one.
+    chunk: Option<bool>,
+    /// The data of the current entry that is yet to be written.
+    data: u64,
+}
+
+impl<W: Write> EntryCounter<W> {
+    fn new(inner: W) -> Self {
+        EntryCounter {
+            inner,
+            bytes: 0,
+            entries: 0,
+            header: Vec::with_capacity(size_of::<EntryHeader>()),
+            chunk: None,
+            data: 0,
+        }
+    }
+
+    fn count(&mut self, mut buf: &[u8]) {
+        while !buf.is_empty() {
+            if self.data > 0 {
+                let skipped = self.data.min(buf.len() as u64);
+                self.data -= skipped;
+                buf = &buf[skipped as usize..];
+                continue;
+            }
+            let len = match self.chunk {
+                None => size_of::<PatchHeader>(),
+                Some(_) => size_of::<EntryHeader>(),
+            };
+            let take = (len - self.header.len()).min(buf.len());
+            self.header.extend_from_slice(&buf[..take]);
+            buf = &buf[take..];
+            if self.header.len() < len {
+                break;
+            }
+            match self.chunk {
+                None => self.chunk = Some(self.header.starts_with(DDELTA_MAGIC_V2)),
+                Some(copies) => {
+                    let (diff, extra) = (
+                        u64::from_be_bytes(self.header[..8].try_into().unwrap()),
+                        u64::from_be_bytes(self.header[8..16].try_into().unwrap()),
+                    );
+                    if self.header.iter().all(|&b| b == 0) {
+                        self.chunk = None;
+                    } else {
+                        self.entries += 1;
+                        self.data = match copies && diff & COPY_FLAG != 0 {
+                            true => 0,
+                            false => diff.saturating_add(extra),
+                        };
+                    }
+                }
+            }
+            self.header.clear();
+        }
+    }
+}
+
+impl<W: Write> Write for EntryCounter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.bytes += written as u64;
+        self.count(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Estimates how large a patch from `old` to `new` would be once compressed, without storing it.
+///
+/// Most of a patch is usually the diff data of the parts of the new file that were found in the
+/// old file, which is almost all zeros and compresses to next to nothing. So the estimate only
+/// counts the headers, the extra data and the bytes of the diff data that aren't zero, which is
+/// good enough to decide between distributing a patch or the whole new file. The files are split
+/// into chunks and searched with `options` as [`generate_chunked_with_options`] does without
+/// windows, content-defined chunking or copies, but only the lengths of the entries are added up,
+/// the patch itself is never built. Sorting and searching still take most of the time.
+pub fn estimate_patch_size(old: &[u8], new: &[u8], options: &DiffOptions) -> Result<u64> {
+    if new.is_empty() {
+        return Ok((size_of::<PatchHeader>() + size_of::<EntryHeader>()) as u64);
+    }
+    let chunk_size = options.windows().1;
+    let mut sorted = Vec::new();
+    let mut size = 0;
+    for (i, new) in new.chunks(chunk_size).enumerate() {
+        let start = i * chunk_size;
+        let old = &old[old.len().min(start)..old.len().min(start + new.len())];
+        size += estimate_chunk(old, new, start as u64, options, &mut sorted)?;
+    }
+    Ok(size)
+}
+
+/// Estimates the size of the patch from one chunk of the old file to the chunk of the new file
+/// starting at `new_start`, for [`estimate_patch_size`].
+fn estimate_chunk(
+    old: &[u8],
+    new: &[u8],
+    new_start: u64,
+    options: &DiffOptions,
+    sorted: &mut Vec<i32>,
+) -> Result<u64> {
+    let entry = size_of::<EntryHeader>() as u64;
+    // The patch header and the terminator
+    let mut size = size_of::<PatchHeader>() as u64 + entry;
+    if let Some(threshold) = options.min_similarity {
+        if estimate_similarity(old, new) < threshold {
+            return Ok(size + entry + new.len() as u64);
+        }
+    }
+    let masks = local_masks(&options.masks, new_start, new.len());
+    let filled;
+    let new = match masks.is_empty() {
+        true => new,
+        false => {
+            filled = fill_masks(old, new, &masks, 0);
+            &filled[..]
+        }
+    };
+    let prefix = match_len(old, new);
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(old, new)| old == new)
+        .count();
+    // The common start and end are diff data that is all zeros, in the entries next to them
+    let old = &old[prefix..old.len() - suffix];
+    let new = &new[prefix..new.len() - suffix];
+    sorted.clear();
+    try_resize(sorted, old.len() + 1)?;
+    sort(old, &mut sorted[..old.len()], options.backend, &mut |_| {});
+    let hints = local_hints(&options.hints, new_start + prefix as u64, new.len(), 0);
+    scan_matches(
+        sorted,
+        old,
+        new,
+        &hints,
+        0,
+        options.max_work.unwrap_or(u64::MAX),
+        &mut |_| {},
+        |s, lenf, lenb| {
+            let differing = (0..lenf)
+                .filter(|i| old[(s.lastpos + i) as usize] != new[(s.lastscan + i) as usize])
+                .count();
+            size += entry + differing as u64 + ((s.scan - lenb) - (s.lastscan + lenf)) as u64;
+            Ok(())
+        },
+    )?;
+    Ok(size)
+}
+
+/// Which implementation of divsufsort builds the suffix arrays, see [`DiffOptions::backend`].
+///
+/// The Rust port is always compiled in. The C library is compiled in as well with the `c` feature,
+/// which is on by default, and then both can be chosen between at runtime, e.g. to compare them on
+/// the machine at hand. Both build the same suffix array, so the patches are the same either way.
+#[derive(Eq, PartialEq, Copy, Clone, Hash, Debug, Default)]
+pub enum SortBackend {
+    /// The C library if it's compiled in, as it's the faster one on the architectures it has been
+    /// measured on, and the Rust port otherwise.
+    #[default]
+    Auto,
+    /// The Rust port.
+    Rust,
+    /// The C library, or the Rust port if the `c` feature is off.
+    C,
+}
+
+impl SortBackend {
+    /// Whether the C library is compiled in, so [`SortBackend::C`] doesn't fall back to Rust.
+    pub fn c_available() -> bool {
+        cfg!(feature = "c")
+    }
+
+    fn sort_in_place(self, old: &[u8], sorted: &mut [i32]) {
+        match self {
+            #[cfg(feature = "c")]
+            SortBackend::Auto | SortBackend::C => cdivsufsort::sort_in_place(old, sorted),
+            _ => divsufsort::sort_in_place(old, sorted),
+        }
+    }
+}
+
+/// Inputs smaller than this are sorted quickly enough to not need progress reports in between.
+const SORT_PROGRESS_THRESHOLD: usize = 4 * 1024 * 1024;
+/// A rough guess at how fast divsufsort is, used to estimate its progress.
+const SORT_BYTES_PER_SEC: f64 = 16. * 1024. * 1024.;
+
+/// Builds the suffix array of `old` in `sorted`, estimating progress along the way.
+pub(crate) fn sort(
+    old: &[u8],
+    sorted: &mut [i32],
+    backend: SortBackend,
+    progress: &mut impl FnMut(State),
+) {
+    span!(DEBUG, "sort", bytes = old.len());
+    let total = old.len() as u64;
+    progress(State::Sorting { done: 0, total });
+    if old.len() < SORT_PROGRESS_THRESHOLD {
+        backend.sort_in_place(old, sorted);
     } else {
+        thread::scope(|s| {
+            let (done_tx, done_rx) = mpsc::channel();
+            s.spawn(move || {
+                backend.sort_in_place(old, sorted);
+                let _ = done_tx.send(());
+            });
+            let start = Instant::now();
+            let expected = total as f64 / SORT_BYTES_PER_SEC;
+            // Approaches, but never reaches, the total, no matter how far off the guess is. If the
+            // sort panics, the channel is disconnected and the panic is passed on by the scope.
+            while let Err(RecvTimeoutError::Timeout) =
+                done_rx.recv_timeout(Duration::from_millis(100))
+            {
+                let ratio = start.elapsed().as_secs_f64() / expected;
+                let done = (total as f64 * (1. - (-ratio).exp())) as u64;
+                progress(State::Sorting {
+                    done: done.min(total - 1),
+                    total,
+                });
+            }
+        });
+    }
+    progress(State::Sorting { done: total, total });
+}
+
+pub(crate) fn match_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter()
+        .zip(b.iter())
+        .enumerate()
+        .take_while(|(_, (old, new))| old == new)
+        .last()
+        .map_or(0, |(i, _)| i + 1)
+}
+
+/// Compares lexicographically the common part of these slices, i.e. takes the smallest length and
+/// compares within that.
+fn min_memcmp(a: &[u8], b: &[u8]) -> Ordering {
+    let len = a.len().min(b.len());
+    a[..len].cmp(&b[..len])
+}
+
+/// Estimates how many bits per byte `bytes` would compress to, from how often each value occurs.
+fn entropy(bytes: impl Iterator<Item = u8>) -> f32 {
+    let mut counts = [0u32; 256];
+    let mut len = 0;
+    for byte in bytes {
+        counts[byte as usize] += 1;
+        len += 1;
+    }
+    let len = len as f32;
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f32 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// This is a binary search of the string `new` in the `old` string using the suffix array
+/// `sorted`. `st` and `en` is the start and end of the search range (inclusive).
+/// Returns the length of the longest prefix found and stores the position of the
+/// string found in `*pos`.
+///
+/// `sorted[en]` is always looked at, so `sorted` needs the extra element after the suffix array
+/// that [`generate_from`] allocates, even for an empty `old`. Positions in `sorted` may be up to
+/// one past the end of `old`.
+pub(crate) fn search(
+    sorted: &[i32],
+    old: &[u8],
+    new: &[u8],
+    mut st: usize,
+    mut en: usize,
+    pos: &mut i64,
+) -> i64 {
+    debug_assert!(st <= en && en < sorted.len());
+    if old.is_empty() {
+        // Nothing can match, and `sorted` only holds the sentinel
+        *pos = 0;
+        return 0;
+    }
+    while en - st >= 2 {
         let x = st + (en - st) / 2;
         if min_memcmp(&old[(sorted[x] as usize)..], new) != Ordering::Greater {
-            search(sorted, old, new, x, en, pos)
+            st = x;
         } else {
-            search(sorted, old, new, st, x, pos)
+            en = x;
         }
     }
+    let x = match_len(&old[(sorted[st] as usize)..], new) as i64;
+    let y = match_len(&old[(sorted[en] as usize)..], new) as i64;
+
+    if x > y {
+        *pos = sorted[st] as i64;
+        x
+    } else {
+        *pos = sorted[en] as i64;
+        y
+    }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "apply"))]
 mod test {
-    use crate::diff::match_len;
+    use std::collections::HashSet;
+    use std::io::{sink, Cursor};
+
+    use crate::diff::{
+        extend_backward, extend_forward, find_match, generate_from, match_len, resolve_overlap,
+        search, sort, try_resize, EntryCounter, Scan, SortBackend, Sorted, RESERVE_LIMIT,
+    };
+    use crate::spec::{parse_chunked, parse_patch};
+    use crate::test_util::Rng;
+    use crate::{
+        apply, apply_chunked, apply_slice, estimate_patch_size, generate,
+        generate_chunked_with_options, generate_streaming, generate_with_report, histogram,
+        DiffError, DiffOptions, Differ, Hint, Patcher, State,
+    };
 
     #[test]
     fn testy() {
@@ -348,4 +2300,856 @@ mod test {
         assert_eq!(match_len(b"abcdef", b"abc"), 3);
         assert_eq!(match_len(b"dabcde", b"abcfed"), 0);
     }
+
+    #[test]
+    fn differ_reuse() {
+        let mut differ = Differ::new(DiffOptions::new().chunk_size(1000));
+        let mut patcher = Patcher::new();
+        for (old, new) in [
+            (
+                &b"hello world, hello world"[..],
+                &b"hello there world, hello"[..],
+            ),
+            (&[7; 5000][..], &[8; 3000][..]),
+            (&b""[..], &b"from nothing"[..]),
+        ] {
+            let mut patch = Vec::new();
+            differ.run(old, new, &mut patch).unwrap();
+            let mut out = Vec::new();
+            patcher
+                .run(&mut Cursor::new(old), &mut out, &mut &patch[..])
+                .unwrap();
+            assert_eq!(out, new);
+
+            let mut patch = Vec::new();
+            differ
+                .run_chunked(&mut &old[..], &mut &new[..], &mut patch)
+                .unwrap();
+            let mut out = Vec::new();
+            patcher
+                .run_chunked(&mut Cursor::new(old), &mut out, &mut &patch[..])
+                .unwrap();
+            assert_eq!(out, new);
+        }
+    }
+
+    #[test]
+    fn reuse_suffix_array() {
+        let old: Vec<u8> = (0..5000u32).map(|i| (i * i) as u8).collect();
+        let mut sorts = 0;
+        let mut differ = Differ::new(DiffOptions::new()).with_progress(|state| {
+            if state
+                == (State::Sorting {
+                    done: 0,
+                    total: 5000,
+                })
+            {
+                sorts += 1;
+            }
+        });
+        for new in [&b"first"[..], b"second", b"third"] {
+            let mut patch = Vec::new();
+            differ.run(&old, new, &mut patch).unwrap();
+            let mut out = Vec::new();
+            apply(&mut Cursor::new(&old), &mut out, &mut &patch[..]).unwrap();
+            assert_eq!(out, new);
+        }
+        let mut other = old.clone();
+        other[1234] ^= 1;
+        differ.run(&other, b"fourth", &mut Vec::new()).unwrap();
+        drop(differ);
+        assert_eq!(sorts, 2);
+    }
+
+    #[test]
+    fn saved_index() {
+        let old: Vec<u8> = (0..5000u32).map(|i| (i * i) as u8).collect();
+        let mut new = old.clone();
+        new[2000..2100].fill(7);
+        let mut index = Vec::new();
+        let mut first = Differ::new(DiffOptions::new());
+        first.save_index(&new, &mut index).unwrap();
+        assert_eq!(index.len(), 24 + 4 * new.len());
+
+        let mut sorts = 0;
+        let mut differ = Differ::new(DiffOptions::new()).with_progress(|state| {
+            if let State::Sorting { done: 0, .. } = state {
+                sorts += 1;
+            }
+        });
+        assert!(differ.load_index(&old, &mut &index[..]).is_err());
+        differ.load_index(&new, &mut &index[..]).unwrap();
+        let mut patch = Vec::new();
+        differ.run(&new, b"next version", &mut patch).unwrap();
+        drop(differ);
+        assert_eq!(sorts, 0);
+        let mut out = Vec::new();
+        apply(&mut Cursor::new(&new), &mut out, &mut &patch[..]).unwrap();
+        assert_eq!(out, b"next version");
+
+        let mut corrupted = index.clone();
+        corrupted[24..28].copy_from_slice(&i32::MAX.to_be_bytes());
+        let mut differ = Differ::new(DiffOptions::new());
+        assert!(differ.load_index(&new, &mut &corrupted[..]).is_err());
+    }
+
+    #[test]
+    fn min_similarity() {
+        let old = [1; 1000];
+        let new: Vec<u8> = (0..2000).map(|i| (i * 7) as u8).collect();
+        let options = DiffOptions::new().chunk_size(1000).min_similarity(0.05);
+        let mut patch = Vec::new();
+        generate_chunked_with_options(&mut &old[..], &mut &new[..], &mut patch, &options, |_| {})
+            .unwrap();
+        // Each chunk is a header, a single entry with the new data, and the terminator
+        assert_eq!(patch.len(), 2 * (16 + 24 + 1000 + 24));
+        let mut out = Vec::new();
+        apply_chunked(&mut Cursor::new(&old), &mut out, &mut &patch[..]).unwrap();
+        assert_eq!(out, new);
+    }
+
+    #[test]
+    fn max_patch_size() {
+        let old = [0; 1000];
+        let new: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+        let mut differ = Differ::new(DiffOptions::new().max_patch_size(500));
+        let mut patch = Vec::new();
+        match differ.run(&old, &new, &mut patch) {
+            Err(DiffError::PatchTooLarge { written }) => {
+                assert!(written <= 500);
+                assert_eq!(written, patch.len() as u64);
+            }
+            other => panic!("{:?}", other),
+        }
+        let mut differ = Differ::new(DiffOptions::new().max_patch_size(2000));
+        differ.run(&old, &new, &mut Vec::new()).unwrap();
+    }
+
+    #[test]
+    fn fallible_allocation() {
+        let mut buf = vec![1u8; 10];
+        assert!(try_resize(&mut buf, usize::MAX).is_err());
+        assert_eq!(buf, [1; 10]);
+        try_resize(&mut buf, 20).unwrap();
+        assert_eq!(buf.len(), 20);
+    }
+
+    #[test]
+    fn shrunk_on_oom() {
+        let old: Vec<u8> = (0..600_000u32).map(|i| (i * 31 % 253) as u8).collect();
+        let mut new = old.clone();
+        new[300_000..300_100].fill(0);
+        let options = DiffOptions::new().chunk_size(1 << 20);
+        RESERVE_LIMIT.set(200_000);
+        let failed = generate_chunked_with_options(
+            &mut &old[..],
+            &mut &new[..],
+            &mut sink(),
+            &options,
+            |_| {},
+        );
+        assert!(matches!(failed, Err(DiffError::OutOfMemory(_))));
+
+        let mut patch = Vec::new();
+        let options = options.shrink_on_oom(true);
+        let result = generate_chunked_with_options(
+            &mut &old[..],
+            &mut &new[..],
+            &mut patch,
+            &options,
+            |_| {},
+        );
+        RESERVE_LIMIT.set(usize::MAX);
+        result.unwrap();
+        // Halved down to 128 KiB, the first size that fits
+        let chunks = parse_chunked(&patch).unwrap();
+        assert!(chunks.iter().all(|chunk| chunk.new_file_size <= 128 * 1024));
+        assert_eq!(chunks.len(), 5);
+        let mut out = Vec::new();
+        apply_slice(&old, &patch, &mut out).unwrap();
+        assert_eq!(out, new);
+    }
+
+    #[test]
+    fn contained() {
+        let old: Vec<u8> = (0..5000u32).map(|i| (i * i) as u8).collect();
+        for (new, entries) in [(&old[..3000], 1), (&old[1000..], 2), (&old[..], 1)] {
+            let mut patch = Vec::new();
+            generate(&old, new, &mut patch, |_| {}).unwrap();
+            assert_eq!(patch.len(), 16 + entries * 24 + new.len() + 24);
+            let mut out = Vec::new();
+            apply(&mut Cursor::new(&old), &mut out, &mut &patch[..]).unwrap();
+            assert_eq!(out, new);
+        }
+    }
+
+    #[test]
+    fn trimmed() {
+        let old: Vec<u8> = (0..5000u32).map(|i| (i * i) as u8).collect();
+        let new = [&old[..2000], b"new", &old[2500..3000], &old[3100..]].concat();
+        let mut sorted = Vec::new();
+        let mut differ = Differ::new(DiffOptions::new()).with_progress(|state| {
+            if let State::Sorting { done: 0, total } = state {
+                sorted.push(total);
+            }
+        });
+        // Only what's between the common start and end is sorted, unless the whole old file
+        // already is
+        for new in [&new[..], b"other", &new] {
+            let mut patch = Vec::new();
+            differ.run(&old, new, &mut patch).unwrap();
+            let mut out = Vec::new();
+            apply(&mut Cursor::new(&old), &mut out, &mut &patch[..]).unwrap();
+            assert_eq!(out, new);
+        }
+        drop(differ);
+        assert_eq!(sorted, [1100, 5000]);
+    }
+
+    #[test]
+    fn align() {
+        let old: Vec<u8> = (0..20_000u32).map(|i| (i * i / 7) as u8).collect();
+        let mut new = [
+            &old[..3001],
+            b"inserted",
+            &old[3001..9000],
+            &old[9100..17_000],
+        ]
+        .concat();
+        new[5555] ^= 1;
+        new.extend(b"appended");
+        let options = DiffOptions::new().chunk_size(8192).align(512);
+        let mut patch = Vec::new();
+        generate_chunked_with_options(&mut &old[..], &mut &new[..], &mut patch, &options, |_| {})
+            .unwrap();
+        let mut out = Vec::new();
+        apply_chunked(&mut Cursor::new(&old), &mut out, &mut &patch[..]).unwrap();
+        assert_eq!(out, new);
+        for chunk in parse_chunked(&patch).unwrap() {
+            let mut at = 0;
+            for entry in chunk.entries {
+                assert_eq!(at % 512, 0);
+                assert_eq!((at + entry.diff.len()) % 512, 0);
+                at += entry.diff.len() + entry.extra.len();
+            }
+        }
+    }
+
+    #[test]
+    fn word_diff() {
+        // Instructions with relative addresses, which all grow by the same amount
+        let mut rng = Rng::new(1);
+        let mut old = Vec::new();
+        let mut new = Vec::new();
+        for _ in 0..4000 {
+            let address = rng.next_u64() as u32 >> 3;
+            old.extend([0xe8, 1, 2, 3]);
+            old.extend(address.to_le_bytes());
+            new.extend([0xe8, 1, 2, 3]);
+            new.extend((address + 0x1234).to_le_bytes());
+        }
+        let options = DiffOptions::new()
+            .chunk_size(8192)
+            .max_run(1001)
+            .word_diff(true);
+        let mut patch = Vec::new();
+        generate_chunked_with_options(&mut &old[..], &mut &new[..], &mut patch, &options, |_| {})
+            .unwrap();
+        let mut out = Vec::new();
+        apply_chunked(&mut Cursor::new(&old), &mut out, &mut &patch[..]).unwrap();
+        assert_eq!(out, new);
+        let mut out = Vec::new();
+        apply_slice(&old, &patch, &mut out).unwrap();
+        assert_eq!(out, new);
+
+        let mut words = HashSet::new();
+        for chunk in parse_chunked(&patch).unwrap() {
+            assert!(chunk.words);
+            for entry in chunk.entries {
+                words.extend(entry.diff.chunks_exact(4).filter(|word| word != &[0; 4]));
+            }
+        }
+        assert_eq!(words, HashSet::from([&0x1234u32.to_le_bytes()[..]]));
+    }
+
+    #[test]
+    fn masked() {
+        // A header with a timestamp and a trailer with a checksum, around data with a few bytes
+        // inserted
+        let old: Vec<u8> = (0..20_000u32).map(|i| (i * i / 7) as u8).collect();
+        let mut new = old.clone();
+        new[8..16].copy_from_slice(b"20261016");
+        new.splice(5000..5000, *b"inserted");
+        let len = new.len();
+        new[len - 4..].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        let masks = [8..16, len as u64 - 4..len as u64];
+
+        let nonzero = |patch: &[u8]| -> usize {
+            let chunks = parse_chunked(patch).unwrap();
+            let entries = chunks.iter().flat_map(|chunk| &chunk.entries);
+            entries
+                .map(|entry| {
+                    let data = entry.diff.iter().chain(entry.extra.iter());
+                    data.filter(|&&byte| byte != 0).count()
+                })
+                .sum()
+        };
+        for options in [DiffOptions::new(), DiffOptions::new().chunk_size(3000)] {
+            let mut plain = Vec::new();
+            generate_chunked_with_options(
+                &mut &old[..],
+                &mut &new[..],
+                &mut plain,
+                &options,
+                |_| {},
+            )
+            .unwrap();
+            let mut patch = Vec::new();
+            let options = options.mask(masks.clone());
+            generate_chunked_with_options(
+                &mut &old[..],
+                &mut &new[..],
+                &mut patch,
+                &options,
+                |_| {},
+            )
+            .unwrap();
+            let mut out = Vec::new();
+            apply_slice(&old, &patch, &mut out).unwrap();
+            out[8..16].copy_from_slice(b"20261016");
+            out[len - 4..].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+            assert_eq!(out, new);
+            assert_eq!(nonzero(&patch), b"inserted".len());
+            assert!(nonzero(&plain) > nonzero(&patch));
+        }
+    }
+
+    #[test]
+    fn coalesced() {
+        let old: Vec<u8> = (0..20_000u32).map(|i| (i * i / 7) as u8).collect();
+        let mut new = old.clone();
+        for i in (100..19_000).step_by(97) {
+            new[i] ^= 0x55;
+        }
+        let mut patch = Vec::new();
+        generate(&old, &new, &mut patch, |_| {}).unwrap();
+        let mut out = Vec::new();
+        apply(&mut Cursor::new(&old), &mut out, &mut &patch[..]).unwrap();
+        assert_eq!(out, new);
+        let chunks = parse_chunked(&patch).unwrap();
+        for pair in chunks[0].entries.windows(2) {
+            assert!(!pair[0].extra.is_empty() || pair[0].seek != 0 || pair[1].diff.is_empty());
+        }
+    }
+
+    #[test]
+    fn max_run() {
+        let old: Vec<u8> = (0..20_000u32).map(|i| (i * i / 7) as u8).collect();
+        let mut new = old.clone();
+        for i in (100..10_000).step_by(97) {
+            new[i] ^= 0x55;
+        }
+        // Long extra data, repeated so copies have something to do
+        let block: Vec<u8> = (0..3000u32).map(|i| (i * 7919 % 251) as u8).collect();
+        new.splice(12_000..12_000, block.iter().chain(&block).copied());
+        for copies in [false, true] {
+            let options = DiffOptions::new().copy_from_new(copies).max_run(700);
+            let mut patch = Vec::new();
+            Differ::new(options.clone())
+                .run(&old, &new, &mut patch)
+                .unwrap();
+            let mut chunked = Vec::new();
+            generate_chunked_with_options(
+                &mut &old[..],
+                &mut &new[..],
+                &mut chunked,
+                &options.chunk_size(8000),
+                |_| {},
+            )
+            .unwrap();
+            for patch in [&patch, &chunked] {
+                let mut out = Vec::new();
+                apply_chunked(&mut Cursor::new(&old), &mut out, &mut &patch[..]).unwrap();
+                assert_eq!(out, new);
+                let entries = parse_chunked(patch)
+                    .unwrap()
+                    .into_iter()
+                    .flat_map(|c| c.entries);
+                for entry in entries {
+                    let len = entry.copy.map_or(0, |(len, _)| len);
+                    assert!(entry.diff.len() <= 700 && entry.extra.len() <= 700 && len <= 700);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn max_work() {
+        // The blocks of the old file in reverse, so each needs its own match
+        let old: Vec<u8> = (0..20_000u32).map(|i| (i * i / 7) as u8).collect();
+        let new: Vec<u8> = old.chunks(1000).rev().flatten().copied().collect();
+        let extra = |options: DiffOptions| {
+            let mut patch = Vec::new();
+            Differ::new(options).run(&old, &new, &mut patch).unwrap();
+            let mut out = Vec::new();
+            apply(&mut Cursor::new(&old), &mut out, &mut &patch[..]).unwrap();
+            assert_eq!(out, new);
+            histogram(&patch).unwrap().extra_bytes
+        };
+        assert!(extra(DiffOptions::new()) < 100);
+        assert!(extra(DiffOptions::new().max_work(10_000_000)) < 100);
+        // Runs out after a few blocks, which leaves the rest as extra data
+        let limited = extra(DiffOptions::new().max_work(5000));
+        assert!((5000..19_000).contains(&limited));
+    }
+
+    #[test]
+    fn prefer_literals() {
+        // New data with two byte values, and old data that only matches it at most positions
+        let mut rng = Rng::new(1);
+        let mut random = move || rng.next_u64() as u8;
+        let new: Vec<u8> = (0..20_000).map(|_| b'a' + (random() & 1)).collect();
+        let old: Vec<u8> = new
+            .iter()
+            .map(|&b| if random() < 90 { random() } else { b })
+            .collect();
+        let diff = |options: DiffOptions| {
+            let mut patch = Vec::new();
+            Differ::new(options).run(&old, &new, &mut patch).unwrap();
+            let mut out = Vec::new();
+            apply(&mut Cursor::new(&old), &mut out, &mut &patch[..]).unwrap();
+            assert_eq!(out, new);
+            histogram(&patch).unwrap().diff_bytes
+        };
+        for copies in [false, true] {
+            let options = DiffOptions::new().copy_from_new(copies);
+            assert!(diff(options.clone().prefer_literals(true)) < diff(options) / 2);
+        }
+    }
+
+    #[test]
+    fn appended() {
+        let old: Vec<u8> = (0..1000u32)
+            .flat_map(|i| format!("line {i}\n").into_bytes())
+            .collect();
+        let new = [&old[..], b"line 1000\nline 1001\n"].concat();
+        let mut sorted = false;
+        let mut patch = Vec::new();
+        Differ::new(DiffOptions::new())
+            .with_progress(|state| sorted |= matches!(state, State::Sorting { .. }))
+            .run(&old, &new, &mut patch)
+            .unwrap();
+        assert!(!sorted);
+        let entries = parse_patch(&patch).unwrap().entries;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].diff.len(), old.len());
+        assert_eq!(entries[1].extra, b"line 1000\nline 1001\n");
+        let mut patched = Vec::new();
+        apply(&mut Cursor::new(&old), &mut patched, &mut &patch[..]).unwrap();
+        assert_eq!(patched, new);
+    }
+
+    #[test]
+    fn hints() {
+        let block: Vec<u8> = (0..500u32).map(|i| (i * i / 3) as u8).collect();
+        // The block is in the old file twice, and the hint says which copy it comes from
+        let old = [&block[..], &[7; 300], &block].concat();
+        let new = [&b"x"[..], &block, b"y"].concat();
+        for from in [0, 800] {
+            let hint = Hint {
+                old: from,
+                new: 1,
+                len: 500,
+            };
+            let mut patch = Vec::new();
+            Differ::new(DiffOptions::new().hints([hint]))
+                .run(&old, &new, &mut patch)
+                .unwrap();
+            let entries = parse_patch(&patch).unwrap().entries;
+            assert_eq!(entries[0].seek, from as i64);
+            let mut patched = Vec::new();
+            apply(&mut Cursor::new(&old), &mut patched, &mut &patch[..]).unwrap();
+            assert_eq!(patched, new);
+        }
+
+        // Chunked, with a hint into an earlier chunk of the old file
+        let old = Rng::new(3).bytes(10_000);
+        let mut new = old.clone();
+        new[4200] ^= 1;
+        let options = DiffOptions::new().chunk_size(4096).hints(vec![Hint {
+            old: 10,
+            new: 100,
+            len: 5000,
+        }]);
+        let mut patch = Vec::new();
+        generate_chunked_with_options(&mut &old[..], &mut &new[..], &mut patch, &options, |_| {})
+            .unwrap();
+        let mut patched = Vec::new();
+        apply_chunked(&mut Cursor::new(&old), &mut patched, &mut &patch[..]).unwrap();
+        assert_eq!(patched, new);
+    }
+
+    #[test]
+    fn offsets_beyond_32_bits() {
+        // Where the applier is in the old file doesn't fit 32 bits, which mustn't be truncated
+        // on 32-bit targets
+        let old: Vec<u8> = (0..5000u32).map(|i| (i * i / 7) as u8).collect();
+        let mut new = old.clone();
+        new[2500] ^= 1;
+        for copies in [false, true] {
+            for offset in [5 << 30, -(5 << 30), i64::from(u32::MAX) + 1] {
+                let options = DiffOptions::new().copy_from_new(copies);
+                let mut patch = Vec::new();
+                generate_from(
+                    &old,
+                    &new,
+                    &mut patch,
+                    offset,
+                    0,
+                    &options,
+                    &mut Sorted::default(),
+                    |_| {},
+                )
+                .unwrap();
+                let chunks = parse_chunked(&patch).unwrap();
+                assert_eq!(chunks[0].entries[0].seek, offset);
+            }
+        }
+    }
+
+    #[test]
+    fn streaming() {
+        let old: Vec<u8> = (0..50_000u32).map(|i| (i * i / 7) as u8).collect();
+        // The end of the old file moved to the start, further than any window reaches
+        let mut new = [&old[40_000..], &old[..40_000]].concat();
+        new[25_000] ^= 1;
+        new.extend(b"appended");
+        let options = DiffOptions::new().new_window(4096);
+        let mut patch = Vec::new();
+        generate_streaming(&old, &mut &new[..], &mut patch, &options, |_| {}).unwrap();
+        let mut out = Vec::new();
+        apply_chunked(&mut Cursor::new(&old), &mut out, &mut &patch[..]).unwrap();
+        assert_eq!(out, new);
+        // Everything was found in the old file, so there's hardly anything but zeros in the diff data
+        assert!(patch.iter().filter(|&&b| b != 0).count() < 2000);
+
+        let mut patch = Vec::new();
+        generate_streaming(&old, &mut &b""[..], &mut patch, &options, |_| {}).unwrap();
+        let mut out = Vec::new();
+        apply_chunked(&mut Cursor::new(&old), &mut out, &mut &patch[..]).unwrap();
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn pipelined() {
+        let old: Vec<u8> = (0..20_000u32).map(|i| (i * i / 7) as u8).collect();
+        let new = [&old[..3001], b"inserted", &old[3001..17_000]].concat();
+        for new in [&new[..], b""] {
+            let patches = [true, false].map(|pipelined| {
+                let options = DiffOptions::new().chunk_size(4096).pipelined(pipelined);
+                let mut patch = Vec::new();
+                let mut last = 0;
+                generate_chunked_with_options(
+                    &mut &old[..],
+                    &mut &new[..],
+                    &mut patch,
+                    &options,
+                    |state| {
+                        if let State::Working(bytes) = state {
+                            assert!(bytes >= last);
+                            last = bytes;
+                        }
+                    },
+                )
+                .unwrap();
+                patch
+            });
+            assert_eq!(patches[0], patches[1]);
+            let mut out = Vec::new();
+            apply_chunked(&mut Cursor::new(&old), &mut out, &mut &patches[0][..]).unwrap();
+            assert_eq!(out, new);
+        }
+        // The diffing thread is stopped when writing fails
+        let options = DiffOptions::new()
+            .chunk_size(4096)
+            .max_patch_size(5000)
+            .pipelined(true);
+        let result = generate_chunked_with_options(
+            &mut &old[..],
+            &mut &new[..],
+            &mut sink(),
+            &options,
+            |_| {},
+        );
+        assert!(matches!(result, Err(DiffError::PatchTooLarge { .. })));
+    }
+
+    #[test]
+    fn search_edge_cases() {
+        for old in [&b""[..], b"x", b"xxxxxxxx", b"xy"] {
+            let mut sorted = vec![0; old.len() + 1];
+            sort(
+                old,
+                &mut sorted[..old.len()],
+                SortBackend::Auto,
+                &mut |_| {},
+            );
+            let searched = &old[..old.len().saturating_sub(1)];
+            for new in [&b""[..], b"x", b"xxxxxxxxxxxx", b"y"] {
+                let mut pos = -1;
+                let len = search(&sorted, searched, new, 0, old.len(), &mut pos);
+                assert!(0 <= pos && pos as usize <= old.len());
+                assert_eq!(len as usize, match_len(&searched[pos as usize..], new));
+            }
+        }
+    }
+
+    #[test]
+    fn next_match() {
+        let old = b"0123456789abcdefghijklmnop";
+        let mut sorted = vec![0; old.len() + 1];
+        sort(
+            old,
+            &mut sorted[..old.len()],
+            SortBackend::Auto,
+            &mut |_| {},
+        );
+        // The start matches at the same offset, then the rest moved back by 2 bytes
+        let new = [&b"0123zz"[..], &old[8..]].concat();
+        let mut s = Scan::default();
+        assert_eq!(
+            find_match(&sorted, old, &new, &[], &mut s, u64::MAX, &mut |_| {}),
+            4
+        );
+        assert_eq!((s.scan, s.len, s.pos), (0, 4, 0));
+        let oldscore = find_match(&sorted, old, &new, &[], &mut s, u64::MAX, &mut |_| {});
+        assert_eq!((oldscore, s.scan, s.len, s.pos), (0, 6, 17, 8));
+
+        let mut s = Scan::default();
+        find_match(&sorted, old, &new, &[], &mut s, 0, &mut |_| {});
+        assert_eq!(s.scan, new.len() as i64);
+    }
+
+    #[test]
+    fn extensions() {
+        let s = Scan {
+            scan: 8,
+            pos: 8,
+            ..Scan::default()
+        };
+        assert_eq!(extend_forward(b"aaaaabbbbb", b"aaaaaccccc", &s), 5);
+        assert_eq!(extend_backward(b"zzzzzabcde", b"qqqqqabcde", &s), 3);
+        let end = Scan { scan: 10, ..s };
+        assert_eq!(extend_backward(b"zzzzzabcde", b"qqqqqabcde", &end), 0);
+    }
+
+    #[test]
+    fn overlap() {
+        // The extensions overlap on "cde", of which "c" matches going forward and "de" going back
+        let s = Scan {
+            scan: 6,
+            pos: 10,
+            ..Scan::default()
+        };
+        assert_eq!(resolve_overlap(b"abcXYZQdef", b"abcdef", &s, 5, 4), (3, 3));
+        assert_eq!(resolve_overlap(b"abcXYZQdef", b"abcdef", &s, 2, 4), (2, 4));
+    }
+
+    #[test]
+    fn report() {
+        let old: Vec<u8> = (0..20_000u32).map(|i| (i * i / 7) as u8).collect();
+        let mut new = [&old[..5000], b"inserted", &old[5000..]].concat();
+        new[12_000] ^= 1;
+        let mut patch = Vec::new();
+        let report = generate_with_report(&old, &new, &mut patch, |_| {}).unwrap();
+        assert_eq!(report.patch_bytes, patch.len() as u64);
+        assert_eq!(report.new_len, new.len() as u64);
+        let chunks = parse_chunked(&patch).unwrap();
+        assert_eq!(report.entries, chunks[0].entries.len() as u64);
+
+        // Chunked, with copies, and written a byte at a time
+        let block = Rng::new(1).bytes(1000);
+        let new = [&new[..], &block, &block].concat();
+        let options = DiffOptions::new().chunk_size(8000).copy_from_new(true);
+        let mut patch = Vec::new();
+        Differ::new(options).run(&old, &new, &mut patch).unwrap();
+        let mut counter = EntryCounter::new(std::io::sink());
+        for byte in &patch {
+            std::io::Write::write_all(&mut counter, &[*byte]).unwrap();
+        }
+        let chunks = parse_chunked(&patch).unwrap();
+        assert!(chunks
+            .iter()
+            .any(|c| c.entries.iter().any(|e| e.copy.is_some())));
+        let entries: usize = chunks.iter().map(|chunk| chunk.entries.len()).sum();
+        assert_eq!(counter.entries, entries as u64);
+    }
+
+    #[test]
+    fn degenerate() {
+        let same = [7; 1000];
+        let mixed: Vec<u8> = (0..1000u32).map(|i| (i * i) as u8).collect();
+        let inputs: [&[u8]; 7] = [b"", b"a", b"\x07", b"ab", &same, &same[..999], &mixed];
+        for old in inputs {
+            for new in inputs {
+                for copies in [false, true] {
+                    let options = DiffOptions::new().copy_from_new(copies);
+                    let mut patch = Vec::new();
+                    Differ::new(options).run(old, new, &mut patch).unwrap();
+                    let mut out = Vec::new();
+                    apply(&mut Cursor::new(old), &mut out, &mut &patch[..]).unwrap();
+                    assert_eq!(out, new, "{:?} {:?}", old.len(), new.len());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn backends() {
+        let old: Vec<u8> = (0..5000u32).map(|i| (i * i / 3) as u8).collect();
+        let new = [&old[2000..], b"new", &old[..2000]].concat();
+        let patches: Vec<Vec<u8>> = [SortBackend::Auto, SortBackend::Rust, SortBackend::C]
+            .into_iter()
+            .map(|backend| {
+                let mut patch = Vec::new();
+                Differ::new(DiffOptions::new().backend(backend))
+                    .run(&old, &new, &mut patch)
+                    .unwrap();
+                patch
+            })
+            .collect();
+        assert_eq!(patches[0], patches[1]);
+        assert_eq!(patches[0], patches[2]);
+    }
+
+    #[test]
+    fn estimate() {
+        let old: Vec<u8> = (0..5000u32).map(|i| (i * i / 3) as u8).collect();
+        let unrelated: Vec<u8> = (0..5000u32).map(|i| (i * 7 + i / 13) as u8).collect();
+        let options = DiffOptions::new().chunk_size(100_000);
+        let same = estimate_patch_size(&old, &old, &options).unwrap();
+        let changed =
+            estimate_patch_size(&old, &[&old[..4000], b"new"].concat(), &options).unwrap();
+        let different = estimate_patch_size(&old, &unrelated, &options).unwrap();
+        assert!(same < 100, "{}", same);
+        assert!(changed < 100, "{}", changed);
+        assert!(different > 4000, "{}", different);
+        // Between the bytes of the real patch that aren't zero and all of them
+        let random = Rng::new(4).bytes(30_000);
+        let edited = [
+            &random[..9000],
+            b"edited",
+            &random[9500..21_000],
+            &unrelated,
+        ]
+        .concat();
+        for (old, new) in [
+            (&old, &unrelated),
+            (&random, &edited),
+            (&random, &random[700..].to_vec()),
+        ] {
+            let options = DiffOptions::new().chunk_size(8000);
+            let mut patch = Vec::new();
+            generate_chunked_with_options(
+                &mut &old[..],
+                &mut &new[..],
+                &mut patch,
+                &options,
+                |_| {},
+            )
+            .unwrap();
+            let estimate = estimate_patch_size(old, new, &options).unwrap();
+            let nonzero = patch.iter().filter(|&&b| b != 0).count() as u64;
+            assert!(
+                (nonzero..=patch.len() as u64).contains(&estimate),
+                "{} {} {}",
+                nonzero,
+                estimate,
+                patch.len()
+            );
+        }
+    }
+
+    #[test]
+    fn summary() {
+        let old: Vec<u8> = (0..5000u32).map(|i| (i * i) as u8).collect();
+        let new = [&old[..2000], b"new", &old[3000..]].concat();
+        let mut patch = Vec::new();
+        let mut last = None;
+        generate_chunked_with_options(
+            &mut &old[..],
+            &mut &new[..],
+            &mut patch,
+            &DiffOptions::new().chunk_size(1000),
+            |state| last = Some(state),
+        )
+        .unwrap();
+        let Some(State::Done(summary)) = last else {
+            panic!("{:?}", last);
+        };
+        assert_eq!(summary.old_bytes, old.len() as u64);
+        assert_eq!(summary.new_bytes, new.len() as u64);
+        assert_eq!(summary.patch_bytes, patch.len() as u64);
+
+        let mut last = None;
+        generate(&old, &new, &mut Vec::new(), |state| last = Some(state)).unwrap();
+        assert!(
+            matches!(last, Some(State::Done(summary)) if summary.new_bytes == new.len() as u64),
+            "{:?}",
+            last
+        );
+    }
+
+    #[test]
+    fn windows() {
+        let old = Rng::new(1).bytes(20_000);
+        let new = [&old[6000..12_000], b"inserted", &old[..3000]].concat();
+        let mut sizes = Vec::new();
+        for (old_window, new_window) in [(2000, 2000), (16_000, 2000), (1000, 4000)] {
+            let options = DiffOptions::new()
+                .old_window(old_window)
+                .new_window(new_window);
+            let mut patch = Vec::new();
+            generate_chunked_with_options(
+                &mut &old[..],
+                &mut &new[..],
+                &mut patch,
+                &options,
+                |_| {},
+            )
+            .unwrap();
+            let mut out = Vec::new();
+            apply_chunked(&mut Cursor::new(&old), &mut out, &mut &patch[..]).unwrap();
+            assert_eq!(out, new);
+            // Matched data is stored as zeros, which compress away
+            sizes.push(patch.iter().filter(|&&b| b != 0).count());
+        }
+        // Only the larger old window finds the moved data
+        assert!(sizes[1] < sizes[0] / 4, "{:?}", sizes);
+    }
+
+    #[test]
+    fn copy_from_new() {
+        let old: Vec<u8> = (0..10_000u32).map(|i| (i * 7) as u8).collect();
+        let block = Rng::new(1).bytes(5000);
+        let new = [&old[..3000], &block, &old[3000..], &block, &block[..1000]].concat();
+        let mut sizes = Vec::new();
+        for copies in [false, true] {
+            let options = DiffOptions::new().chunk_size(100_000).copy_from_new(copies);
+            let mut patch = Vec::new();
+            generate_chunked_with_options(
+                &mut &old[..],
+                &mut &new[..],
+                &mut patch,
+                &options,
+                |_| {},
+            )
+            .unwrap();
+            let mut out = Vec::new();
+            apply_chunked(&mut Cursor::new(&old), &mut out, &mut &patch[..]).unwrap();
+            assert_eq!(out, new);
+            sizes.push(patch.len());
+        }
+        assert!(sizes[1] < sizes[0] - 5000, "{:?}", sizes);
+    }
 }
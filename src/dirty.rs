@@ -0,0 +1,123 @@
+//! Patch generation for files that were changed in place, where the changed parts are known.
+
+use std::io::Write;
+use std::iter;
+use std::ops::Range;
+
+use crate::diff::Result;
+use crate::{ChunkedPatchWriter, DiffOptions, State};
+
+/// How far around a changed range of the new file its data is looked for in the old file, unless
+/// an old window is set.
+const AROUND: u64 = 1024 * 1024;
+
+/// Generate a chunked patch for a file that was changed in place, given which ranges of it were
+/// written to, e.g. from inotify or the NTFS change journal.
+///
+/// Everything outside of the `dirty` ranges is expected to be unchanged at the same offset, and
+/// becomes entries that take the old file as-is, without sorting or searching it. Only the dirty
+/// ranges are diffed, each against the same range of the old file plus 1 MiB on either side, or
+/// half of [`old_window`][DiffOptions::old_window] if that is set. Dirty ranges closer together
+/// than that are diffed as one. A small edit to a huge file then costs about as much as diffing a
+/// few MiB. Whatever the new file has past the end of the old one counts as dirty.
+///
+/// The ranges don't have to be sorted or disjoint. A change outside of them is still diffed
+/// correctly, just without the speedup, since unchanged ranges are checked before they are
+/// trusted. Of the `options`, the [`chunk_size`][DiffOptions::chunk_size] limits both kinds of
+/// ranges, and otherwise they are used like in [`ChunkedPatchWriter`]. The output is applied with
+/// [`apply_chunked`][crate::apply_chunked].
+pub fn generate_dirty(
+    old: &[u8],
+    new: &[u8],
+    dirty: &[Range<u64>],
+    patch: &mut impl Write,
+    options: &DiffOptions,
+    progress: impl FnMut(State),
+) -> Result<()> {
+    let around = options
+        .old_window
+        .map_or(AROUND, |window| window as u64 / 2);
+    let max = options.max_chunk_size() as u64;
+    let (old_len, new_len) = (old.len() as u64, new.len() as u64);
+    let mut dirty: Vec<Range<u64>> = dirty
+        .iter()
+        .map(|range| range.start.min(new_len)..range.end.min(new_len))
+        .filter(|range| !range.is_empty())
+        .collect();
+    if old_len < new_len {
+        dirty.push(old_len..new_len);
+    }
+    dirty.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<u64>> = Vec::new();
+    for range in dirty {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(around) => {
+                last.end = last.end.max(range.end)
+            }
+            _ => merged.push(range),
+        }
+    }
+
+    let mut writer = ChunkedPatchWriter::new(patch, options.clone()).with_progress(progress);
+    let mut pos = 0;
+    // An empty range at the end picks up the unchanged rest of the file
+    for range in merged.into_iter().chain(iter::once(new_len..new_len)) {
+        // Ends before the old file does, as what's past it is dirty
+        for start in (pos..range.start).step_by(max as usize) {
+            let end = (start + max).min(range.start);
+            let unchanged = start as usize..end as usize;
+            writer.push(&old[unchanged.clone()], start, &new[unchanged])?;
+        }
+        for start in (range.start..range.end).step_by(max as usize) {
+            let end = (start + max).min(range.end);
+            let old_start = start.saturating_sub(around).min(old_len);
+            let old_end = end.saturating_add(around).min(old_start + max).min(old_len);
+            writer.push(
+                &old[old_start as usize..old_end as usize],
+                old_start,
+                &new[start as usize..end as usize],
+            )?;
+        }
+        pos = range.end;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+#[cfg(all(test, feature = "apply"))]
+mod test {
+    use std::io::Cursor;
+
+    use super::generate_dirty;
+    use crate::{apply_chunked, DiffOptions, State};
+
+    #[test]
+    fn dirty() {
+        let old: Vec<u8> = (0..200_000u64).map(|i| (i * i / 13) as u8).collect();
+        let mut new = old.clone();
+        new[1000..1010].fill(b'x');
+        new[150_000..150_100].copy_from_slice(&old[20_000..20_100]);
+        new.extend(b"appended");
+        let options = DiffOptions::new().old_window(20_000);
+        for (dirty, sorted_at_most) in [
+            (&[150_000..150_100, 1005..1020, 990..1000][..], 50_000),
+            // A change that isn't reported is still found
+            (&[150_000..150_100, 200_000..200_008][..], old.len() as u64),
+        ] {
+            let mut sorted = 0;
+            let mut patch = Vec::new();
+            generate_dirty(&old, &new, dirty, &mut patch, &options, |state| {
+                if let State::Sorting { done, total } = state {
+                    if done == total {
+                        sorted += total;
+                    }
+                }
+            })
+            .unwrap();
+            assert!(sorted <= sorted_at_most, "{sorted}");
+            let mut patched = Vec::new();
+            apply_chunked(&mut Cursor::new(&old), &mut patched, &mut &patch[..]).unwrap();
+            assert_eq!(patched, new);
+        }
+    }
+}
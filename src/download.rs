@@ -0,0 +1,225 @@
+//! Planning which parts of a new file to download, from a signature of it and a local old file, as
+//! done by zsync.
+//!
+//! This is [`delta`][crate::delta] turned around: the server publishes a [`Signature`] of the new
+//! file instead of the client sending one of the old file, so the server only serves static files.
+//! The client looks for the blocks of the new file at every offset of its old file. Blocks it finds
+//! are copied from the old file, and the rest are fetched, e.g. with HTTP range requests. The
+//! result is an ordinary patch, with the fetched data as its extra data.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+
+use zerocopy::AsBytes;
+
+use crate::be::{I64, U64};
+use crate::diff::{write_ending, write_header, DiffError, Result};
+use crate::signature::{strong, Rolling};
+use crate::spec::Terminator;
+use crate::{EntryHeader, Signature};
+
+/// A part of the new file, and where it comes from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+    /// This many bytes at this offset in the old file.
+    Local { old: u64, len: u64 },
+    /// This many bytes that have to be fetched.
+    Fetch(u64),
+}
+
+/// Which parts of the new file have to be fetched, and how to put it together from them and the
+/// old file, see [`plan_download`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DownloadPlan {
+    new_len: u64,
+    /// The new file, in order, with neighbouring parts of the same kind merged.
+    parts: Vec<Part>,
+    fetch: Vec<Range<u64>>,
+}
+
+impl DownloadPlan {
+    /// The ranges of the new file to fetch, in order and not touching each other.
+    pub fn fetch(&self) -> &[Range<u64>] {
+        &self.fetch
+    }
+
+    /// How much of the new file has to be fetched.
+    pub fn fetch_len(&self) -> u64 {
+        self.fetch.iter().map(|range| range.end - range.start).sum()
+    }
+
+    /// How much of the new file is taken from the old file.
+    pub fn local_len(&self) -> u64 {
+        self.new_len - self.fetch_len()
+    }
+
+    /// Writes a patch from the old file to the new file, with the data of the
+    /// [`fetch`][Self::fetch] ranges read from `fetched`, one after the other.
+    ///
+    /// The patch is applied with [`apply`][crate::apply] to the old file the plan was made for.
+    /// Nothing checks that the fetched data is the right one, so compare a checksum of the new
+    /// file after applying.
+    pub fn write_patch(&self, fetched: &mut impl Read, patch: &mut impl Write) -> Result<()> {
+        write_header(patch, self.new_len)?;
+        // Entries as where their diff data starts in the old file, its length, and the length of
+        // the extra data, starting with one that only seeks to the first local part
+        let mut entries = vec![(0, 0, 0)];
+        for part in &self.parts {
+            match *part {
+                Part::Local { old, len } => entries.push((old, len, 0)),
+                Part::Fetch(len) => entries.last_mut().unwrap().2 += len,
+            }
+        }
+        for (i, &(old, diff, extra)) in entries.iter().enumerate() {
+            let next_old = entries.get(i + 1).map_or(old + diff, |next| next.0);
+            let seek = next_old as i64 - (old + diff) as i64;
+            // An empty entry would be read as the end of the patch
+            if Terminator::matches(diff, extra, seek) {
+                continue;
+            }
+            patch.write_all(
+                EntryHeader {
+                    diff: U64::new(diff),
+                    extra: U64::new(extra),
+                    seek: I64::new(seek),
+                }
+                .as_bytes(),
+            )?;
+            // The blocks are unchanged, so the difference is all zeros
+            io::copy(&mut io::repeat(0).take(diff), patch)?;
+            if io::copy(&mut fetched.take(extra), patch)? < extra {
+                return Err(DiffError::Internal("The fetched data is too short".into()));
+            }
+        }
+        write_ending(patch)?;
+        patch.flush()?;
+        Ok(())
+    }
+}
+
+/// Finds the blocks of the new file described by `signature` in `old`, and plans which parts of
+/// the new file have to be fetched.
+///
+/// `signature` is made from the new file with [`signature`][crate::signature], e.g. by the
+/// server when publishing it. Full blocks are found at any offset of the old file. A shorter last
+/// block is only looked for after wherever the block before it was found, and at the end of the
+/// old file. As with [`delta`][crate::delta], the strong checksum has to be trusted to tell blocks
+/// apart.
+pub fn plan_download(signature: &Signature, old: &[u8]) -> Result<DownloadPlan> {
+    // A parsed signature may use any algorithm
+    strong(signature.algorithm, &[])?;
+    let block_size = signature.block_size();
+    let new_len = signature.old_len();
+    let blocks = &signature.blocks;
+    let tail_len = (new_len % block_size as u64) as usize;
+    let full = blocks.len() - usize::from(tail_len > 0);
+    let mut by_weak: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (i, block) in blocks[..full].iter().enumerate() {
+        by_weak.entry(block.weak).or_default().push(i);
+    }
+    // Where each block of the new file was found in the old file
+    let mut found: Vec<Option<u64>> = vec![None; blocks.len()];
+    let mut pos = 0;
+    let mut rolling = (block_size <= old.len()).then(|| Rolling::of(&old[..block_size]));
+    while let Some(current) = &mut rolling {
+        let candidates = by_weak.get(&current.get()).map_or(&[][..], Vec::as_slice);
+        let mut matched = false;
+        if candidates.iter().any(|&i| found[i].is_none()) {
+            let strong = signature.strong(&old[pos..pos + block_size]);
+            for &i in candidates {
+                if found[i].is_none() && blocks[i].strong == strong {
+                    found[i] = Some(pos as u64);
+                    matched = true;
+                }
+            }
+        }
+        if matched {
+            pos += block_size;
+            rolling =
+                (pos + block_size <= old.len()).then(|| Rolling::of(&old[pos..][..block_size]));
+        } else if pos + block_size < old.len() {
+            current.roll(old[pos], old[pos + block_size]);
+            pos += 1;
+        } else {
+            rolling = None;
+        }
+    }
+    if tail_len > 0 {
+        let after = full
+            .checked_sub(1)
+            .and_then(|i| found[i])
+            .map(|at| at as usize + block_size);
+        let end = old.len().checked_sub(tail_len);
+        let tail = after.into_iter().chain(end).find(|&at| {
+            old.get(at..at + tail_len)
+                .is_some_and(|tail| signature.strong(tail) == blocks[full].strong)
+        });
+        found[full] = tail.map(|at| at as u64);
+    }
+
+    let mut parts: Vec<Part> = Vec::new();
+    for (i, found) in found.into_iter().enumerate() {
+        let len = (new_len - (i * block_size) as u64).min(block_size as u64);
+        match (parts.last_mut(), found) {
+            (Some(Part::Local { old, len: last }), Some(at)) if *old + *last == at => *last += len,
+            (Some(Part::Fetch(last)), None) => *last += len,
+            (_, Some(old)) => parts.push(Part::Local { old, len }),
+            (_, None) => parts.push(Part::Fetch(len)),
+        }
+    }
+    let mut fetch = Vec::new();
+    let mut start = 0;
+    for part in &parts {
+        match *part {
+            Part::Local { len, .. } => start += len,
+            Part::Fetch(len) => {
+                fetch.push(start..start + len);
+                start += len;
+            }
+        }
+    }
+    Ok(DownloadPlan {
+        new_len,
+        parts,
+        fetch,
+    })
+}
+
+#[cfg(all(test, feature = "apply", feature = "xxhash64"))]
+mod test {
+    use std::io::Cursor;
+
+    use super::plan_download;
+    use crate::{apply, signature, ChecksumAlgorithm};
+
+    #[test]
+    fn planned() {
+        let old: Vec<u8> = (0..10_000u32).map(|i| (i * i / 7) as u8).collect();
+        let mut new = [&old[..3001], b"inserted", &old[3001..6000], &old[7000..]].concat();
+        new[8000] ^= 1;
+        for new in [&new[..], &old, &old[5000..], b"", b"short"] {
+            let signature = signature(new, 256, ChecksumAlgorithm::XxHash64).unwrap();
+            let plan = plan_download(&signature, &old).unwrap();
+            assert_eq!(plan.local_len() + plan.fetch_len(), new.len() as u64);
+            let fetched: Vec<u8> = plan
+                .fetch()
+                .iter()
+                .flat_map(|range| &new[range.start as usize..range.end as usize])
+                .copied()
+                .collect();
+            let mut patch = Vec::new();
+            plan.write_patch(&mut &fetched[..], &mut patch).unwrap();
+            let mut out = Vec::new();
+            apply(&mut Cursor::new(&old), &mut out, &mut &patch[..]).unwrap();
+            assert_eq!(out, new);
+            // Only the blocks that were changed are fetched
+            assert!(plan.fetch_len() < 1000 || new.len() < 1000);
+            // Without all of the fetched data, there's no patch
+            if let Some(short) = fetched.len().checked_sub(1) {
+                let result = plan.write_patch(&mut &fetched[..short], &mut Vec::new());
+                assert!(result.is_err());
+            }
+        }
+    }
+}
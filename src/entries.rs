@@ -0,0 +1,199 @@
+//! Reading the headers and entries of a patch, shared by everything that reads patches from a
+//! stream.
+//!
+//! [`PatchEntries`] handles the parts of the format that don't depend on the old or new file: the
+//! extended header, the magic numbers, where a plain or chunked patch ends, and whether the entries
+//! of a chunk add up to its size. The data that follows an entry is left to the caller.
+
+use std::io::{self, Read};
+use std::mem::size_of;
+
+use zerocopy::{AsBytes, FromBytes, Ref};
+
+use crate::filter::{filter_of, filtered};
+use crate::io::read_up_to;
+use crate::patch::{checked_offset, read, PatchError, Result, Trailing};
+use crate::spec::Terminator;
+use crate::units::{Len, NewOffset};
+use crate::{
+    EntryHeader, ExtendedHeader, PatchHeader, WouldBlock, COPY_FLAG, DDELTA_MAGIC, DDELTA_MAGIC_V2,
+    DDELTA_MAGIC_WORDS,
+};
+
+/// What [`PatchEntries::next`] read.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Event {
+    /// The start of a chunk, or of the whole patch if it isn't chunked.
+    Header(PatchHeader),
+    /// An entry other than the terminator. Its diff and extra data follow, unless it's a copy, and
+    /// have to be read before the next event.
+    Entry(EntryHeader),
+    /// The terminator of a chunk, after entries that made up exactly the size in its header.
+    End,
+}
+
+/// Reads a patch as a sequence of [`Event`]s.
+pub(crate) struct PatchEntries<R> {
+    patch: R,
+    would_block: WouldBlock,
+    /// Whether more chunks may follow the first one.
+    chunked: bool,
+    trailing: Trailing,
+    extended: Option<ExtendedHeader>,
+    /// Whether patches made with a [`Filter`][crate::Filter] are read, instead of rejected.
+    filters: bool,
+    /// Whether the current chunk may contain copies.
+    copies: bool,
+    /// Whether the diff data of the current chunk is added in words.
+    words: bool,
+    /// Where the current chunk starts in the new file, and where its entries so far end.
+    /// [`None`] between chunks.
+    chunk: Option<(NewOffset, PatchHeader, NewOffset)>,
+    /// The end of the last chunk in the new file.
+    new_size: NewOffset,
+    /// Whether the next header is the first one.
+    first: bool,
+}
+
+impl<R: Read> PatchEntries<R> {
+    pub(crate) fn new(patch: R, chunked: bool, would_block: WouldBlock) -> Self {
+        PatchEntries {
+            patch,
+            would_block,
+            chunked,
+            trailing: Trailing::Ignore,
+            extended: None,
+            filters: false,
+            copies: false,
+            words: false,
+            chunk: None,
+            new_size: NewOffset::default(),
+            first: true,
+        }
+    }
+
+    /// Sets what may follow the last chunk.
+    pub(crate) fn trailing(mut self, trailing: Trailing) -> Self {
+        self.trailing = trailing;
+        self
+    }
+
+    /// Sets whether patches made with a [`Filter`][crate::Filter] are read. Only callers that undo
+    /// the filter, or don't apply the patch, should allow them.
+    pub(crate) fn filters(mut self, allowed: bool) -> Self {
+        self.filters = allowed;
+        self
+    }
+
+    /// The patch, to read the data of an entry from.
+    pub(crate) fn patch(&mut self) -> &mut R {
+        &mut self.patch
+    }
+
+    /// The extended header in front of the first chunk, once that has been read.
+    pub(crate) fn extended(&self) -> Option<&ExtendedHeader> {
+        self.extended.as_ref()
+    }
+
+    /// Whether the current chunk may copy from the new file.
+    pub(crate) fn copies(&self) -> bool {
+        self.copies
+    }
+
+    /// Whether the diff data of the current chunk is added in 32-bit words, see
+    /// [`add_diff`][crate::patch::add_diff].
+    pub(crate) fn words(&self) -> bool {
+        self.words
+    }
+
+    /// Where the current chunk starts in the new file, which is also where it starts in the old
+    /// file.
+    pub(crate) fn chunk_start(&self) -> NewOffset {
+        self.chunk.map_or(self.new_size, |(start, _, _)| start)
+    }
+
+    /// Reads the next header or entry, or returns [`None`] at the end of the patch. A plain patch
+    /// ends after the terminator of its only chunk, even if more data follows. A chunked patch
+    /// ends where a header would start.
+    pub(crate) fn next(&mut self) -> Result<Option<Event>> {
+        let Some((start, header, end)) = &mut self.chunk else {
+            return self.next_header();
+        };
+        let entry = read!(&mut self.patch, EntryHeader, self.would_block)?;
+        let (diff, extra, seek) = (entry.diff.get(), entry.extra.get(), entry.seek.get());
+        if Terminator::matches(diff, extra, seek) {
+            if end.since(*start) != Some(Len::new(header.new_file_size.get())) {
+                return Err(PatchError::Internal(
+                    "Terminator before the end of the chunk".into(),
+                ));
+            }
+            self.new_size = *end;
+            self.chunk = None;
+            return Ok(Some(Event::End));
+        }
+        // Checked before the data is read or copied, so a corrupt entry fails right away
+        *end = if self.copies && diff & COPY_FLAG != 0 {
+            checked_offset(*end, diff & !COPY_FLAG, 0)?
+        } else {
+            checked_offset(*end, diff, extra)?
+        };
+        if end.since(*start) > Some(Len::new(header.new_file_size.get())) {
+            return Err(PatchError::Internal(
+                "Entry past the end of the chunk".into(),
+            ));
+        }
+        Ok(Some(Event::Entry(entry)))
+    }
+
+    fn next_header(&mut self) -> Result<Option<Event>> {
+        if !self.first && !self.chunked && self.trailing == Trailing::Ignore {
+            return Ok(None);
+        }
+        let mut header = self.read_header()?;
+        if !self.first {
+            if let (Some(header), Trailing::Block(magic)) = (&header, &self.trailing) {
+                if header.as_bytes().starts_with(magic) {
+                    return Ok(None);
+                }
+            }
+            if !self.chunked && header.is_some() {
+                return Err(PatchError::Internal("Trailing data after the patch".into()));
+            }
+        }
+        if self.first {
+            if let Some(extended) = header.filter(|h| ExtendedHeader::matches(&h.magic)) {
+                if !self.filters && filter_of(&extended.magic).is_some() {
+                    return Err(filtered());
+                }
+                self.extended = ExtendedHeader::read_from(extended.as_bytes());
+                header = self.read_header()?;
+            }
+            if header.is_none() && !self.chunked {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            self.first = false;
+        }
+        let Some(header) = header else {
+            return Ok(None);
+        };
+        (self.copies, self.words) = match &header.magic {
+            DDELTA_MAGIC => (false, false),
+            DDELTA_MAGIC_V2 => (true, false),
+            DDELTA_MAGIC_WORDS => (false, true),
+            _ => return Err(PatchError::BadMagic),
+        };
+        checked_offset(self.new_size, header.new_file_size.get(), 0)?;
+        self.chunk = Some((self.new_size, header, self.new_size));
+        Ok(Some(Event::Header(header)))
+    }
+
+    /// Reads the header of the next chunk, or returns `None` if the patch ends right before it.
+    fn read_header(&mut self) -> Result<Option<PatchHeader>> {
+        let mut buf = [0; size_of::<PatchHeader>()];
+        // A header that's cut off fails to read, instead of being filled up with zeros
+        match read_up_to(&mut self.patch, &mut buf, self.would_block)? {
+            0 => Ok(None),
+            n => read!(&mut &buf[..n], PatchHeader).map(Some),
+        }
+    }
+}
@@ -0,0 +1,173 @@
+//! Writing the entries the differ found, merging neighbours and aligning boundaries on the way.
+//!
+//! Each entry is held back until the next one arrives. If the next one continues right where the
+//! held back one ends, in both the old and the new file, they're merged into one, which saves an
+//! entry header. This happens a lot around short matches, where the scan loop splits up what could
+//! have been a single entry.
+//!
+//! With [`DiffOptions::align`][crate::DiffOptions::align], the held back entry is also extended
+//! with the start of the next one up to the next aligned offset in the new file. Those bytes, and
+//! the diff data after the last aligned offset of an entry, are stored as extra data instead, which
+//! only depends on the new file.
+//!
+//! Bytes of the new file that are [masked][crate::DiffOptions::mask] are written as zeros, whether
+//! they end up in the diff or the extra data.
+
+use std::borrow::Cow;
+use std::io::Write;
+use std::ops::Range;
+
+use zerocopy::AsBytes;
+
+use crate::be::{I64, U64};
+use crate::diff::{DiffError, Result};
+use crate::spec::Terminator;
+use crate::EntryHeader;
+
+/// Where an entry starts in the new file, the length of its diff and extra data, and where its
+/// diff data starts in the old file.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Entry {
+    pub(crate) new: usize,
+    pub(crate) diff: usize,
+    pub(crate) extra: usize,
+    pub(crate) old: i64,
+}
+
+/// Writes the entries of a single patch.
+pub(crate) struct EntryWriter<'a> {
+    old: &'a [u8],
+    new: &'a [u8],
+    align: usize,
+    /// Whether the diff data is the difference of words, see
+    /// [`DiffOptions::word_diff`][crate::DiffOptions::word_diff].
+    words: bool,
+    /// The ranges of the new file whose bytes don't matter, sorted.
+    masks: &'a [Range<usize>],
+    pending: Entry,
+}
+
+impl<'a> EntryWriter<'a> {
+    /// Creates a writer for a patch from `old` to `new`, which the applier starts `old_offset`
+    /// bytes before the start of `old`. Entry boundaries are aligned to `align` bytes, which may be
+    /// 1 to leave them where they are.
+    pub(crate) fn new(
+        old: &'a [u8],
+        new: &'a [u8],
+        align: usize,
+        words: bool,
+        old_offset: i64,
+    ) -> Self {
+        EntryWriter {
+            old,
+            new,
+            align,
+            words,
+            masks: &[],
+            // An empty entry that only seeks to wherever the first one starts
+            pending: Entry {
+                new: 0,
+                diff: 0,
+                extra: 0,
+                old: -old_offset,
+            },
+        }
+    }
+
+    /// Writes zeros for the bytes in `masks`, which are sorted ranges of the new file.
+    pub(crate) fn masked(mut self, masks: &'a [Range<usize>]) -> Self {
+        self.masks = masks;
+        self
+    }
+
+    /// Adds the entry that follows the ones added before.
+    pub(crate) fn push(&mut self, patch: &mut impl Write, mut entry: Entry) -> Result<()> {
+        let pending = &mut self.pending;
+        let end = pending.new + pending.diff + pending.extra;
+        debug_assert_eq!(entry.new, end);
+        let taken = (end.next_multiple_of(self.align) - end).min(entry.diff + entry.extra);
+        let from_diff = taken.min(entry.diff);
+        pending.extra += taken;
+        entry.new += taken;
+        entry.diff -= from_diff;
+        entry.old += from_diff as i64;
+        entry.extra -= taken - from_diff;
+        if entry.diff == 0 && entry.extra == 0 {
+            // All of it went into the pending entry, which might still not end aligned
+            return Ok(());
+        }
+        // The entry starts aligned now, so this is how far its diff data goes past an aligned offset
+        let moved = entry.diff % self.align;
+        entry.diff -= moved;
+        entry.extra += moved;
+        if entry.diff == 0 {
+            pending.extra += entry.extra;
+            return Ok(());
+        }
+        if pending.extra == 0 && pending.old + pending.diff as i64 == entry.old {
+            pending.diff += entry.diff;
+            pending.extra = entry.extra;
+            return Ok(());
+        }
+        let pending = std::mem::replace(&mut self.pending, entry);
+        self.write(patch, pending, entry.old)
+    }
+
+    /// Writes the last entry.
+    pub(crate) fn finish(self, patch: &mut impl Write) -> Result<()> {
+        let last = self.pending;
+        self.write(patch, last, last.old + last.diff as i64)
+    }
+
+    /// Writes `entry`, seeking to `next_old` after it.
+    fn write(&self, patch: &mut impl Write, entry: Entry, next_old: i64) -> Result<()> {
+        let seek = next_old - (entry.old + entry.diff as i64);
+        // An empty entry would be read as the end of the patch
+        if Terminator::matches(entry.diff as u64, entry.extra as u64, seek) {
+            return Ok(());
+        }
+        patch.write_all(
+            EntryHeader {
+                diff: U64::new(entry.diff as u64),
+                extra: U64::new(entry.extra as u64),
+                seek: I64::new(seek),
+            }
+            .as_bytes(),
+        )?;
+        let old = match entry.diff {
+            0 => &[][..],
+            _ => usize::try_from(entry.old)
+                .ok()
+                .and_then(|old| self.old.get(old..)?.get(..entry.diff))
+                .ok_or_else(|| DiffError::Internal("Entry outside of the old file".into()))?,
+        };
+        let mut new = Cow::Borrowed(&self.new[entry.new..][..entry.diff + entry.extra]);
+        let first = self.masks.partition_point(|mask| mask.end <= entry.new);
+        for mask in &self.masks[first..] {
+            let start = mask.start.saturating_sub(entry.new);
+            let end = (mask.end - entry.new).min(new.len());
+            if start >= end {
+                break;
+            }
+            // Diff data that adds nothing, and extra data of zeros
+            for i in start..end {
+                new.to_mut()[i] = old.get(i).copied().unwrap_or(0);
+            }
+        }
+        if entry.diff > 0 {
+            let mut rest = 0;
+            if self.words {
+                let word = |bytes: &[u8]| u32::from_le_bytes(bytes.try_into().unwrap());
+                for (n, o) in new[..entry.diff].chunks_exact(4).zip(old.chunks_exact(4)) {
+                    patch.write_all(&word(n).wrapping_sub(word(o)).to_le_bytes())?;
+                }
+                rest = entry.diff & !3;
+            }
+            for (n, o) in new[rest..entry.diff].iter().zip(&old[rest..]) {
+                patch.write_all(&[n.wrapping_sub(*o)])?;
+            }
+        }
+        patch.write_all(&new[entry.diff..][..entry.extra])?;
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+//! Stable numeric error codes, for callers on the other side of a C ABI.
+
+use std::ffi::CStr;
+#[cfg(any(feature = "diff", feature = "apply"))]
+use std::io::ErrorKind;
+
+#[cfg(feature = "diff")]
+use crate::DiffError;
+#[cfg(feature = "apply")]
+use crate::PatchError;
+
+/// What kind of error happened, as a number that stays the same between versions.
+///
+/// Bindings for other languages can't match on [`PatchError`] or [`DiffError`], so they get the
+/// number of one of these from [`PatchError::code`] or [`DiffError::code`] instead, and the
+/// message for it from [`message`][Self::message]. New codes may be added, but existing ones keep
+/// their number and meaning, so treat unknown ones like [`Other`][Self::Other].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(i32)]
+#[non_exhaustive]
+pub enum ErrorCode {
+    /// No error.
+    Ok = 0,
+    /// Reading or writing a file failed.
+    Io = 1,
+    /// The patch doesn't start with a known magic number, so it isn't a patch of this format.
+    Magic = 2,
+    /// The patch, or the old file, ends too early.
+    Truncated = 3,
+    /// The old file isn't the one the patch was made for.
+    Checksum = 4,
+    /// A size limit was reached: the patch would get too large, or its sizes overflow.
+    Limit = 5,
+    /// A buffer couldn't be allocated.
+    Oom = 6,
+    /// The caller stopped the operation. Nothing in this crate returns this, it's reserved for
+    /// bindings whose callbacks can ask to stop.
+    Cancelled = 7,
+    /// The patch is damaged or doesn't follow the format.
+    Corrupt = 8,
+    /// The signature of the patch doesn't check out.
+    Signature = 9,
+    /// Anything else, e.g. invalid arguments.
+    Other = 10,
+}
+
+impl ErrorCode {
+    /// The code with the number `code`, or [`None`] if there's no such code.
+    pub fn from_code(code: i32) -> Option<Self> {
+        use ErrorCode::*;
+        [
+            Ok, Io, Magic, Truncated, Checksum, Limit, Oom, Cancelled, Corrupt, Signature, Other,
+        ]
+        .into_iter()
+        .find(|known| *known as i32 == code)
+    }
+
+    /// A short description of the code in English, as a C string that lives as long as the
+    /// program, e.g. to be returned by a `ddelta_error_message` function of a C ABI.
+    pub fn message(self) -> &'static CStr {
+        match self {
+            ErrorCode::Ok => c"no error",
+            ErrorCode::Io => c"i/o error",
+            ErrorCode::Magic => c"not a patch: invalid magic number",
+            ErrorCode::Truncated => c"unexpected end of input",
+            ErrorCode::Checksum => c"old file doesn't match the patch",
+            ErrorCode::Limit => c"size limit exceeded",
+            ErrorCode::Oom => c"out of memory",
+            ErrorCode::Cancelled => c"cancelled",
+            ErrorCode::Corrupt => c"patch is corrupt",
+            ErrorCode::Signature => c"invalid signature",
+            ErrorCode::Other => c"error",
+        }
+    }
+}
+
+#[cfg(any(feature = "diff", feature = "apply"))]
+fn io_code(kind: ErrorKind) -> ErrorCode {
+    match kind {
+        ErrorKind::UnexpectedEof => ErrorCode::Truncated,
+        ErrorKind::OutOfMemory => ErrorCode::Oom,
+        _ => ErrorCode::Io,
+    }
+}
+
+#[cfg(feature = "apply")]
+impl PatchError {
+    /// The stable code of this error, see [`ErrorCode`].
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            PatchError::Io(e) | PatchError::Write { error: e, .. } => io_code(e.kind()),
+            PatchError::Internal(_) => ErrorCode::Corrupt,
+            PatchError::BadMagic => ErrorCode::Magic,
+            PatchError::OffsetOverflow | PatchError::MemoryLimit { .. } => ErrorCode::Limit,
+            PatchError::OldMismatch { .. } => ErrorCode::Checksum,
+            PatchError::BadSignature(_) => ErrorCode::Signature,
+            PatchError::OldTooShort { .. } | PatchError::Truncated { .. } => ErrorCode::Truncated,
+        }
+    }
+}
+
+#[cfg(feature = "diff")]
+impl DiffError {
+    /// The stable code of this error, see [`ErrorCode`].
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            DiffError::Io(e) => io_code(e.kind()),
+            DiffError::Internal(_) => ErrorCode::Other,
+            DiffError::PatchTooLarge { .. } => ErrorCode::Limit,
+            DiffError::OutOfMemory(_) => ErrorCode::Oom,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ErrorCode;
+
+    #[test]
+    fn codes() {
+        for code in 0..=10 {
+            let known = ErrorCode::from_code(code).unwrap();
+            assert_eq!(known as i32, code);
+            assert!(!known.message().is_empty());
+        }
+        assert_eq!(ErrorCode::from_code(11), None);
+        assert_eq!(ErrorCode::Truncated as i32, 3);
+    }
+
+    #[cfg(feature = "apply")]
+    #[test]
+    fn patch_errors() {
+        use std::io::Cursor;
+
+        use crate::{apply, PatchError};
+
+        let code = |patch: &[u8]| {
+            let result = apply(&mut Cursor::new(b""), &mut Vec::new(), &mut &patch[..]);
+            result.map_err(|e: PatchError| e.code())
+        };
+        assert_eq!(code(b"NOTAPATCH0000000"), Err(ErrorCode::Magic));
+        assert_eq!(code(b"DDELTA40"), Err(ErrorCode::Truncated));
+    }
+}
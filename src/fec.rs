@@ -0,0 +1,370 @@
+//! Forward error correction for patches sent over lossy channels, such as radio.
+//!
+//! The trailer is the magic number, the parity bytes, and twice a footer of the patch size (u64)
+//! and the parity count (u8), so it's found from the end of the data even if one of them is
+//! damaged.
+
+use std::io::Write;
+
+use crate::patch::{PatchError, Result};
+use crate::{apply_chunked, OldSource};
+
+/// Magic number at the start of the trailer.
+const FEC_MAGIC: &[u8; 8] = b"DDELTAFC";
+/// The size of a footer: the size of the patch and the parity count.
+const FOOTER_LEN: usize = 9;
+
+/// Logarithms and exponentials of GF(2^8), with the polynomial x^8 + x^4 + x^3 + x^2 + 1 and
+/// generator 2. The exponentials are repeated so sums of two logarithms don't need a modulo.
+struct Tables {
+    exp: [u8; 512],
+    log: [u8; 256],
+}
+
+const TABLES: Tables = {
+    let mut tables = Tables {
+        exp: [0; 512],
+        log: [0; 256],
+    };
+    let mut x: u16 = 1;
+    let mut i = 0;
+    while i < 255 {
+        tables.exp[i] = x as u8;
+        tables.exp[i + 255] = x as u8;
+        tables.log[x as usize] = i as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= 0x11d;
+        }
+        i += 1;
+    }
+    tables
+};
+
+fn mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    TABLES.exp[TABLES.log[a as usize] as usize + TABLES.log[b as usize] as usize]
+}
+
+fn div(a: u8, b: u8) -> u8 {
+    if a == 0 {
+        return 0;
+    }
+    TABLES.exp[TABLES.log[a as usize] as usize + 255 - TABLES.log[b as usize] as usize]
+}
+
+/// 2 to the power of `e`, which may be negative.
+fn pow2(e: isize) -> u8 {
+    TABLES.exp[e.rem_euclid(255) as usize]
+}
+
+/// Evaluates `poly`, highest coefficient first, at `x`.
+fn eval(poly: &[u8], x: u8) -> u8 {
+    poly.iter().fold(0, |acc, &c| mul(acc, x) ^ c)
+}
+
+/// The generator polynomial (x - 2^0)(x - 2^1)…(x - 2^(parity-1)), highest coefficient first.
+fn generator(parity: usize) -> Vec<u8> {
+    let mut poly = vec![1];
+    for i in 0..parity {
+        let root = pow2(i as isize);
+        poly.push(0);
+        for j in (1..poly.len()).rev() {
+            poly[j] ^= mul(poly[j - 1], root);
+        }
+    }
+    poly
+}
+
+/// Computes the parity of `data` into `parity`: the remainder of dividing `data` times x^parity
+/// by the generator.
+fn encode(data: impl Iterator<Item = u8>, generator: &[u8], parity: &mut [u8]) {
+    parity.fill(0);
+    for byte in data {
+        let factor = byte ^ parity[0];
+        parity.rotate_left(1);
+        *parity.last_mut().unwrap() = 0;
+        for (p, &g) in parity.iter_mut().zip(&generator[1..]) {
+            *p ^= mul(g, factor);
+        }
+    }
+}
+
+/// Repairs `codeword`, which ends in `parity` parity bytes, and returns the positions that were
+/// changed. Fails if there are more errors than can be repaired, as far as that can be told.
+fn decode(codeword: &mut [u8], parity: usize) -> Option<Vec<usize>> {
+    let syndromes: Vec<u8> = (0..parity)
+        .map(|i| eval(codeword, pow2(i as isize)))
+        .collect();
+    if syndromes.iter().all(|&s| s == 0) {
+        return Some(Vec::new());
+    }
+
+    // Berlekamp-Massey, for the error locator with the lowest coefficient first
+    let (mut locator, mut prev) = (vec![1u8], vec![1u8]);
+    let (mut errors, mut shift, mut prev_discrepancy) = (0, 1, 1);
+    for n in 0..parity {
+        let discrepancy = (1..=errors).fold(syndromes[n], |d, i| {
+            d ^ mul(locator.get(i).copied().unwrap_or(0), syndromes[n - i])
+        });
+        if discrepancy == 0 {
+            shift += 1;
+            continue;
+        }
+        let factor = div(discrepancy, prev_discrepancy);
+        let mut next = locator.clone();
+        next.resize(next.len().max(prev.len() + shift), 0);
+        for (i, &p) in prev.iter().enumerate() {
+            next[i + shift] ^= mul(factor, p);
+        }
+        if 2 * errors <= n {
+            prev = std::mem::replace(&mut locator, next);
+            errors = n + 1 - errors;
+            prev_discrepancy = discrepancy;
+            shift = 1;
+        } else {
+            locator = next;
+            shift += 1;
+        }
+    }
+    locator.truncate(errors + 1);
+    if errors > parity / 2 {
+        return None;
+    }
+
+    // Chien search: position `i` is the coefficient of x^(len-1-i), with locator 2^(len-1-i)
+    let len = codeword.len() as isize;
+    let highest_first: Vec<u8> = locator.iter().rev().copied().collect();
+    let positions: Vec<usize> = (0..codeword.len())
+        .filter(|&i| eval(&highest_first, pow2(-(len - 1 - i as isize))) == 0)
+        .collect();
+    if positions.len() != errors {
+        return None;
+    }
+
+    // Forney: the evaluator is the syndromes times the locator, modulo x^parity
+    let mut evaluator = vec![0u8; parity];
+    for (i, &l) in locator.iter().enumerate() {
+        for (j, &s) in syndromes.iter().enumerate().take(parity - i) {
+            evaluator[i + j] ^= mul(l, s);
+        }
+    }
+    evaluator.reverse();
+    // The odd terms of the locator make up its derivative
+    let derivative: Vec<u8> = locator
+        .iter()
+        .enumerate()
+        .skip(1)
+        .map(|(i, &l)| if i % 2 == 1 { l } else { 0 })
+        .rev()
+        .collect();
+    for &i in &positions {
+        let x = pow2(len - 1 - i as isize);
+        let x_inv = pow2(-(len - 1 - i as isize));
+        let denominator = eval(&derivative, x_inv);
+        if denominator == 0 {
+            return None;
+        }
+        codeword[i] ^= mul(x, div(eval(&evaluator, x_inv), denominator));
+    }
+    let repaired = (0..parity).all(|i| eval(codeword, pow2(i as isize)) == 0);
+    repaired.then_some(positions)
+}
+
+/// How a patch of `len` bytes is split into blocks: their number, and the number of patch bytes
+/// in each.
+fn layout(len: usize, parity: usize) -> (usize, usize) {
+    let blocks = len.div_ceil(255 - parity).max(1);
+    (blocks, len.div_ceil(blocks))
+}
+
+/// The size of the trailer for a patch of `len` bytes.
+fn trailer_len(len: usize, parity: usize) -> Option<usize> {
+    let (blocks, _) = layout(len, parity);
+    blocks
+        .checked_mul(parity)?
+        .checked_add(FEC_MAGIC.len() + 2 * FOOTER_LEN)
+}
+
+/// Write an error correction trailer for `patch`, to be appended to it, so [`repair_fec`] can
+/// repair some damage to both.
+///
+/// The trailer holds Reed-Solomon parity over the bytes of the patch. The patch is split into
+/// interleaved blocks: block `j` holds every byte at an offset that is `j` modulo the number of
+/// blocks, so a burst of damage is spread over all of them. `parity` is the number of parity
+/// bytes in each block of up to 255 bytes, between 2 and 254. Half of that many damaged bytes can
+/// be repaired in each block, so e.g. 32 makes the patch about 14% larger and repairs up to 16
+/// damaged bytes in every 255.
+///
+/// The trailer starts with the magic number `DDELTAFC`. [`apply`][crate::apply] ignores it like
+/// anything else after a plain patch, but chunked appliers read it as the next chunk and fail. Apply
+/// the patch with [`apply_fec`], or with a [`Patcher`][crate::Patcher] that allows
+/// [`Trailing::Block`][crate::Trailing::Block] with this magic number.
+pub fn write_fec(patch: &[u8], parity: u8, out: &mut impl Write) -> Result<()> {
+    if !(2..=254).contains(&parity) {
+        return Err(PatchError::Internal(
+            "The parity must be between 2 and 254 bytes".into(),
+        ));
+    }
+    let parity = parity as usize;
+    let (blocks, per_block) = layout(patch.len(), parity);
+    let generator = generator(parity);
+    let mut parity_bytes = vec![0; blocks * parity];
+    let mut block_parity = vec![0; parity];
+    for j in 0..blocks {
+        let data = (0..per_block).map(|i| patch.get(j + i * blocks).copied().unwrap_or(0));
+        encode(data, &generator, &mut block_parity);
+        for (i, &p) in block_parity.iter().enumerate() {
+            parity_bytes[j + i * blocks] = p;
+        }
+    }
+    let mut footer = [0; FOOTER_LEN];
+    footer[..8].copy_from_slice(&(patch.len() as u64).to_be_bytes());
+    footer[8] = parity as u8;
+    out.write_all(FEC_MAGIC)?;
+    out.write_all(&parity_bytes)?;
+    out.write_all(&footer)?;
+    out.write_all(&footer)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// The result of [`repair_fec`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Repaired {
+    /// The size of the patch without the trailer.
+    pub patch_len: usize,
+    /// How many bytes were repaired, in the patch or the trailer.
+    pub corrected: usize,
+}
+
+/// Repair a patch followed by a trailer from [`write_fec`] in place.
+///
+/// Fails if neither footer of the trailer is intact, or if a block has more damage than its parity
+/// can repair. Reed-Solomon codes notice most, but not all, cases of too much damage, so checking
+/// a checksum of the new file is still a good idea.
+pub fn repair_fec(data: &mut [u8]) -> Result<Repaired> {
+    let footers = [1, 2].map(|n| {
+        let end = data.len().checked_sub((n - 1) * FOOTER_LEN)?;
+        let footer = data.get(end.checked_sub(FOOTER_LEN)?..end)?;
+        let len = usize::try_from(u64::from_be_bytes(footer[..8].try_into().unwrap())).ok()?;
+        let parity = footer[8] as usize;
+        if !(2..=254).contains(&parity) {
+            return None;
+        }
+        let total = len.checked_add(trailer_len(len, parity)?)?;
+        (total == data.len() && data[len..].starts_with(FEC_MAGIC)).then_some((len, parity))
+    });
+    let (len, parity) = footers
+        .into_iter()
+        .flatten()
+        .next()
+        .ok_or_else(|| PatchError::Internal("No intact error correction trailer".into()))?;
+    let (blocks, per_block) = layout(len, parity);
+    let parity_start = len + FEC_MAGIC.len();
+    // Where each byte of a codeword is in `data`, or None for padding past the end of the patch
+    let position = |j: usize, i: usize| match i < per_block {
+        true => Some(j + i * blocks).filter(|&at| at < len),
+        false => Some(parity_start + j + (i - per_block) * blocks),
+    };
+
+    let mut corrected = 0;
+    let mut codeword = vec![0; per_block + parity];
+    for j in 0..blocks {
+        for (i, byte) in codeword.iter_mut().enumerate() {
+            *byte = position(j, i).map_or(0, |at| data[at]);
+        }
+        let damaged = decode(&mut codeword, parity).ok_or_else(|| {
+            PatchError::Internal(format!("Too much damage to repair in block {j}").into())
+        })?;
+        for i in damaged {
+            let Some(at) = position(j, i) else {
+                return Err(PatchError::Internal(
+                    format!("Too much damage to repair in block {j}").into(),
+                ));
+            };
+            data[at] = codeword[i];
+            corrected += 1;
+        }
+    }
+    Ok(Repaired {
+        patch_len: len,
+        corrected,
+    })
+}
+
+/// Apply a patch that is followed by a trailer from [`write_fec`], repairing it in place first.
+/// Returns how many bytes were repaired. This accepts the same patches as [`apply_chunked`].
+pub fn apply_fec(
+    old: &mut impl OldSource,
+    new: &mut impl Write,
+    patch: &mut [u8],
+) -> Result<usize> {
+    let repaired = repair_fec(patch)?;
+    apply_chunked(old, new, &mut &patch[..repaired.patch_len])?;
+    Ok(repaired.corrected)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::{apply_fec, decode, encode, generator, repair_fec, write_fec};
+    use crate::apply;
+    use crate::spec::{encode_entry, encode_header, Terminator};
+
+    #[test]
+    fn codeword() {
+        let data: Vec<u8> = (0..200u32).map(|i| (i * 7 + 3) as u8).collect();
+        let mut parity = [0; 16];
+        encode(data.iter().copied(), &generator(16), &mut parity);
+        let clean = [&data[..], &parity].concat();
+        for damage in [0, 1, 5, 8] {
+            let mut codeword = clean.clone();
+            for k in 0..damage {
+                codeword[k * 23 + 1] ^= 0x5a;
+            }
+            let fixed = decode(&mut codeword, 16).unwrap();
+            assert_eq!(fixed.len(), damage);
+            assert_eq!(codeword, clean);
+        }
+    }
+
+    #[test]
+    fn repair() {
+        let new: Vec<u8> = (0..3000u32).map(|i| (i * i / 7) as u8).collect();
+        // The whole new file as extra data
+        let patch = [
+            encode_header(new.len() as u64),
+            encode_entry(b"", &new, 0),
+            Terminator::BYTES.to_vec(),
+        ]
+        .concat();
+        let mut protected = patch.clone();
+        write_fec(&patch, 32, &mut protected).unwrap();
+
+        // Old appliers skip the trailer
+        let mut out = Vec::new();
+        apply(&mut Cursor::new(b""), &mut out, &mut &protected[..]).unwrap();
+        assert_eq!(out, new);
+
+        // 14 blocks that can each repair 16 bytes, so a burst of 200 is fine, even across the
+        // header, and with one of the footers damaged
+        let mut damaged = protected.clone();
+        damaged[10..210].iter_mut().for_each(|b| *b ^= 0xff);
+        let end = damaged.len();
+        damaged[end - 3] ^= 1;
+        let mut out = Vec::new();
+        let corrected = apply_fec(&mut Cursor::new(b""), &mut out, &mut damaged).unwrap();
+        assert_eq!(corrected, 200);
+        assert_eq!(out, new);
+        assert_eq!(damaged[..end - 3], protected[..end - 3]);
+
+        let mut damaged = protected.clone();
+        damaged[10..400].iter_mut().for_each(|b| *b ^= 0xff);
+        assert!(repair_fec(&mut damaged).is_err());
+        assert!(write_fec(&patch, 0, &mut Vec::new()).is_err());
+    }
+}
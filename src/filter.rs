@@ -0,0 +1,179 @@
+//! Filters for machine code, which turn relative branch targets into absolute ones before diffing.
+//!
+//! When code is inserted into a program, every call across it changes, as calls store how far away
+//! their target is. Most of the bytes that differ between two builds are those distances, so the
+//! diff data is full of small, scattered changes. Converted to absolute addresses, the calls to a
+//! function that didn't move stay the same, and the patch gets a lot smaller. This is what the BCJ
+//! filters of xz do, and these work the same way, though their output isn't the same as xz's.
+//!
+//! [`PatchBuilder::filter`][crate::PatchBuilder::filter] filters both files before diffing, and
+//! records the filter in the magic number of the extended header. [`apply_filtered`] filters the
+//! old file the same way, applies the patch, and converts the new file back. The other apply
+//! functions reject such patches, as they would write the filtered new file.
+
+#[cfg(feature = "apply")]
+use crate::apply_slice;
+#[cfg(feature = "apply")]
+use crate::patch::{PatchError, Result};
+#[cfg(feature = "apply")]
+use crate::spec::PATCH_HEADER_SIZE;
+use crate::DDELTA_MAGIC_FILTERED;
+
+/// A converter for the branches of one kind of machine code.
+///
+/// Filters only change the operands of what looks like a branch, so other data goes through them
+/// mostly unchanged, and filtering with the wrong filter only makes the patch larger.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Filter {
+    /// x86 and x86-64, converting the 32-bit operands of `call` and `jmp` (opcodes `E8` and `E9`)
+    /// that point less than 16 MiB away.
+    X86,
+    /// 32-bit ARM, converting the 24-bit operands of `bl`, at multiples of 4 bytes.
+    Arm,
+}
+
+impl Filter {
+    /// The number stored at the end of the magic number of the extended header, as an ASCII digit.
+    pub fn id(self) -> u8 {
+        match self {
+            Filter::X86 => 1,
+            Filter::Arm => 2,
+        }
+    }
+
+    /// The filter with the given [`id`][Self::id], or `None` for 0 and ids this version doesn't
+    /// know.
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(Filter::X86),
+            2 => Some(Filter::Arm),
+            _ => None,
+        }
+    }
+
+    /// The magic number of the extended header of patches made with this filter.
+    pub(crate) fn magic(self) -> [u8; 8] {
+        let mut magic = [b'0' + self.id(); 8];
+        magic[..7].copy_from_slice(DDELTA_MAGIC_FILTERED);
+        magic
+    }
+
+    /// Converts the branches in `data` to absolute addresses, as if `data` was loaded at 0.
+    pub fn encode(self, data: &mut [u8]) {
+        self.convert(data, true);
+    }
+
+    /// Converts the branches in `data` back, undoing [`encode`][Self::encode].
+    pub fn decode(self, data: &mut [u8]) {
+        self.convert(data, false);
+    }
+
+    /// Adds (or subtracts) the position of each branch to its operand. Which bytes are operands only
+    /// depends on bytes that are never changed, so both directions find the same ones.
+    fn convert(self, data: &mut [u8], encode: bool) {
+        let offset = |value: u32, pos: u32| match encode {
+            true => value.wrapping_add(pos),
+            false => value.wrapping_sub(pos),
+        };
+        match self {
+            Filter::X86 => {
+                let mut i = 0;
+                while i + 5 <= data.len() {
+                    if data[i] != 0xe8 && data[i] != 0xe9 {
+                        i += 1;
+                        continue;
+                    }
+                    let operand = &mut data[i + 1..i + 5];
+                    let value = u32::from_le_bytes(operand.try_into().unwrap());
+                    // Only operands that are 25-bit signed numbers, which are mapped onto each
+                    // other, so the conversion can be undone
+                    if matches!(value >> 24, 0 | 0xff) {
+                        let value = offset(value, (i + 5) as u32) << 7;
+                        operand.copy_from_slice(&((value as i32 >> 7) as u32).to_le_bytes());
+                    }
+                    i += 5;
+                }
+            }
+            Filter::Arm => {
+                for (i, instruction) in data.chunks_exact_mut(4).enumerate() {
+                    if instruction[3] != 0xeb {
+                        continue;
+                    }
+                    let value =
+                        u32::from_le_bytes([instruction[0], instruction[1], instruction[2], 0]);
+                    let value = offset(value, ((i * 4 + 8) >> 2) as u32);
+                    instruction[..3].copy_from_slice(&value.to_le_bytes()[..3]);
+                }
+            }
+        }
+    }
+}
+
+/// The filter of a patch that starts with `header`, or `None` if it has no filter or one this
+/// version doesn't know.
+pub(crate) fn filter_of(header: &[u8]) -> Option<Filter> {
+    match header.strip_prefix(DDELTA_MAGIC_FILTERED)? {
+        [digit, ..] => Filter::from_id(digit.checked_sub(b'0')?),
+        [] => None,
+    }
+}
+
+/// The error for patches with a filter, in apply functions that don't undo it.
+#[cfg(feature = "apply")]
+pub(crate) fn filtered() -> PatchError {
+    PatchError::Internal("The patch is for filtered files, apply it with apply_filtered".into())
+}
+
+/// Apply a patch that is in memory, like [`apply_slice`], undoing the [`Filter`] it was made with.
+/// Patches without a filter are applied as they are.
+///
+/// The old file is copied to filter it, and the new file is converted back in `out` at the end, so
+/// this needs memory for the old file on top of what `apply_slice` does.
+#[cfg(feature = "apply")]
+pub fn apply_filtered(old: &[u8], patch: &[u8], out: &mut Vec<u8>) -> Result<()> {
+    let Some(filter) = patch.get(..PATCH_HEADER_SIZE).and_then(filter_of) else {
+        return apply_slice(old, patch, out);
+    };
+    let mut filtered = old.to_vec();
+    filter.encode(&mut filtered);
+    let start = out.len();
+    apply_slice(&filtered, &patch[PATCH_HEADER_SIZE..], out)?;
+    filter.decode(&mut out[start..]);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::Filter;
+    use crate::test_util::Rng;
+
+    #[test]
+    fn round_trip() {
+        let mut rng = Rng::new(7);
+        // Lots of opcodes and small operands
+        let data: Vec<u8> = (0..100_000)
+            .map(|_| [0xe8, 0xe9, 0xeb, 0, 0xff, rng.next_u64() as u8][rng.below(6)])
+            .collect();
+        for filter in [Filter::X86, Filter::Arm] {
+            assert_eq!(Filter::from_id(filter.id()), Some(filter));
+            let mut filtered = data.clone();
+            filter.encode(&mut filtered);
+            assert_ne!(filtered, data);
+            filter.decode(&mut filtered);
+            assert_eq!(filtered, data);
+        }
+        assert_eq!(Filter::from_id(0), None);
+
+        // Two calls to the same function have the same operand after filtering
+        let mut code = [
+            &[0xe8][..],
+            &100u32.to_le_bytes(),
+            &[0x90; 3],
+            &[0xe8],
+            &92u32.to_le_bytes(),
+        ]
+        .concat();
+        Filter::X86.encode(&mut code);
+        assert_eq!(code[1..5], code[9..13]);
+    }
+}
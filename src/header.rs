@@ -0,0 +1,266 @@
+//! Tagging patches with an application-defined identifier, and reading it back.
+
+#[cfg(any(feature = "diff", feature = "apply"))]
+use std::io::Read;
+use std::io::{self, Write};
+
+use zerocopy::AsBytes;
+
+use crate::be::U32;
+#[cfg(feature = "apply")]
+use crate::entries::{Event, PatchEntries};
+#[cfg(feature = "apply")]
+use crate::filter::filter_of;
+#[cfg(feature = "apply")]
+use crate::patch::Result;
+#[cfg(feature = "apply")]
+use crate::units::Len;
+#[cfg(feature = "apply")]
+use crate::WouldBlock;
+#[cfg(feature = "diff")]
+use crate::{generate_chunked_with_options, DiffOptions, Differ, State};
+use crate::{ExtendedHeader, Filter, DDELTA_MAGIC_EXT};
+
+/// Generates patches that start with an application-defined tag and flags.
+///
+/// Tools that wrap patches in their own format usually need to store which application (or
+/// version of it) a patch is for, and how it was made. The tag and flags are stored in an extended
+/// header in front of the first chunk, and can be read back with [`inspect`] without reading the
+/// rest of the patch. The meaning of both is up to the application. All apply functions of this
+/// crate skip the extended header, but older versions and the original ddelta tool reject it.
+#[derive(Clone, Debug)]
+pub struct PatchBuilder {
+    tag: [u8; 4],
+    flags: u32,
+    filter: Option<Filter>,
+    #[cfg(feature = "diff")]
+    options: DiffOptions,
+}
+
+impl PatchBuilder {
+    /// Creates a builder for patches tagged with `tag`, with no flags and the default options.
+    pub fn new(tag: [u8; 4]) -> Self {
+        PatchBuilder {
+            tag,
+            flags: 0,
+            filter: None,
+            #[cfg(feature = "diff")]
+            options: DiffOptions::new(),
+        }
+    }
+
+    /// Sets the flags that are stored next to the tag.
+    pub fn flags(mut self, flags: u32) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Converts both files with `filter` before diffing, which makes much smaller patches for
+    /// executables. The patch can then only be applied with
+    /// [`apply_filtered`][crate::apply_filtered], which undoes the filter. The filter is stored in
+    /// the magic number of the extended header, so the other apply functions, older versions and
+    /// the original ddelta tool reject the patch.
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Sets the options patches are generated with.
+    #[cfg(feature = "diff")]
+    pub fn options(mut self, options: DiffOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Writes just the extended header, for patches that are generated some other way. It has to
+    /// come right before the first chunk.
+    pub fn write_header(&self, patch: &mut impl Write) -> io::Result<()> {
+        let header = ExtendedHeader {
+            magic: self.filter.map_or(*DDELTA_MAGIC_EXT, Filter::magic),
+            tag: self.tag,
+            flags: U32::new(self.flags),
+        };
+        patch.write_all(header.as_bytes())
+    }
+
+    /// Generates a tagged patch, like [`generate`][crate::generate] does with the options of
+    /// [`Differ::run`].
+    #[cfg(feature = "diff")]
+    pub fn generate(
+        &self,
+        old: &[u8],
+        new: &[u8],
+        patch: &mut impl Write,
+        progress: impl FnMut(State),
+    ) -> crate::diff::Result<()> {
+        self.write_header(patch)?;
+        let mut differ = Differ::new(self.options.clone()).with_progress(progress);
+        match self.filter {
+            None => differ.run(old, new, patch),
+            Some(filter) => differ.run(&filtered(filter, old), &filtered(filter, new), patch),
+        }
+    }
+
+    /// Generates a tagged chunked patch, like
+    /// [`generate_chunked_with_options`][crate::generate_chunked_with_options]. With a
+    /// [`filter`][Self::filter], both files are read into memory to convert them first.
+    #[cfg(feature = "diff")]
+    pub fn generate_chunked(
+        &self,
+        old: &mut impl Read,
+        new: &mut impl Read,
+        patch: &mut impl Write,
+        progress: impl FnMut(State),
+    ) -> crate::diff::Result<()> {
+        self.write_header(patch)?;
+        let Some(filter) = self.filter else {
+            return generate_chunked_with_options(old, new, patch, &self.options, progress);
+        };
+        let (mut old_data, mut new_data) = (Vec::new(), Vec::new());
+        old.read_to_end(&mut old_data)?;
+        new.read_to_end(&mut new_data)?;
+        filter.encode(&mut old_data);
+        filter.encode(&mut new_data);
+        generate_chunked_with_options(
+            &mut &old_data[..],
+            &mut &new_data[..],
+            patch,
+            &self.options,
+            progress,
+        )
+    }
+}
+
+/// A filtered copy of `data`.
+#[cfg(feature = "diff")]
+fn filtered(filter: Filter, data: &[u8]) -> Vec<u8> {
+    let mut data = data.to_vec();
+    filter.encode(&mut data);
+    data
+}
+
+/// What [`inspect`] found at the start of a patch.
+#[cfg(feature = "apply")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Inspection {
+    /// The tag set with [`PatchBuilder`], if the patch has one.
+    pub tag: Option<[u8; 4]>,
+    /// The flags set with [`PatchBuilder::flags`], or 0 if the patch has no tag.
+    pub flags: u32,
+    /// The filter set with [`PatchBuilder::filter`], which [`apply_filtered`][crate::apply_filtered]
+    /// undoes.
+    pub filter: Option<Filter>,
+    /// The size of the new file, or of its first chunk for chunked patches. [`None`] if the patch
+    /// has no chunks.
+    pub new_file_size: Option<Len>,
+    /// Whether the first chunk may copy from the new file, which needs a newer applier.
+    pub copies: bool,
+    /// Whether the first chunk has word diffs, which need a newer applier as well.
+    pub words: bool,
+}
+
+/// Reads the headers at the start of a patch, without reading any further.
+#[cfg(feature = "apply")]
+pub fn inspect(patch: &mut impl Read) -> Result<Inspection> {
+    let mut entries = PatchEntries::new(patch, true, WouldBlock::Fail).filters(true);
+    let header = match entries.next()? {
+        Some(Event::Header(header)) => Some(header),
+        _ => None,
+    };
+    Ok(Inspection {
+        tag: entries.extended().map(|extended| extended.tag),
+        flags: entries
+            .extended()
+            .map_or(0, |extended| extended.flags.get()),
+        filter: entries
+            .extended()
+            .and_then(|extended| filter_of(&extended.magic)),
+        new_file_size: header.map(|header| Len::new(header.new_file_size.get())),
+        copies: entries.copies(),
+        words: entries.words(),
+    })
+}
+
+#[cfg(all(test, feature = "diff", feature = "apply"))]
+mod test {
+    use std::io::Cursor;
+
+    use super::{inspect, Inspection, PatchBuilder};
+    use crate::{apply, apply_chunked, apply_filtered, apply_slice, DiffOptions, Filter, Len};
+
+    #[test]
+    fn tagged() {
+        let old = b"hello world, hello world";
+        let new = b"hello there world, hello";
+        let builder = PatchBuilder::new(*b"TEST")
+            .flags(0x0102_0304)
+            .options(DiffOptions::new().chunk_size(1000).copy_from_new(true));
+
+        let mut patch = Vec::new();
+        builder.generate(old, new, &mut patch, |_| {}).unwrap();
+        let mut out = Vec::new();
+        apply(&mut Cursor::new(old), &mut out, &mut &patch[..]).unwrap();
+        assert_eq!(out, new);
+        assert_eq!(
+            inspect(&mut &patch[..]).unwrap(),
+            Inspection {
+                tag: Some(*b"TEST"),
+                flags: 0x0102_0304,
+                filter: None,
+                new_file_size: Some(Len::new(new.len() as u64)),
+                copies: true,
+                words: false,
+            }
+        );
+
+        let mut patch = Vec::new();
+        builder
+            .generate_chunked(&mut &old[..], &mut &new[..], &mut patch, |_| {})
+            .unwrap();
+        let mut out = Vec::new();
+        apply_chunked(&mut Cursor::new(old), &mut out, &mut &patch[..]).unwrap();
+        assert_eq!(out, new);
+        out.clear();
+        apply_slice(old, &patch, &mut out).unwrap();
+        assert_eq!(out, new);
+        assert_eq!(inspect(&mut &patch[..]).unwrap().tag, Some(*b"TEST"));
+    }
+
+    #[test]
+    fn filtered() {
+        // Calls to the same functions, with 3 bytes inserted in the middle
+        let call = |at: usize, target: usize| {
+            [&[0xe8][..], &((target - at - 5) as u32).to_le_bytes()].concat()
+        };
+        let old: Vec<u8> = (0..200).flat_map(|i| call(i * 5, 5000)).collect();
+        let new: Vec<u8> = (0..100)
+            .flat_map(|i| call(i * 5, 5003))
+            .chain(*b"new")
+            .chain((100..200).flat_map(|i| call(i * 5 + 3, 5003)))
+            .collect();
+        let builder = PatchBuilder::new(*b"TEST")
+            .flags(0xff00_0001)
+            .filter(Filter::X86);
+
+        let mut patch = Vec::new();
+        builder.generate(&old, &new, &mut patch, |_| {}).unwrap();
+        let mut out = Vec::new();
+        apply_filtered(&old, &patch, &mut out).unwrap();
+        assert_eq!(out, new);
+        let inspection = inspect(&mut &patch[..]).unwrap();
+        assert_eq!(
+            (inspection.flags, inspection.filter),
+            (0xff00_0001, Some(Filter::X86))
+        );
+        assert!(apply_slice(&old, &patch, &mut Vec::new()).is_err());
+        assert!(apply(&mut Cursor::new(&old), &mut Vec::new(), &mut &patch[..]).is_err());
+
+        let mut patch = Vec::new();
+        builder
+            .generate_chunked(&mut &old[..], &mut &new[..], &mut patch, |_| {})
+            .unwrap();
+        out.clear();
+        apply_filtered(&old, &patch, &mut out).unwrap();
+        assert_eq!(out, new);
+    }
+}
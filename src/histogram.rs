@@ -0,0 +1,155 @@
+//! How the entries of a patch are distributed, to help choose chunk sizes and options.
+
+use std::fmt::Write;
+
+use crate::spec::{parse_chunked, SpecError};
+
+/// How many values fell between `min` and `max` (inclusive).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bucket {
+    pub min: u64,
+    pub max: u64,
+    pub count: u64,
+}
+
+/// Counts values in buckets of powers of two, with 0 in a bucket of its own.
+fn add(buckets: &mut Vec<Bucket>, value: u64) {
+    let (min, max) = match value.checked_ilog2() {
+        None => (0, 0),
+        Some(log) => (1 << log, (1 << log) - 1 + (1 << log)),
+    };
+    match buckets.binary_search_by_key(&min, |bucket| bucket.min) {
+        Ok(i) => buckets[i].count += 1,
+        Err(i) => buckets.insert(i, Bucket { min, max, count: 1 }),
+    }
+}
+
+/// The distribution of the entries of a patch, from [`histogram`].
+///
+/// Lots of small entries mean the chunks are too small to find long matches, or the files differ
+/// in many small places. Long seeks mean data moved around, which a larger
+/// [`old_window`][crate::DiffOptions::old_window] may pick up better. Backward seeks make patches
+/// slower to apply from sources that read ahead. A high [`literal_ratio`][Self::literal_ratio]
+/// means much of the new file wasn't found in the old one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PatchHistogram {
+    pub chunks: u64,
+    pub entries: u64,
+    /// How much of the new file each entry writes, as diff and extra data, or as a copy. Only
+    /// buckets with entries in them are listed, in order.
+    pub entry_sizes: Vec<Bucket>,
+    /// How far entries seek in the old file after their diff data, in either direction.
+    pub seek_distances: Vec<Bucket>,
+    /// How many entries seek backwards.
+    pub backward_seeks: u64,
+    /// The bytes of the new file that are built from the old file.
+    pub diff_bytes: u64,
+    /// The bytes of the new file that are stored in the patch as-is.
+    pub extra_bytes: u64,
+    /// The bytes of the new file that are copied from earlier in the new file.
+    pub copied_bytes: u64,
+}
+
+impl PatchHistogram {
+    /// How much of the new file is stored as-is, from 0 to 1.
+    pub fn literal_ratio(&self) -> f64 {
+        let total = (self.diff_bytes + self.extra_bytes).saturating_add(self.copied_bytes);
+        match total {
+            0 => 0.0,
+            total => self.extra_bytes as f64 / total as f64,
+        }
+    }
+
+    /// The histogram as a JSON object, with the same field names, and the literal ratio as
+    /// `literal_ratio`.
+    pub fn to_json(&self) -> String {
+        // Only numbers, so this doesn't need serde
+        let buckets = |buckets: &[Bucket]| {
+            let buckets: Vec<String> = buckets
+                .iter()
+                .map(|b| format!(r#"{{"min":{},"max":{},"count":{}}}"#, b.min, b.max, b.count))
+                .collect();
+            format!("[{}]", buckets.join(","))
+        };
+        let mut json = String::new();
+        write!(
+            json,
+            concat!(
+                r#"{{"chunks":{},"entries":{},"entry_sizes":{},"seek_distances":{},"#,
+                r#""backward_seeks":{},"diff_bytes":{},"extra_bytes":{},"copied_bytes":{},"#,
+                r#""literal_ratio":{}}}"#
+            ),
+            self.chunks,
+            self.entries,
+            buckets(&self.entry_sizes),
+            buckets(&self.seek_distances),
+            self.backward_seeks,
+            self.diff_bytes,
+            self.extra_bytes,
+            self.copied_bytes,
+            self.literal_ratio(),
+        )
+        .unwrap();
+        json
+    }
+}
+
+/// Analyzes a (chunked or plain) patch without applying it, counting how large its entries are
+/// and how far they seek.
+pub fn histogram(patch: &[u8]) -> Result<PatchHistogram, SpecError> {
+    let mut histogram = PatchHistogram::default();
+    for chunk in parse_chunked(patch)? {
+        histogram.chunks += 1;
+        for entry in chunk.entries {
+            histogram.entries += 1;
+            if let Some((len, _)) = entry.copy {
+                histogram.copied_bytes = histogram.copied_bytes.saturating_add(len);
+                add(&mut histogram.entry_sizes, len);
+                continue;
+            }
+            let (diff, extra) = (entry.diff.len() as u64, entry.extra.len() as u64);
+            histogram.diff_bytes += diff;
+            histogram.extra_bytes += extra;
+            add(&mut histogram.entry_sizes, diff + extra);
+            add(&mut histogram.seek_distances, entry.seek.unsigned_abs());
+            if entry.seek < 0 {
+                histogram.backward_seeks += 1;
+            }
+        }
+    }
+    Ok(histogram)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{histogram, Bucket};
+    use crate::spec::{encode_entry, encode_header, Terminator};
+
+    #[test]
+    fn counted() {
+        // 5 bytes from the old file and 2 of extra data, a seek back, and 5 more from the old file
+        let patch = [
+            encode_header(12),
+            encode_entry(&[0; 5], &[0; 2], -3),
+            encode_entry(&[0; 5], b"", 0),
+            Terminator::BYTES.to_vec(),
+        ]
+        .concat();
+
+        let counted = histogram(&patch).unwrap();
+        let bucket = |min, max, count| Bucket { min, max, count };
+        assert_eq!(counted.entry_sizes, [bucket(4, 7, 2)]);
+        assert_eq!(counted.seek_distances, [bucket(0, 0, 1), bucket(2, 3, 1)]);
+        assert_eq!(counted.backward_seeks, 1);
+        assert_eq!(
+            counted.to_json(),
+            concat!(
+                r#"{"chunks":1,"entries":2,"entry_sizes":[{"min":4,"max":7,"count":2}],"#,
+                r#""seek_distances":[{"min":0,"max":0,"count":1},{"min":2,"max":3,"count":1}],"#,
+                r#""backward_seeks":1,"diff_bytes":10,"extra_bytes":2,"copied_bytes":0,"#,
+                r#""literal_ratio":0.16666666666666666}"#
+            )
+        );
+        assert!(histogram(&patch[..40]).is_err());
+    }
+}
@@ -0,0 +1,277 @@
+//! Delta encoding of HTTP resources ([RFC 3229]) with ddelta patches.
+//!
+//! A client with a cached copy of a resource asks for a delta by sending the ETag of its copy in
+//! `If-None-Match`, along with `A-IM: ddelta`. If the server still has that version, it answers with
+//! status 226 (IM Used), `IM: ddelta`, the ETag of the base in `Delta-Base`, and a chunked patch from
+//! the base to the current version as the body. The client applies that to its copy.
+//!
+//! Nothing here speaks HTTP itself. These are the pieces to plug into whichever client or server is
+//! used: strong ETags derived from a [`Checksum`], parsing of the request headers, and building and
+//! applying the response.
+//!
+//! [RFC 3229]: https://www.rfc-editor.org/rfc/rfc3229
+
+use std::fmt;
+
+use crate::{apply_chunked, Checksum, ChecksumAlgorithm, PatchError, SliceSource};
+#[cfg(feature = "diff")]
+use crate::{generate_chunked_with_options, DiffError, DiffOptions};
+
+type Str = Box<str>;
+
+/// The name of the instance manipulation, as used in the `A-IM` and `IM` headers.
+pub const IM: &str = "ddelta";
+/// The status code of a response with a delta as its body.
+pub const STATUS_IM_USED: u16 = 226;
+
+#[derive(Debug)]
+pub enum HttpError {
+    UnsupportedChecksum(ChecksumAlgorithm),
+    NotDelta(Str),
+    BaseMismatch {
+        expected: Str,
+        found: Str,
+    },
+    EtagMismatch {
+        expected: Str,
+        found: Str,
+    },
+    #[cfg(feature = "diff")]
+    Diff(DiffError),
+    Patch(PatchError),
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpError::UnsupportedChecksum(a) => {
+                write!(f, "checksum algorithm {a:?} is not supported")
+            }
+            HttpError::NotDelta(e) => write!(f, "response is not a ddelta: {e}"),
+            HttpError::BaseMismatch { expected, found } => write!(
+                f,
+                "delta is based on {expected}, but the cached copy is {found}"
+            ),
+            HttpError::EtagMismatch { expected, found } => {
+                write!(f, "result should be {expected}, but is {found}")
+            }
+            #[cfg(feature = "diff")]
+            HttpError::Diff(e) => e.fmt(f),
+            HttpError::Patch(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for HttpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "diff")]
+            HttpError::Diff(e) => e.source(),
+            HttpError::Patch(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "diff")]
+impl From<DiffError> for HttpError {
+    fn from(e: DiffError) -> Self {
+        HttpError::Diff(e)
+    }
+}
+
+impl From<PatchError> for HttpError {
+    fn from(e: PatchError) -> Self {
+        HttpError::Patch(e)
+    }
+}
+
+/// The strong ETag of `data`: its checksum in text form, in quotes, e.g. `"crc32:0d4a1185"`.
+pub fn etag(algorithm: ChecksumAlgorithm, data: &[u8]) -> Result<String, HttpError> {
+    let checksum =
+        Checksum::of(algorithm, data).ok_or(HttpError::UnsupportedChecksum(algorithm))?;
+    Ok(format!("\"{}\"", checksum))
+}
+
+/// Whether the value of an `A-IM` request header accepts ddelta, i.e. lists it without `q=0`.
+pub fn accepts_delta(a_im: &str) -> bool {
+    a_im.split(',').any(|item| {
+        let mut params = item.split(';').map(str::trim);
+        let name = params.next().unwrap_or_default();
+        name.eq_ignore_ascii_case(IM)
+            && !params.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .or_else(|| param.strip_prefix("Q="))
+                    .and_then(|q| q.parse::<f32>().ok())
+                    == Some(0.)
+            })
+    })
+}
+
+/// The strong entity tags listed in the value of an `If-None-Match` request header, with their
+/// quotes, in order. A server can send a delta from any of them it still has. Weak tags and `*` are
+/// skipped, as a delta needs the exact bytes of its base.
+pub fn if_none_match(value: &str) -> Vec<&str> {
+    let mut tags = Vec::new();
+    let mut rest = value;
+    while let Some(start) = rest.find('"') {
+        let weak = rest[..start].trim_end().ends_with("W/");
+        let Some(len) = rest[start + 1..].find('"') else {
+            break;
+        };
+        let end = start + len + 2;
+        if !weak {
+            tags.push(&rest[start..end]);
+        }
+        rest = &rest[end..];
+    }
+    tags
+}
+
+/// A delta-encoded response: a patch from the version a client has to the current one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Delta {
+    /// The ETag of the version the patch applies to.
+    pub delta_base: String,
+    /// The ETag of the version the patch creates.
+    pub etag: String,
+    /// A chunked patch, see [`apply_chunked`].
+    pub body: Vec<u8>,
+}
+
+impl Delta {
+    /// Creates the delta from `old` to `new`, with ETags computed with `algorithm`.
+    #[cfg(feature = "diff")]
+    pub fn encode(
+        old: &[u8],
+        new: &[u8],
+        algorithm: ChecksumAlgorithm,
+        options: &DiffOptions,
+    ) -> Result<Self, HttpError> {
+        let delta_base = etag(algorithm, old)?;
+        let etag = etag(algorithm, new)?;
+        let mut body = Vec::new();
+        generate_chunked_with_options(&mut &old[..], &mut &new[..], &mut body, options, |_| {})?;
+        Ok(Delta {
+            delta_base,
+            etag,
+            body,
+        })
+    }
+
+    /// The headers to send along with the body, in a response with status [`STATUS_IM_USED`].
+    pub fn headers(&self) -> [(&'static str, &str); 3] {
+        [
+            ("IM", IM),
+            ("Delta-Base", &self.delta_base),
+            ("ETag", &self.etag),
+        ]
+    }
+
+    /// Collects a delta from the headers and body of a response with status [`STATUS_IM_USED`].
+    /// Header names are compared case-insensitively.
+    pub fn from_response<'a>(
+        headers: impl IntoIterator<Item = (&'a str, &'a str)>,
+        body: Vec<u8>,
+    ) -> Result<Self, HttpError> {
+        let (mut im, mut delta_base, mut etag) = (None, None, None);
+        for (name, value) in headers {
+            let slot = match name.to_ascii_lowercase().as_str() {
+                "im" => &mut im,
+                "delta-base" => &mut delta_base,
+                "etag" => &mut etag,
+                _ => continue,
+            };
+            *slot = Some(value.trim());
+        }
+        match im {
+            Some(im) if im.eq_ignore_ascii_case(IM) => {}
+            Some(im) => return Err(HttpError::NotDelta(format!("IM is {im}").into())),
+            None => return Err(HttpError::NotDelta("IM header is missing".into())),
+        }
+        let missing = |name: &str| HttpError::NotDelta(format!("{name} header is missing").into());
+        Ok(Delta {
+            delta_base: delta_base.ok_or_else(|| missing("Delta-Base"))?.into(),
+            etag: etag.ok_or_else(|| missing("ETag"))?.into(),
+            body,
+        })
+    }
+
+    /// Applies the delta to `old`, the cached copy whose ETag is `old_etag`.
+    ///
+    /// Fails if the delta isn't based on that copy. If the ETag of the result was created by
+    /// [`etag`] with a supported algorithm, the result is checked against it as well.
+    pub fn apply(&self, old: &[u8], old_etag: &str) -> Result<Vec<u8>, HttpError> {
+        if old_etag != self.delta_base {
+            return Err(HttpError::BaseMismatch {
+                expected: self.delta_base.as_str().into(),
+                found: old_etag.into(),
+            });
+        }
+        let mut new = Vec::new();
+        apply_chunked(&mut SliceSource::new(old), &mut new, &mut &self.body[..])?;
+        let expected = self
+            .etag
+            .strip_prefix('"')
+            .and_then(|tag| tag.strip_suffix('"'))
+            .and_then(Checksum::parse);
+        if let Some(expected) = expected.filter(|c| c.algorithm.is_supported()) {
+            let found = etag(expected.algorithm, &new)?;
+            if found != self.etag {
+                return Err(HttpError::EtagMismatch {
+                    expected: self.etag.as_str().into(),
+                    found: found.into(),
+                });
+            }
+        }
+        Ok(new)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{accepts_delta, if_none_match};
+
+    #[test]
+    fn request_headers() {
+        assert!(accepts_delta("ddelta"));
+        assert!(accepts_delta("vcdiff, DDelta;q=0.5"));
+        assert!(!accepts_delta("vcdiff"));
+        assert!(!accepts_delta("ddelta;q=0"));
+        assert_eq!(
+            if_none_match(r#""a", W/"b",  "c,d" , *"#),
+            [r#""a""#, r#""c,d""#]
+        );
+        assert!(if_none_match("*").is_empty());
+    }
+
+    #[cfg(all(feature = "diff", feature = "crc32"))]
+    #[test]
+    fn round_trip() {
+        use super::{Delta, HttpError};
+        use crate::{ChecksumAlgorithm, DiffOptions};
+
+        let old = b"the quick brown fox jumps over the lazy dog".repeat(10);
+        let mut new = old.clone();
+        new[100..105].copy_from_slice(b"HELLO");
+        let delta =
+            Delta::encode(&old, &new, ChecksumAlgorithm::Crc32, &DiffOptions::new()).unwrap();
+        let received = Delta::from_response(delta.headers(), delta.body.clone()).unwrap();
+        assert_eq!(received, delta);
+        assert_eq!(received.apply(&old, &delta.delta_base).unwrap(), new);
+        assert!(matches!(
+            received.apply(&new, &delta.etag),
+            Err(HttpError::BaseMismatch { .. })
+        ));
+        let tampered = Delta {
+            etag: delta.delta_base.clone(),
+            ..received
+        };
+        assert!(matches!(
+            tampered.apply(&old, &delta.delta_base),
+            Err(HttpError::EtagMismatch { .. })
+        ));
+        assert!(Delta::from_response([("IM", "vcdiff")], Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,155 @@
+//! Reading and writing helpers shared by generating and applying patches.
+
+use std::io::{self, ErrorKind, Read};
+use std::thread;
+use std::time::Duration;
+
+/// What to do when a non-blocking reader has no data available yet.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum WouldBlock {
+    /// Return the [`ErrorKind::WouldBlock`] error, like any other error.
+    #[default]
+    Fail,
+    /// Retry right away, yielding to other threads in between.
+    Yield,
+    /// Retry after sleeping for the given time.
+    Sleep(Duration),
+}
+
+impl WouldBlock {
+    /// Waits according to this strategy, or returns the error if it is [`WouldBlock::Fail`].
+    fn wait(self, e: io::Error) -> io::Result<()> {
+        match self {
+            WouldBlock::Fail => return Err(e),
+            WouldBlock::Yield => thread::yield_now(),
+            WouldBlock::Sleep(duration) => thread::sleep(duration),
+        }
+        Ok(())
+    }
+}
+
+/// Decides whether a failed write of the new file is tried again, see
+/// [`Patcher::retry_writes`][crate::Patcher::retry_writes].
+///
+/// Writes that are interrupted are always retried, and a write of zero bytes counts as failing with
+/// [`ErrorKind::WriteZero`]. Closures taking the error and the number of the attempt that failed,
+/// starting at 1, are policies as well.
+pub trait RetryPolicy {
+    /// How long to wait before trying again, or `None` to give up and return `error`.
+    fn retry(&mut self, error: &io::Error, attempt: u32) -> Option<Duration>;
+}
+
+impl<F: FnMut(&io::Error, u32) -> Option<Duration>> RetryPolicy for F {
+    fn retry(&mut self, error: &io::Error, attempt: u32) -> Option<Duration> {
+        self(error, attempt)
+    }
+}
+
+/// Retries writes that failed for reasons that may pass, such as a sink that's not ready, a
+/// timeout or a short write, waiting twice as long each time.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Retry {
+    /// How often to try again before giving up.
+    pub attempts: u32,
+    /// How long to wait before trying again the first time.
+    pub delay: Duration,
+}
+
+impl RetryPolicy for Retry {
+    fn retry(&mut self, error: &io::Error, attempt: u32) -> Option<Duration> {
+        let transient = matches!(
+            error.kind(),
+            ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::WriteZero
+        );
+        (transient && attempt <= self.attempts)
+            .then(|| self.delay.saturating_mul(1 << (attempt - 1).min(16)))
+    }
+}
+
+/// Reads into `buf` until it is full or the reader reaches its end, returning how much was read.
+///
+/// Unlike a single call to [`Read::read`], short reads (as returned by pipes and sockets) are
+/// continued, and reads are retried when interrupted. Readers that aren't ready yet are handled
+/// as specified by `would_block`.
+pub fn read_up_to(
+    reader: &mut (impl Read + ?Sized),
+    buf: &mut [u8],
+    would_block: WouldBlock,
+) -> io::Result<usize> {
+    let mut bytes_read = 0;
+    while bytes_read < buf.len() {
+        match reader.read(&mut buf[bytes_read..]) {
+            Ok(0) => break,
+            Ok(n) => {
+                bytes_read += n;
+            }
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) if e.kind() == ErrorKind::WouldBlock => would_block.wait(e)?,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(bytes_read)
+}
+
+/// Fills all of `buf` like [`read_up_to`], failing with [`ErrorKind::UnexpectedEof`] if the reader
+/// ends before that.
+pub fn read_full(
+    reader: &mut (impl Read + ?Sized),
+    buf: &mut [u8],
+    would_block: WouldBlock,
+) -> io::Result<()> {
+    if read_up_to(reader, buf, would_block)? == buf.len() {
+        Ok(())
+    } else {
+        Err(ErrorKind::UnexpectedEof.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, ErrorKind, Read};
+
+    use std::time::Duration;
+
+    use super::{read_full, read_up_to, Retry, RetryPolicy, WouldBlock};
+
+    /// Returns a byte at a time, and isn't ready every other call.
+    struct Flaky<'a>(&'a [u8], bool);
+
+    impl Read for Flaky<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.1 = !self.1;
+            if self.1 {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            let n = self.0.len().min(buf.len()).min(1);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn would_block() {
+        let mut buf = [0; 4];
+        let err = read_up_to(&mut Flaky(b"abc", false), &mut buf, WouldBlock::Fail).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        let n = read_up_to(&mut Flaky(b"abc", false), &mut buf, WouldBlock::Yield).unwrap();
+        assert_eq!(&buf[..n], b"abc");
+        let err = read_full(&mut Flaky(b"abc", false), &mut buf, WouldBlock::Yield).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn retry() {
+        let mut retry = Retry {
+            attempts: 2,
+            delay: Duration::from_millis(10),
+        };
+        let timeout = io::Error::from(ErrorKind::TimedOut);
+        assert_eq!(retry.retry(&timeout, 1), Some(Duration::from_millis(10)));
+        assert_eq!(retry.retry(&timeout, 2), Some(Duration::from_millis(20)));
+        assert_eq!(retry.retry(&timeout, 3), None);
+        assert_eq!(retry.retry(&ErrorKind::BrokenPipe.into(), 1), None);
+    }
+}
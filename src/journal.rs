@@ -0,0 +1,176 @@
+//! An undo journal for applying patches over an existing file.
+
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
+
+use zerocopy::{AsBytes, FromBytes, FromZeroes, Ref, Unaligned};
+
+use crate::be::U64;
+use crate::patch::{read, PatchError, Result};
+
+const JOURNAL_MAGIC: &[u8; 8] = b"DDJRNL01";
+
+#[derive(Debug, Copy, Clone, FromZeroes, FromBytes, AsBytes, Unaligned)]
+#[repr(C)]
+struct JournalHeader {
+    magic: [u8; 8],
+    original_len: U64,
+}
+
+/// Followed by `len` bytes of the previous contents of the target at `offset`.
+#[derive(Debug, Copy, Clone, FromZeroes, FromBytes, AsBytes, Unaligned)]
+#[repr(C)]
+struct JournalRecord {
+    offset: U64,
+    len: U64,
+}
+
+/// A writer that overwrites an existing file, recording everything it overwrites in a journal.
+///
+/// Pass this as the `new` parameter of any of the apply functions to patch a file in place, for
+/// example to update an installed file without needing space for a second copy. If the result
+/// turns out to be unusable afterwards, [`rollback`] restores the previous contents from the
+/// journal. The journal is flushed before the target is modified, so it can also be used to recover
+/// from an interrupted apply.
+///
+/// The old file must not be the same file as the target, as it would be read after being
+/// overwritten.
+pub struct JournaledWriter<T, J> {
+    target: T,
+    journal: J,
+    pos: u64,
+    original_len: u64,
+}
+
+impl<T: Read + Write + Seek, J: Write> JournaledWriter<T, J> {
+    /// Starts overwriting `target` from the beginning, writing the journal to `journal`.
+    pub fn new(mut target: T, mut journal: J) -> Result<Self> {
+        let original_len = target.seek(SeekFrom::End(0))?;
+        target.seek(SeekFrom::Start(0))?;
+        journal.write_all(
+            JournalHeader {
+                magic: *JOURNAL_MAGIC,
+                original_len: U64::new(original_len),
+            }
+            .as_bytes(),
+        )?;
+        Ok(JournaledWriter {
+            target,
+            journal,
+            pos: 0,
+            original_len,
+        })
+    }
+
+    /// Saves `len` bytes of the target at the current position to the journal.
+    fn save(&mut self, len: u64) -> io::Result<()> {
+        let len = len.min(self.original_len.saturating_sub(self.pos));
+        if len == 0 {
+            return Ok(());
+        }
+        self.journal.write_all(
+            JournalRecord {
+                offset: U64::new(self.pos),
+                len: U64::new(len),
+            }
+            .as_bytes(),
+        )?;
+        self.target.seek(SeekFrom::Start(self.pos))?;
+        let copied = io::copy(&mut (&mut self.target).take(len), &mut self.journal)?;
+        if copied != len {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        self.journal.flush()?;
+        self.target.seek(SeekFrom::Start(self.pos))?;
+        Ok(())
+    }
+
+    /// Finishes writing, and returns the target, the journal, and the length of the new contents.
+    ///
+    /// Anything in the target after the new contents is saved to the journal as well, so the target
+    /// can then be truncated to the returned length.
+    pub fn finish(mut self) -> Result<(T, J, u64)> {
+        self.save(u64::MAX)?;
+        self.target.flush()?;
+        self.journal.flush()?;
+        Ok((self.target, self.journal, self.pos))
+    }
+}
+
+impl<T: Read + Write + Seek, J: Write> Write for JournaledWriter<T, J> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.save(buf.len() as u64)?;
+        let written = self.target.write(buf)?;
+        self.pos += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.target.flush()
+    }
+}
+
+/// Restores the contents a target had before it was overwritten by a [`JournaledWriter`].
+///
+/// Returns the original length of the target, which it has to be truncated to (e.g. with
+/// [`File::set_len`][std::fs::File::set_len]) if the new contents were longer. Rolling back a
+/// journal of an interrupted apply is fine, as is rolling back more than once.
+pub fn rollback(journal: &mut impl Read, target: &mut (impl Write + Seek)) -> Result<u64> {
+    let header = read!(journal, JournalHeader)?;
+    if &header.magic != JOURNAL_MAGIC {
+        return Err(PatchError::Internal("Invalid journal magic number".into()));
+    }
+    loop {
+        let record = match read!(journal, JournalRecord) {
+            Ok(record) => record,
+            // The apply was interrupted while writing this record, so the target wasn't touched
+            Err(PatchError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+        let mut data = Vec::new();
+        journal.take(record.len.get()).read_to_end(&mut data)?;
+        if data.len() as u64 != record.len.get() {
+            break;
+        }
+        target.seek(SeekFrom::Start(record.offset.get()))?;
+        target.write_all(&data)?;
+    }
+    target.flush()?;
+    Ok(header.original_len.get())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::{rollback, JournaledWriter};
+    use crate::apply;
+    use crate::spec::conformance_vectors;
+
+    #[test]
+    fn roll_back() {
+        let vector = conformance_vectors()
+            .into_iter()
+            .find(|v| v.name == "negative seek")
+            .unwrap();
+        for previous in [&b"previous contents"[..], b"ab"] {
+            let mut target = Cursor::new(previous.to_vec());
+            let mut journal = Vec::new();
+            let mut writer = JournaledWriter::new(&mut target, &mut journal).unwrap();
+            apply(
+                &mut Cursor::new(&vector.old),
+                &mut writer,
+                &mut &vector.patch[..],
+            )
+            .unwrap();
+            let (_, _, len) = writer.finish().unwrap();
+            let mut patched = target.get_ref().clone();
+            patched.truncate(len as usize);
+            assert_eq!(Some(patched), vector.expected);
+
+            let len = rollback(&mut &journal[..], &mut target).unwrap();
+            target.get_mut().truncate(len as usize);
+            assert_eq!(target.get_ref(), previous);
+        }
+    }
+}
@@ -9,37 +9,290 @@
 //! directly to an [encoder][XzEncoder], and read via a
 //! [decoder implementing a compression algorithm][XzDecoder] to not require much disk space.
 //! Additionally, no checksum is performed, so you should strongly consider doing a checksum of at
-//! least either the old or new file once written.
+//! least either the old or new file once written. [`ChecksumHasher`] can compute one with the
+//! algorithm of your choice, and [`apply_if_matches`] checks the old file before applying. To make
+//! sure a patch comes from you, sign it with [`sign_chain`] and apply it with
+//! [`apply_verified_chain`].
+//!
+//! Patches between two builds of a program get much smaller when the branches in both are made
+//! absolute first, see [`Filter`] and [`apply_filtered`].
 //!
 //! ## Features
 //!
 //! This crate optionally supports compiling the c library, divsufsort, which is enabled by default.
 //! A Rust port is available; however, it has worse performance than the C version. If you'd like
-//! to use the Rust version instead, for example if you don't have a C compiler installed, add
-//! `default-features = false` to your Cargo.toml, i.e.
+//! to use the Rust version instead, for example if you don't have a C compiler installed, turn off
+//! the default features and add back the ones you need, i.e.
 //!
 //! ```toml
 //! [dependencies]
-//! ddelta = { version = "0.1.0", default-features = false }
+//! ddelta = { version = "0.1.0", default-features = false, features = ["diff", "apply"] }
 //! ```
 //!
+//! With the C library compiled in, [`DiffOptions::backend`] can still pick the Rust port at runtime.
+//!
+//! Generating and applying patches are behind the `diff` and `apply` features, which are both on
+//! by default. Appliers on small devices can use `default-features = false, features = ["apply"]`,
+//! so neither the generator nor divsufsort is compiled. The crate still needs `std` either way.
+//! Before this split, `default-features = false` only left out the C library, so builds that set
+//! it need `features = ["diff", "apply"]` now to keep generating and applying patches.
+//!
+//! The checksum algorithms of [`ChecksumAlgorithm`] are enabled with the `crc32`, `xxhash64` and
+//! `blake3` features.
+//!
+//! The `manifest` feature adds [`Manifest`], which describes the versions and patches offered by an
+//! update server, and finds the cheapest way for a client to update.
+//!
+//! The `http` feature adds the [`http`] module, for delta-encoded HTTP responses as described in
+//! RFC 3229.
+//!
+//! The `reflink` feature adds [`apply_reflink`] on Linux, which shares the unchanged parts of the old
+//! file with the new one on filesystems that support it. The `mmap` feature adds
+//! [`apply_mmap_out`] on Unix, which writes the new file into a memory mapping of it. The
+//! `preallocate` feature adds [`preallocate`], which the file based appliers then use to reserve
+//! disk space for the new file before writing it.
+//!
+//! The `test-util` feature adds the [`test_util`] module, which makes synthetic old and new files for
+//! regression tests of pipelines built on this crate. The `corpus` feature adds [`mutations`] and
+//! [`write_corpus`], which make broken variants of a patch that appliers have to reject, as a corpus
+//! for fuzzers and negative tests.
+//!
+//! With the `tracing` feature, generating and applying patches is instrumented with [tracing]
+//! spans: `chunk`, `sort` and `scan` at the debug level while generating, and `apply_chunk` and
+//! (at the trace level) `entry` while applying. Their fields are the byte counts involved. The
+//! `indicatif` feature adds [`ProgressAdapter`], which shows progress on an [indicatif] bar.
+//!
+//! ## Thread safety
+//!
+//! Nothing in this crate has global state, so any number of patches can be generated and applied
+//! on different threads at the same time. [`Differ`] and [`Patcher`] keep buffers between runs
+//! instead, and are [`Send`] and [`Sync`] as long as their progress callback is. They need `&mut`
+//! to run, so give each thread its own, e.g. one per worker of a thread pool, rather than sharing
+//! one behind a lock. [`DiffOptions`], [`Signature`] and the other plain data types can be shared
+//! freely. `examples/parallel_batch.rs` shows a batch of patches being generated and applied on
+//! several threads.
+//!
+//! ## Unsafe code
+//!
+//! The crate is compiled with `#![deny(unsafe_code)]`, except for the system calls behind the
+//! `mmap`, `reflink` and `preallocate` features, which are kept in one module with their
+//! invariants documented. Patches are parsed through [zerocopy], whose derives check the layout of
+//! the headers at compile time. The tests of that (`layout`) avoid files and the C library so they
+//! can be run under Miri, though no CI job does. The `c` feature links the C version of
+//! divsufsort, which isn't covered by this.
+//!
 //! [ddelta]: https://github.com/julian-klode/ddelta
 //! [bsdiff]: http://www.daemonology.net/bsdiff/
 //! [XzEncoder]: https://docs.rs/xz2/*/xz2/write/struct.XzEncoder.html
 //! [XzDecoder]: https://docs.rs/xz2/*/xz2/read/struct.XzDecoder.html
+//! [tracing]: https://docs.rs/tracing
+//! [indicatif]: https://docs.rs/indicatif
+//! [zerocopy]: https://docs.rs/zerocopy
 
-use byteorder::BigEndian;
-use zerocopy::{AsBytes, FromBytes, FromZeroes, Unaligned, I64, U64};
+#![deny(unsafe_code)]
 
+use zerocopy::{AsBytes, FromBytes, FromZeroes, Unaligned};
+
+use crate::be::{I64, U32, U64};
+
+#[cfg(all(feature = "diff", feature = "apply"))]
+pub use bsdiff::generate_bsdiff;
+#[cfg(feature = "apply")]
+pub use bsdiff::{apply_bsdiff, from_bsdiff, to_bsdiff};
+#[cfg(feature = "apply")]
+pub use certs::{apply_verified_chain, sign_chain, verify_chain, Certificate, PublicKey};
+pub use checksum::{Checksum, ChecksumAlgorithm, ChecksumHasher};
+#[cfg(feature = "apply")]
+pub use chunk_reader::{ChunkedPatchReader, PatchChunk};
+#[cfg(feature = "diff")]
+pub use chunk_writer::ChunkedPatchWriter;
+#[cfg(feature = "corpus")]
+pub use corpus::{mutations, write_corpus, Mutation};
+#[cfg(feature = "diff")]
+pub use diff::{
+    estimate_patch_size, generate, generate_chunked, generate_chunked_dyn,
+    generate_chunked_with_options, generate_dyn, generate_streaming, generate_with_report,
+    DiffError, DiffOptions, Differ, GenerateReport, Hint, SortBackend,
+};
+#[cfg(feature = "diff")]
+pub use dirty::generate_dirty;
+#[cfg(feature = "diff")]
+pub use download::{plan_download, DownloadPlan};
+pub use error_code::ErrorCode;
+#[cfg(feature = "apply")]
+pub use fec::{apply_fec, repair_fec, write_fec, Repaired};
+#[cfg(feature = "apply")]
+pub use filter::apply_filtered;
+pub use filter::Filter;
+pub use header::PatchBuilder;
+#[cfg(feature = "apply")]
+pub use header::{inspect, Inspection};
+pub use histogram::{histogram, Bucket, PatchHistogram};
+pub use io::{read_full, read_up_to, Retry, RetryPolicy, WouldBlock};
+#[cfg(feature = "apply")]
+pub use journal::{rollback, JournaledWriter};
+#[cfg(feature = "diff")]
+pub use lowmem::generate_lowmem;
+#[cfg(feature = "manifest")]
+pub use manifest::{Manifest, ManifestError, PatchInfo, SignedManifest, Update, Version};
+#[cfg(all(feature = "mmap", unix))]
+pub use mmap::apply_mmap_out;
+pub use model::{Patch, PatchEntry};
+#[cfg(feature = "apply")]
+pub use old::{CachedOldSource, FetchSource, OldFetcher, OldSource, SliceSource};
+#[cfg(all(feature = "apply", any(unix, windows)))]
+pub use overlapped::apply_overlapped;
+#[cfg(feature = "apply")]
+pub use partial::{apply_partial, old_ranges, PartialSource};
+#[cfg(feature = "apply")]
+pub use patch::{
+    apply, apply_chain, apply_checked, apply_chunked, apply_chunked_dyn, apply_dyn,
+    apply_exact_len, apply_if_matches, apply_region, ApplyProgress, EntryInfo, PatchError, Patcher,
+    ReadSeek, Trailing,
+};
+#[cfg(feature = "preallocate")]
+pub use prealloc::preallocate;
+#[cfg(feature = "indicatif")]
+pub use progress_bar::ProgressAdapter;
+#[cfg(all(feature = "diff", feature = "apply"))]
+pub use rechunk::rechunk;
 #[cfg(feature = "diff")]
-pub use diff::{generate, generate_chunked, DiffError};
-pub use patch::{apply, apply_chunked, PatchError};
+pub use records::generate_records;
+#[cfg(feature = "apply")]
+pub use recover::{apply_chunked_lenient, Damage};
+#[cfg(all(feature = "reflink", target_os = "linux"))]
+pub use reflink::apply_reflink;
+pub use report::{dedup_report, DedupReport};
+#[cfg(feature = "diff")]
+pub use signature::{delta, signature, Signature};
+#[cfg(feature = "diff")]
+pub use similarity::{estimate_similarity, pair_files, Pairing};
+#[cfg(feature = "apply")]
+pub use slice::apply_slice;
+#[cfg(all(feature = "diff", feature = "apply"))]
+pub use splice::splice;
+#[cfg(feature = "diff")]
+pub use summary::Summary;
+pub use units::{Len, NewOffset, OldOffset};
 
 const DDELTA_MAGIC: &[u8; 8] = b"DDELTA40";
+/// Magic number of patches that may contain copies from the new file.
+const DDELTA_MAGIC_V2: &[u8; 8] = b"DDELTA41";
+/// Magic number of patches whose diff data is added to the old file in 32-bit words.
+const DDELTA_MAGIC_WORDS: &[u8; 8] = b"DDELTA42";
+/// Magic number of the optional [`ExtendedHeader`] in front of the first chunk.
+const DDELTA_MAGIC_EXT: &[u8; 8] = b"DDELTAEX";
+/// Magic number of the [`ExtendedHeader`] of patches between files converted with a [`Filter`],
+/// without its last byte, which is the [id][Filter::id] of the filter as an ASCII digit.
+const DDELTA_MAGIC_FILTERED: &[u8; 7] = b"DDELTAF";
+/// Set in the `diff` field of entries that copy from the new file, in [`DDELTA_MAGIC_V2`] patches.
+const COPY_FLAG: u64 = 1 << 63;
+/// How far back in the new file copies may reach.
+const COPY_WINDOW: u64 = 4 * 1024 * 1024;
 
+/// Enters a `tracing` span until the end of the current scope, if the `tracing` feature is enabled.
+#[allow(unused_macros)]
+macro_rules! span {
+    ($level: ident, $name: expr $(, $($fields: tt)*)?) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::span!(tracing::Level::$level, $name $(, $($fields)*)?).entered();
+    };
+}
+
+mod be;
+#[cfg(feature = "apply")]
+mod bsdiff;
+#[cfg(feature = "diff")]
+mod cdc;
+#[cfg(feature = "apply")]
+mod certs;
+mod checksum;
+#[cfg(feature = "apply")]
+mod chunk_reader;
+#[cfg(feature = "diff")]
+mod chunk_writer;
+#[cfg(feature = "diff")]
+mod copy;
+#[cfg(feature = "corpus")]
+mod corpus;
 #[cfg(feature = "diff")]
 mod diff;
+#[cfg(feature = "diff")]
+mod dirty;
+#[cfg(feature = "diff")]
+mod download;
+#[cfg(feature = "apply")]
+mod entries;
+#[cfg(feature = "diff")]
+mod entry_writer;
+mod error_code;
+#[cfg(feature = "apply")]
+mod fec;
+mod filter;
+mod header;
+mod histogram;
+#[cfg(feature = "http")]
+pub mod http;
+mod io;
+#[cfg(feature = "apply")]
+mod journal;
+#[cfg(feature = "diff")]
+mod lowmem;
+#[cfg(feature = "manifest")]
+mod manifest;
+#[cfg(all(feature = "mmap", unix))]
+mod mmap;
+mod model;
+#[cfg(feature = "apply")]
+mod old;
+#[cfg(all(feature = "apply", any(unix, windows)))]
+mod overlapped;
+#[cfg(feature = "apply")]
+mod partial;
+#[cfg(feature = "apply")]
 mod patch;
+#[cfg(feature = "preallocate")]
+mod prealloc;
+#[cfg(feature = "indicatif")]
+mod progress_bar;
+#[cfg(all(feature = "diff", feature = "apply"))]
+mod rechunk;
+#[cfg(feature = "diff")]
+mod records;
+#[cfg(feature = "apply")]
+mod recover;
+#[cfg(all(feature = "reflink", target_os = "linux"))]
+mod reflink;
+mod report;
+#[cfg(feature = "diff")]
+mod signature;
+#[cfg(feature = "diff")]
+mod similarity;
+#[cfg(feature = "apply")]
+mod slice;
+pub mod spec;
+#[cfg(all(feature = "diff", feature = "apply"))]
+mod splice;
+#[cfg(feature = "diff")]
+mod split;
+pub mod store;
+#[cfg(feature = "diff")]
+mod summary;
+#[cfg(all(
+    unix,
+    any(
+        feature = "mmap",
+        feature = "reflink",
+        all(
+            feature = "preallocate",
+            any(target_os = "linux", target_vendor = "apple")
+        )
+    )
+))]
+mod sys;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+mod units;
 
 /// The current state of the generator.
 ///
@@ -50,25 +303,140 @@ pub enum State {
     /// The new or old file is currently being read. This is currently only used in
     /// [`generate_chunked`].
     Reading,
-    /// The internal algorithm, divsufsort, is currently being run.
-    Sorting,
+    /// The internal algorithm, divsufsort, is currently being run on `total` bytes of the old file.
+    ///
+    /// divsufsort can't report how far along it is, so `done` is estimated from the time spent so
+    /// far. It is reported at least every 100ms for large inputs, and only reaches `total` once
+    /// sorting has finished.
+    Sorting { done: u64, total: u64 },
     /// The generator is currently working its way through the data. The number represents how much
     /// of the new file has been worked through. In other words, if calculating a percentage, divide
     /// this number by the size of the new file.
     Working(u64),
+    /// The patch is complete. This is the last report, with how long each phase took.
+    Done(Summary),
 }
 
 #[derive(Debug, Copy, Clone, FromZeroes, FromBytes, AsBytes, Unaligned)]
 #[repr(C)]
 struct PatchHeader {
     magic: [u8; 8],
-    new_file_size: U64<BigEndian>,
+    new_file_size: U64,
+}
+
+/// Written by [`PatchBuilder`], and the same size as a [`PatchHeader`] so appliers can tell them
+/// apart by the magic number.
+#[derive(Debug, Copy, Clone, FromZeroes, FromBytes, AsBytes, Unaligned)]
+#[repr(C)]
+struct ExtendedHeader {
+    magic: [u8; 8],
+    tag: [u8; 4],
+    flags: U32,
+}
+
+impl ExtendedHeader {
+    /// Whether `header` starts with the magic number of an extended header, with or without a
+    /// filter this version knows.
+    fn matches(header: &[u8]) -> bool {
+        header.starts_with(DDELTA_MAGIC_EXT) || filter::filter_of(header).is_some()
+    }
 }
 
 #[derive(Debug, Copy, Clone, FromZeroes, FromBytes, AsBytes, Unaligned)]
 #[repr(C)]
 struct EntryHeader {
-    diff: U64<BigEndian>,
-    extra: U64<BigEndian>,
-    seek: I64<BigEndian>,
+    diff: U64,
+    extra: U64,
+    seek: I64,
+}
+
+// The headers are read and written as they are in memory, so this is what keeps them matching the
+// format described in `spec`
+const _: () = {
+    use std::mem::{align_of, offset_of, size_of};
+    assert!(size_of::<PatchHeader>() == spec::PATCH_HEADER_SIZE);
+    assert!(size_of::<ExtendedHeader>() == spec::PATCH_HEADER_SIZE);
+    assert!(size_of::<EntryHeader>() == spec::ENTRY_HEADER_SIZE);
+    assert!(align_of::<PatchHeader>() == 1 && align_of::<EntryHeader>() == 1);
+    assert!(offset_of!(PatchHeader, new_file_size) == 8);
+    assert!(offset_of!(ExtendedHeader, tag) == 8 && offset_of!(ExtendedHeader, flags) == 12);
+    assert!(offset_of!(EntryHeader, extra) == 8 && offset_of!(EntryHeader, seek) == 16);
+};
+
+/// Fails to compile if a type that is meant to be used from thread pools stops being [`Send`] and
+/// [`Sync`], e.g. by gaining a [`Cell`][std::cell::Cell].
+#[allow(dead_code)]
+fn assert_thread_safe() {
+    fn is<T: Send + Sync>() {}
+    is::<ChecksumHasher>();
+    is::<PatchBuilder>();
+    is::<Patch>();
+    #[cfg(feature = "apply")]
+    {
+        is::<Patcher>();
+        is::<PatchError>();
+        is::<Trailing>();
+        is::<JournaledWriter<Vec<u8>, Vec<u8>>>();
+    }
+    #[cfg(feature = "diff")]
+    {
+        is::<Differ>();
+        is::<DiffOptions>();
+        is::<DiffError>();
+        is::<Signature>();
+        is::<State>();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use zerocopy::{AsBytes, FromBytes, Ref};
+
+    use crate::be::U64;
+    use crate::{EntryHeader, ExtendedHeader, PatchHeader, DDELTA_MAGIC_V2};
+
+    /// Without default features, nothing but zerocopy should be built, so that the crate stays
+    /// usable where there's no C compiler and few dependencies are wanted.
+    #[test]
+    fn minimal_dependencies() {
+        let manifest = include_str!("../Cargo.toml");
+        let dependencies = manifest.split("[dependencies]\n").nth(1).unwrap();
+        let required: Vec<&str> = dependencies
+            .lines()
+            .take_while(|line| !line.is_empty())
+            .filter(|line| !line.contains("optional = true"))
+            .filter_map(|line| line.split(' ').next())
+            .collect();
+        assert_eq!(required, ["zerocopy"]);
+    }
+
+    /// Headers are read from wherever they are in a buffer, so this reads them at every alignment.
+    /// It doesn't touch files or the C library, so it can run under Miri.
+    #[test]
+    fn layout() {
+        let entry = [1u64, 2, (-3i64) as u64].map(u64::to_be_bytes).concat();
+        let mut buf = vec![0; 7];
+        for offset in 0..8 {
+            buf.truncate(offset);
+            buf.extend(&entry);
+            let header = EntryHeader::read_from(&buf[offset..]).unwrap();
+            assert_eq!(
+                (header.diff.get(), header.extra.get(), header.seek.get()),
+                (1, 2, -3)
+            );
+            let header = Ref::<_, EntryHeader>::new(&buf[offset..]).unwrap();
+            assert_eq!(header.as_bytes(), &entry[..]);
+        }
+        assert!(EntryHeader::read_from(&entry[1..]).is_none());
+        let header = PatchHeader {
+            magic: *DDELTA_MAGIC_V2,
+            new_file_size: U64::new(5),
+        };
+        assert_eq!(
+            header.as_bytes(),
+            [&b"DDELTA41"[..], &5u64.to_be_bytes()].concat()
+        );
+        let extended = ExtendedHeader::read_from(header.as_bytes()).unwrap();
+        assert_eq!(extended.flags.get(), 5);
+    }
 }
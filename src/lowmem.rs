@@ -0,0 +1,255 @@
+//! Patch generation with a hash index instead of a suffix array, for when memory is tight.
+//!
+//! The old file is indexed by hashing a sample of its `MIN_MATCH`-byte substrings into a fixed-size
+//! table. The new file is then scanned with a rolling hash, and every hit in the table that really
+//! matches is extended as far as it goes. Since only some substrings of the old file are indexed,
+//! and only one position is kept per hash, short or repetitive matches are missed more often than
+//! with a suffix array.
+
+use std::io::Write;
+
+use zerocopy::AsBytes;
+
+use crate::be::{I64, U64};
+use crate::diff::{try_resize, write_ending, write_header, DiffError, Result};
+use crate::summary::Recorder;
+use crate::{EntryHeader, State};
+
+/// The length of the substrings that are indexed, and so the shortest match that is found.
+pub(crate) const MIN_MATCH: usize = 16;
+/// Base of the polynomial rolling hash.
+const BASE: u64 = 0x100_0000_01b3;
+/// How much worse than its best point a match may get while being extended past a mismatch.
+const EXTEND_FUZZ: isize = 32;
+
+/// Hash of `data[..MIN_MATCH]`.
+pub(crate) fn hash(data: &[u8]) -> u64 {
+    data[..MIN_MATCH]
+        .iter()
+        .fold(0, |h, &b| h.wrapping_mul(BASE).wrapping_add(b as u64))
+}
+
+/// A fixed-size table from substring hashes to their position.
+pub(crate) struct Index {
+    /// Positions plus one, so that zero marks an empty slot.
+    slots: Vec<u32>,
+    bits: u32,
+}
+
+impl Index {
+    /// Creates an empty index, using at most `memory_limit` bytes.
+    pub(crate) fn new(memory_limit: usize) -> Result<Self> {
+        // The largest power of two that fits, but at least 1024 slots
+        let len = (memory_limit / 4).max(1024);
+        let bits = usize::BITS - 1 - len.leading_zeros();
+        let mut slots = Vec::new();
+        try_resize(&mut slots, 1 << bits)?;
+        Ok(Index { slots, bits })
+    }
+
+    /// Indexes `old`, using at most `memory_limit` bytes.
+    fn of(old: &[u8], memory_limit: usize) -> Result<Self> {
+        let mut index = Index::new(memory_limit)?;
+        // Keep the table at most half full
+        let step = old.len().div_ceil(index.slots.len() / 2).max(1);
+        for pos in (0..old.len().saturating_sub(MIN_MATCH - 1)).step_by(step) {
+            index.insert(hash(&old[pos..]), pos);
+        }
+        Ok(index)
+    }
+
+    fn slot(&self, hash: u64) -> usize {
+        (hash.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (64 - self.bits)) as usize
+    }
+
+    /// Sets the position for `hash`, replacing whatever was there before.
+    pub(crate) fn insert(&mut self, hash: u64, pos: usize) {
+        let slot = self.slot(hash);
+        self.slots[slot] = pos as u32 + 1;
+    }
+
+    pub(crate) fn get(&self, hash: u64) -> Option<usize> {
+        match self.slots[self.slot(hash)] {
+            0 => None,
+            pos => Some(pos as usize - 1),
+        }
+    }
+}
+
+/// The part of the new file that is encoded as a difference to the old file.
+#[derive(Copy, Clone, Debug, Default)]
+struct Region {
+    new: usize,
+    old: usize,
+    len: usize,
+}
+
+/// Writes `region` and the data up to `next`, as a single entry.
+fn write_entry(
+    patch: &mut impl Write,
+    old: &[u8],
+    new: &[u8],
+    region: Region,
+    next: Region,
+) -> Result<()> {
+    let extra = &new[region.new + region.len..next.new];
+    let seek = next.old as i64 - (region.old + region.len) as i64;
+    // An empty entry would be read as the end of the patch, and does nothing anyway
+    if region.len == 0 && extra.is_empty() && seek == 0 {
+        return Ok(());
+    }
+    patch.write_all(
+        EntryHeader {
+            diff: U64::new(region.len as u64),
+            extra: U64::new(extra.len() as u64),
+            seek: I64::new(seek),
+        }
+        .as_bytes(),
+    )?;
+    for i in 0..region.len {
+        patch.write_all(&[new[region.new + i].wrapping_sub(old[region.old + i])])?;
+    }
+    patch.write_all(extra)?;
+    Ok(())
+}
+
+/// Generate a ddelta patch using a fixed amount of memory for finding matches.
+///
+/// [`generate`][crate::generate] needs 4 bytes per byte of the old file for its suffix array (in
+/// addition to the files themselves). This instead indexes the old file in a hash table of at most
+/// `memory_limit` bytes. The patch is somewhat larger, more so the smaller the table is compared to
+/// the old file, but it makes it possible to diff files that are larger than the available memory
+/// (e.g. when they are memory mapped). `old` may be up to 4GiB; `new` has no limit. The output is
+/// compatible with [`generate`][crate::generate], and is applied with [`apply`][crate::apply].
+pub fn generate_lowmem(
+    old: &[u8],
+    new: &[u8],
+    patch: &mut impl Write,
+    memory_limit: usize,
+    mut progress: impl FnMut(State),
+) -> Result<()> {
+    let recorder = Recorder::new();
+    recorder.inputs(old, new);
+    generate_patch(
+        old,
+        new,
+        &mut recorder.patch_writer(patch),
+        memory_limit,
+        &mut progress,
+    )?;
+    progress(State::Done(recorder.finish()));
+    Ok(())
+}
+
+fn generate_patch(
+    old: &[u8],
+    new: &[u8],
+    patch: &mut impl Write,
+    memory_limit: usize,
+    mut progress: impl FnMut(State),
+) -> Result<()> {
+    if old.len() >= u32::MAX as usize {
+        return Err(DiffError::Internal(
+            format!("The old file must be smaller than {} bytes", u32::MAX).into(),
+        ));
+    }
+    let index = Index::of(old, memory_limit)?;
+    write_header(patch, new.len() as u64)?;
+
+    // BASE^(MIN_MATCH-1), to remove the oldest byte from the rolling hash
+    let top = (1..MIN_MATCH).fold(1u64, |p, _| p.wrapping_mul(BASE));
+    let mut region = Region::default();
+    let mut scan = 0;
+    let mut h = None;
+    while scan + MIN_MATCH <= new.len() {
+        if scan % 10_000 == 0 {
+            progress(State::Working(scan as u64));
+        }
+        let current = *h.get_or_insert_with(|| hash(&new[scan..]));
+        let found = index
+            .get(current)
+            .filter(|&pos| old[pos..].starts_with(&new[scan..scan + MIN_MATCH]));
+        let Some(pos) = found else {
+            h = (scan + MIN_MATCH < new.len()).then(|| {
+                current
+                    .wrapping_sub((new[scan] as u64).wrapping_mul(top))
+                    .wrapping_mul(BASE)
+                    .wrapping_add(new[scan + MIN_MATCH] as u64)
+            });
+            scan += 1;
+            continue;
+        };
+
+        // Extend backwards over the data that would otherwise be stored as-is
+        let back = new[region.new + region.len..scan]
+            .iter()
+            .rev()
+            .zip(old[..pos].iter().rev())
+            .take_while(|(n, o)| n == o)
+            .count();
+        // Extend forwards past small differences, as long as most bytes still match
+        let mut len = MIN_MATCH;
+        let (mut score, mut best) = (0, 0);
+        for (i, (n, o)) in new[scan..]
+            .iter()
+            .zip(&old[pos..])
+            .enumerate()
+            .skip(MIN_MATCH)
+        {
+            score += if n == o { 1 } else { -1 };
+            if score > best {
+                best = score;
+                len = i + 1;
+            } else if score < best - EXTEND_FUZZ {
+                break;
+            }
+        }
+
+        let next = Region {
+            new: scan - back,
+            old: pos - back,
+            len: len + back,
+        };
+        write_entry(patch, old, new, region, next)?;
+        region = next;
+        scan = next.new + next.len;
+        h = None;
+    }
+    let end = Region {
+        new: new.len(),
+        old: region.old + region.len,
+        len: 0,
+    };
+    write_entry(patch, old, new, region, end)?;
+    write_ending(patch)?;
+    patch.flush()?;
+    Ok(())
+}
+
+#[cfg(all(test, feature = "apply"))]
+mod test {
+    use std::io::Cursor;
+
+    use super::generate_lowmem;
+    use crate::apply;
+    use crate::test_util::Rng;
+
+    #[test]
+    fn lowmem() {
+        let old = Rng::new(1).bytes(200_000);
+        let mut new = old.clone();
+        new.splice(50_000..50_100, [1; 300]);
+        new.drain(120_000..130_000);
+        new[150_000] ^= 0xff;
+        new.extend_from_slice(&old[..20_000]);
+        for (old, new) in [(&old[..], &new[..]), (b"", b"short"), (b"abc", b"")] {
+            let mut patch = Vec::new();
+            generate_lowmem(old, new, &mut patch, 16 * 1024, |_| {}).unwrap();
+            let mut out = Vec::new();
+            apply(&mut Cursor::new(old), &mut out, &mut &patch[..]).unwrap();
+            assert_eq!(out, new);
+            // Only the inserted and changed bytes are stored as-is
+            assert!(patch.iter().filter(|&&b| b != 0).count() < 1000);
+        }
+    }
+}
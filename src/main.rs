@@ -0,0 +1,199 @@
+//! Command line interface for creating and applying patches.
+//!
+//! Any file can be given as `-` to use stdin or stdout instead, so this can be used in pipelines.
+
+use std::env;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use argh::FromArgs;
+use ddelta::{apply_chunked, PatchHistogram, SliceSource};
+
+/// argh takes a lone `-` for an option, so it's passed on as this instead, which can't be a path.
+const STD: &str = "\0-";
+
+#[derive(FromArgs)]
+/// Create and apply ddelta patches.
+struct Args {
+    #[argh(subcommand)]
+    command: Command,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    #[cfg(feature = "diff")]
+    Diff(Diff),
+    Patch(Patch),
+    Inspect(Inspect),
+    #[cfg(feature = "corpus")]
+    Corpus(Corpus),
+}
+
+#[cfg(feature = "diff")]
+#[derive(FromArgs)]
+#[argh(subcommand, name = "diff")]
+/// Create a patch that turns OLD into NEW.
+struct Diff {
+    #[argh(positional)]
+    old: PathBuf,
+    #[argh(positional)]
+    new: PathBuf,
+    /// where to write the patch, stdout by default
+    #[argh(positional, default = "PathBuf::from(STD)")]
+    patch: PathBuf,
+    /// the maximum size of the chunks the files are split into, 64 MiB by default. Memory use is
+    /// about 6 times this
+    #[argh(option, default = "64 * 1024 * 1024")]
+    chunk_size: usize,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "patch")]
+/// Apply PATCH to OLD, creating NEW.
+struct Patch {
+    #[argh(positional)]
+    old: PathBuf,
+    #[argh(positional)]
+    patch: PathBuf,
+    /// where to write the new file, stdout by default
+    #[argh(positional, default = "PathBuf::from(STD)")]
+    new: PathBuf,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "inspect")]
+/// Show how large the entries of PATCH are and how far they seek, to help choose options.
+struct Inspect {
+    #[argh(positional)]
+    patch: PathBuf,
+    /// print the histogram as JSON
+    #[argh(switch)]
+    json: bool,
+}
+
+#[cfg(feature = "corpus")]
+#[derive(FromArgs)]
+#[argh(subcommand, name = "corpus")]
+/// Write broken variants of PATCH to DIR, as a corpus for fuzzers and negative tests.
+struct Corpus {
+    #[argh(positional)]
+    patch: PathBuf,
+    #[argh(positional)]
+    dir: PathBuf,
+}
+
+fn is_std(path: &Path) -> bool {
+    path == Path::new(STD)
+}
+
+fn input(path: &Path) -> io::Result<Box<dyn Read>> {
+    Ok(if is_std(path) {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(BufReader::new(File::open(path)?))
+    })
+}
+
+fn output(path: &Path) -> io::Result<Box<dyn Write>> {
+    Ok(if is_std(path) {
+        Box::new(BufWriter::new(io::stdout().lock()))
+    } else {
+        Box::new(BufWriter::new(File::create(path)?))
+    })
+}
+
+fn print_histogram(histogram: &PatchHistogram) {
+    println!(
+        "{} chunks, {} entries, {} seeking backwards",
+        histogram.chunks, histogram.entries, histogram.backward_seeks
+    );
+    println!(
+        "{} bytes from the old file, {} stored as-is ({:.1}%), {} copied",
+        histogram.diff_bytes,
+        histogram.extra_bytes,
+        histogram.literal_ratio() * 100.0,
+        histogram.copied_bytes
+    );
+    for (name, buckets) in [
+        ("entry sizes", &histogram.entry_sizes),
+        ("seek distances", &histogram.seek_distances),
+    ] {
+        println!("{name}:");
+        for bucket in buckets {
+            println!("{:>20} - {:<20} {}", bucket.min, bucket.max, bucket.count);
+        }
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = env::args()
+        .map(|arg| if arg == "-" { STD.into() } else { arg })
+        .collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let args = Args::from_args(&["ddelta"], &args[1..]).unwrap_or_else(|early_exit| {
+        match early_exit.status {
+            Ok(()) => println!("{}", early_exit.output),
+            Err(()) => eprintln!(
+                "{}\nRun ddelta --help for more information.",
+                early_exit.output
+            ),
+        }
+        exit(early_exit.status.map_or(1, |()| 0))
+    });
+    match args.command {
+        #[cfg(feature = "diff")]
+        Command::Diff(diff) => {
+            if is_std(&diff.old) && is_std(&diff.new) {
+                return Err("only one of OLD and NEW can be read from stdin".into());
+            }
+            let mut patch = output(&diff.patch)?;
+            ddelta::generate_chunked(
+                &mut input(&diff.old)?,
+                &mut input(&diff.new)?,
+                &mut patch,
+                diff.chunk_size,
+                |_| {},
+            )?;
+            patch.flush()?;
+        }
+        Command::Patch(args) => {
+            if is_std(&args.old) && is_std(&args.patch) {
+                return Err("only one of OLD and PATCH can be read from stdin".into());
+            }
+            let mut new = output(&args.new)?;
+            let mut patch = input(&args.patch)?;
+            if is_std(&args.old) {
+                // Applying seeks in the old file, so it has to be read into memory
+                let mut old = Vec::new();
+                io::stdin().lock().read_to_end(&mut old)?;
+                apply_chunked(&mut SliceSource::new(&old), &mut new, &mut patch)?;
+            } else {
+                let mut old = BufReader::new(File::open(&args.old)?);
+                apply_chunked(&mut old, &mut new, &mut patch)?;
+            }
+            new.flush()?;
+        }
+        Command::Inspect(args) => {
+            let mut patch = Vec::new();
+            input(&args.patch)?.read_to_end(&mut patch)?;
+            let histogram = ddelta::histogram(&patch)?;
+            if args.json {
+                println!("{}", histogram.to_json());
+            } else {
+                print_histogram(&histogram);
+            }
+        }
+        #[cfg(feature = "corpus")]
+        Command::Corpus(args) => {
+            let mut patch = Vec::new();
+            input(&args.patch)?.read_to_end(&mut patch)?;
+            let written = ddelta::write_corpus(&patch, &args.dir)?;
+            eprintln!("wrote {written} files to {}", args.dir.display());
+        }
+    }
+    Ok(())
+}
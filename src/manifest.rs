@@ -0,0 +1,329 @@
+//! Manifests listing the versions and patches an update server offers, and finding the cheapest
+//! way to update with them.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{Checksum, ChecksumAlgorithm};
+
+#[derive(Debug)]
+pub enum ManifestError {
+    UnsupportedChecksum(ChecksumAlgorithm),
+    Json(serde_json::Error),
+    BadSignature,
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManifestError::UnsupportedChecksum(a) => {
+                write!(f, "checksum algorithm {a:?} is not supported")
+            }
+            ManifestError::Json(e) => write!(f, "invalid manifest: {e}"),
+            ManifestError::BadSignature => f.write_str("manifest signature is invalid"),
+        }
+    }
+}
+
+impl std::error::Error for ManifestError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ManifestError::Json(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for ManifestError {
+    fn from(e: serde_json::Error) -> Self {
+        ManifestError::Json(e)
+    }
+}
+
+/// Serializes checksums in their text form.
+mod checksum_text {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(checksum: &Checksum, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(checksum)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Checksum, D::Error> {
+        let text = String::deserialize(d)?;
+        Checksum::parse(&text).ok_or_else(|| serde::de::Error::custom("invalid checksum"))
+    }
+}
+
+/// A version of the file that is being updated.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Version {
+    pub version: String,
+    pub size: u64,
+    #[serde(with = "checksum_text")]
+    pub checksum: Checksum,
+    /// Where to download the whole file, if it's offered.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+/// A chunked patch from one version to another.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PatchInfo {
+    pub from: String,
+    pub to: String,
+    pub size: u64,
+    #[serde(with = "checksum_text")]
+    pub checksum: Checksum,
+    pub url: String,
+}
+
+/// The versions and patches offered for a file.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub versions: Vec<Version>,
+    pub patches: Vec<PatchInfo>,
+}
+
+/// A signed manifest, as it is sent to clients.
+///
+/// The manifest is kept as the exact text that was signed, so verifying doesn't depend on how it
+/// would be serialized again.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SignedManifest {
+    pub manifest: String,
+    /// The signature of `manifest`, in hexadecimal.
+    pub signature: String,
+}
+
+/// How a client gets to the version it wants.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Update<'a> {
+    /// The client already has that version.
+    UpToDate,
+    /// Apply these patches, in order.
+    Patches(Vec<&'a PatchInfo>),
+    /// Download the whole file, since that is smaller than any chain of patches.
+    Full(&'a Version),
+}
+
+impl Manifest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a version with the given contents, which can be downloaded in full from `url`.
+    pub fn add_version(
+        &mut self,
+        version: impl Into<String>,
+        data: &[u8],
+        algorithm: ChecksumAlgorithm,
+        url: impl Into<Option<String>>,
+    ) -> Result<&mut Self, ManifestError> {
+        self.versions.push(Version {
+            version: version.into(),
+            size: data.len() as u64,
+            checksum: checksum(algorithm, data)?,
+            url: url.into(),
+        });
+        Ok(self)
+    }
+
+    /// Adds a patch from `from` to `to`, which can be downloaded from `url`.
+    ///
+    /// The checksum is of the patch as it is downloaded, so if it is compressed, pass the compressed
+    /// data here.
+    pub fn add_patch(
+        &mut self,
+        from: impl Into<String>,
+        to: impl Into<String>,
+        patch: &[u8],
+        algorithm: ChecksumAlgorithm,
+        url: impl Into<String>,
+    ) -> Result<&mut Self, ManifestError> {
+        self.patches.push(PatchInfo {
+            from: from.into(),
+            to: to.into(),
+            size: patch.len() as u64,
+            checksum: checksum(algorithm, patch)?,
+            url: url.into(),
+        });
+        Ok(self)
+    }
+
+    /// Serializes the manifest as JSON, and signs it with `sign`, which should return the signature
+    /// of the bytes it is given (e.g. using Ed25519).
+    pub fn sign(&self, sign: impl FnOnce(&[u8]) -> Vec<u8>) -> Result<String, ManifestError> {
+        let manifest = serde_json::to_string(self)?;
+        let signature = sign(manifest.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        Ok(serde_json::to_string(&SignedManifest {
+            manifest,
+            signature,
+        })?)
+    }
+
+    /// Parses a manifest created by [`sign`][Self::sign], and checks its signature with `verify`,
+    /// which is given the signed bytes and the signature.
+    pub fn verify(
+        signed: &str,
+        verify: impl FnOnce(&[u8], &[u8]) -> bool,
+    ) -> Result<Self, ManifestError> {
+        let signed: SignedManifest = serde_json::from_str(signed)?;
+        let hex = signed.signature.as_bytes();
+        let signature = hex
+            .chunks(2)
+            .map(|pair| {
+                std::str::from_utf8(pair)
+                    .ok()
+                    .filter(|pair| pair.len() == 2)
+                    .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+            })
+            .collect::<Option<Vec<u8>>>()
+            .ok_or(ManifestError::BadSignature)?;
+        if !verify(signed.manifest.as_bytes(), &signature) {
+            return Err(ManifestError::BadSignature);
+        }
+        Ok(serde_json::from_str(&signed.manifest)?)
+    }
+
+    /// Finds the cheapest way to update from `current` to `target`: the chain of patches with the
+    /// smallest total size, or a full download if that is smaller (or no chain exists). Returns
+    /// [`None`] if there is no way to get to `target`.
+    ///
+    /// A client that has an unknown or modified version should pass [`None`] as `current`, and will
+    /// then always be offered the full download.
+    pub fn resolve(&self, current: Option<&str>, target: &str) -> Option<Update<'_>> {
+        if current == Some(target) {
+            return Some(Update::UpToDate);
+        }
+        let full = self
+            .versions
+            .iter()
+            .find(|v| v.version == target && v.url.is_some());
+        let chain = current.and_then(|current| self.cheapest_chain(current, target));
+        match (chain, full) {
+            (Some((size, chain)), Some(full)) if size < full.size => Some(Update::Patches(chain)),
+            (Some((_, chain)), None) => Some(Update::Patches(chain)),
+            (_, Some(full)) => Some(Update::Full(full)),
+            (None, None) => None,
+        }
+    }
+
+    /// Dijkstra's algorithm over the versions, with the patches as edges weighted by their size.
+    fn cheapest_chain(&self, from: &str, to: &str) -> Option<(u64, Vec<&PatchInfo>)> {
+        // The cheapest known way to reach each version, as the total size and the last patch
+        let mut best: BTreeMap<&str, (u64, Option<&PatchInfo>)> = BTreeMap::new();
+        let mut queue = BTreeSet::new();
+        best.insert(from, (0, None));
+        queue.insert((0, from));
+        while let Some((size, version)) = queue.pop_first() {
+            if version == to {
+                let mut chain = Vec::new();
+                let mut at = to;
+                while let Some((_, Some(patch))) = best.get(at) {
+                    chain.push(*patch);
+                    at = &patch.from;
+                }
+                chain.reverse();
+                return Some((size, chain));
+            }
+            for patch in self.patches.iter().filter(|p| p.from == version) {
+                let total = size.saturating_add(patch.size);
+                match best.get(patch.to.as_str()) {
+                    Some(&(previous, _)) if previous <= total => continue,
+                    Some(&(previous, _)) => {
+                        queue.remove(&(previous, patch.to.as_str()));
+                    }
+                    None => {}
+                }
+                best.insert(&patch.to, (total, Some(patch)));
+                queue.insert((total, &patch.to));
+            }
+        }
+        None
+    }
+}
+
+fn checksum(algorithm: ChecksumAlgorithm, data: &[u8]) -> Result<Checksum, ManifestError> {
+    Checksum::of(algorithm, data).ok_or(ManifestError::UnsupportedChecksum(algorithm))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Manifest, ManifestError, Update};
+    use crate::{Checksum, ChecksumAlgorithm};
+
+    fn manifest() -> Manifest {
+        let checksum = Checksum {
+            algorithm: ChecksumAlgorithm::Crc32,
+            digest: [1, 2, 3, 4].into(),
+        };
+        let version = |version: &str, size, url: Option<&str>| super::Version {
+            version: version.into(),
+            size,
+            checksum: checksum.clone(),
+            url: url.map(Into::into),
+        };
+        let patch = |from: &str, to: &str, size| super::PatchInfo {
+            from: from.into(),
+            to: to.into(),
+            size,
+            checksum: checksum.clone(),
+            url: format!("{}-{}.patch", from, to),
+        };
+        Manifest {
+            versions: vec![
+                version("1", 1000, None),
+                version("2", 1000, None),
+                version("3", 1000, Some("3.bin")),
+            ],
+            patches: vec![
+                patch("1", "2", 100),
+                patch("2", "3", 100),
+                patch("1", "3", 300),
+                patch("0", "3", 2000),
+            ],
+        }
+    }
+
+    #[test]
+    fn resolve() {
+        let manifest = manifest();
+        let urls = |update| match update {
+            Some(Update::Patches(chain)) => chain.iter().map(|p| p.url.clone()).collect(),
+            Some(Update::Full(version)) => vec![version.url.clone().unwrap()],
+            Some(Update::UpToDate) => vec![],
+            None => vec!["none".into()],
+        };
+        assert_eq!(
+            urls(manifest.resolve(Some("1"), "3")),
+            ["1-2.patch", "2-3.patch"]
+        );
+        assert_eq!(urls(manifest.resolve(Some("2"), "3")), ["2-3.patch"]);
+        assert_eq!(urls(manifest.resolve(Some("0"), "3")), ["3.bin"]);
+        assert_eq!(urls(manifest.resolve(None, "3")), ["3.bin"]);
+        assert_eq!(urls(manifest.resolve(Some("3"), "3")), Vec::<String>::new());
+        assert_eq!(urls(manifest.resolve(Some("1"), "2")), ["1-2.patch"]);
+        assert_eq!(urls(manifest.resolve(Some("3"), "1")), ["none"]);
+    }
+
+    #[test]
+    fn signature() {
+        let manifest = manifest();
+        // Not a real signature scheme, just something that depends on the content
+        let sign = |data: &[u8]| vec![data.iter().fold(0u8, |a, b| a.wrapping_add(*b)), 0xab];
+        let signed = manifest.sign(sign).unwrap();
+        let verified = Manifest::verify(&signed, |data, sig| sign(data) == sig).unwrap();
+        assert_eq!(verified, manifest);
+
+        let tampered = signed.replace("1-2.patch", "1-2.evil!");
+        assert!(matches!(
+            Manifest::verify(&tampered, |data, sig| sign(data) == sig),
+            Err(ManifestError::BadSignature)
+        ));
+    }
+}
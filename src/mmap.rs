@@ -0,0 +1,121 @@
+//! Applying patches straight into a memory mapped output file.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+
+use crate::patch::Result;
+use crate::sys::Mapping;
+use crate::{OldSource, Patcher};
+
+/// Writeback of the mapping is started whenever this much more of it has been written.
+const WRITEBACK_INTERVAL: usize = 64 * 1024 * 1024;
+
+/// Writes into a mapping, starting writeback behind itself.
+struct Writer<'a> {
+    mapping: &'a mut Mapping,
+    pos: usize,
+    /// Everything before this has had its writeback started.
+    synced: usize,
+}
+
+impl Write for Writer<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let pos = self.pos;
+        let n = buf.len().min(self.mapping.len() - pos);
+        if n == 0 && !buf.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "the new file is larger than the output file",
+            ));
+        }
+        self.mapping.write(pos, &buf[..n]);
+        self.pos += n;
+        if self.pos - self.synced >= WRITEBACK_INTERVAL {
+            self.mapping.writeback(self.synced, self.pos)?;
+            self.synced = self.pos;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Apply a chunked patch file into a memory mapped output file, and return the size of the new
+/// file. This accepts the same patches as [`apply_chunked`][crate::apply_chunked].
+///
+/// `out` has to be opened for reading and writing, and already be at least as large as the new
+/// file (e.g. with [`File::set_len`]), as it's mapped as it is. The new file is then copied straight
+/// into the mapping, without a write call for every block. Writeback of what has been written is
+/// started every 64 MiB, so huge outputs don't pile up in memory as dirty pages. If the output file
+/// is larger than the new file, it's truncated at the end. Call [`File::sync_all`] afterwards to
+/// wait for the data to be on disk.
+///
+/// Nothing else may change the size of `out` while this runs, as accessing a mapping beyond the
+/// end of the file kills the process with `SIGBUS`. Other writes to `out` meanwhile are safe, but
+/// end up mixed into the new file. So does running out of disk space while writing to the
+/// mapping, if `out` was made larger with [`File::set_len`], which doesn't reserve any. With the
+/// `preallocate` feature, all of `out` is reserved with [`preallocate`][crate::preallocate] first.
+pub fn apply_mmap_out(old: &mut impl OldSource, patch: &mut impl Read, out: &File) -> Result<u64> {
+    // Writing to a mapping of a sparse file when the disk is full kills the process, so a full
+    // disk has to be found out here
+    #[cfg(feature = "preallocate")]
+    crate::preallocate(out, out.metadata()?.len())?;
+    let mut mapping = Mapping::new(out)?;
+    let mut writer = Writer {
+        mapping: &mut mapping,
+        pos: 0,
+        synced: 0,
+    };
+    Patcher::new().run_chunked(old, &mut writer, patch)?;
+    let written = writer.pos;
+    if written < mapping.len() {
+        drop(mapping);
+        out.set_len(written as u64)?;
+    }
+    Ok(written as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, OpenOptions};
+    use std::io::Cursor;
+
+    use super::apply_mmap_out;
+    use crate::spec::{encode_entry, encode_header, Terminator};
+
+    #[test]
+    fn mmap_out() {
+        let path = std::env::temp_dir().join(format!("ddelta-mmap-{}", std::process::id()));
+        let old = b"hello world";
+        // "hello" from the old file, a new ",", and " world" from the old file
+        let patch = [
+            encode_header(12),
+            encode_entry(&[0; 5], b",", 0),
+            encode_entry(&[0; 6], b"", 0),
+            Terminator::BYTES.to_vec(),
+        ]
+        .concat();
+
+        for (size, expected) in [(20, Ok(&b"hello, world"[..])), (5, Err(()))] {
+            let out = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&path)
+                .unwrap();
+            out.set_len(size).unwrap();
+            let result = apply_mmap_out(&mut Cursor::new(old), &mut &patch[..], &out);
+            match expected {
+                Ok(expected) => {
+                    assert_eq!(result.unwrap(), expected.len() as u64);
+                    assert_eq!(fs::read(&path).unwrap(), expected);
+                }
+                Err(()) => assert!(result.is_err()),
+            }
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
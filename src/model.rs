@@ -0,0 +1,296 @@
+//! Patches held in memory as a list of entries, for algorithms that build or rewrite them.
+
+use std::io::{self, Write};
+
+use zerocopy::AsBytes;
+
+use crate::be::{I64, U32, U64};
+use crate::filter::filter_of;
+use crate::spec::{self, SpecError, Terminator, PATCH_HEADER_SIZE};
+use crate::{
+    EntryHeader, ExtendedHeader, Filter, PatchHeader, COPY_FLAG, COPY_WINDOW, DDELTA_MAGIC,
+    DDELTA_MAGIC_EXT, DDELTA_MAGIC_V2, DDELTA_MAGIC_WORDS,
+};
+
+/// An entry of a [`Patch`], with its data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PatchEntry {
+    /// Adds `diff` to the next bytes of the old file, appends `extra`, then moves the position in
+    /// the old file by `seek`.
+    Data {
+        diff: Vec<u8>,
+        extra: Vec<u8>,
+        seek: i64,
+    },
+    /// Appends `len` bytes from `distance` bytes before the end of what was written so far, then
+    /// moves the position in the old file by `seek`.
+    Copy { len: u64, distance: u64, seek: i64 },
+}
+
+impl PatchEntry {
+    /// How much of the new file the entry writes.
+    pub fn len(&self) -> u64 {
+        match self {
+            PatchEntry::Data { diff, extra, .. } => (diff.len() + extra.len()) as u64,
+            PatchEntry::Copy { len, .. } => *len,
+        }
+    }
+
+    /// Whether the entry writes nothing.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How far the entry moves the position in the old file after it.
+    pub fn seek(&self) -> i64 {
+        match self {
+            PatchEntry::Data { seek, .. } | PatchEntry::Copy { seek, .. } => *seek,
+        }
+    }
+}
+
+/// A plain patch held in memory, as an alternative to reading and writing the format as a stream.
+///
+/// Everything the format can express can be built, inspected and changed here, and then written
+/// with [`write_to`][Self::write_to]. The size of the new file is the sum of the entries, so it
+/// can't get out of sync with them, and the header allows copies exactly when there are some.
+/// Reading a patch keeps all of it in memory, so this is meant for tools that work on whole
+/// patches, not for applying them.
+///
+/// ```
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use ddelta::{Patch, PatchEntry};
+///
+/// let patch = Patch::new()
+///     .entry(PatchEntry::Data { diff: vec![0; 5], extra: b", there".to_vec(), seek: 0 })
+///     .entry(PatchEntry::Copy { len: 7, distance: 7, seek: 0 });
+/// let mut bytes = Vec::new();
+/// patch.write_to(&mut bytes)?;
+/// assert_eq!(Patch::from_bytes(&bytes)?, patch);
+///
+/// let mut patched = Vec::new();
+/// ddelta::apply(&mut std::io::Cursor::new(b"hello"), &mut patched, &mut &bytes[..])?;
+/// assert_eq!(patched, b"hello, there, there");
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Patch {
+    /// The tag and flags of the extended header, see [`PatchBuilder`][crate::PatchBuilder].
+    pub tag: Option<([u8; 4], u32)>,
+    /// The filter both files were converted with, see
+    /// [`PatchBuilder::filter`][crate::PatchBuilder::filter]. It's stored in the extended header,
+    /// so without a [`tag`][Self::tag] the patch is written with an all-zero tag and flags.
+    pub filter: Option<Filter>,
+    /// Whether the diff data is added in 32-bit words, see [`spec`]. Such patches can't have
+    /// copies.
+    pub words: bool,
+    pub entries: Vec<PatchEntry>,
+}
+
+impl Patch {
+    /// Creates an empty patch, which makes an empty new file.
+    pub fn new() -> Self {
+        Patch::default()
+    }
+
+    /// Sets the tag and flags of the extended header.
+    pub fn tag(mut self, tag: [u8; 4], flags: u32) -> Self {
+        self.tag = Some((tag, flags));
+        self
+    }
+
+    /// Sets the filter both files were converted with.
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Sets whether the diff data is added in 32-bit words.
+    pub fn words(mut self, enabled: bool) -> Self {
+        self.words = enabled;
+        self
+    }
+
+    /// Appends an entry.
+    pub fn entry(mut self, entry: PatchEntry) -> Self {
+        self.entries.push(entry);
+        self
+    }
+
+    /// The size of the new file.
+    pub fn new_len(&self) -> u64 {
+        self.entries.iter().map(PatchEntry::len).sum()
+    }
+
+    /// Whether any entry copies from the new file, which needs a newer applier.
+    pub fn copies(&self) -> bool {
+        self.entries
+            .iter()
+            .any(|entry| matches!(entry, PatchEntry::Copy { .. }))
+    }
+
+    /// Reads a plain patch. Anything after it is ignored, like [`apply`][crate::apply] does.
+    pub fn from_bytes(patch: &[u8]) -> Result<Self, SpecError> {
+        let tag = match patch.get(..PATCH_HEADER_SIZE) {
+            Some(header) if ExtendedHeader::matches(header) => Some((
+                header[8..12].try_into().unwrap(),
+                u32::from_be_bytes(header[12..].try_into().unwrap()),
+            )),
+            _ => None,
+        };
+        let filter = patch.get(..PATCH_HEADER_SIZE).and_then(filter_of);
+        let chunk = spec::parse_patch(patch)?;
+        let entries = chunk
+            .entries
+            .into_iter()
+            .map(|entry| match entry.copy {
+                Some((len, distance)) => PatchEntry::Copy {
+                    len,
+                    distance,
+                    seek: entry.seek,
+                },
+                None => PatchEntry::Data {
+                    diff: entry.diff.to_vec(),
+                    extra: entry.extra.to_vec(),
+                    seek: entry.seek,
+                },
+            })
+            .collect();
+        Ok(Patch {
+            tag,
+            filter,
+            words: chunk.words,
+            entries,
+        })
+    }
+
+    /// Writes the patch in the format read by [`apply`][crate::apply].
+    ///
+    /// Entries that write nothing and don't seek are left out, as they would end the patch. Fails
+    /// with [`io::ErrorKind::InvalidInput`] before anything is written if a copy reaches outside of
+    /// the new file or further back than the format allows, or if a patch with
+    /// [`words`][Self::words] has copies at all.
+    pub fn write_to(&self, patch: &mut impl Write) -> io::Result<()> {
+        if self.words && self.copies() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "patches with word diffs can't have copies",
+            ));
+        }
+        let mut written = 0u64;
+        for entry in &self.entries {
+            if let PatchEntry::Copy { len, distance, .. } = *entry {
+                if len & COPY_FLAG != 0
+                    || distance == 0
+                    || distance > written
+                    || distance > COPY_WINDOW
+                {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("copy from {distance} bytes back is outside of the new file"),
+                    ));
+                }
+            }
+            written += entry.len();
+        }
+        if self.tag.is_some() || self.filter.is_some() {
+            let (tag, flags) = self.tag.unwrap_or_default();
+            let header = ExtendedHeader {
+                magic: self.filter.map_or(*DDELTA_MAGIC_EXT, Filter::magic),
+                tag,
+                flags: U32::new(flags),
+            };
+            patch.write_all(header.as_bytes())?;
+        }
+        let header = PatchHeader {
+            magic: if self.copies() {
+                *DDELTA_MAGIC_V2
+            } else if self.words {
+                *DDELTA_MAGIC_WORDS
+            } else {
+                *DDELTA_MAGIC
+            },
+            new_file_size: U64::new(written),
+        };
+        patch.write_all(header.as_bytes())?;
+        for entry in &self.entries {
+            let (diff, extra, data) = match entry {
+                PatchEntry::Data { diff, extra, .. } => {
+                    (diff.len() as u64, extra.len() as u64, Some((diff, extra)))
+                }
+                PatchEntry::Copy { len, distance, .. } => (len | COPY_FLAG, *distance, None),
+            };
+            if Terminator::matches(diff, extra, entry.seek()) {
+                continue;
+            }
+            let header = EntryHeader {
+                diff: U64::new(diff),
+                extra: U64::new(extra),
+                seek: I64::new(entry.seek()),
+            };
+            patch.write_all(header.as_bytes())?;
+            if let Some((diff, extra)) = data {
+                patch.write_all(diff)?;
+                patch.write_all(extra)?;
+            }
+        }
+        Terminator::write(patch)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Patch, PatchEntry};
+    use crate::Filter;
+
+    #[test]
+    fn round_trip() {
+        let patch = Patch::new()
+            .tag(*b"TEST", 7)
+            .entry(PatchEntry::Data {
+                diff: vec![1, 2],
+                extra: b"xyz".to_vec(),
+                seek: -2,
+            })
+            .entry(PatchEntry::Data {
+                diff: vec![],
+                extra: vec![],
+                seek: 0,
+            });
+        let mut bytes = Vec::new();
+        patch.write_to(&mut bytes).unwrap();
+        assert!(bytes.starts_with(b"DDELTAEXTEST"));
+        // The empty entry is left out
+        let read = Patch::from_bytes(&bytes).unwrap();
+        assert_eq!(read.entries, patch.entries[..1]);
+        assert_eq!(
+            (read.tag, read.new_len(), read.copies()),
+            (patch.tag, 5, false)
+        );
+
+        let mut bytes = Vec::new();
+        Patch::new().write_to(&mut bytes).unwrap();
+        assert_eq!(Patch::from_bytes(&bytes).unwrap(), Patch::new());
+
+        let filtered = patch.filter(Filter::Arm);
+        let mut bytes = Vec::new();
+        filtered.write_to(&mut bytes).unwrap();
+        assert!(bytes.starts_with(b"DDELTAF2TEST"));
+        let read = Patch::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            (read.tag, read.filter),
+            (Some((*b"TEST", 7)), Some(Filter::Arm))
+        );
+
+        // A copy before anything was written
+        let invalid = Patch::new().entry(PatchEntry::Copy {
+            len: 1,
+            distance: 1,
+            seek: 0,
+        });
+        let mut bytes = Vec::new();
+        assert!(invalid.write_to(&mut bytes).is_err());
+        assert!(bytes.is_empty());
+    }
+}
@@ -0,0 +1,375 @@
+//! Access to the old file while applying a patch.
+
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+
+use crate::io::read_full;
+use crate::units::OldOffset;
+use crate::WouldBlock;
+
+/// Where the old file is read from while applying a patch.
+///
+/// Applying reads the old file mostly sequentially, with a relative seek between entries, and an
+/// absolute seek at the start of each chunk of a chunked patch. This is implemented for everything
+/// that implements [`Read`] and [`Seek`], and by [`SliceSource`] for data that is already in memory
+/// (including memory mapped files). Implement it directly when the old file lives somewhere
+/// without a convenient `Read` + `Seek` interface, e.g. to read flash memory a window at a time.
+/// For old files that are fetched in ranges, e.g. over the network, implement [`OldFetcher`]
+/// instead, and use a [`FetchSource`].
+pub trait OldSource {
+    /// Fills `buf` with the data at the current position, and moves past it. Failing to fill
+    /// all of `buf` is an error. Sources that can block should handle that as `would_block` says.
+    fn read_to(&mut self, buf: &mut [u8], would_block: WouldBlock) -> io::Result<()>;
+
+    /// Moves the current position by `offset` bytes.
+    fn seek_by(&mut self, offset: i64) -> io::Result<()>;
+
+    /// Moves the current position to `pos` bytes from the start.
+    fn seek_to(&mut self, pos: u64) -> io::Result<()>;
+}
+
+impl<T: Read + Seek + ?Sized> OldSource for T {
+    fn read_to(&mut self, buf: &mut [u8], would_block: WouldBlock) -> io::Result<()> {
+        read_full(self, buf, would_block)
+    }
+
+    fn seek_by(&mut self, offset: i64) -> io::Result<()> {
+        self.seek(SeekFrom::Current(offset)).map(drop)
+    }
+
+    fn seek_to(&mut self, pos: u64) -> io::Result<()> {
+        self.seek(SeekFrom::Start(pos)).map(drop)
+    }
+}
+
+/// An old file that's entirely in memory.
+///
+/// Reads are plain copies without any I/O, which makes this the fastest source when the old file
+/// is small, or memory mapped.
+#[derive(Debug, Clone)]
+pub struct SliceSource<'a> {
+    data: &'a [u8],
+    pos: u64,
+}
+
+impl<'a> SliceSource<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        SliceSource { data, pos: 0 }
+    }
+}
+
+impl OldSource for SliceSource<'_> {
+    fn read_to(&mut self, buf: &mut [u8], _: WouldBlock) -> io::Result<()> {
+        let data = usize::try_from(self.pos)
+            .ok()
+            .and_then(|start| self.data.get(start..)?.get(..buf.len()))
+            .ok_or(ErrorKind::UnexpectedEof)?;
+        buf.copy_from_slice(data);
+        self.pos += buf.len() as u64;
+        Ok(())
+    }
+
+    fn seek_by(&mut self, offset: i64) -> io::Result<()> {
+        self.pos = OldOffset::new(self.pos).seek(offset)?.get();
+        Ok(())
+    }
+
+    fn seek_to(&mut self, pos: u64) -> io::Result<()> {
+        self.pos = pos;
+        Ok(())
+    }
+}
+
+/// Something that hands out ranges of the old file on request, e.g. with HTTP range requests or
+/// from object storage.
+///
+/// Wrap it in a [`FetchSource`] to apply patches with it. Unlike [`OldSource`], it doesn't need to
+/// track a position, and it owns the buffer the data is returned in.
+pub trait OldFetcher {
+    /// Returns the data at `offset`, at most `len` bytes of it. Less than that may be returned,
+    /// e.g. when a response was cut short, and the rest is then asked for again. Nothing is
+    /// returned only past the end of the old file.
+    fn fetch(&mut self, offset: u64, len: usize) -> io::Result<&[u8]>;
+}
+
+impl OldFetcher for &[u8] {
+    fn fetch(&mut self, offset: u64, len: usize) -> io::Result<&[u8]> {
+        let data = usize::try_from(offset)
+            .ok()
+            .and_then(|start| self.get(start..))
+            .unwrap_or_default();
+        Ok(&data[..len.min(data.len())])
+    }
+}
+
+/// An [`OldSource`] that gets the old file from an [`OldFetcher`].
+///
+/// Patches read the old file in pieces of up to 32 KiB, which is a lot of round trips when every
+/// fetch is a network request. Set [`readahead`][Self::readahead] to fetch larger ranges at once,
+/// and keep them until the patch moves past them.
+pub struct FetchSource<F> {
+    fetcher: F,
+    pos: u64,
+    readahead: usize,
+    /// What was fetched last, and where it starts in the old file.
+    buf: Vec<u8>,
+    buf_start: u64,
+}
+
+impl<F: OldFetcher> FetchSource<F> {
+    pub fn new(fetcher: F) -> Self {
+        FetchSource {
+            fetcher,
+            pos: 0,
+            readahead: 0,
+            buf: Vec::new(),
+            buf_start: 0,
+        }
+    }
+
+    /// Fetches at least `bytes` at once, even if less is needed right away.
+    pub fn readahead(mut self, bytes: usize) -> Self {
+        self.readahead = bytes;
+        self
+    }
+
+    /// The fetcher, e.g. to look at how much it has fetched.
+    pub fn into_inner(self) -> F {
+        self.fetcher
+    }
+}
+
+impl<F: OldFetcher> OldSource for FetchSource<F> {
+    fn read_to(&mut self, buf: &mut [u8], _: WouldBlock) -> io::Result<()> {
+        let buffered = self.pos >= self.buf_start
+            && self.pos + buf.len() as u64 <= self.buf_start + self.buf.len() as u64;
+        if !buffered {
+            let want = buf.len().max(self.readahead);
+            self.buf.clear();
+            self.buf_start = self.pos;
+            while self.buf.len() < want {
+                let offset = self.pos + self.buf.len() as u64;
+                let data = self.fetcher.fetch(offset, want - self.buf.len())?;
+                if data.is_empty() {
+                    break;
+                }
+                self.buf
+                    .extend_from_slice(&data[..data.len().min(want - self.buf.len())]);
+            }
+            if self.buf.len() < buf.len() {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+        }
+        let at = (self.pos - self.buf_start) as usize;
+        buf.copy_from_slice(&self.buf[at..at + buf.len()]);
+        self.pos += buf.len() as u64;
+        Ok(())
+    }
+
+    fn seek_by(&mut self, offset: i64) -> io::Result<()> {
+        self.pos = OldOffset::new(self.pos).seek(offset)?.get();
+        Ok(())
+    }
+
+    fn seek_to(&mut self, pos: u64) -> io::Result<()> {
+        self.pos = pos;
+        Ok(())
+    }
+}
+
+/// An [`OldSource`] that keeps recently read blocks of the old file in memory.
+///
+/// Patches that seek backwards read the same parts of the old file again. That's cheap for a local
+/// file, but not when every read is a request to a slow device or a server. This reads the old
+/// file in blocks of `block_size` bytes and keeps the `blocks` most recently used ones, so reading
+/// them again doesn't touch the wrapped source. The cache holds `block_size * blocks` bytes at most.
+pub struct CachedOldSource<S> {
+    source: S,
+    pos: u64,
+    /// Where the wrapped source is, if known, to skip seeks between consecutive blocks.
+    source_pos: Option<u64>,
+    block_size: usize,
+    blocks: usize,
+    /// The cached blocks by their index, least recently used first.
+    cache: Vec<(u64, Box<[u8]>)>,
+    /// The first block that couldn't be read in full, and is read directly instead.
+    end: Option<u64>,
+}
+
+impl<S: OldSource> CachedOldSource<S> {
+    /// Caches up to `blocks` blocks of `block_size` bytes read from `source`.
+    ///
+    /// # Panics
+    ///
+    /// If `block_size` is 0.
+    pub fn new(source: S, block_size: usize, blocks: usize) -> Self {
+        assert!(block_size > 0, "block size must not be 0");
+        CachedOldSource {
+            source,
+            pos: 0,
+            source_pos: None,
+            block_size,
+            blocks,
+            cache: Vec::with_capacity(blocks),
+            end: None,
+        }
+    }
+
+    /// The wrapped source.
+    pub fn into_inner(self) -> S {
+        self.source
+    }
+
+    /// Returns the block with the given index, reading it if it isn't cached. `None` if it's
+    /// (partly) past the end of the old file, or nothing is cached.
+    fn block(&mut self, index: u64, would_block: WouldBlock) -> io::Result<Option<&[u8]>> {
+        if let Some(at) = self.cache.iter().position(|(i, _)| *i == index) {
+            let entry = self.cache.remove(at);
+            self.cache.push(entry);
+        } else if self.blocks == 0 || self.end.is_some_and(|end| index >= end) {
+            return Ok(None);
+        } else {
+            let start = index
+                .checked_mul(self.block_size as u64)
+                .ok_or(ErrorKind::UnexpectedEof)?;
+            if self.source_pos != Some(start) {
+                self.source.seek_to(start)?;
+            }
+            self.source_pos = None;
+            let mut block = vec![0; self.block_size].into_boxed_slice();
+            match self.source.read_to(&mut block, would_block) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    self.end = Some(index);
+                    return Ok(None);
+                }
+                Err(e) => return Err(e),
+            }
+            self.source_pos = Some(start + self.block_size as u64);
+            if self.cache.len() == self.blocks {
+                self.cache.remove(0);
+            }
+            self.cache.push((index, block));
+        }
+        Ok(self.cache.last().map(|(_, block)| &block[..]))
+    }
+}
+
+impl<S: OldSource> OldSource for CachedOldSource<S> {
+    fn read_to(&mut self, buf: &mut [u8], would_block: WouldBlock) -> io::Result<()> {
+        let block_size = self.block_size as u64;
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = self.pos + done as u64;
+            let at = (pos % block_size) as usize;
+            let n = (self.block_size - at).min(buf.len() - done);
+            match self.block(pos / block_size, would_block)? {
+                Some(block) => buf[done..done + n].copy_from_slice(&block[at..at + n]),
+                // The short last block, or no room to cache, so read directly
+                None => {
+                    self.source.seek_to(pos)?;
+                    self.source_pos = None;
+                    self.source.read_to(&mut buf[done..], would_block)?;
+                    done = buf.len();
+                    break;
+                }
+            }
+            done += n;
+        }
+        self.pos += done as u64;
+        Ok(())
+    }
+
+    fn seek_by(&mut self, offset: i64) -> io::Result<()> {
+        self.pos = OldOffset::new(self.pos).seek(offset)?.get();
+        Ok(())
+    }
+
+    fn seek_to(&mut self, pos: u64) -> io::Result<()> {
+        self.pos = pos;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use super::{CachedOldSource, FetchSource, OldFetcher, OldSource, SliceSource};
+    use crate::spec::{encode_entry, encode_header, Terminator};
+    use crate::{apply, WouldBlock};
+
+    /// Hands out at most 3 bytes at a time, and counts the fetches.
+    struct Slow<'a>(&'a [u8], usize);
+
+    impl OldFetcher for Slow<'_> {
+        fn fetch(&mut self, offset: u64, len: usize) -> io::Result<&[u8]> {
+            self.1 += 1;
+            self.0.fetch(offset, len.min(3))
+        }
+    }
+
+    #[test]
+    fn fetched() {
+        let old = b"hello world";
+        // "hello" from the old file, ", " as extra data, a seek past the space, and "world"
+        let patch = [
+            encode_header(12),
+            encode_entry(&[0; 5], b", ", 1),
+            encode_entry(&[0; 5], b"", 0),
+            Terminator::BYTES.to_vec(),
+        ]
+        .concat();
+
+        for (readahead, fetches) in [(0, 4), (100, 5)] {
+            let mut old = FetchSource::new(Slow(old, 0)).readahead(readahead);
+            let mut out = Vec::new();
+            apply(&mut old, &mut out, &mut &patch[..]).unwrap();
+            assert_eq!(out, b"hello, world");
+            assert_eq!(old.into_inner().1, fetches);
+        }
+        let mut old = FetchSource::new(&old[..5]);
+        assert!(apply(&mut old, &mut Vec::new(), &mut &patch[..]).is_err());
+    }
+
+    /// Counts the reads.
+    struct Counted<'a>(SliceSource<'a>, usize);
+
+    impl OldSource for Counted<'_> {
+        fn read_to(&mut self, buf: &mut [u8], would_block: WouldBlock) -> io::Result<()> {
+            self.1 += 1;
+            self.0.read_to(buf, would_block)
+        }
+
+        fn seek_by(&mut self, offset: i64) -> io::Result<()> {
+            self.0.seek_by(offset)
+        }
+
+        fn seek_to(&mut self, pos: u64) -> io::Result<()> {
+            self.0.seek_to(pos)
+        }
+    }
+
+    #[test]
+    fn cached() {
+        let old: Vec<u8> = (0..20).collect();
+        // Reads 0..16 four times, seeking back each time, then 16..20
+        let mut patch = encode_header(68);
+        for seek in [-16, -16, -16, 0] {
+            patch.extend(encode_entry(&[1; 16], b"", seek));
+        }
+        patch.extend(encode_entry(&[1; 4], b"", 0));
+        patch.extend(Terminator::BYTES);
+        let mut expected = Vec::new();
+        apply(&mut SliceSource::new(&old), &mut expected, &mut &patch[..]).unwrap();
+
+        // The short last block is tried once, and then read directly
+        for (blocks, reads) in [(0, 5), (1, 10), (2, 4)] {
+            let source = Counted(SliceSource::new(&old), 0);
+            let mut old = CachedOldSource::new(source, 8, blocks);
+            let mut out = Vec::new();
+            apply(&mut old, &mut out, &mut &patch[..]).unwrap();
+            assert_eq!(out, expected);
+            assert_eq!(old.into_inner().1, reads);
+        }
+    }
+}
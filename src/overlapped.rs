@@ -0,0 +1,269 @@
+//! Applying patches between files with positioned reads and writes, so several writes to the new
+//! file are in flight while the old file and the patch are read.
+//!
+//! Positioned I/O (`pread`/`pwrite` on Unix) doesn't use the file's position, so any number of
+//! threads can use the same file at once. The new file is written in blocks by a pool of threads,
+//! each with a write in flight, while the calling thread goes on applying the patch into the next
+//! block.
+//!
+//! On Windows, reads and writes with an `OVERLAPPED` offset only run at the same time on handles
+//! opened with `FILE_FLAG_OVERLAPPED`. The files are opened by the caller, and [`File`] opens them
+//! without it, so the I/O manager runs the writes one after another. They're still correct, but
+//! only overlap with applying the patch on the calling thread, not with each other.
+
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::patch::{PatchError, Result};
+use crate::{FetchSource, OldFetcher, Patcher};
+
+/// How much of the new file a single write covers, and how much of the old file is read at once.
+const BLOCK_SIZE: usize = 256 * 1024;
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn write_at(file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::write_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn write_at(file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_write(file, buf, offset)
+}
+
+fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match write_at(file, buf, offset) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Reads the old file with positioned reads.
+struct Positioned<'a> {
+    file: &'a File,
+    buf: Vec<u8>,
+}
+
+impl OldFetcher for Positioned<'_> {
+    fn fetch(&mut self, offset: u64, len: usize) -> io::Result<&[u8]> {
+        self.buf.resize(len.min(BLOCK_SIZE), 0);
+        loop {
+            match read_at(self.file, &mut self.buf, offset) {
+                Ok(n) => return Ok(&self.buf[..n]),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Collects the new file into blocks, and hands full ones to the writing threads.
+struct Writer<'a> {
+    buf: Vec<u8>,
+    /// Where `buf` goes in the new file.
+    pos: u64,
+    blocks: SyncSender<(u64, Vec<u8>)>,
+    /// Blocks that have been written, to be filled again.
+    free: Receiver<Vec<u8>>,
+    /// Set once a write has failed, after which the rest of the patch doesn't matter.
+    failed: &'a AtomicBool,
+}
+
+impl Writer<'_> {
+    fn submit(&mut self) -> io::Result<()> {
+        if self.failed.load(Ordering::Relaxed) {
+            return Err(stopped());
+        }
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        // Waits for a write to finish if all blocks are in flight
+        let next = self.free.recv().map_err(|_| stopped())?;
+        let buf = std::mem::replace(&mut self.buf, next);
+        let len = buf.len() as u64;
+        self.blocks.send((self.pos, buf)).map_err(|_| stopped())?;
+        self.pos += len;
+        Ok(())
+    }
+}
+
+/// A write failed, which is the error that's returned instead.
+fn stopped() -> io::Error {
+    io::Error::new(ErrorKind::BrokenPipe, "writing the new file failed")
+}
+
+impl Write for Writer<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(BLOCK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&buf[..n]);
+        if self.buf.len() == BLOCK_SIZE {
+            self.submit()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.submit()
+    }
+}
+
+/// Apply a chunked patch file from `old` to `new` with positioned I/O, with up to `depth` writes
+/// to `new` in flight at once, and return the size of the new file. This accepts the same patches
+/// as [`apply_chunked`][crate::apply_chunked].
+///
+/// The new file is written in blocks of 256 KiB by `depth` threads, which mostly helps when the
+/// storage handles several requests at once, e.g. SSDs and network filesystems. On Windows,
+/// [`File`]s are opened without `FILE_FLAG_OVERLAPPED`, so the I/O manager runs positioned writes
+/// to one of them one after another. They then only overlap with applying the patch, and a
+/// `depth` above 1 makes little difference. Meanwhile the patch is applied on the
+/// calling thread, reading the old file ahead in blocks of the same size. Neither file's position
+/// is used or changed. `new` is written from its start, and truncated to the size of the new file.
+/// Call [`File::sync_all`] afterwards to wait for the data to be on disk.
+///
+/// At most `2 * depth` blocks are held in memory. A `depth` of 0 is treated as 1. With the
+/// `preallocate` feature, disk space for the new file is reserved ahead of the writes with
+/// [`preallocate`][crate::preallocate].
+pub fn apply_overlapped(
+    old: &File,
+    new: &File,
+    patch: &mut impl Read,
+    depth: usize,
+) -> Result<u64> {
+    let depth = depth.max(1);
+    let mut old = FetchSource::new(Positioned {
+        file: old,
+        buf: Vec::new(),
+    })
+    .readahead(BLOCK_SIZE);
+    let (blocks_tx, blocks_rx) = mpsc::sync_channel::<(u64, Vec<u8>)>(depth);
+    let blocks_rx = Mutex::new(blocks_rx);
+    let (free_tx, free_rx) = mpsc::channel();
+    for _ in 1..2 * depth {
+        let _ = free_tx.send(Vec::with_capacity(BLOCK_SIZE));
+    }
+    let error = Mutex::new(None);
+    let failed = AtomicBool::new(false);
+    let result = thread::scope(|s| {
+        for _ in 0..depth {
+            let free_tx = free_tx.clone();
+            let (blocks_rx, error, failed) = (&blocks_rx, &error, &failed);
+            s.spawn(move || {
+                while let Ok((pos, mut buf)) = blocks_rx.lock().unwrap().recv() {
+                    // After a failure, blocks are only passed back so nothing waits for them
+                    if !failed.load(Ordering::Relaxed) {
+                        if let Err(e) = write_all_at(new, &buf, pos) {
+                            error.lock().unwrap().get_or_insert(e);
+                            failed.store(true, Ordering::Relaxed);
+                        }
+                    }
+                    buf.clear();
+                    let _ = free_tx.send(buf);
+                }
+            });
+        }
+        let mut writer = Writer {
+            buf: Vec::with_capacity(BLOCK_SIZE),
+            pos: 0,
+            blocks: blocks_tx,
+            free: free_rx,
+            failed: &failed,
+        };
+        #[cfg(not(feature = "preallocate"))]
+        let mut patcher = Patcher::new();
+        // Reserves the new file as far as each entry writes, as its size is only known at the end
+        #[cfg(feature = "preallocate")]
+        let mut patcher = {
+            let (mut reserved, mut supported) = (0, true);
+            Patcher::new().on_entry(move |entry| {
+                if supported && entry.new.end > reserved {
+                    supported = crate::preallocate(new, entry.new.end)?;
+                    reserved = entry.new.end;
+                }
+                Ok(())
+            })
+        };
+        let result = patcher
+            .run_chunked(&mut old, &mut writer, patch)
+            .and_then(|()| Ok(writer.flush()?));
+        // Dropping `writer` closes the channel, so the threads stop once the blocks in flight are
+        // written
+        result.map(|()| writer.pos)
+    });
+    if let Some(e) = error.into_inner().unwrap() {
+        return Err(PatchError::Io(e));
+    }
+    let len = result?;
+    new.set_len(len)?;
+    Ok(len)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, OpenOptions};
+
+    use super::{apply_overlapped, BLOCK_SIZE};
+    use crate::spec::{encode_entry, encode_header, Terminator};
+
+    #[test]
+    fn overlapped() {
+        let dir = std::env::temp_dir();
+        let old_path = dir.join(format!("ddelta-overlapped-old-{}", std::process::id()));
+        let new_path = dir.join(format!("ddelta-overlapped-new-{}", std::process::id()));
+        let old = b"hello world";
+        fs::write(&old_path, old).unwrap();
+        // "hello" from the old file, enough extra data for several blocks, and " world"
+        let extra: Vec<u8> = (0..BLOCK_SIZE * 3 + 5).map(|i| i as u8).collect();
+        let patch = [
+            encode_header((extra.len() + old.len()) as u64),
+            encode_entry(&[0; 5], &extra, 0),
+            encode_entry(&[0; 6], b"", 0),
+            Terminator::BYTES.to_vec(),
+        ]
+        .concat();
+
+        let old_file = fs::File::open(&old_path).unwrap();
+        let new_file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&new_path)
+            .unwrap();
+        // Longer than the new file, so it has to be truncated
+        new_file.set_len(BLOCK_SIZE as u64 * 5).unwrap();
+        for depth in [0, 1, 4] {
+            let written = apply_overlapped(&old_file, &new_file, &mut &patch[..], depth).unwrap();
+            assert_eq!(written, (extra.len() + old.len()) as u64);
+            assert_eq!(
+                fs::read(&new_path).unwrap(),
+                [&b"hello"[..], &extra, b" world"].concat()
+            );
+        }
+        // A patch that reads past the end of the old file
+        let mut short = patch.clone();
+        short[24..32].copy_from_slice(&50u64.to_be_bytes());
+        assert!(apply_overlapped(&old_file, &new_file, &mut &short[..], 2).is_err());
+        fs::remove_file(&old_path).unwrap();
+        fs::remove_file(&new_path).unwrap();
+    }
+}
@@ -0,0 +1,275 @@
+//! Applying patches when only some parts of the old file are available, e.g. from a sparse cache.
+
+use std::collections::BTreeMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::ops::Range;
+
+use crate::entries::{Event, PatchEntries};
+use crate::patch::{PatchError, Result};
+use crate::units::{Len, OldOffset};
+use crate::{apply_chunked, OldSource, WouldBlock, COPY_FLAG};
+
+/// The ranges of the old file that applying `patch` reads, sorted and with neighbouring ranges
+/// merged. This accepts the same patches as [`apply_chunked`].
+pub fn old_ranges(patch: &mut impl Read) -> Result<Vec<Range<u64>>> {
+    let mut entries = PatchEntries::new(patch, true, WouldBlock::Fail);
+    let mut ranges = Vec::new();
+    let mut pos = OldOffset::default();
+    while let Some(event) = entries.next()? {
+        let entry = match event {
+            Event::Header(_) => {
+                pos = entries.chunk_start().in_old();
+                continue;
+            }
+            Event::Entry(entry) => entry,
+            Event::End => continue,
+        };
+        let (diff, extra, seek) = (entry.diff.get(), entry.extra.get(), entry.seek.get());
+        if entries.copies() && diff & COPY_FLAG != 0 {
+            continue;
+        }
+        let end = pos
+            .checked_add(Len::new(diff))
+            .ok_or(PatchError::OffsetOverflow)?;
+        if diff > 0 {
+            ranges.push(pos.get()..end.get());
+        }
+        let skipped = io::copy(&mut entries.patch().take(diff + extra), &mut io::sink())?;
+        if skipped != diff + extra {
+            return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+        }
+        pos = end.seek(seek)?;
+    }
+    Ok(merge(ranges))
+}
+
+/// Sorts `ranges`, and merges the ones that overlap or touch.
+fn merge(mut ranges: Vec<Range<u64>>) -> Vec<Range<u64>> {
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// An old file of which only some regions are known.
+///
+/// Reading anything else fails with [`ErrorKind::NotFound`], unless a function to fetch missing
+/// regions is set with [`with_fetch`][Self::with_fetch]. Use [`apply_partial`] to find out
+/// whether a patch can be applied before writing any of the new file.
+pub struct PartialSource<F = fn(Range<u64>) -> io::Result<Vec<u8>>> {
+    /// Non-overlapping regions by their offset, with neighbouring ones merged.
+    regions: BTreeMap<u64, Vec<u8>>,
+    pos: OldOffset,
+    fetch: Option<F>,
+}
+
+impl PartialSource {
+    /// Creates a source that doesn't know any part of the old file yet.
+    pub fn new() -> Self {
+        PartialSource {
+            regions: BTreeMap::new(),
+            pos: OldOffset::default(),
+            fetch: None,
+        }
+    }
+}
+
+impl Default for PartialSource {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<F: FnMut(Range<u64>) -> io::Result<Vec<u8>>> PartialSource<F> {
+    /// Adds `data` as the contents of the old file at `offset`. Where it overlaps what's known
+    /// already, it replaces that.
+    pub fn region(mut self, offset: u64, data: impl Into<Vec<u8>>) -> Self {
+        self.insert(offset, data.into());
+        self
+    }
+
+    /// Sets the function that's called with the ranges of the old file that are needed but not
+    /// known. It has to return exactly the data in that range, which is then kept like any other
+    /// region.
+    pub fn with_fetch<G: FnMut(Range<u64>) -> io::Result<Vec<u8>>>(
+        self,
+        fetch: G,
+    ) -> PartialSource<G> {
+        PartialSource {
+            regions: self.regions,
+            pos: self.pos,
+            fetch: Some(fetch),
+        }
+    }
+
+    /// The parts of `ranges` that aren't known, sorted.
+    pub fn missing(&self, ranges: &[Range<u64>]) -> Vec<Range<u64>> {
+        let mut missing = Vec::new();
+        for range in merge(ranges.to_vec()) {
+            let mut pos = range.start;
+            for (&start, data) in self.regions.range(..range.end) {
+                let end = start + data.len() as u64;
+                if end <= pos {
+                    continue;
+                }
+                if start > pos {
+                    missing.push(pos..start);
+                }
+                pos = end;
+            }
+            if pos < range.end {
+                missing.push(pos..range.end);
+            }
+        }
+        missing
+    }
+
+    fn insert(&mut self, offset: u64, data: Vec<u8>) {
+        if data.is_empty() {
+            return;
+        }
+        let end = offset + data.len() as u64;
+        // Everything that overlaps or touches the new region is merged into it
+        let touching: Vec<u64> = self
+            .regions
+            .range(..=end)
+            .filter(|(&start, data)| start + data.len() as u64 >= offset)
+            .map(|(&start, _)| start)
+            .collect();
+        let start = touching.first().map_or(offset, |&first| first.min(offset));
+        let mut merged = Vec::new();
+        for old_start in touching {
+            let old = self.regions.remove(&old_start).unwrap();
+            let at = (old_start - start) as usize;
+            merged.resize(merged.len().max(at + old.len()), 0);
+            merged[at..at + old.len()].copy_from_slice(&old);
+        }
+        let at = (offset - start) as usize;
+        merged.resize(merged.len().max(at + data.len()), 0);
+        merged[at..at + data.len()].copy_from_slice(&data);
+        self.regions.insert(start, merged);
+    }
+
+    /// Fetches `range`, or fails if there's no function to fetch it with.
+    fn fetch(&mut self, range: Range<u64>) -> io::Result<()> {
+        let Some(fetch) = &mut self.fetch else {
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!("old file range {range:?} is not available"),
+            ));
+        };
+        let data = fetch(range.clone())?;
+        if data.len() as u64 != range.end - range.start {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("fetched {} bytes for old file range {range:?}", data.len()),
+            ));
+        }
+        self.insert(range.start, data);
+        Ok(())
+    }
+}
+
+impl<F: FnMut(Range<u64>) -> io::Result<Vec<u8>>> OldSource for PartialSource<F> {
+    fn read_to(&mut self, buf: &mut [u8], _: WouldBlock) -> io::Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let range = self.pos.get()..self.pos.get() + buf.len() as u64;
+        for missing in self.missing(std::slice::from_ref(&range)) {
+            self.fetch(missing)?;
+        }
+        // Fetching merged everything in the range into a single region
+        let (&start, data) = self
+            .regions
+            .range(..=range.start)
+            .next_back()
+            .expect("just fetched");
+        let at = (range.start - start) as usize;
+        buf.copy_from_slice(&data[at..at + buf.len()]);
+        self.pos = OldOffset::new(range.end);
+        Ok(())
+    }
+
+    fn seek_by(&mut self, offset: i64) -> io::Result<()> {
+        self.pos = self.pos.seek(offset)?;
+        Ok(())
+    }
+
+    fn seek_to(&mut self, pos: u64) -> io::Result<()> {
+        self.pos = OldOffset::new(pos);
+        Ok(())
+    }
+}
+
+/// Apply a patch that is in memory to an old file of which only some regions are known.
+///
+/// Before anything is written, the patch is checked for the ranges of the old file it reads. If
+/// some of them aren't known, they're fetched all at once, or if `old` has no function to fetch
+/// them with, this fails without writing anything. This accepts the same patches as
+/// [`apply_chunked`].
+pub fn apply_partial<F: FnMut(Range<u64>) -> io::Result<Vec<u8>>>(
+    old: &mut PartialSource<F>,
+    new: &mut impl Write,
+    patch: &[u8],
+) -> Result<()> {
+    let missing = old.missing(&old_ranges(&mut &patch[..])?);
+    if old.fetch.is_none() && !missing.is_empty() {
+        return Err(PatchError::Internal(
+            format!("Old file ranges {missing:?} are not available").into(),
+        ));
+    }
+    for range in missing {
+        old.fetch(range)?;
+    }
+    old.seek_to(0)?;
+    apply_chunked(old, new, &mut &patch[..])
+}
+
+#[cfg(test)]
+mod test {
+    use super::{apply_partial, old_ranges, PartialSource};
+    use crate::spec::{encode_entry, encode_header, Terminator};
+    use crate::OldSource;
+
+    #[test]
+    fn partial() {
+        let old = b"hello world";
+        // "hello" from the old file, ", " as extra data, a seek past the space, and "world"
+        let patch = [
+            encode_header(12),
+            encode_entry(&[0; 5], b", ", 1),
+            encode_entry(&[0; 5], b"", 0),
+            Terminator::BYTES.to_vec(),
+        ]
+        .concat();
+        assert_eq!(old_ranges(&mut &patch[..]).unwrap(), [0..5, 6..11]);
+
+        let mut source = PartialSource::new()
+            .region(0, &old[..3])
+            .region(2, &old[2..5]);
+        let mut out = Vec::new();
+        assert!(apply_partial(&mut source, &mut out, &patch).is_err());
+        assert!(out.is_empty());
+        assert_eq!(source.missing(&[0..4, 3..11]), vec![5..11]);
+
+        let mut fetched = Vec::new();
+        let mut source = source.with_fetch(|range| {
+            fetched.push(range.clone());
+            Ok(old[range.start as usize..range.end as usize].to_vec())
+        });
+        apply_partial(&mut source, &mut out, &patch).unwrap();
+        assert_eq!(out, b"hello, world");
+        let mut buf = [0; 11];
+        source.seek_to(0).unwrap();
+        source.read_to(&mut buf, Default::default()).unwrap();
+        assert_eq!(&buf, old);
+        drop(source);
+        assert_eq!(fetched, [6..11, 5..6]);
+    }
+}
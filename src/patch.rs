@@ -1,28 +1,126 @@
-use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
-use std::mem::size_of;
+use std::cell::Cell;
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::mem::take;
+use std::ops::Range;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use thiserror::Error;
-use zerocopy::Ref;
-
-use crate::{EntryHeader, PatchHeader, DDELTA_MAGIC};
+use crate::entries::{Event, PatchEntries};
+use crate::io::read_full;
+use crate::units::{Len, NewOffset};
+use crate::{
+    old_ranges, Checksum, ChecksumHasher, EntryHeader, OldSource, RetryPolicy, SliceSource,
+    WouldBlock, COPY_FLAG, COPY_WINDOW,
+};
 
 type Str = Box<str>;
-type Result<T> = std::result::Result<T, PatchError>;
+pub(crate) type Result<T> = std::result::Result<T, PatchError>;
 
-#[derive(Error, Debug)]
+#[derive(Debug)]
 pub enum PatchError {
-    #[error("io error while applying patch {0}")]
-    Io(#[from] std::io::Error),
-    #[error("patch application failed: {0}")]
+    Io(std::io::Error),
     Internal(Str),
+    /// The patch doesn't start with the magic number of a patch.
+    BadMagic,
+    /// The sizes in the patch add up to more than a file can have.
+    OffsetOverflow,
+    /// The old file isn't the one the patch was made for, see [`apply_if_matches`].
+    OldMismatch {
+        expected: Checksum,
+        found: Checksum,
+    },
+    /// The patch's signature or its certificates don't check out, see
+    /// [`verify_chain`][crate::verify_chain].
+    BadSignature(Str),
+    /// The patch reads up to `needed` bytes of the old file, which only has `len`, see
+    /// [`apply_checked`].
+    OldTooShort {
+        needed: u64,
+        len: u64,
+    },
+    /// The patch stream ended after `read` of the `len` bytes it was declared to have, see
+    /// [`apply_exact_len`].
+    Truncated {
+        read: u64,
+        len: u64,
+    },
+    /// Applying the patch needs `needed` bytes of buffers, more than the `limit` set with
+    /// [`Patcher::max_memory`].
+    MemoryLimit {
+        needed: u64,
+        limit: u64,
+    },
+    /// Writing the new file failed after `offset` bytes of it were written, and wasn't retried or
+    /// failed every retry allowed by [`Patcher::retry_writes`].
+    ///
+    /// Only [`Patcher`] and the functions that use it, like [`apply`] and [`apply_chunked`], return
+    /// this. They used to return such errors as [`Io`][Self::Io], so code that looks at I/O errors
+    /// should match both. Appliers that write into memory or at given offsets, like
+    /// [`apply_slice`][crate::apply_slice] and [`ChunkedPatchReader`][crate::ChunkedPatchReader],
+    /// still return [`Io`][Self::Io].
+    Write {
+        offset: u64,
+        error: std::io::Error,
+    },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::Io(e) => write!(f, "io error while applying patch {e}"),
+            PatchError::Internal(e) => write!(f, "patch application failed: {e}"),
+            PatchError::BadMagic => f.write_str("not a patch: invalid magic number"),
+            PatchError::OffsetOverflow => f.write_str("patch sizes overflow the file offset"),
+            PatchError::OldMismatch { expected, found } => write!(
+                f,
+                "old file has checksum {found}, but the patch needs {expected}"
+            ),
+            PatchError::BadSignature(e) => write!(f, "patch signature is invalid: {e}"),
+            PatchError::OldTooShort { needed, len } => write!(
+                f,
+                "patch reads {needed} bytes of the old file, but it only has {len}"
+            ),
+            PatchError::Truncated { read, len } => {
+                write!(f, "patch ends after {read} of its {len} bytes")
+            }
+            PatchError::MemoryLimit { needed, limit } => write!(
+                f,
+                "applying the patch needs {needed} bytes of memory, but the limit is {limit}"
+            ),
+            PatchError::Write { offset, error } => {
+                write!(
+                    f,
+                    "io error while writing the new file at {offset}: {error}"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for PatchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PatchError::Io(e) | PatchError::Write { error: e, .. } => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for PatchError {
+    fn from(e: std::io::Error) -> Self {
+        PatchError::Io(e)
+    }
 }
 
 const BLOCK_SIZE: u64 = 32 * 1024;
 macro_rules! read {
-    ($reader: expr, $type: ty) => {{
+    ($reader: expr, $type: ty) => {
+        read!($reader, $type, $crate::WouldBlock::Fail)
+    };
+    ($reader: expr, $type: ty, $would_block: expr) => {{
         let mut buf = [0; size_of::<$type>()];
-        let data: Result<$type> = $reader
-            .read_exact(&mut buf)
+        let data: Result<$type> = $crate::io::read_full($reader, &mut buf, $would_block)
             .map_err(|err| err.into())
             .and_then(|_| {
                 Ref::<_, $type>::new(&buf[..])
@@ -32,68 +130,597 @@ macro_rules! read {
         data
     }};
 }
-fn apply_diff(
+pub(crate) use read;
+
+/// Buffers used while applying, so they don't need to be on the stack or reallocated, and how to
+/// read into them.
+pub(crate) struct Buffers {
+    old: Box<[u8]>,
+    patch: Box<[u8]>,
+    /// The end of the new file, for copies. Only allocated once a patch needs it.
+    history: Vec<u8>,
+    would_block: WouldBlock,
+    /// How many bytes all of the buffers may take, if limited.
+    limit: Option<u64>,
+}
+
+impl Default for Buffers {
+    fn default() -> Self {
+        Buffers::with_limit(None)
+    }
+}
+
+impl Buffers {
+    /// Creates buffers that take at most `limit` bytes, or that fail to apply anything if that's
+    /// too little, see [`Patcher::max_memory`].
+    pub(crate) fn with_limit(limit: Option<u64>) -> Self {
+        let block = limit.map_or(BLOCK_SIZE, |limit| (limit / 2).clamp(1, BLOCK_SIZE)) as usize;
+        Buffers {
+            old: vec![0; block].into(),
+            patch: vec![0; block].into(),
+            history: Vec::new(),
+            would_block: WouldBlock::default(),
+            limit,
+        }
+    }
+
+    /// Sets the limit, keeping how to read.
+    pub(crate) fn set_limit(&mut self, limit: Option<u64>) {
+        *self = Buffers {
+            would_block: self.would_block,
+            ..Buffers::with_limit(limit)
+        };
+    }
+
+    /// Checks that the buffers for a chunk, with or without copies, fit into the limit.
+    fn check(&self, copies: bool) -> Result<()> {
+        let needed = (self.old.len() + self.patch.len()) as u64 + copies as u64 * COPY_WINDOW;
+        match self.limit {
+            Some(limit) if needed > limit => Err(PatchError::MemoryLimit { needed, limit }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Passes the new file through, keeping the last [`COPY_WINDOW`] bytes to copy from.
+struct History<'a, W> {
+    inner: &'a mut W,
+    /// A ring buffer, which is empty if the patch can't contain copies.
+    ring: Vec<u8>,
+    written: u64,
+}
+
+impl<'a, W: Write> History<'a, W> {
+    fn new(inner: &'a mut W, ring: Vec<u8>) -> Self {
+        History {
+            inner,
+            ring,
+            written: 0,
+        }
+    }
+
+    /// Appends `len` bytes, starting `distance` bytes back from the end of the new file.
+    fn copy(&mut self, distance: u64, len: u64, buf: &mut [u8]) -> Result<()> {
+        if distance == 0 || distance > self.written || distance > self.ring.len() as u64 {
+            return Err(PatchError::Internal(
+                "Copy from outside of the new file".into(),
+            ));
+        }
+        let window = self.ring.len() as u64;
+        let mut remaining = len;
+        while remaining > 0 {
+            let n = (buf.len() as u64).min(remaining) as usize;
+            // Byte by byte, since a copy may repeat what it just wrote
+            for b in &mut buf[..n] {
+                *b = self.ring[((self.written - distance) % window) as usize];
+                self.ring[(self.written % window) as usize] = *b;
+                self.written += 1;
+            }
+            self.inner.write_all(&buf[..n])?;
+            remaining -= n as u64;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for History<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if !self.ring.is_empty() {
+            let window = self.ring.len() as u64;
+            for &b in &buf[..written] {
+                self.ring[(self.written % window) as usize] = b;
+                self.written += 1;
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub(crate) fn apply_diff(
     patch_f: &mut impl Read,
-    old_f: &mut impl Read,
+    old_f: &mut impl OldSource,
     new_f: &mut impl Write,
     mut size: u64,
+    words: bool,
+    bufs: &mut Buffers,
 ) -> Result<()> {
-    let mut old = [0; BLOCK_SIZE as usize];
-    let mut patch = [0; BLOCK_SIZE as usize];
+    // Words mustn't be split between blocks
+    let block = match words {
+        true => bufs.old.len() & !3,
+        false => bufs.old.len(),
+    };
+    if block == 0 && size > 0 {
+        return Err(PatchError::MemoryLimit {
+            needed: 8,
+            limit: bufs.limit.unwrap_or_default(),
+        });
+    }
     while size > 0 {
-        let to_read = BLOCK_SIZE.min(size) as usize;
-        let old = &mut old[..to_read];
-        let patch = &mut patch[..to_read];
+        let to_read = (block as u64).min(size) as usize;
+        let old = &mut bufs.old[..to_read];
+        let patch = &mut bufs.patch[..to_read];
 
-        patch_f.read_exact(patch)?;
-        old_f.read_exact(old)?;
+        read_full(patch_f, patch, bufs.would_block)?;
+        old_f.read_to(old, bufs.would_block)?;
 
-        old.iter_mut()
-            .zip(patch.iter())
-            .for_each(|(old, patch)| *old = old.wrapping_add(*patch));
+        // Unchanged runs of the old file have diff data of all zeros, and are passed on as they are
+        if !is_zero(patch) {
+            add_diff(old, patch, words);
+        }
 
-        new_f.write_all(&old)?;
+        new_f.write_all(old)?;
 
         size -= to_read as u64;
     }
     Ok(())
 }
 
-fn copy_bytes(src: &mut impl Read, dst: &mut impl Write, mut bytes: u64) -> Result<()> {
-    let mut buf = [0; BLOCK_SIZE as usize];
+/// Adds the diff data `diff` to the same amount of `old` data, a byte at a time, or in
+/// little-endian 32-bit words from the start of `diff` for chunks with word diffs. The last 1 to 3
+/// bytes that don't make up a word are added a byte at a time either way.
+pub(crate) fn add_diff(old: &mut [u8], diff: &[u8], words: bool) {
+    let mut rest = 0;
+    if words {
+        let word = |bytes: &[u8]| u32::from_le_bytes(bytes.try_into().unwrap());
+        for (old, diff) in old.chunks_exact_mut(4).zip(diff.chunks_exact(4)) {
+            old.copy_from_slice(&word(old).wrapping_add(word(diff)).to_le_bytes());
+        }
+        rest = diff.len() & !3;
+    }
+    old[rest..]
+        .iter_mut()
+        .zip(&diff[rest..])
+        .for_each(|(old, diff)| *old = old.wrapping_add(*diff));
+}
+
+/// Whether `bytes` are all zeros. It's checked in small blocks, which compile to vector
+/// instructions, unlike a check that stops at the first byte that isn't zero.
+pub(crate) fn is_zero(bytes: &[u8]) -> bool {
+    bytes
+        .chunks(64)
+        .all(|block| block.iter().fold(0, |acc, &b| acc | b) == 0)
+}
+
+pub(crate) fn copy_bytes(
+    src: &mut impl Read,
+    dst: &mut impl Write,
+    mut bytes: u64,
+    bufs: &mut Buffers,
+) -> Result<()> {
     while bytes > 0 {
-        let to_read = BLOCK_SIZE.min(bytes) as usize;
-        let buf = &mut buf[..to_read];
-        src.read_exact(buf)?;
+        let to_read = (bufs.patch.len() as u64).min(bytes) as usize;
+        let buf = &mut bufs.patch[..to_read];
+        read_full(src, buf, bufs.would_block)?;
         dst.write_all(buf)?;
         bytes -= to_read as u64;
     }
     Ok(())
 }
 
-fn apply_with_header(
-    old: &mut (impl Read + Seek),
+/// Applies the chunk that `entries` just read the header of.
+pub(crate) fn apply_chunk<R: Read>(
+    old: &mut impl OldSource,
     new: &mut impl Write,
-    patch: &mut impl Read,
-    header: PatchHeader,
+    entries: &mut PatchEntries<R>,
+    bufs: &mut Buffers,
+    on_entry: &mut impl FnMut(&EntryHeader, bool) -> Result<()>,
 ) -> Result<()> {
-    if !(&header.magic == DDELTA_MAGIC) {
-        return Err(PatchError::Internal("Invalid magic number".into()));
+    span!(DEBUG, "apply_chunk", start = entries.chunk_start().get());
+    let copies = entries.copies();
+    bufs.check(copies)?;
+    let mut ring = take(&mut bufs.history);
+    if copies {
+        ring.resize(COPY_WINDOW as usize, 0);
     }
-    let mut bytes_written = 0;
+    let mut new = History::new(new, ring);
+    let result = apply_entries(old, &mut new, entries, copies, bufs, on_entry);
+    bufs.history = new.ring;
+    result
+}
+
+fn apply_entries<W: Write, R: Read>(
+    old: &mut impl OldSource,
+    new: &mut History<W>,
+    entries: &mut PatchEntries<R>,
+    copies: bool,
+    bufs: &mut Buffers,
+    on_entry: &mut impl FnMut(&EntryHeader, bool) -> Result<()>,
+) -> Result<()> {
     loop {
-        let entry = read!(patch, EntryHeader)?;
-        if entry.diff.get() == 0 && entry.extra.get() == 0 && entry.seek.get() == 0 {
-            return if bytes_written == header.new_file_size.get() {
+        let entry = match entries.next()? {
+            Some(Event::Entry(entry)) => entry,
+            _ => return Ok(()),
+        };
+        span!(
+            TRACE,
+            "entry",
+            diff = entry.diff.get(),
+            extra = entry.extra.get(),
+            seek = entry.seek.get()
+        );
+        let copy = copies && entry.diff.get() & COPY_FLAG != 0;
+        on_entry(&entry, copy)?;
+        if copy {
+            let len = entry.diff.get() & !COPY_FLAG;
+            new.copy(entry.extra.get(), len, &mut bufs.patch)?;
+        } else {
+            let words = entries.words();
+            apply_diff(entries.patch(), old, new, entry.diff.get(), words, bufs)?;
+            copy_bytes(entries.patch(), new, entry.extra.get(), bufs)?;
+        }
+        old.seek_by(entry.seek.get())?;
+    }
+}
+
+/// Moves `offset` past `a` and `b` bytes, failing if the result isn't a valid file offset.
+pub(crate) fn checked_offset(offset: NewOffset, a: u64, b: u64) -> Result<NewOffset> {
+    offset
+        .checked_add(Len::new(a))
+        .and_then(|offset| offset.checked_add(Len::new(b)))
+        .ok_or(PatchError::OffsetOverflow)
+}
+
+/// How far along applying a patch is, passed to the callback set with [`Patcher::with_progress`].
+#[derive(Eq, PartialEq, Copy, Clone, Hash, Debug, Default)]
+pub struct ApplyProgress {
+    /// The index of the chunk being applied, which is always 0 for plain patches.
+    pub chunk: u64,
+    /// The size of the new file in the chunk being applied.
+    pub chunk_size: u64,
+    /// How much of the new file has been written, over all chunks so far.
+    pub written: u64,
+    /// How much of the patch has been read. The number of chunks and the size of the new file
+    /// aren't known until the end, but the size of the patch usually is, so divide this by it for
+    /// a progress bar.
+    pub patch_read: u64,
+}
+
+/// An entry of a patch, passed to the callback set with [`Patcher::on_entry`] before it's applied.
+#[derive(Eq, PartialEq, Clone, Hash, Debug, Default)]
+pub struct EntryInfo {
+    /// The index of the chunk the entry is in, which is always 0 for plain patches.
+    pub chunk: u64,
+    /// The part of the new file the entry writes.
+    pub new: Range<u64>,
+    /// The part of the old file that the start of [`new`][Self::new] is made from, with the diff
+    /// data added to it. The rest of `new` is extra data from the patch, or copied. For plain
+    /// patches, this counts from where the old file was when applying started.
+    pub old: Range<u64>,
+    /// For copies, how far back in the new file they copy from. Copies don't read the old file.
+    pub copy_distance: Option<u64>,
+}
+
+/// Progress is reported at most this often.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Counts the bytes read from the patch.
+struct Counted<'a, R> {
+    inner: R,
+    count: &'a Cell<u64>,
+}
+
+impl<R: Read> Read for Counted<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+}
+
+/// Passes the new file through, reporting progress whenever enough time has passed.
+struct Reporter<'a, W, P> {
+    inner: W,
+    progress: &'a mut P,
+    state: ApplyProgress,
+    patch_read: &'a Cell<u64>,
+    last: Instant,
+    /// When writing started, and how many bytes per second it may average, if limited.
+    throttle: Option<(Instant, u64)>,
+    retry: Option<&'a mut (dyn RetryPolicy + Send + Sync)>,
+    /// Where writing failed, if it did.
+    failed_at: Option<u64>,
+}
+
+impl<W, P: FnMut(ApplyProgress)> Reporter<'_, W, P> {
+    fn report(&mut self) {
+        self.last = Instant::now();
+        self.state.patch_read = self.patch_read.get();
+        (self.progress)(self.state);
+    }
+}
+
+impl<W: Write, P> Reporter<'_, W, P> {
+    /// Runs `op` on the new file until it succeeds or the retry policy gives up.
+    fn retrying<T>(&mut self, mut op: impl FnMut(&mut W) -> io::Result<T>) -> io::Result<T> {
+        let mut attempt = 0;
+        loop {
+            let error = match op(&mut self.inner) {
+                Ok(result) => return Ok(result),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => e,
+            };
+            attempt += 1;
+            match self
+                .retry
+                .as_mut()
+                .and_then(|retry| retry.retry(&error, attempt))
+            {
+                Some(delay) => thread::sleep(delay),
+                None => {
+                    self.failed_at = Some(self.state.written);
+                    return Err(error);
+                }
+            }
+        }
+    }
+}
+
+impl<W: Write, P: FnMut(ApplyProgress)> Write for Reporter<'_, W, P> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.retrying(|inner| match inner.write(buf) {
+            Ok(0) if !buf.is_empty() => Err(ErrorKind::WriteZero.into()),
+            result => result,
+        })?;
+        self.state.written += written as u64;
+        if self.last.elapsed() >= PROGRESS_INTERVAL {
+            self.report();
+        }
+        if let Some((start, bytes_per_sec)) = self.throttle {
+            let due = Duration::from_secs_f64(self.state.written as f64 / bytes_per_sec as f64);
+            if let Some(ahead) = due.checked_sub(start.elapsed()) {
+                thread::sleep(ahead);
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.retrying(|inner| inner.flush())
+    }
+}
+
+/// What may follow a patch, see [`Patcher::trailing`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Trailing {
+    /// Anything, which isn't read. This is what the original ddelta tool does.
+    #[default]
+    Ignore,
+    /// Nothing, so two patches that were concatenated by mistake are caught.
+    Forbid,
+    /// Nothing, or a block that starts with this magic number of up to 16 bytes, such as a
+    /// signature. The block isn't read past its magic number.
+    Block(Box<[u8]>),
+}
+
+/// A patch applier that keeps its progress callback and buffers between runs.
+///
+/// This is what [`apply`] and [`apply_chunked`] use internally, but when applying many patches in
+/// a row it avoids setting up the buffers again every time.
+pub struct Patcher<P = fn(ApplyProgress), E = fn(&EntryInfo) -> Result<()>> {
+    bufs: Buffers,
+    trailing: Trailing,
+    progress: P,
+    on_entry: E,
+    max_throughput: Option<u64>,
+    retry: Option<Box<dyn RetryPolicy + Send + Sync>>,
+}
+
+impl Default for Patcher {
+    fn default() -> Self {
+        Patcher {
+            bufs: Buffers::default(),
+            trailing: Trailing::default(),
+            progress: |_| {},
+            on_entry: |_| Ok(()),
+            max_throughput: None,
+            retry: None,
+        }
+    }
+}
+
+impl Patcher {
+    /// Creates a patcher with freshly allocated buffers, which doesn't report progress.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<P: FnMut(ApplyProgress), E: FnMut(&EntryInfo) -> Result<()>> Patcher<P, E> {
+    /// Sets the function that will be called with progress updates.
+    ///
+    /// It is called at most every 100ms while the new file is written, however many chunks that
+    /// spans, and once more at the end with the totals.
+    pub fn with_progress<Q: FnMut(ApplyProgress)>(self, progress: Q) -> Patcher<Q, E> {
+        Patcher {
+            bufs: self.bufs,
+            trailing: self.trailing,
+            progress,
+            on_entry: self.on_entry,
+            max_throughput: self.max_throughput,
+            retry: self.retry,
+        }
+    }
+
+    /// Sets a function that is called with each entry before it's applied, e.g. to log every
+    /// change an installer makes, or to refuse some of them.
+    ///
+    /// If it returns an error, applying stops with that error before the entry writes anything.
+    /// What was written before stays, so write to a temporary file or a
+    /// [`JournaledWriter`][crate::JournaledWriter] when a refused patch mustn't leave a trace.
+    pub fn on_entry<F: FnMut(&EntryInfo) -> Result<()>>(self, on_entry: F) -> Patcher<P, F> {
+        Patcher {
+            bufs: self.bufs,
+            trailing: self.trailing,
+            progress: self.progress,
+            on_entry,
+            max_throughput: self.max_throughput,
+            retry: self.retry,
+        }
+    }
+
+    /// Sets what may follow the patch, which is ignored by default.
+    ///
+    /// Chunked patches are read up to their end either way, so anything that isn't a chunk is
+    /// rejected unless it's an allowed [`Trailing::Block`].
+    pub fn trailing(mut self, trailing: Trailing) -> Self {
+        self.trailing = trailing;
+        self
+    }
+
+    /// Limits writing the new file to an average of `bytes_per_sec`, by sleeping between blocks.
+    ///
+    /// This is meant for patching on live systems, so applying a large patch doesn't take all of the
+    /// disk bandwidth from other services. Reading the old file and the patch is roughly
+    /// proportional to writing the new one, so it's slowed down as well. Time spent waiting for the
+    /// inputs counts towards the average, so a slow source isn't slowed down further.
+    pub fn max_throughput(mut self, bytes_per_sec: impl Into<Option<u64>>) -> Self {
+        self.max_throughput = bytes_per_sec.into().filter(|&bytes| bytes > 0);
+        self
+    }
+
+    /// Sets when to try writing the new file again after it failed, e.g. [`Retry`][crate::Retry]
+    /// for a network sink that times out now and then.
+    ///
+    /// Without a policy, only interrupted writes are retried. Either way, a write error that ends
+    /// applying is returned as [`PatchError::Write`], with how much of the new file was written,
+    /// where it used to be [`PatchError::Io`].
+    pub fn retry_writes(mut self, policy: impl RetryPolicy + Send + Sync + 'static) -> Self {
+        self.retry = Some(Box::new(policy));
+        self
+    }
+
+    /// Sets what to do when the old file or patch are non-blocking and not ready yet.
+    pub fn would_block(mut self, would_block: WouldBlock) -> Self {
+        self.bufs.would_block = would_block;
+        self
+    }
+
+    /// Limits the buffers used while applying to `bytes`, for devices with little RAM.
+    ///
+    /// The old file and the patch are read through two buffers of up to 32 KiB each, which are made
+    /// smaller to fit. Patches that copy from the new file need another 4 MiB for the end of the
+    /// new file, so a chunk with copies fails with [`PatchError::MemoryLimit`] before anything of
+    /// it is written if that doesn't fit as well. This doesn't count what `old`, `new` and `patch`
+    /// buffer themselves.
+    pub fn max_memory(mut self, bytes: impl Into<Option<u64>>) -> Self {
+        self.bufs.set_limit(bytes.into());
+        self
+    }
+
+    /// Apply a patch file, see [`apply`].
+    pub fn run(
+        &mut self,
+        old: &mut impl OldSource,
+        new: &mut impl Write,
+        patch: &mut impl Read,
+    ) -> Result<()> {
+        self.run_entries(old, new, patch, false)
+    }
+
+    /// Apply a chunked patch file, see [`apply_chunked`].
+    pub fn run_chunked(
+        &mut self,
+        old: &mut impl OldSource,
+        new: &mut impl Write,
+        patch: &mut impl Read,
+    ) -> Result<()> {
+        self.run_entries(old, new, patch, true)
+    }
+
+    fn run_entries(
+        &mut self,
+        old: &mut impl OldSource,
+        new: &mut impl Write,
+        patch: &mut impl Read,
+        chunked: bool,
+    ) -> Result<()> {
+        let patch_read = Cell::new(0);
+        let patch = Counted {
+            inner: patch,
+            count: &patch_read,
+        };
+        let mut new = Reporter {
+            inner: new,
+            progress: &mut self.progress,
+            state: ApplyProgress::default(),
+            patch_read: &patch_read,
+            last: Instant::now(),
+            throttle: self.max_throughput.map(|rate| (Instant::now(), rate)),
+            retry: self.retry.as_deref_mut().map(|retry| retry as _),
+            failed_at: None,
+        };
+        let mut entries = PatchEntries::new(patch, chunked, self.bufs.would_block)
+            .trailing(self.trailing.clone());
+        let on_entry = &mut self.on_entry;
+        // Chunks are applied up to their end, so only headers are left here
+        let mut chunks = 0;
+        while let Some(Event::Header(header)) = entries.next()? {
+            new.state.chunk = chunks;
+            new.state.chunk_size = header.new_file_size.get();
+            chunks += 1;
+            // Each chunk expects to start from the beginning of the old file, so we can take
+            // advantage of the fact that the chunks of old & new are always the same, and if
+            // they're not, no data is read from the old file
+            if chunked {
+                old.seek_to(entries.chunk_start().in_old().get())?;
+            }
+            let (chunk, start) = (chunks - 1, entries.chunk_start().get());
+            // Where the next entry starts in both files
+            let (mut new_pos, mut old_pos) = (start, start as i64);
+            let mut report = |entry: &EntryHeader, copy: bool| {
+                let (diff, extra) = (entry.diff.get(), entry.extra.get());
+                let (len, diff, copy_distance) = match copy {
+                    true => (diff & !COPY_FLAG, 0, Some(extra)),
+                    false => (diff + extra, diff, None),
+                };
+                let old_start = u64::try_from(old_pos).map_err(|_| PatchError::OffsetOverflow)?;
+                on_entry(&EntryInfo {
+                    chunk,
+                    new: new_pos..new_pos + len,
+                    old: old_start..old_start + diff,
+                    copy_distance,
+                })?;
+                new_pos += len;
+                old_pos = old_pos
+                    .checked_add(diff as i64)
+                    .and_then(|pos| pos.checked_add(entry.seek.get()))
+                    .ok_or(PatchError::OffsetOverflow)?;
                 Ok(())
-            } else {
-                Err(PatchError::Internal("Patch too short".into()))
             };
+            apply_chunk(old, &mut new, &mut entries, &mut self.bufs, &mut report).map_err(|e| {
+                match (e, new.failed_at) {
+                    (PatchError::Io(error), Some(offset)) => PatchError::Write { offset, error },
+                    (e, _) => e,
+                }
+            })?;
         }
-        apply_diff(patch, old, new, entry.diff.get())?;
-        copy_bytes(patch, new, entry.extra.get())?;
-        old.seek(SeekFrom::Current(entry.seek.get()))?;
-        bytes_written += entry.diff.get() + entry.extra.get();
+        new.report();
+        Ok(())
     }
 }
 
@@ -102,39 +729,744 @@ fn apply_with_header(
 ///
 /// However, it is not compatible with the format created by
 /// [`generate_chunked`][crate::generate_chunked]. In that case, use [`apply_chunked`].
-pub fn apply(
-    old: &mut (impl Read + Seek),
-    new: &mut impl Write,
-    patch: &mut impl Read,
-) -> Result<()> {
-    let header = read!(patch, PatchHeader)?;
-    apply_with_header(old, new, patch, header)
+pub fn apply(old: &mut impl OldSource, new: &mut impl Write, patch: &mut impl Read) -> Result<()> {
+    Patcher::new().run(old, new, patch)
 }
 
 /// Apply a patch file. This is compatible with the formats created by
 /// [`generate`][crate::generate], [`generate_chunked`][crate::generate_chunked], as well as the
 /// original ddelta program.
+///
+/// Patches that copy from the new file, as created with
+/// [`DiffOptions::copy_from_new`][crate::DiffOptions::copy_from_new], keep the last 4 MiB of each
+/// chunk of the new file in memory while applying.
 pub fn apply_chunked(
+    old: &mut impl OldSource,
+    new: &mut impl Write,
+    patch: &mut impl Read,
+) -> Result<()> {
+    Patcher::new().run_chunked(old, new, patch)
+}
+
+/// Apply a (chunked or plain) patch like [`apply_chunked`], but only if the old file has the
+/// `expected` checksum.
+///
+/// A patch applied to a different old file than it was made for usually doesn't fail, but produces
+/// garbage. This reads all of the old file to check it before anything is written to `new`, then
+/// goes back to its start to apply the patch. A mismatch is [`PatchError::OldMismatch`].
+pub fn apply_if_matches(
     old: &mut (impl Read + Seek),
+    expected: &Checksum,
     new: &mut impl Write,
     patch: &mut impl Read,
 ) -> Result<()> {
-    let mut bytes_written = 0;
-    loop {
-        let header = match read!(patch, PatchHeader) {
-            Ok(header) => header,
-            Err(e) => {
-                return match e {
-                    PatchError::Io(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(()),
-                    PatchError::Internal(_) | PatchError::Io(_) => Err(e),
+    let mut hasher = ChecksumHasher::new(expected.algorithm).ok_or_else(|| {
+        let name = expected.algorithm.name();
+        PatchError::Internal(format!("Checksum algorithm {name} isn't enabled").into())
+    })?;
+    old.rewind()?;
+    io::copy(old, &mut hasher)?;
+    let found = hasher.finish();
+    if found != *expected {
+        return Err(PatchError::OldMismatch {
+            expected: expected.clone(),
+            found,
+        });
+    }
+    old.rewind()?;
+    apply_chunked(old, new, patch)
+}
+
+/// Apply a (chunked or plain) patch like [`apply_chunked`], but only if it doesn't read past
+/// `old_len` bytes of the old file.
+///
+/// Otherwise, a patch for a longer old file would write part of the new file before failing at the
+/// first entry that reads past the end. This reads through `patch` once with [`old_ranges`]
+/// first, and fails with [`PatchError::OldTooShort`] before anything is written to `new`. Then
+/// it goes back to where `patch` was, and applies it.
+///
+/// [`old_ranges`]: crate::old_ranges
+pub fn apply_checked(
+    old: &mut impl OldSource,
+    old_len: u64,
+    new: &mut impl Write,
+    patch: &mut (impl Read + Seek),
+) -> Result<()> {
+    let start = patch.stream_position()?;
+    let needed = old_ranges(patch)?.last().map_or(0, |range| range.end);
+    if needed > old_len {
+        return Err(PatchError::OldTooShort {
+            needed,
+            len: old_len,
+        });
+    }
+    patch.seek(SeekFrom::Start(start))?;
+    apply_chunked(old, new, patch)
+}
+
+/// Apply a (chunked or plain) patch to `region` of `target`, e.g. one partition inside a disk image,
+/// which is both the old file and where the new one is written.
+///
+/// The new file must be exactly as large as the region. The region is read into memory and the new
+/// file is built in memory as well, so this takes twice the size of the region, and fails with an
+/// [`ErrorKind::OutOfMemory`] error if that can't be allocated. A new file that gets larger than
+/// the region fails as soon as it does. `target` is only written once the whole patch applied, so
+/// if it fails, the region is left as it was.
+pub fn apply_region(
+    target: &mut (impl Read + Write + Seek),
+    region: Range<u64>,
+    patch: &mut impl Read,
+) -> Result<()> {
+    let len = region
+        .end
+        .checked_sub(region.start)
+        .ok_or_else(|| PatchError::Internal(format!("Invalid region {region:?}").into()))?;
+    let len = usize::try_from(len).map_err(|_| PatchError::OffsetOverflow)?;
+    // Whole partitions may not fit, which is an error instead of an abort
+    let reserve = |buf: &mut Vec<u8>| {
+        buf.try_reserve_exact(len)
+            .map_err(|e| io::Error::new(ErrorKind::OutOfMemory, e))
+    };
+    let mut old = Vec::new();
+    reserve(&mut old)?;
+    old.resize(len, 0);
+    target.seek(SeekFrom::Start(region.start))?;
+    target.read_exact(&mut old)?;
+    let mut new = Region {
+        buf: Vec::new(),
+        len,
+        overflowed: false,
+    };
+    reserve(&mut new.buf)?;
+    let result = apply_chunked(&mut SliceSource::new(&old), &mut new, patch);
+    if new.overflowed {
+        return Err(PatchError::Internal(
+            format!("New file is larger than the region of {len} bytes").into(),
+        ));
+    }
+    result?;
+    if new.buf.len() != len {
+        return Err(PatchError::Internal(
+            format!(
+                "New file has {} bytes, but the region has {len}",
+                new.buf.len()
+            )
+            .into(),
+        ));
+    }
+    target.seek(SeekFrom::Start(region.start))?;
+    target.write_all(&new.buf)?;
+    Ok(())
+}
+
+/// The new file of [`apply_region`], which fails as soon as it gets larger than the region.
+struct Region {
+    buf: Vec<u8>,
+    len: usize,
+    overflowed: bool,
+}
+
+impl Write for Region {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() > self.len - self.buf.len() {
+            self.overflowed = true;
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "The new file is larger than the region",
+            ));
+        }
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads at most `left` bytes of a patch, and remembers whether the stream ended before that.
+struct Exact<'a, R> {
+    inner: &'a mut R,
+    left: u64,
+    ended: bool,
+}
+
+impl<R: Read> Read for Exact<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf
+            .len()
+            .min(usize::try_from(self.left).unwrap_or(usize::MAX));
+        if len == 0 {
+            return Ok(0);
+        }
+        let n = self.inner.read(&mut buf[..len])?;
+        self.ended |= n == 0;
+        self.left -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Apply a (chunked or plain) patch like [`apply_chunked`], which is exactly `patch_len` bytes
+/// long, e.g. because it's framed inside a larger protocol.
+///
+/// Nothing after those bytes is read from `patch`, so the stream can carry more data after the
+/// patch. If the stream ends before them, this fails with [`PatchError::Truncated`], even where
+/// the patch could have ended. A patch that doesn't end exactly at `patch_len` is rejected as well.
+/// As with the other apply functions, part of the new file may have been written on failure.
+pub fn apply_exact_len(
+    old: &mut impl OldSource,
+    new: &mut impl Write,
+    patch: &mut impl Read,
+    patch_len: u64,
+) -> Result<()> {
+    let mut exact = Exact {
+        inner: patch,
+        left: patch_len,
+        ended: false,
+    };
+    // The end of the declared length looks like the end of a chunked patch
+    let result = apply_chunked(old, new, &mut exact);
+    let read = patch_len - exact.left;
+    match result {
+        _ if exact.ended => Err(PatchError::Truncated {
+            read,
+            len: patch_len,
+        }),
+        Err(PatchError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof && exact.left == 0 => {
+            Err(PatchError::Internal(
+                format!("Patch is longer than {patch_len} bytes").into(),
+            ))
+        }
+        result => result,
+    }
+}
+
+/// Apply a sequence of (chunked or plain) patches, each one to the output of the previous one,
+/// and write the output of the last one to `new`. See [`apply_chunked`].
+///
+/// This is for clients that are several versions behind, and would otherwise have to write every
+/// version in between to disk. Patches can read from anywhere in their old file, so the
+/// intermediate versions are kept in memory (at most two at a time), while the last one is
+/// streamed to `new`. An empty chain is an error.
+pub fn apply_chain(
+    old: &mut impl OldSource,
+    patches: &mut [impl Read],
+    new: &mut impl Write,
+) -> Result<()> {
+    let Some((last, rest)) = patches.split_last_mut() else {
+        return Err(PatchError::Internal("No patches to apply".into()));
+    };
+    let mut patcher = Patcher::new();
+    // The version that the next patch is applied to, if it's not the old file
+    let mut input: Option<Vec<u8>> = None;
+    let mut output = Vec::new();
+    for patch in rest {
+        output.clear();
+        match &input {
+            Some(input) => patcher.run_chunked(&mut SliceSource::new(input), &mut output, patch)?,
+            None => patcher.run_chunked(old, &mut output, patch)?,
+        }
+        output = input.replace(output).unwrap_or_default();
+    }
+    match &input {
+        Some(input) => patcher.run_chunked(&mut SliceSource::new(input), new, last),
+        None => patcher.run_chunked(old, new, last),
+    }
+}
+
+/// A trait object friendly combination of [`Read`] and [`Seek`], implemented for all types that
+/// implement both.
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek + ?Sized> ReadSeek for T {}
+
+/// [`apply`], taking trait objects.
+///
+/// Every reader and writer type used with the generic functions gets its own copy of the applier.
+/// This function is compiled once, which keeps binaries small when many types are involved, and is
+/// easier to call through FFI or plugin layers.
+pub fn apply_dyn(old: &mut dyn ReadSeek, new: &mut dyn Write, patch: &mut dyn Read) -> Result<()> {
+    apply(&mut { old }, &mut { new }, &mut { patch })
+}
+
+/// [`apply_chunked`], taking trait objects. See [`apply_dyn`].
+pub fn apply_chunked_dyn(
+    old: &mut dyn ReadSeek,
+    new: &mut dyn Write,
+    patch: &mut dyn Read,
+) -> Result<()> {
+    apply_chunked(&mut { old }, &mut { new }, &mut { patch })
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Cursor, Write};
+    use std::time::{Duration, Instant};
+
+    use crate::spec::{encode_copy, encode_entry, encode_header, encode_header_v2, Terminator};
+    use crate::{
+        apply, apply_chain, apply_checked, apply_chunked, apply_chunked_lenient, apply_exact_len,
+        apply_region, apply_slice, ApplyProgress, EntryInfo, PatchError, Patcher, Retry, Trailing,
+    };
+
+    /// An entry header without its data, for lengths too large for [`encode_entry`] to write.
+    fn entry(diff: u64, extra: u64, seek: i64) -> Vec<u8> {
+        [diff.to_be_bytes(), extra.to_be_bytes(), seek.to_be_bytes()].concat()
+    }
+
+    #[test]
+    fn offset_overflow() {
+        let patches = [
+            [
+                encode_header(1),
+                encode_entry(b"", b"x", 0),
+                entry(0, u64::MAX, 0),
+            ]
+            .concat(),
+            [encode_header(1), entry(u64::MAX, 1, 0)].concat(),
+            [encode_header(0), entry(0, i64::MAX as u64 + 1, 0)].concat(),
+        ];
+        for patch in patches {
+            let result = apply(&mut Cursor::new([]), &mut Vec::new(), &mut &patch[..]);
+            assert!(
+                matches!(result, Err(PatchError::OffsetOverflow)),
+                "{:?}",
+                result
+            );
+            let result = apply_chunked(&mut Cursor::new([]), &mut Vec::new(), &mut &patch[..]);
+            assert!(
+                matches!(result, Err(PatchError::OffsetOverflow)),
+                "{:?}",
+                result
+            );
+        }
+        // The largest possible offset is fine, up to the point where the data is missing
+        let patch = [encode_header(i64::MAX as u64), entry(0, i64::MAX as u64, 0)].concat();
+        let result = apply(&mut Cursor::new([]), &mut Vec::new(), &mut &patch[..]);
+        assert!(matches!(result, Err(PatchError::Io(_))), "{:?}", result);
+        // Chunk sizes adding up past the limit
+        let mut patch = [
+            encode_header(1),
+            encode_entry(b"", b"x", 0),
+            Terminator::BYTES.to_vec(),
+        ]
+        .concat();
+        patch.extend([encode_header(i64::MAX as u64), Terminator::BYTES.to_vec()].concat());
+        let result = apply_chunked(&mut Cursor::new([]), &mut Vec::new(), &mut &patch[..]);
+        assert!(
+            matches!(result, Err(PatchError::OffsetOverflow)),
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn chain() {
+        let first = [
+            encode_header(4),
+            encode_entry(&[0, 0, b'X'.wrapping_sub(b'c'), 0], b"", 0),
+            Terminator::BYTES.to_vec(),
+        ]
+        .concat();
+        let second = [
+            encode_header(6),
+            encode_entry(&[0; 4], b"ef", 0),
+            Terminator::BYTES.to_vec(),
+        ]
+        .concat();
+        let third = [
+            encode_header(3),
+            encode_entry(b"", b"xyz", 0),
+            Terminator::BYTES.to_vec(),
+        ]
+        .concat();
+
+        let mut new = Vec::new();
+        apply_chain(
+            &mut Cursor::new(b"abcd"),
+            &mut [&first[..], &second, &third[..3]],
+            &mut new,
+        )
+        .unwrap_err();
+        for (patches, expected) in [
+            (vec![&first[..]], &b"abXd"[..]),
+            (vec![&first, &second], b"abXdef"),
+            (vec![&first, &second, &third], b"xyz"),
+        ] {
+            new.clear();
+            apply_chain(&mut Cursor::new(b"abcd"), &mut patches.clone(), &mut new).unwrap();
+            assert_eq!(new, expected);
+        }
+        assert!(apply_chain(&mut Cursor::new(b"abcd"), &mut [] as &mut [&[u8]], &mut new).is_err());
+    }
+
+    #[test]
+    fn progress() {
+        let mut patch = [
+            encode_header(3),
+            encode_entry(b"", b"abc", 0),
+            Terminator::BYTES.to_vec(),
+        ]
+        .concat();
+        patch.extend(
+            [
+                encode_header(2),
+                encode_entry(b"", b"de", 0),
+                Terminator::BYTES.to_vec(),
+            ]
+            .concat(),
+        );
+        let mut reports = Vec::new();
+        let mut new = Vec::new();
+        Patcher::new()
+            .with_progress(|progress| reports.push(progress))
+            .run_chunked(&mut Cursor::new([]), &mut new, &mut &patch[..])
+            .unwrap();
+        assert_eq!(new, b"abcde");
+        assert_eq!(
+            reports.last(),
+            Some(&ApplyProgress {
+                chunk: 1,
+                chunk_size: 2,
+                written: 5,
+                patch_read: patch.len() as u64,
+            })
+        );
+    }
+
+    #[test]
+    fn trailing() {
+        let patch = [
+            encode_header(3),
+            encode_entry(b"", b"abc", 0),
+            Terminator::BYTES.to_vec(),
+        ]
+        .concat();
+        let run = |trailing: Trailing, chunked: bool, after: &[u8]| {
+            let patch = [&patch[..], after].concat();
+            let mut patcher = Patcher::new().trailing(trailing);
+            let mut new = Vec::new();
+            match chunked {
+                false => patcher.run(&mut Cursor::new([]), &mut new, &mut &patch[..]),
+                true => patcher.run_chunked(&mut Cursor::new([]), &mut new, &mut &patch[..]),
+            }
+            .is_ok()
+        };
+        let block = || Trailing::Block(b"SIG".to_vec().into());
+        assert!(run(Trailing::Ignore, false, b"garbage"));
+        assert!(run(Trailing::Forbid, false, b""));
+        assert!(!run(Trailing::Forbid, false, b"garbage"));
+        assert!(!run(Trailing::Forbid, false, &patch));
+        assert!(run(block(), false, b"SIG and signature"));
+        assert!(!run(block(), false, b"garbage"));
+        assert!(run(block(), true, b"SIG and signature"));
+        assert!(!run(Trailing::Ignore, true, b"SIG and signature"));
+    }
+
+    #[test]
+    #[cfg(feature = "crc32")]
+    fn if_matches() {
+        use crate::{apply_if_matches, Checksum, ChecksumAlgorithm};
+
+        let patch = [
+            encode_header(3),
+            encode_entry(&[1, 1, 1], b"", 0),
+            Terminator::BYTES.to_vec(),
+        ]
+        .concat();
+        let checksum = Checksum::of(ChecksumAlgorithm::Crc32, b"abc").unwrap();
+        let mut new = Vec::new();
+        let mut old = Cursor::new(b"abc");
+        old.set_position(2);
+        apply_if_matches(&mut old, &checksum, &mut new, &mut &patch[..]).unwrap();
+        assert_eq!(new, b"bcd");
+        let mut new = Vec::new();
+        let result = apply_if_matches(
+            &mut Cursor::new(b"abd"),
+            &checksum,
+            &mut new,
+            &mut &patch[..],
+        );
+        assert!(
+            matches!(&result, Err(PatchError::OldMismatch { expected, .. }) if *expected == checksum),
+            "{:?}",
+            result
+        );
+        assert!(new.is_empty());
+    }
+
+    #[test]
+    fn exact_len() {
+        let patch = [
+            encode_header(3),
+            encode_entry(&[1, 1, 1], b"", 0),
+            Terminator::BYTES.to_vec(),
+        ]
+        .concat();
+        let len = patch.len() as u64;
+        let run = |stream: &[u8], len| {
+            let mut stream = stream;
+            let mut new = Vec::new();
+            let result = apply_exact_len(&mut Cursor::new(b"abc"), &mut new, &mut stream, len);
+            result.map(|()| (new, stream.len()))
+        };
+        // The next frame is left in the stream
+        let framed = [&patch[..], b"next"].concat();
+        assert_eq!(run(&framed, len).unwrap(), (b"bcd".to_vec(), 4));
+        // Cut off in an entry, and right after a chunk
+        for (stream, read) in [(&patch[..30], 30), (&patch[..len as usize], len)] {
+            let result = run(stream, len + 10);
+            assert!(
+                matches!(result, Err(PatchError::Truncated { read: r, len: l }) if r == read && l == len + 10),
+                "{result:?}"
+            );
+        }
+        assert!(run(&framed, len - 1).is_err());
+        assert!(run(&framed, len + 2).is_err());
+    }
+
+    #[test]
+    fn on_entry() {
+        // "ab" from the old file, "!" and a seek back, then a chunk starting at "d"
+        let chunk = [
+            encode_header(3),
+            encode_entry(&[0, 0], b"!", -2),
+            Terminator::BYTES.to_vec(),
+        ];
+        let second = [
+            encode_header(2),
+            encode_entry(&[0, 0], b"", 0),
+            Terminator::BYTES.to_vec(),
+        ];
+        let patch = [chunk.concat(), second.concat()].concat();
+        let mut old = Cursor::new(b"abcdef");
+        let mut seen = Vec::new();
+        let mut new = Vec::new();
+        Patcher::new()
+            .on_entry(|entry| {
+                seen.push(entry.clone());
+                Ok(())
+            })
+            .run_chunked(&mut old, &mut new, &mut &patch[..])
+            .unwrap();
+        assert_eq!(new, b"ab!de");
+        let info = |chunk, new, old| EntryInfo {
+            chunk,
+            new,
+            old,
+            copy_distance: None,
+        };
+        assert_eq!(seen, [info(0, 0..3, 0..2), info(1, 3..5, 3..5)]);
+
+        // Refused before anything is written
+        let mut new = Vec::new();
+        let result = Patcher::new()
+            .on_entry(|entry| match entry.chunk {
+                0 => Ok(()),
+                _ => Err(PatchError::Internal("refused".into())),
+            })
+            .run_chunked(&mut Cursor::new(b"abcdef"), &mut new, &mut &patch[..]);
+        assert!(result.is_err());
+        assert_eq!(new, b"ab!");
+    }
+
+    #[test]
+    fn region() {
+        // Turns "abc" into "cab"
+        let patch = [
+            encode_header(3),
+            encode_entry(b"", b"c", 0),
+            encode_entry(&[0; 2], b"", 0),
+            Terminator::BYTES.to_vec(),
+        ]
+        .concat();
+        let mut image = Cursor::new(b"--abc--".to_vec());
+        apply_region(&mut image, 2..5, &mut &patch[..]).unwrap();
+        assert_eq!(image.get_ref(), b"--cab--");
+        // Doesn't fill the region, so nothing is written
+        let result = apply_region(&mut image, 2..6, &mut &patch[..]);
+        assert!(result.is_err());
+        assert_eq!(image.get_ref(), b"--cab--");
+        // Overfills it, which fails before the rest is applied
+        let result = apply_region(&mut image, 2..4, &mut &patch[..]);
+        assert!(
+            matches!(&result, Err(PatchError::Internal(e)) if e.contains("larger")),
+            "{result:?}"
+        );
+        // Too large to fit in memory
+        assert!(apply_region(&mut image, 0..u64::MAX, &mut &patch[..]).is_err());
+        assert_eq!(image.get_ref(), b"--cab--");
+    }
+
+    #[test]
+    fn memory_limit() {
+        let data: Vec<u8> = (0..100).collect();
+        let patch = [
+            encode_header(100),
+            encode_entry(b"", &data, 0),
+            Terminator::BYTES.to_vec(),
+        ]
+        .concat();
+        let mut new = Vec::new();
+        Patcher::new()
+            .max_memory(10)
+            .run(&mut Cursor::new(b""), &mut new, &mut &patch[..])
+            .unwrap();
+        assert_eq!(new, data);
+
+        let result = Patcher::new().max_memory(1).run(
+            &mut Cursor::new(b""),
+            &mut Vec::new(),
+            &mut &patch[..],
+        );
+        assert!(matches!(
+            result,
+            Err(PatchError::MemoryLimit {
+                needed: 2,
+                limit: 1
+            })
+        ));
+
+        // A copy needs the end of the new file
+        let patch = [
+            encode_header_v2(6),
+            encode_entry(b"", b"abc", 0),
+            encode_copy(3, 3, 0),
+            Terminator::BYTES.to_vec(),
+        ]
+        .concat();
+        let mut new = Vec::new();
+        let result = Patcher::new().max_memory(1024 * 1024).run(
+            &mut Cursor::new(b""),
+            &mut new,
+            &mut &patch[..],
+        );
+        assert!(matches!(result, Err(PatchError::MemoryLimit { .. })));
+        assert!(new.is_empty());
+        Patcher::new()
+            .max_memory(8 * 1024 * 1024)
+            .run(&mut Cursor::new(b""), &mut new, &mut &patch[..])
+            .unwrap();
+        assert_eq!(new, b"abcabc");
+    }
+
+    #[test]
+    fn copy_past_chunk() {
+        // A copy that would write far more than the chunk has room for
+        let patch = [
+            encode_header_v2(6),
+            encode_entry(b"", b"abc", 0),
+            encode_copy(1 << 62, 3, 0),
+            Terminator::BYTES.to_vec(),
+        ]
+        .concat();
+        let mut new = Vec::new();
+        let result = apply_chunked(&mut Cursor::new(b""), &mut new, &mut &patch[..]);
+        assert!(matches!(result, Err(PatchError::Internal(_))), "{result:?}");
+        assert_eq!(new, b"abc");
+        new.clear();
+        let result = apply_slice(b"", &patch, &mut new);
+        assert!(matches!(result, Err(PatchError::Internal(_))), "{result:?}");
+        assert_eq!(new, b"abc");
+        assert_eq!(apply_chunked_lenient(b"", &patch, &mut Vec::new()).len(), 1);
+    }
+
+    #[test]
+    fn throttled() {
+        let patch = [
+            encode_header(20_000),
+            encode_entry(b"", &[7; 20_000], 0),
+            Terminator::BYTES.to_vec(),
+        ];
+        let start = Instant::now();
+        let mut new = Vec::new();
+        Patcher::new()
+            .max_throughput(100_000)
+            .run(&mut Cursor::new(b""), &mut new, &mut &patch.concat()[..])
+            .unwrap();
+        assert_eq!(new, [7; 20_000]);
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[test]
+    fn retried_writes() {
+        /// Writes up to 3 bytes at a time, times out every other call, and breaks after `left`.
+        struct Flaky {
+            data: Vec<u8>,
+            timeout: bool,
+            left: usize,
+        }
+        impl Write for Flaky {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.timeout = !self.timeout;
+                if self.timeout {
+                    return Err(io::ErrorKind::TimedOut.into());
                 }
+                let n = buf.len().min(3).min(self.left);
+                self.data.extend(&buf[..n]);
+                self.left -= n;
+                Ok(n)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
             }
+        }
+
+        let patch = [
+            encode_header(10),
+            encode_entry(b"", &[7; 10], 0),
+            Terminator::BYTES.to_vec(),
+        ]
+        .concat();
+        let mut patcher = Patcher::new().retry_writes(Retry {
+            attempts: 1,
+            delay: Duration::ZERO,
+        });
+        for (left, result) in [(100, None), (5, Some(5))] {
+            let mut new = Flaky {
+                data: Vec::new(),
+                timeout: false,
+                left,
+            };
+            let applied = patcher.run(&mut Cursor::new(b""), &mut new, &mut &patch[..]);
+            match result {
+                None => assert_eq!(new.data, [7; 10]),
+                Some(offset) => assert!(
+                    matches!(&applied, Err(PatchError::Write { offset: o, error })
+                        if *o == offset && error.kind() == io::ErrorKind::WriteZero),
+                    "{applied:?}"
+                ),
+            }
+        }
+        // Without retries, the first timeout is where it fails
+        let mut new = Flaky {
+            data: Vec::new(),
+            timeout: false,
+            left: 100,
         };
-        // Each iteration expects to start from the beginning of the old file, so we can take
-        // advantage of the fact that the chunks of old & new are always the same, and if they're
-        // not, no data is read from the old file
-        old.seek(SeekFrom::Start(bytes_written))?;
-        bytes_written += header.new_file_size.get();
-        apply_with_header(old, new, patch, header)?;
+        let applied = apply(&mut Cursor::new(b""), &mut new, &mut &patch[..]);
+        assert!(matches!(applied, Err(PatchError::Write { offset: 0, .. })));
+    }
+
+    #[test]
+    fn checked() {
+        // Reads 3 bytes at offset 2 of the old file
+        let patch = [
+            encode_header(3),
+            encode_entry(b"", b"", 2),
+            encode_entry(&[0; 3], b"", 0),
+            Terminator::BYTES.to_vec(),
+        ];
+        let mut patch = Cursor::new(patch.concat());
+        let mut new = Vec::new();
+        let result = apply_checked(&mut Cursor::new(b"abcd"), 4, &mut new, &mut patch);
+        assert!(
+            matches!(result, Err(PatchError::OldTooShort { needed: 5, len: 4 })),
+            "{result:?}"
+        );
+        assert!(new.is_empty());
+        patch.set_position(0);
+        apply_checked(&mut Cursor::new(b"abcde"), 5, &mut new, &mut patch).unwrap();
+        assert_eq!(new, b"cde");
     }
 }
@@ -0,0 +1,65 @@
+//! Reserving disk space for the new file before it's written.
+
+use std::fs::File;
+use std::io::{self, ErrorKind};
+
+/// Reserves disk space for the first `len` bytes of `file`, without changing its size, and returns
+/// whether the platform and filesystem could.
+///
+/// Reserving the whole new file up front lets the filesystem place it in one piece, rather than
+/// growing it a block at a time, and a full disk is reported here instead of halfway through
+/// writing a large file. This uses `fallocate` on Linux and `F_PREALLOCATE` on macOS and iOS. On
+/// other platforms, and on filesystems that don't support it, nothing happens and this returns
+/// `Ok(false)`, so the file is written as it would be without it.
+///
+/// [`apply_overlapped`][crate::apply_overlapped] and [`apply_mmap_out`][crate::apply_mmap_out]
+/// call this themselves when the feature is enabled.
+pub fn preallocate(file: &File, len: u64) -> io::Result<bool> {
+    if len == 0 {
+        return Ok(true);
+    }
+    match allocate(file, len) {
+        Ok(()) => Ok(true),
+        Err(e) if unsupported(&e) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(any(target_os = "linux", target_vendor = "apple"))]
+use crate::sys::allocate;
+
+#[cfg(not(any(target_os = "linux", target_vendor = "apple")))]
+fn allocate(_: &File, _: u64) -> io::Result<()> {
+    Err(ErrorKind::Unsupported.into())
+}
+
+/// Whether `e` means that space can't be reserved this way, rather than that there's no space.
+fn unsupported(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    if let Some(code) = e.raw_os_error() {
+        return [libc::EOPNOTSUPP, libc::ENOTSUP, libc::ENOSYS, libc::EINVAL].contains(&code);
+    }
+    e.kind() == ErrorKind::Unsupported
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, OpenOptions};
+
+    use super::preallocate;
+
+    #[test]
+    fn preallocated() {
+        let path = std::env::temp_dir().join(format!("ddelta-prealloc-{}", std::process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        preallocate(&file, 1024 * 1024).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 0);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,84 @@
+//! Progress bars with [indicatif](https://docs.rs/indicatif), behind the `indicatif` feature.
+
+use indicatif::ProgressBar;
+
+#[cfg(feature = "apply")]
+use crate::ApplyProgress;
+#[cfg(feature = "diff")]
+use crate::State;
+
+/// Shows the progress of generating or applying a patch on an indicatif [`ProgressBar`].
+///
+/// The bar's position is set to how much of the new file has been generated, or how much of the
+/// patch has been read while applying, so set its length to the size of the new file or of the
+/// patch. What's being done right now is shown as the bar's message. The bar is finished when a
+/// generation is done; after applying, that's up to the caller.
+///
+/// ```no_run
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// use ddelta::{Patcher, ProgressAdapter};
+/// use indicatif::ProgressBar;
+///
+/// let (old, patch) = (std::fs::read("old")?, std::fs::read("patch")?);
+/// let progress = ProgressAdapter::new(ProgressBar::new(patch.len() as u64));
+/// let mut patcher = Patcher::new().with_progress(progress.apply());
+/// patcher.run_chunked(&mut std::io::Cursor::new(&old), &mut Vec::new(), &mut &patch[..])?;
+/// progress.bar().finish();
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct ProgressAdapter {
+    bar: ProgressBar,
+}
+
+impl ProgressAdapter {
+    pub fn new(bar: ProgressBar) -> Self {
+        ProgressAdapter { bar }
+    }
+
+    /// The bar that is updated.
+    pub fn bar(&self) -> &ProgressBar {
+        &self.bar
+    }
+
+    /// A progress callback for generating a patch.
+    ///
+    /// ```no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use ddelta::ProgressAdapter;
+    /// use indicatif::ProgressBar;
+    ///
+    /// let (old, new) = (std::fs::read("old")?, std::fs::read("new")?);
+    /// let progress = ProgressAdapter::new(ProgressBar::new(new.len() as u64));
+    /// ddelta::generate(&old, &new, &mut Vec::new(), progress.generate())?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "diff")]
+    pub fn generate(&self) -> impl FnMut(State) + Send + 'static {
+        let bar = self.bar.clone();
+        move |state| match state {
+            State::Reading => bar.set_message("reading"),
+            State::Sorting { done, total } => {
+                bar.set_message(format!("sorting {}%", done * 100 / total.max(1)))
+            }
+            State::Working(bytes) => {
+                bar.set_message("diffing");
+                bar.set_position(bytes);
+            }
+            State::Done(_) => bar.finish_with_message("done"),
+        }
+    }
+
+    /// A progress callback for applying a patch, e.g. with
+    /// [`Patcher::with_progress`][crate::Patcher::with_progress].
+    #[cfg(feature = "apply")]
+    pub fn apply(&self) -> impl FnMut(ApplyProgress) + Send + 'static {
+        let bar = self.bar.clone();
+        move |progress| {
+            bar.set_message(format!("chunk {}", progress.chunk + 1));
+            bar.set_position(progress.patch_read);
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Converting a chunked patch to another chunk size.
+
+use std::io::{self, Read, Write};
+
+use crate::diff::{DiffError, Result};
+use crate::{apply_chunked, ChunkedPatchWriter, DiffOptions, PatchError, SliceSource};
+
+/// Passes the new file on to a [`ChunkedPatchWriter`] one chunk at a time, as it's written.
+struct Rechunker<'a, W> {
+    old: &'a [u8],
+    writer: ChunkedPatchWriter<W>,
+    chunk_size: usize,
+    buf: Vec<u8>,
+    /// What went wrong while diffing, which can't be returned through [`Write`].
+    error: Option<DiffError>,
+}
+
+impl<W: Write> Rechunker<'_, W> {
+    /// Diffs what's buffered against the same range of the old file.
+    fn push(&mut self, len: usize) -> Result<()> {
+        let start = usize::try_from(self.writer.new_len())
+            .unwrap_or(usize::MAX)
+            .min(self.old.len());
+        let old = &self.old[start..(start + len).min(self.old.len())];
+        self.writer.push(old, start as u64, &self.buf[..len])?;
+        self.buf.drain(..len);
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for Rechunker<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.chunk_size - self.buf.len());
+        self.buf.extend_from_slice(&buf[..len]);
+        if self.buf.len() == self.chunk_size {
+            if let Err(e) = self.push(self.chunk_size) {
+                self.error = Some(e);
+                return Err(io::Error::other("generating the new chunk failed"));
+            }
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Turns an error in a patch that's read into a [`DiffError`], keeping I/O errors as they are.
+pub(crate) fn patch_error(e: PatchError) -> DiffError {
+    match e {
+        PatchError::Io(e) | PatchError::Write { error: e, .. } => DiffError::Io(e),
+        e => DiffError::Internal(e.to_string().into()),
+    }
+}
+
+/// Converts a (chunked or plain) patch into a chunked patch with chunks of `new_chunk_size` bytes,
+/// as if it had been made with [`generate_chunked`][crate::generate_chunked] at that size.
+///
+/// Distributors that receive patches made with different chunk sizes can standardize them without
+/// having the new files: the patch is applied to `old`, and each chunk of the new file is diffed
+/// against the same range of `old` as soon as it's complete. Only one chunk of the new file is
+/// kept in memory. Errors in `patch_in` are returned as [`DiffError::Internal`], or
+/// [`DiffError::Io`] for I/O errors.
+pub fn rechunk(
+    patch_in: &mut impl Read,
+    old: &[u8],
+    new_chunk_size: usize,
+    patch_out: &mut impl Write,
+) -> Result<()> {
+    let options = DiffOptions::new().chunk_size(new_chunk_size);
+    let chunk_size = options.max_chunk_size().max(1);
+    let mut rechunker = Rechunker {
+        old,
+        writer: ChunkedPatchWriter::new(patch_out, options),
+        chunk_size,
+        buf: Vec::new(),
+        error: None,
+    };
+    let applied = apply_chunked(&mut SliceSource::new(old), &mut rechunker, patch_in);
+    if let Some(e) = rechunker.error.take() {
+        return Err(e);
+    }
+    applied.map_err(patch_error)?;
+    rechunker.push(rechunker.buf.len())?;
+    rechunker.writer.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::rechunk;
+    use crate::{apply_chunked, generate_chunked, histogram};
+
+    #[test]
+    fn rechunked() {
+        let old: Vec<u8> = (0..20_000u32).map(|i| (i * 31 % 253) as u8).collect();
+        let mut new = old.clone();
+        new[500..600].fill(b'x');
+        new.splice(9000..9000, *b"inserted");
+        let mut patch = Vec::new();
+        generate_chunked(&mut &old[..], &mut &new[..], &mut patch, 1000, |_| {}).unwrap();
+        assert_eq!(histogram(&patch).unwrap().chunks, 21);
+
+        let mut rechunked = Vec::new();
+        rechunk(&mut &patch[..], &old, 4096, &mut rechunked).unwrap();
+        assert_eq!(histogram(&rechunked).unwrap().chunks, 5);
+        let mut patched = Vec::new();
+        apply_chunked(&mut Cursor::new(&old), &mut patched, &mut &rechunked[..]).unwrap();
+        assert_eq!(patched, new);
+
+        let result = rechunk(&mut &patch[..100], &old, 4096, &mut Vec::new());
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,223 @@
+//! Patch generation for files made of fixed-size records, e.g. database pages.
+//!
+//! Every record of the new file gets exactly one entry. The old data an entry is diffed against is
+//! picked from a few candidates: where the previous record's left off, the same offset in the old
+//! file, and wherever the suffix array finds parts of the record. Whichever has the most bytes in
+//! common is used, as long as at least half of them are. Otherwise, the record is stored as extra
+//! data. Seeks happen at the end of entries, so the first record can only be diffed against the
+//! start of the old file.
+
+use std::io::Write;
+
+use zerocopy::AsBytes;
+
+use crate::be::{I64, U64};
+use crate::diff::{search, sort, try_resize, write_ending, write_header, DiffError, Result};
+use crate::summary::Recorder;
+use crate::{EntryHeader, SortBackend, State};
+
+/// How many places in each record are searched for in the old file.
+const PROBES: usize = 4;
+
+/// The entry of a single record: where it starts in the new file, how long it is, where its diff
+/// data starts in the old file, and how much of it is diff data.
+#[derive(Copy, Clone, Debug)]
+struct Record {
+    new: usize,
+    len: usize,
+    old: i64,
+    diff: usize,
+}
+
+/// Writes `record`, seeking to `next_old` after it.
+fn write_record(
+    patch: &mut impl Write,
+    old: &[u8],
+    new: &[u8],
+    record: Record,
+    next_old: i64,
+) -> Result<()> {
+    patch.write_all(
+        EntryHeader {
+            diff: U64::new(record.diff as u64),
+            extra: U64::new((record.len - record.diff) as u64),
+            seek: I64::new(next_old - (record.old + record.diff as i64)),
+        }
+        .as_bytes(),
+    )?;
+    let new = &new[record.new..][..record.len];
+    let old = &old[record.old as usize..][..record.diff];
+    for (n, o) in new.iter().zip(old) {
+        patch.write_all(&[n.wrapping_sub(*o)])?;
+    }
+    patch.write_all(&new[record.diff..])?;
+    Ok(())
+}
+
+/// Generate a ddelta patch with one entry for each record of `record_size` bytes in `new`.
+///
+/// Entry `i` writes bytes `i * record_size..(i + 1) * record_size` of the new file (the last one
+/// may be shorter), so appliers that work on whole records, e.g. database pages, can map entries
+/// to records without tracking offsets. Matches are only searched for whole records, so the patch
+/// is usually larger than one from [`generate`][crate::generate], unless records move around or
+/// change in place. The first record is only compared to the start of the old file. `old` must be
+/// smaller than 2^31-1 bytes; `new` has no limit. The output is applied with
+/// [`apply`][crate::apply].
+///
+/// # Panics
+///
+/// If `record_size` is 0.
+pub fn generate_records(
+    old: &[u8],
+    new: &[u8],
+    record_size: usize,
+    patch: &mut impl Write,
+    mut progress: impl FnMut(State),
+) -> Result<()> {
+    assert!(record_size > 0, "records must not be empty");
+    let recorder = Recorder::new();
+    recorder.inputs(old, new);
+    generate_patch(
+        old,
+        new,
+        record_size,
+        &mut recorder.patch_writer(patch),
+        |state| {
+            recorder.observe(state);
+            progress(state)
+        },
+    )?;
+    progress(State::Done(recorder.finish()));
+    Ok(())
+}
+
+fn generate_patch(
+    old: &[u8],
+    new: &[u8],
+    record_size: usize,
+    patch: &mut impl Write,
+    mut progress: impl FnMut(State),
+) -> Result<()> {
+    if old.len() >= i32::MAX as usize {
+        return Err(DiffError::Internal(
+            format!("The old file must be smaller than {} bytes", i32::MAX).into(),
+        ));
+    }
+    let mut sorted = Vec::new();
+    try_resize(&mut sorted, old.len() + 1)?;
+    write_header(patch, new.len() as u64)?;
+    sort(
+        old,
+        &mut sorted[..old.len()],
+        SortBackend::default(),
+        &mut progress,
+    );
+
+    let mut pending: Option<Record> = None;
+    for start in (0..new.len()).step_by(record_size) {
+        if start % 10_000 < record_size {
+            progress(State::Working(start as u64));
+        }
+        let record = &new[start..(start + record_size).min(new.len())];
+        // Continuing where the last record left off needs no seek, so it's tried first
+        let next = pending.map_or(0, |p| p.old + p.diff as i64);
+        let probes = match pending {
+            Some(_) => 0..PROBES,
+            None => 0..0,
+        };
+        let searched = probes.filter_map(|probe| {
+            let offset = record.len() * probe / PROBES;
+            let mut pos = 0;
+            let len = search(
+                &sorted,
+                &old[..old.len().saturating_sub(1)],
+                &record[offset..],
+                0,
+                old.len(),
+                &mut pos,
+            );
+            (len > 0 && pos >= offset as i64).then(|| pos - offset as i64)
+        });
+        // How many bytes of the record can be diffed against the old file from there, and how
+        // many of those are the same
+        let score = |at: i64| {
+            let old = usize::try_from(at).ok().and_then(|at| old.get(at..));
+            let old = old.unwrap_or_default();
+            let same = record.iter().zip(old).filter(|(n, o)| n == o).count();
+            (same, record.len().min(old.len()))
+        };
+        let (mut at, (mut same, mut diff)) = (next, score(next));
+        for candidate in [start as i64].into_iter().chain(searched) {
+            let (candidate_same, candidate_diff) = score(candidate);
+            if candidate_same > same {
+                (at, same, diff) = (candidate, candidate_same, candidate_diff);
+            }
+        }
+        let record = match same * 2 > diff {
+            true => Record {
+                new: start,
+                len: record.len(),
+                old: at,
+                diff,
+            },
+            false => Record {
+                new: start,
+                len: record.len(),
+                old: next,
+                diff: 0,
+            },
+        };
+        if let Some(pending) = pending.replace(record) {
+            write_record(patch, old, new, pending, record.old)?;
+        }
+    }
+    if let Some(last) = pending {
+        write_record(patch, old, new, last, last.old + last.diff as i64)?;
+    }
+    write_ending(patch)?;
+    patch.flush()?;
+    Ok(())
+}
+
+#[cfg(all(test, feature = "apply"))]
+mod test {
+    use std::io::Cursor;
+
+    use crate::spec::parse_patch;
+    use crate::{apply, generate_records};
+
+    #[test]
+    fn one_entry_per_record() {
+        let pages: Vec<Vec<u8>> = (0..8u8)
+            .map(|page| (0..64).map(|i| page.wrapping_mul(31) ^ i).collect())
+            .collect();
+        let old = pages.concat();
+        // Pages moved around, one changed in place, one that's new, and a short one at the end
+        let mut changed = pages[5].clone();
+        changed[10] = 0xff;
+        let new = [
+            &pages[3][..],
+            &pages[4],
+            &changed,
+            &[0x55; 64],
+            &pages[0],
+            &pages[1][..20],
+        ]
+        .concat();
+
+        let mut patch = Vec::new();
+        generate_records(&old, &new, 64, &mut patch, |_| {}).unwrap();
+        let entries = parse_patch(&patch).unwrap().entries;
+        let sizes: Vec<usize> = entries
+            .iter()
+            .map(|entry| entry.diff.len() + entry.extra.len())
+            .collect();
+        assert_eq!(sizes, [64, 64, 64, 64, 64, 20]);
+        // The moved and changed pages are diffed, the new one is stored as-is
+        assert!(entries[1..3].iter().all(|entry| entry.extra.is_empty()));
+        assert_eq!(entries[3].diff.len(), 0);
+        let mut patched = Vec::new();
+        apply(&mut Cursor::new(&old), &mut patched, &mut &patch[..]).unwrap();
+        assert_eq!(patched, new);
+    }
+}
@@ -0,0 +1,146 @@
+//! Applying damaged patches as far as possible.
+
+use std::mem::size_of;
+use std::ops::Range;
+
+use crate::patch::PatchError;
+use crate::slice::{apply_chunk, read_header};
+use crate::units::NewOffset;
+use crate::{ExtendedHeader, PatchHeader, DDELTA_MAGIC, DDELTA_MAGIC_V2, DDELTA_MAGIC_WORDS};
+
+/// A part of the new file that [`apply_chunked_lenient`] couldn't recover.
+#[derive(Debug)]
+pub struct Damage {
+    /// The part of the new file that was filled with zeros instead. This is empty if the header of
+    /// the chunk is damaged, so its size isn't known. The new file is shorter than it should be
+    /// then, and the chunks after it are applied to the wrong part of the old file.
+    pub range: Range<u64>,
+    /// Where the damaged chunk starts in the patch.
+    pub patch_offset: usize,
+    /// Why the chunk couldn't be applied.
+    pub error: PatchError,
+}
+
+/// Finds the next chunk header in `patch`, at or after `from`.
+fn next_header(patch: &[u8], from: usize) -> Option<usize> {
+    let found = patch
+        .get(from..)?
+        .windows(DDELTA_MAGIC.len())
+        .position(|window| {
+            window == DDELTA_MAGIC || window == DDELTA_MAGIC_V2 || window == DDELTA_MAGIC_WORDS
+        })?;
+    Some(from + found)
+}
+
+/// Apply a chunked patch that may be damaged, appending the new file to `out` and returning the
+/// parts of it that couldn't be recovered. This is meant for recovering what is left of damaged
+/// files, use [`apply_slice`][crate::apply_slice] for anything else.
+///
+/// A chunk that fails to apply is replaced with zeros. The rest of the patch is then searched for
+/// the next chunk header, and applying continues from there. Data that happens to look like a
+/// header is tried as well, which fails (and is reported) like any other damaged chunk, unless it
+/// is in the diff data and the damage is just the right size. An empty result means the patch was
+/// applied without problems.
+pub fn apply_chunked_lenient(old: &[u8], patch: &[u8], out: &mut Vec<u8>) -> Vec<Damage> {
+    let mut damage = Vec::new();
+    let start = out.len();
+    let mut bytes_written = 0;
+    let mut offset = 0;
+    if ExtendedHeader::matches(patch) {
+        offset = size_of::<ExtendedHeader>();
+    }
+    while offset < patch.len() {
+        let mut rest = &patch[offset..];
+        let header = match read_header(&mut rest) {
+            Ok(header) => header,
+            Err(error) => {
+                damage.push(Damage {
+                    range: bytes_written..bytes_written,
+                    patch_offset: offset,
+                    error,
+                });
+                match next_header(patch, offset + 1) {
+                    Some(next) => offset = next,
+                    None => break,
+                }
+                continue;
+            }
+        };
+        match apply_chunk(
+            old,
+            &mut rest,
+            &header,
+            out,
+            start,
+            NewOffset::new(bytes_written),
+        ) {
+            Ok(end) => {
+                bytes_written = end.get();
+                offset = patch.len() - rest.len();
+            }
+            Err(error) => {
+                out.truncate(start + bytes_written as usize);
+                // A size beyond what the rest of the patch could make is more likely damaged than
+                // real, so it isn't filled in
+                let size = header.new_file_size.get();
+                let limit = (old.len() + patch.len()) as u64;
+                let end = bytes_written + if size <= limit { size } else { 0 };
+                out.resize(start + end as usize, 0);
+                damage.push(Damage {
+                    range: bytes_written..end,
+                    patch_offset: offset,
+                    error,
+                });
+                bytes_written = end;
+                match next_header(patch, offset + size_of::<PatchHeader>()) {
+                    Some(next) => offset = next,
+                    None => break,
+                }
+            }
+        }
+    }
+    damage
+}
+
+#[cfg(test)]
+mod test {
+    use super::apply_chunked_lenient;
+    use crate::spec::{encode_entry, encode_header, Terminator};
+
+    fn chunk(data: &[u8]) -> Vec<u8> {
+        [
+            encode_header(data.len() as u64),
+            encode_entry(b"", data, 0),
+            Terminator::BYTES.to_vec(),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn lenient() {
+        let patch = [chunk(b"abc"), chunk(b"def"), chunk(b"ghi")].concat();
+        let mut out = Vec::new();
+        assert!(apply_chunked_lenient(b"", &patch, &mut out).is_empty());
+        assert_eq!(out, b"abcdefghi");
+
+        // The second chunk is cut short, so its terminator is missing
+        let mut damaged = patch.clone();
+        let second = chunk(b"abc").len();
+        damaged.drain(second + 40..second + 50);
+        out.clear();
+        let damage = apply_chunked_lenient(b"", &damaged, &mut out);
+        assert_eq!(out, b"abc\0\0\0ghi");
+        assert_eq!(damage.len(), 1);
+        assert_eq!(damage[0].range, 3..6);
+        assert_eq!(damage[0].patch_offset, second);
+
+        // The header of the second chunk is damaged
+        let mut damaged = patch.clone();
+        damaged[second] = b'X';
+        out.clear();
+        let damage = apply_chunked_lenient(b"", &damaged, &mut out);
+        assert_eq!(out, b"abcghi");
+        assert_eq!(damage.len(), 1);
+        assert_eq!(damage[0].range, 3..3);
+    }
+}
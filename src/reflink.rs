@@ -0,0 +1,276 @@
+//! Applying patches between files, sharing unchanged blocks with the old file instead of copying
+//! them.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::fs::{FileExt, MetadataExt};
+
+use crate::entries::{Event, PatchEntries};
+use crate::io::read_full;
+use crate::patch::{add_diff, is_zero, PatchError, Result};
+use crate::sys::clone_range;
+use crate::units::{Len, NewOffset, OldOffset};
+use crate::{WouldBlock, COPY_FLAG, COPY_WINDOW};
+
+const BLOCK_SIZE: usize = 32 * 1024;
+
+/// Writes the new file, cloning blocks of the old file where possible.
+struct Writer<'a> {
+    old: &'a File,
+    new: &'a File,
+    /// The filesystem's block size, which clones have to be aligned to.
+    block: u64,
+    /// Whether the filesystem supports cloning, as far as we know.
+    reflink: bool,
+    /// Blocks to be cloned, as the position in the old file, the position in the new file and the
+    /// length. They're collected so neighbouring blocks can be cloned at once.
+    pending: Option<(u64, u64, u64)>,
+    /// The size of the new file so far.
+    pos: u64,
+    /// Whether the diff data of the current chunk is added in words.
+    words: bool,
+    cloned: u64,
+    old_buf: Vec<u8>,
+    patch_buf: Vec<u8>,
+}
+
+impl Writer<'_> {
+    /// Clones the pending blocks, falling back to copying them if the filesystem can't.
+    fn flush(&mut self) -> io::Result<()> {
+        let Some((old_pos, new_pos, len)) = self.pending.take() else {
+            return Ok(());
+        };
+        if self.reflink {
+            if clone_range(self.old, self.new, old_pos, new_pos, len).is_ok() {
+                self.cloned += len;
+                return Ok(());
+            }
+            // Unsupported by the filesystem, or the files are on different filesystems, so don't
+            // try again
+            self.reflink = false;
+        }
+        let mut done = 0;
+        while done < len {
+            let n = (len - done).min(BLOCK_SIZE as u64) as usize;
+            let buf = &mut self.old_buf[..n];
+            self.old.read_exact_at(buf, old_pos + done)?;
+            self.new.write_all_at(buf, new_pos + done)?;
+            done += n as u64;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.flush()?;
+        self.new.write_all_at(buf, self.pos)?;
+        self.pos += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Adds `len` bytes of diff data from the patch to the old file at `old_pos`. Whole blocks that
+    /// the diff leaves unchanged are cloned if they're aligned in both files, and for word diffs,
+    /// start at a word.
+    fn diff(&mut self, patch: &mut impl Read, old_pos: u64, len: u64) -> Result<()> {
+        let mut done = 0;
+        while done < len {
+            let n = (len - done).min(BLOCK_SIZE as u64) as usize;
+            read_full(patch, &mut self.patch_buf[..n], WouldBlock::Fail)?;
+            let old_pos = old_pos + done;
+            // Where the data that isn't cloned starts
+            let mut literal = 0;
+            let mut i = 0;
+            while i < n {
+                let at = self.pos + (i - literal) as u64;
+                let end = n.min(i + (self.block - at % self.block) as usize);
+                let aligned = at.is_multiple_of(self.block)
+                    && (old_pos + i as u64).is_multiple_of(self.block)
+                    && (!self.words || (i.is_multiple_of(4) && self.block.is_multiple_of(4)));
+                if self.reflink
+                    && aligned
+                    && (end - i) as u64 == self.block
+                    && is_zero(&self.patch_buf[i..end])
+                {
+                    self.add_diff(old_pos, literal..i)?;
+                    let (old_at, len) = (old_pos + i as u64, self.block);
+                    match &mut self.pending {
+                        Some((_, new_pos, pending)) if *new_pos + *pending == at => *pending += len,
+                        _ => {
+                            self.flush()?;
+                            self.pending = Some((old_at, at, len));
+                        }
+                    }
+                    self.pos += len;
+                    literal = end;
+                }
+                i = end;
+            }
+            self.add_diff(old_pos, literal..n)?;
+            done += n as u64;
+        }
+        Ok(())
+    }
+
+    /// Writes the old file plus the diff data in `range` of the patch buffer.
+    fn add_diff(&mut self, old_pos: u64, range: std::ops::Range<usize>) -> Result<()> {
+        if range.is_empty() {
+            return Ok(());
+        }
+        self.flush()?;
+        let mut old = std::mem::take(&mut self.old_buf);
+        let buf = &mut old[range.clone()];
+        self.old.read_exact_at(buf, old_pos + range.start as u64)?;
+        add_diff(buf, &self.patch_buf[range], self.words);
+        self.write(buf)?;
+        self.old_buf = old;
+        Ok(())
+    }
+
+    fn extra(&mut self, patch: &mut impl Read, len: u64) -> Result<()> {
+        let mut done = 0;
+        let mut buf = std::mem::take(&mut self.patch_buf);
+        while done < len {
+            let n = (len - done).min(BLOCK_SIZE as u64) as usize;
+            read_full(patch, &mut buf[..n], WouldBlock::Fail)?;
+            self.write(&buf[..n])?;
+            done += n as u64;
+        }
+        self.patch_buf = buf;
+        Ok(())
+    }
+
+    /// Appends `len` bytes, starting `distance` bytes back from the end of the new file.
+    fn copy(&mut self, distance: u64, len: u64) -> Result<()> {
+        self.flush()?;
+        let mut done = 0;
+        let mut buf = std::mem::take(&mut self.patch_buf);
+        while done < len {
+            // A copy may repeat what it just wrote, so copy at most `distance` bytes at a time
+            let n = (len - done).min(distance).min(BLOCK_SIZE as u64) as usize;
+            self.new.read_exact_at(&mut buf[..n], self.pos - distance)?;
+            self.write(&buf[..n])?;
+            done += n as u64;
+        }
+        self.patch_buf = buf;
+        Ok(())
+    }
+}
+
+/// Apply a chunked patch file from one file to another, sharing the unchanged parts of the old file
+/// with the new one where possible. This accepts the same patches as
+/// [`apply_chunked`][crate::apply_chunked], and returns how many bytes were shared.
+///
+/// On filesystems that support reflinks (such as btrfs and XFS), whole blocks that the patch leaves
+/// unchanged are cloned from the old file instead of being written again, as long as they are at
+/// the same offset within a block in both files. This takes next to no time or disk space, so
+/// updating a large file that has barely changed is almost instant. Everything else, and
+/// everything on filesystems that can't clone (or if the files are on different filesystems), is
+/// written as usual.
+///
+/// The new file is written from the start, and truncated to the size of the new file at the end.
+/// It has to be opened for reading as well as writing, as copies in the new file are read back
+/// from it.
+pub fn apply_reflink(old: &File, new: &File, patch: &mut impl Read) -> Result<u64> {
+    let mut writer = Writer {
+        old,
+        new,
+        block: new.metadata()?.blksize().max(1),
+        reflink: true,
+        pending: None,
+        pos: 0,
+        words: false,
+        cloned: 0,
+        old_buf: vec![0; BLOCK_SIZE],
+        patch_buf: vec![0; BLOCK_SIZE],
+    };
+    let mut entries = PatchEntries::new(patch, true, WouldBlock::Fail);
+    // The chunks of the old and new file start at the same position
+    let mut old_pos = OldOffset::default();
+    while let Some(event) = entries.next()? {
+        let entry = match event {
+            Event::Header(_) => {
+                old_pos = entries.chunk_start().in_old();
+                writer.words = entries.words();
+                continue;
+            }
+            Event::End => continue,
+            Event::Entry(entry) => entry,
+        };
+        let (diff, extra, seek) = (entry.diff.get(), entry.extra.get(), entry.seek.get());
+        if entries.copies() && diff & COPY_FLAG != 0 {
+            let window = NewOffset::new(writer.pos).since(entries.chunk_start());
+            if extra == 0 || Some(Len::new(extra)) > window || extra > COPY_WINDOW {
+                return Err(PatchError::Internal(
+                    "Copy from outside of the new file".into(),
+                ));
+            }
+            writer.copy(extra, diff & !COPY_FLAG)?;
+        } else {
+            writer.diff(entries.patch(), old_pos.get(), diff)?;
+            writer.extra(entries.patch(), extra)?;
+            old_pos = old_pos
+                .checked_add(Len::new(diff))
+                .ok_or(PatchError::OffsetOverflow)?;
+        }
+        old_pos = old_pos.seek(seek)?;
+    }
+    writer.flush()?;
+    new.set_len(writer.pos)?;
+    Ok(writer.cloned)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, File, OpenOptions};
+    use std::io::Write;
+
+    use super::apply_reflink;
+    use crate::spec::{
+        conformance_vectors, encode_entry, encode_header, parse_chunked, Terminator,
+    };
+
+    #[test]
+    fn reflink() {
+        let dir = std::env::temp_dir().join(format!("ddelta-reflink-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let apply = |old_data: &[u8], patch: &[u8]| {
+            File::create(dir.join("old"))
+                .unwrap()
+                .write_all(old_data)
+                .unwrap();
+            let old = File::open(dir.join("old")).unwrap();
+            let new = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(dir.join("new"))
+                .unwrap();
+            apply_reflink(&old, &new, &mut &patch[..]).map(|_| fs::read(dir.join("new")).unwrap())
+        };
+
+        for vector in conformance_vectors() {
+            if !vector.chunked && parse_chunked(&vector.patch).is_err() {
+                continue;
+            }
+            let applied = apply(&vector.old, &vector.patch);
+            assert_eq!(applied.ok(), vector.expected, "{}", vector.name);
+        }
+
+        // Blocks that are unchanged, around a changed byte and extra data
+        let old: Vec<u8> = (0..20000u32).map(|i| (i * 7 + i / 300) as u8).collect();
+        let mut diff = vec![0; old.len()];
+        diff[5000] = 1;
+        let patch = [
+            encode_header(old.len() as u64 + 3),
+            encode_entry(&diff, b"xyz", 0),
+            Terminator::BYTES.to_vec(),
+        ]
+        .concat();
+        let mut expected = old.clone();
+        expected[5000] += 1;
+        expected.extend(b"xyz");
+        assert_eq!(apply(&old, &patch).ok(), Some(expected));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
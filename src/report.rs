@@ -0,0 +1,108 @@
+//! Which parts of the old and new file a patch shares.
+
+use std::ops::Range;
+
+use crate::spec::{parse_chunked, SpecError};
+
+/// Which regions of the old file a patch reuses, and which regions of the new file it brings in.
+///
+/// All ranges are sorted, and neighbouring or overlapping ranges are merged.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DedupReport {
+    /// Ranges of the old file that the new file is built from. The patch may still change some of
+    /// their bytes, but old data outside of these ranges isn't needed to apply the patch.
+    pub reused: Vec<Range<u64>>,
+    /// Ranges of the new file that are stored in the patch as-is, as they don't appear in the old
+    /// file.
+    pub novel: Vec<Range<u64>>,
+    /// Ranges of the new file that repeat earlier parts of the new file.
+    pub copied: Vec<Range<u64>>,
+}
+
+/// Pushes `range` onto `ranges`, extending the last range if they touch.
+fn push(ranges: &mut Vec<Range<u64>>, range: Range<u64>) {
+    if range.is_empty() {
+        return;
+    }
+    match ranges.last_mut() {
+        Some(last) if last.end >= range.start => last.end = last.end.max(range.end),
+        _ => ranges.push(range),
+    }
+}
+
+/// Analyzes a (chunked or plain) patch without applying it, reporting which regions of the old
+/// file it reuses, and which regions of the new file are novel.
+///
+/// Plain patches with data after their terminator are rejected, like in
+/// [`parse_chunked`][crate::spec::parse_chunked].
+pub fn dedup_report(patch: &[u8]) -> Result<DedupReport, SpecError> {
+    let mut report = DedupReport::default();
+    let mut reused = Vec::new();
+    let mut written = 0u64;
+    for chunk in parse_chunked(patch)? {
+        // The chunks of the old and new file start at the same position
+        let mut pos = written;
+        for entry in chunk.entries {
+            if let Some((len, _)) = entry.copy {
+                push(&mut report.copied, written..written.saturating_add(len));
+                written = written.saturating_add(len);
+            } else {
+                let (diff, extra) = (entry.diff.len() as u64, entry.extra.len() as u64);
+                reused.push(pos..pos.saturating_add(diff));
+                pos = pos.saturating_add(diff);
+                written = written.saturating_add(diff);
+                push(&mut report.novel, written..written.saturating_add(extra));
+                written = written.saturating_add(extra);
+            }
+            pos = pos.saturating_add_signed(entry.seek);
+        }
+    }
+    reused.sort_by_key(|range| range.start);
+    for range in reused {
+        push(&mut report.reused, range);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{dedup_report, DedupReport};
+    use crate::spec::conformance_vectors;
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn report() {
+        let vector = |name| {
+            conformance_vectors()
+                .into_iter()
+                .find(|v| v.name == name)
+                .unwrap()
+                .patch
+        };
+        assert_eq!(
+            dedup_report(&vector("chunks restart at the new offset")),
+            Ok(DedupReport {
+                reused: vec![0..4],
+                novel: vec![4..5],
+                copied: vec![],
+            })
+        );
+        assert_eq!(
+            dedup_report(&vector("negative seek")),
+            Ok(DedupReport {
+                reused: vec![0..2],
+                novel: vec![],
+                copied: vec![],
+            })
+        );
+        assert_eq!(
+            dedup_report(&vector("overlapping copy")),
+            Ok(DedupReport {
+                reused: vec![0..3],
+                novel: vec![],
+                copied: vec![2..7],
+            })
+        );
+        assert!(dedup_report(&vector("missing terminator")).is_err());
+    }
+}
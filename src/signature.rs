@@ -0,0 +1,337 @@
+//! Patch generation from a signature of the old file, for when the old and new file are in
+//! different places, as done by rsync.
+//!
+//! The side with the old file splits it into blocks and sends a [`Signature`]: a cheap rolling
+//! checksum and a strong checksum of each block. The side with the new file then looks for those
+//! blocks at every offset of the new file, using the rolling checksum to rule out most offsets
+//! without hashing them. Matching blocks become entries with diff data that is all zeros, and
+//! everything in between is stored as extra data. The patch is larger than one made with both
+//! files at hand, as only whole, unchanged blocks are found.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+use zerocopy::AsBytes;
+
+use crate::be::{I64, U64};
+use crate::diff::{write_ending, write_header, DiffError, Result};
+use crate::spec::Terminator;
+use crate::{Checksum, ChecksumAlgorithm, EntryHeader};
+
+/// Magic number of the stored form of a [`Signature`].
+const SIGNATURE_MAGIC: &[u8; 8] = b"DDELTASG";
+
+/// The checksums of a block of the old file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Block {
+    pub(crate) weak: u32,
+    pub(crate) strong: Box<[u8]>,
+}
+
+/// The checksums of the blocks of an old file, see [`signature`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Signature {
+    block_size: u32,
+    old_len: u64,
+    pub(crate) algorithm: ChecksumAlgorithm,
+    pub(crate) blocks: Vec<Block>,
+}
+
+impl Signature {
+    /// The size of the blocks, except for the last one, which may be shorter.
+    pub fn block_size(&self) -> usize {
+        self.block_size as usize
+    }
+
+    /// The size of the old file.
+    pub fn old_len(&self) -> u64 {
+        self.old_len
+    }
+
+    /// The stored form: the magic number, the block size, the size of the old file, the strong
+    /// checksum algorithm, then the weak and strong checksum of every block.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let digest_len = self.algorithm.digest_len();
+        let mut out = Vec::with_capacity(21 + self.blocks.len() * (4 + digest_len));
+        out.extend_from_slice(SIGNATURE_MAGIC);
+        out.extend_from_slice(&self.block_size.to_be_bytes());
+        out.extend_from_slice(&self.old_len.to_be_bytes());
+        out.push(self.algorithm.id());
+        for block in &self.blocks {
+            out.extend_from_slice(&block.weak.to_be_bytes());
+            out.extend_from_slice(&block.strong);
+        }
+        out
+    }
+
+    /// Parses the stored form. Fails if it's malformed, or doesn't contain exactly as many blocks
+    /// as the old file has.
+    pub fn from_bytes(input: &[u8]) -> Option<Self> {
+        let rest = input.strip_prefix(SIGNATURE_MAGIC)?;
+        let (block_size, rest) = rest.split_first_chunk()?;
+        let (old_len, rest) = rest.split_first_chunk()?;
+        let (&id, rest) = rest.split_first()?;
+        let (block_size, old_len) = (
+            u32::from_be_bytes(*block_size),
+            u64::from_be_bytes(*old_len),
+        );
+        let algorithm = ChecksumAlgorithm::from_id(id)?;
+        let entry_len = 4 + algorithm.digest_len();
+        if block_size == 0
+            || rest.len() % entry_len != 0
+            || (rest.len() / entry_len) as u64 != old_len.div_ceil(block_size as u64)
+        {
+            return None;
+        }
+        let blocks = rest
+            .chunks_exact(entry_len)
+            .map(|entry| Block {
+                weak: u32::from_be_bytes(entry[..4].try_into().unwrap()),
+                strong: entry[4..].into(),
+            })
+            .collect();
+        Some(Signature {
+            block_size,
+            old_len,
+            algorithm,
+            blocks,
+        })
+    }
+
+    pub(crate) fn strong(&self, data: &[u8]) -> Box<[u8]> {
+        strong(self.algorithm, data).expect("checked before searching")
+    }
+}
+
+/// The rsync rolling checksum of a block: two 16-bit sums of its bytes, one of them weighted by
+/// their distance from the end.
+#[derive(Copy, Clone)]
+pub(crate) struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    pub(crate) fn of(block: &[u8]) -> Self {
+        let len = block.len() as u32;
+        let (a, b) = block
+            .iter()
+            .enumerate()
+            .fold((0u32, 0u32), |(a, b), (i, &x)| {
+                let x = x as u32;
+                (
+                    a.wrapping_add(x),
+                    b.wrapping_add((len - i as u32).wrapping_mul(x)),
+                )
+            });
+        Rolling { a, b, len }
+    }
+
+    /// Moves the block one byte forward, from starting with `out` to ending with `in_`.
+    pub(crate) fn roll(&mut self, out: u8, in_: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(in_ as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    pub(crate) fn get(self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+pub(crate) fn strong(algorithm: ChecksumAlgorithm, data: &[u8]) -> Result<Box<[u8]>> {
+    match Checksum::of(algorithm, data) {
+        Some(checksum) => Ok(checksum.digest),
+        None => Err(DiffError::Internal(
+            format!("checksum algorithm {:?} is not supported", algorithm).into(),
+        )),
+    }
+}
+
+/// Computes the signature of `old`, split into blocks of `block_size` bytes, with `algorithm` as
+/// the strong checksum.
+///
+/// The signature takes 4 bytes plus the digest length for every block. Smaller blocks find more
+/// matches, but make the signature larger; something around the square root of the file size is a
+/// good start. The strong checksum has to be trusted to tell blocks apart, so a short one like
+/// CRC32 is only fine for small files.
+pub fn signature(old: &[u8], block_size: usize, algorithm: ChecksumAlgorithm) -> Result<Signature> {
+    let block_size = u32::try_from(block_size)
+        .ok()
+        .filter(|&size| size > 0)
+        .ok_or_else(|| DiffError::Internal("The block size must be between 1 and 2^32-1".into()))?;
+    let blocks = old
+        .chunks(block_size as usize)
+        .map(|block| {
+            Ok(Block {
+                weak: Rolling::of(block).get(),
+                strong: strong(algorithm, block)?,
+            })
+        })
+        .collect::<Result<_>>()?;
+    Ok(Signature {
+        block_size,
+        old_len: old.len() as u64,
+        algorithm,
+        blocks,
+    })
+}
+
+/// Generate a ddelta patch from the old file described by `signature` to `new`.
+///
+/// The output is applied with [`apply`][crate::apply] or [`apply_chunked`][crate::apply_chunked],
+/// to the old file the signature was computed from. Nothing checks that it's the right one, so
+/// compare a checksum of the new file after applying.
+pub fn delta(signature: &Signature, new: &[u8], patch: &mut impl Write) -> Result<()> {
+    // A parsed signature may use any algorithm
+    strong(signature.algorithm, &[])?;
+    let block_size = signature.block_size();
+    // A last block that's shorter can only match at the end of the new file
+    let tail = (!signature.old_len.is_multiple_of(block_size as u64)).then(|| {
+        let index = signature.blocks.len() - 1;
+        (index, (signature.old_len % block_size as u64) as usize)
+    });
+    let full = signature.blocks.len() - tail.map_or(0, |_| 1);
+    let mut by_weak: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (i, block) in signature.blocks[..full].iter().enumerate() {
+        by_weak.entry(block.weak).or_default().push(i);
+    }
+    write_header(patch, new.len() as u64)?;
+
+    let mut writer = Writer {
+        patch,
+        new,
+        // An empty entry that only seeks to wherever the first match is
+        pending: (0, 0, 0),
+    };
+    let mut pos = 0;
+    let mut rolling = (block_size <= new.len()).then(|| Rolling::of(&new[..block_size]));
+    while pos < new.len() {
+        if let Some((index, len)) = tail.filter(|&(_, len)| pos + len == new.len()) {
+            if signature.blocks[index].strong == signature.strong(&new[pos..]) {
+                writer.push(pos, index as u64 * block_size as u64, len)?;
+                break;
+            }
+        }
+        let Some(current) = &mut rolling else {
+            break;
+        };
+        let window = &new[pos..pos + block_size];
+        let found = by_weak.get(&current.get()).and_then(|candidates| {
+            let strong = signature.strong(window);
+            // Prefer the block right after the last match, which continues the same entry
+            let next = writer.next_old() / block_size as u64;
+            candidates
+                .iter()
+                .filter(|&&i| signature.blocks[i].strong == strong)
+                .min_by_key(|&&i| i as u64 != next)
+        });
+        match found {
+            Some(&index) => {
+                writer.push(pos, index as u64 * block_size as u64, block_size)?;
+                pos += block_size;
+                rolling =
+                    (pos + block_size <= new.len()).then(|| Rolling::of(&new[pos..][..block_size]));
+            }
+            None => {
+                if pos + block_size < new.len() {
+                    current.roll(new[pos], new[pos + block_size]);
+                } else {
+                    rolling = None;
+                }
+                pos += 1;
+            }
+        }
+    }
+    writer.finish()?;
+    write_ending(patch)?;
+    patch.flush()?;
+    Ok(())
+}
+
+/// Turns matches into entries, merging matches of neighbouring blocks.
+struct Writer<'a, W> {
+    patch: &'a mut W,
+    new: &'a [u8],
+    /// The entry that's being extended: where it starts in the new and old file, and the length
+    /// of its diff data.
+    pending: (usize, u64, usize),
+}
+
+impl<W: Write> Writer<'_, W> {
+    /// Where the diff data of the pending entry ends in the old file.
+    fn next_old(&self) -> u64 {
+        let (_, old, diff) = self.pending;
+        old + diff as u64
+    }
+
+    /// Adds a match of `len` bytes at `new` in the new file and `old` in the old file.
+    fn push(&mut self, new: usize, old: u64, len: usize) -> Result<()> {
+        let (start, _, diff) = self.pending;
+        if start + diff == new && self.next_old() == old {
+            self.pending.2 += len;
+            return Ok(());
+        }
+        self.write(new, old)?;
+        self.pending = (new, old, len);
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        let next_old = self.next_old();
+        self.write(self.new.len(), next_old)
+    }
+
+    /// Writes the pending entry, with the new file up to `next_new` as extra data and a seek to
+    /// `next_old`.
+    fn write(&mut self, next_new: usize, next_old: u64) -> Result<()> {
+        let (start, old, diff) = self.pending;
+        let extra = &self.new[start + diff..next_new];
+        let seek = next_old as i64 - (old + diff as u64) as i64;
+        // An empty entry would be read as the end of the patch
+        if Terminator::matches(diff as u64, extra.len() as u64, seek) {
+            return Ok(());
+        }
+        self.patch.write_all(
+            EntryHeader {
+                diff: U64::new(diff as u64),
+                extra: U64::new(extra.len() as u64),
+                seek: I64::new(seek),
+            }
+            .as_bytes(),
+        )?;
+        // The blocks are unchanged, so the difference is all zeros
+        io::copy(&mut io::repeat(0).take(diff as u64), self.patch)?;
+        self.patch.write_all(extra)?;
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "apply", feature = "xxhash64"))]
+mod test {
+    use std::io::Cursor;
+
+    use super::{delta, signature, Signature};
+    use crate::{apply, ChecksumAlgorithm};
+
+    #[test]
+    fn remote() {
+        let old: Vec<u8> = (0..10_000u32).map(|i| (i * i / 7) as u8).collect();
+        let mut new = [&old[..3001], b"inserted", &old[3001..6000], &old[7000..]].concat();
+        new[8000] ^= 1;
+        for new in [&new[..], &old, &old[..5000], b""] {
+            let signature = signature(&old, 256, ChecksumAlgorithm::XxHash64).unwrap();
+            let signature = Signature::from_bytes(&signature.to_bytes()).unwrap();
+            let mut patch = Vec::new();
+            delta(&signature, new, &mut patch).unwrap();
+            let mut out = Vec::new();
+            apply(&mut Cursor::new(&old), &mut out, &mut &patch[..]).unwrap();
+            assert_eq!(out, new);
+            // Only the blocks that were changed are stored
+            assert!(patch.iter().filter(|&&b| b != 0).count() < 1500);
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Cheap estimation of how similar two files are, to decide whether diffing them is worth it, and
+//! which old file a new one is best diffed against.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::Hasher;
+
+use crate::cdc::GEAR;
+
+/// How many of the smallest hashes are kept per file.
+const SKETCH_SIZE: usize = 256;
+
+/// Bottom-k MinHash sketch over the 64-byte windows of `data`.
+fn sketch(data: &[u8]) -> BTreeSet<u64> {
+    let mut sketch = BTreeSet::new();
+    let mut hash = 0u64;
+    for &b in data {
+        // The gear hash only depends on the last 64 bytes, so it doubles as a rolling hash
+        hash = (hash << 1).wrapping_add(GEAR[b as usize]);
+        let mixed = mix(hash);
+        if sketch.len() < SKETCH_SIZE {
+            sketch.insert(mixed);
+        } else if mixed < *sketch.last().unwrap() && sketch.insert(mixed) {
+            sketch.pop_last();
+        }
+    }
+    sketch
+}
+
+/// The gear hash's low bits only depend on the last few bytes, so spread them out before ordering.
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Estimates how similar `old` and `new` are, from 0 (nothing in common) to 1 (the same content).
+///
+/// This estimates the Jaccard similarity of the sets of 64-byte substrings of both files using
+/// MinHash, which takes a single pass over each file and very little memory. It is much cheaper
+/// than generating a patch, so it can be used to decide whether generating one is worth it.
+/// Rearranged content is still considered similar, as it can be reused by a patch.
+pub fn estimate_similarity(old: &[u8], new: &[u8]) -> f32 {
+    compare(&sketch(old), &sketch(new))
+}
+
+/// The similarity of the files the sketches are of, see [`estimate_similarity`]. Only empty files
+/// have empty sketches.
+fn compare(old: &BTreeSet<u64>, new: &BTreeSet<u64>) -> f32 {
+    if old.is_empty() || new.is_empty() {
+        return if old.is_empty() && new.is_empty() {
+            1.
+        } else {
+            0.
+        };
+    }
+    // The smallest hashes of the union, and how many of them are in both sketches
+    let union: BTreeSet<u64> = old.union(new).copied().take(SKETCH_SIZE).collect();
+    let both = union
+        .iter()
+        .filter(|h| old.contains(h) && new.contains(h))
+        .count();
+    both as f32 / union.len() as f32
+}
+
+/// Which old file a new file is paired with by [`pair_files`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Pairing {
+    /// The old file at this index has the same content, so the new file is a copy or a rename of
+    /// it and needs no patch.
+    Identical(usize),
+    /// The old file at this index is the most similar one, so the new file is best diffed
+    /// against it.
+    Similar(usize),
+    /// No old file is similar enough, so the new file is best distributed as a whole.
+    Unpaired,
+}
+
+/// Pairs each of the `new` files with an old file to diff it against, regardless of their names.
+///
+/// Files are paired with an old file with the same content first, found by hashing the files and
+/// comparing those with the same hash. The others are paired with the old file that is most similar
+/// according to [`estimate_similarity`], if that's at least `threshold`. An old file can be paired
+/// with several new files. This detects renamed and moved files when diffing directories, so a
+/// renamed file is stored as a reference to its old path instead of as a whole.
+pub fn pair_files(old: &[&[u8]], new: &[&[u8]], threshold: f32) -> Vec<Pairing> {
+    let hash = |data: &[u8]| {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(data);
+        hasher.finish()
+    };
+    let mut by_hash: HashMap<u64, Vec<usize>> = HashMap::new();
+    for (i, data) in old.iter().enumerate() {
+        by_hash.entry(hash(data)).or_default().push(i);
+    }
+    // Sketched lazily, as there's nothing to sketch if every file has an identical one
+    let mut old_sketches = None;
+    new.iter()
+        .map(|data| {
+            let identical = by_hash
+                .get(&hash(data))
+                .and_then(|candidates| candidates.iter().find(|&&i| old[i] == *data));
+            if let Some(&i) = identical {
+                return Pairing::Identical(i);
+            }
+            let old_sketches: &Vec<_> =
+                old_sketches.get_or_insert_with(|| old.iter().map(|data| sketch(data)).collect());
+            let new = sketch(data);
+            old_sketches
+                .iter()
+                .map(|old| compare(old, &new))
+                .enumerate()
+                .filter(|&(_, similarity)| similarity >= threshold)
+                .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map_or(Pairing::Unpaired, |(i, _)| Pairing::Similar(i))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{estimate_similarity, pair_files, Pairing};
+    use crate::test_util::Rng;
+
+    #[test]
+    fn similarity() {
+        let a: Vec<u8> = (0..100_000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        let b: Vec<u8> = (0..100_000u32)
+            .map(|i| (i.wrapping_mul(40_503) >> 7) as u8)
+            .collect();
+        assert_eq!(estimate_similarity(&a, &a), 1.);
+        assert!(estimate_similarity(&a, &b) < 0.05);
+        let mut half = a.clone();
+        half[50_000..].copy_from_slice(&b[50_000..]);
+        let s = estimate_similarity(&a, &half);
+        assert!(0.2 < s && s < 0.5, "{}", s);
+        assert_eq!(estimate_similarity(b"", b""), 1.);
+        assert_eq!(estimate_similarity(b"", b"x"), 0.);
+    }
+
+    #[test]
+    fn pairs() {
+        let a = Rng::new(1).bytes(20_000);
+        let b = Rng::new(2).bytes(20_000);
+        let c = Rng::new(3).bytes(20_000);
+        let edited = [&b[..15_000], b"edited", &b[15_100..]].concat();
+        let old: [&[u8]; 3] = [&a, &b, b""];
+        let new: [&[u8]; 5] = [&edited, &c, &a, b"", &a];
+        assert_eq!(
+            pair_files(&old, &new, 0.5),
+            [
+                Pairing::Similar(1),
+                Pairing::Unpaired,
+                Pairing::Identical(0),
+                Pairing::Identical(2),
+                Pairing::Identical(0),
+            ]
+        );
+        assert_eq!(pair_files(&[], &new[..1], 0.), [Pairing::Unpaired]);
+    }
+}
@@ -0,0 +1,145 @@
+//! Applying patches that are entirely in memory.
+
+use std::io::ErrorKind;
+use std::mem::size_of;
+
+use zerocopy::FromBytes;
+
+use crate::filter::{filter_of, filtered};
+use crate::patch::{add_diff, checked_offset, PatchError, Result};
+use crate::spec::Terminator;
+use crate::units::{Len, NewOffset};
+use crate::{
+    EntryHeader, ExtendedHeader, PatchHeader, COPY_FLAG, COPY_WINDOW, DDELTA_MAGIC,
+    DDELTA_MAGIC_V2, DDELTA_MAGIC_WORDS,
+};
+
+/// Splits `len` bytes off the start of `input`.
+fn split<'a>(input: &mut &'a [u8], len: u64) -> Result<&'a [u8]> {
+    let len = usize::try_from(len).map_err(|_| PatchError::OffsetOverflow)?;
+    if input.len() < len {
+        return Err(PatchError::Io(ErrorKind::UnexpectedEof.into()));
+    }
+    let (data, rest) = input.split_at(len);
+    *input = rest;
+    Ok(data)
+}
+
+fn read<T: FromBytes>(input: &mut &[u8]) -> Result<T> {
+    let data = split(input, size_of::<T>() as u64)?;
+    T::read_from(data).ok_or_else(|| PatchError::Internal("Bytes not aligned".into()))
+}
+
+/// Apply a patch that is in memory to an old file that is in memory, appending the new file to
+/// `out`. This accepts the same patches as [`apply_chunked`][crate::apply_chunked].
+///
+/// This works directly on the slices, without buffers, readers or writers in between, so it is the
+/// fastest way to apply many small patches. Reuse `out` (after clearing it) to avoid allocating for
+/// every patch. If this fails, `out` may contain part of the new file.
+pub fn apply_slice(old: &[u8], mut patch: &[u8], out: &mut Vec<u8>) -> Result<()> {
+    let start = out.len();
+    // The size of the new file so far
+    let mut bytes_written = NewOffset::default();
+    if ExtendedHeader::matches(patch) {
+        if filter_of(patch).is_some() {
+            return Err(filtered());
+        }
+        split(&mut patch, size_of::<ExtendedHeader>() as u64)?;
+    }
+    while !patch.is_empty() {
+        let header = read_header(&mut patch)?;
+        bytes_written = apply_chunk(old, &mut patch, &header, out, start, bytes_written)?;
+    }
+    Ok(())
+}
+
+/// Reads the header of a chunk, checking its magic number.
+pub(crate) fn read_header(patch: &mut &[u8]) -> Result<PatchHeader> {
+    let header: PatchHeader = read(patch)?;
+    match &header.magic {
+        DDELTA_MAGIC | DDELTA_MAGIC_V2 | DDELTA_MAGIC_WORDS => Ok(header),
+        _ => Err(PatchError::BadMagic),
+    }
+}
+
+/// Applies the entries of the chunk that `header` starts, where `start` is where the new file
+/// starts in `out`, and `chunk_start` is where the chunk starts in it. Returns where the chunk
+/// ends.
+pub(crate) fn apply_chunk(
+    old: &[u8],
+    patch: &mut &[u8],
+    header: &PatchHeader,
+    out: &mut Vec<u8>,
+    start: usize,
+    chunk_start: NewOffset,
+) -> Result<NewOffset> {
+    let copies = &header.magic == DDELTA_MAGIC_V2;
+    let words = &header.magic == DDELTA_MAGIC_WORDS;
+    let chunk_end = checked_offset(chunk_start, header.new_file_size.get(), 0)?;
+    let written = |out: &Vec<u8>| NewOffset::new((out.len() - start) as u64);
+    // The new file can't be larger than the old file and patch together, apart from copies
+    let limit = (old.len() + patch.len()) as u64;
+    out.reserve(header.new_file_size.get().min(limit) as usize);
+    let mut pos = chunk_start.in_old();
+    loop {
+        let entry: EntryHeader = read(patch)?;
+        let (diff, extra, seek) = (entry.diff.get(), entry.extra.get(), entry.seek.get());
+        if Terminator::matches(diff, extra, seek) {
+            if written(out) != chunk_end {
+                return Err(PatchError::Internal(
+                    "Terminator before the end of the chunk".into(),
+                ));
+            }
+            return Ok(chunk_end);
+        }
+        if copies && diff & COPY_FLAG != 0 {
+            let len = diff & !COPY_FLAG;
+            let distance = extra;
+            if checked_offset(written(out), len, 0)? > chunk_end {
+                return Err(PatchError::Internal(
+                    "Entry past the end of the chunk".into(),
+                ));
+            }
+            if distance == 0
+                || Len::new(distance) > written(out).since(chunk_start).unwrap_or_default()
+                || distance > COPY_WINDOW
+            {
+                return Err(PatchError::Internal(
+                    "Copy from outside of the new file".into(),
+                ));
+            }
+            let mut remaining = usize::try_from(len).map_err(|_| PatchError::OffsetOverflow)?;
+            // A copy may repeat what it just wrote, so copy at most `distance` bytes at a time
+            while remaining > 0 {
+                let from = out.len() - distance as usize;
+                let n = remaining.min(distance as usize);
+                out.extend_from_within(from..from + n);
+                remaining -= n;
+            }
+        } else {
+            if checked_offset(written(out), diff, extra)? > chunk_end {
+                return Err(PatchError::Internal(
+                    "Entry past the end of the chunk".into(),
+                ));
+            }
+            // Chunks that don't use the old file may start past its end
+            let old = match diff {
+                0 => &[],
+                _ => usize::try_from(pos.get())
+                    .ok()
+                    .zip(usize::try_from(diff).ok())
+                    .and_then(|(pos, diff)| old.get(pos..)?.get(..diff))
+                    .ok_or(PatchError::Io(ErrorKind::UnexpectedEof.into()))?,
+            };
+            let diff = split(patch, diff)?;
+            let at = out.len();
+            out.extend_from_slice(old);
+            add_diff(&mut out[at..], diff, words);
+            out.extend_from_slice(split(patch, extra)?);
+            pos = pos
+                .checked_add(Len::new(diff.len() as u64))
+                .ok_or(PatchError::OffsetOverflow)?;
+        }
+        pos = pos.seek(seek)?;
+    }
+}
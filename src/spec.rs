@@ -0,0 +1,749 @@
+//! The patch format, written down as a parser.
+//!
+//! ```text
+//! chunked-patch = ext-header? chunk*
+//! patch         = ext-header? chunk
+//! ext-header    = ("DDELTAEX" | "DDELTAF" filter) tag:byte{4} flags:u32
+//! filter        = "1" | "2"                                     ; x86, ARM
+//! chunk         = header entry* terminator
+//!               | header-v2 (entry | copy)* terminator
+//!               | header-words entry* terminator
+//! header        = "DDELTA40" new-size:u64
+//! header-v2     = "DDELTA41" new-size:u64
+//! header-words  = "DDELTA42" new-size:u64
+//! entry         = diff:u64 extra:u64 seek:i64 diff-bytes extra-bytes  ; not all three zero
+//! copy          = (2^63 + len):u64 distance:u64 seek:i64
+//! diff-bytes    = byte{diff}
+//! extra-bytes   = byte{extra}
+//! terminator    = 0:u64 0:u64 0:i64
+//! ```
+//!
+//! All integers are big-endian, on every target, and there's no padding. A `header`, `header-v2`,
+//! `header-words` or `ext-header` takes [`PATCH_HEADER_SIZE`] bytes, and the three integers at the start of an
+//! `entry`, a `copy` or the `terminator` take [`ENTRY_HEADER_SIZE`] bytes. The `diff` and `extra` lengths of a chunk's entries must add up to
+//! the chunk's `new-size`. A plain patch is read up to its terminator; the original ddelta tool
+//! ignores anything after it.
+//!
+//! Each entry adds its `diff-bytes` to the next `diff` bytes of the old file (wrapping), appends the
+//! `extra-bytes` as-is, then moves the position in the old file by `seek`. In a chunked patch, the
+//! old file position is reset at the start of each chunk to the number of bytes written so far.
+//!
+//! The `terminator` is what an entry that writes nothing and doesn't seek would look like, so such
+//! entries can't be written; they would do nothing anyway. [`Terminator`] tells the two apart.
+//!
+//! The extended header is written by [`PatchBuilder`][crate::PatchBuilder], and is only allowed at
+//! the very start. Its tag and flags are defined by the application, and don't affect applying.
+//! With the `DDELTAF` magic, both files were converted with the [`Filter`][crate::Filter] whose id
+//! follows it, and the new file the patch makes has to be converted back.
+//!
+//! Chunks with the second header may also contain copies, which are told apart from entries by the
+//! top bit of their first field. A copy appends `len` bytes, starting `distance` bytes before the
+//! end of what this chunk has written so far, one byte at a time (so a copy may overlap with its
+//! own output), then seeks like an entry does. `distance` must be between 1 and 4 MiB, and not
+//! reach before the start of the chunk. Copies count towards `new-size` with their `len`.
+//!
+//! Chunks with the third header add the `diff-bytes` of each entry in 32-bit words instead. From
+//! the start of an entry's `diff-bytes`, each group of 4 is read as a little-endian `u32` and added
+//! (wrapping) to the next 4 bytes of the old file, read the same way, and the last 1 to 3 bytes are
+//! added one at a time. Relative addresses in machine code, which all change by the same amount
+//! when the code between them grows, then have the same `diff-bytes` instead of ones that depend on
+//! carries.
+//!
+//! The parsers in this module accept exactly this grammar and nothing else, and
+//! [`conformance_vectors`] provides a set of valid and invalid patches with their expected results,
+//! so that other implementations can be tested against this one.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crate::{
+    ExtendedHeader, COPY_FLAG, COPY_WINDOW, DDELTA_MAGIC, DDELTA_MAGIC_EXT, DDELTA_MAGIC_V2,
+    DDELTA_MAGIC_WORDS,
+};
+
+/// The size of a `header`, `header-v2`, `header-words` or `ext-header`, in bytes.
+pub const PATCH_HEADER_SIZE: usize = 16;
+/// The size of the `diff`, `extra` and `seek` fields of an `entry`, a `copy` or the `terminator`,
+/// in bytes.
+pub const ENTRY_HEADER_SIZE: usize = 24;
+
+/// The `terminator` at the end of every chunk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Terminator;
+
+impl Terminator {
+    /// The terminator as it's written.
+    pub const BYTES: [u8; ENTRY_HEADER_SIZE] = [0; ENTRY_HEADER_SIZE];
+
+    /// Whether the fields of an `entry` or `copy` are the terminator instead. Writers have to
+    /// leave out entries for which this is true.
+    pub fn matches(diff: u64, extra: u64, seek: i64) -> bool {
+        diff == 0 && extra == 0 && seek == 0
+    }
+
+    /// Writes the terminator.
+    pub fn write(patch: &mut impl Write) -> io::Result<()> {
+        patch.write_all(&Self::BYTES)
+    }
+
+    /// Reads the `diff`, `extra` and `seek` fields that start an entry, a copy or the terminator,
+    /// and returns them, or [`None`] for the terminator.
+    pub fn read(patch: &mut impl Read) -> io::Result<Option<(u64, u64, i64)>> {
+        let mut fields = [0; ENTRY_HEADER_SIZE];
+        patch.read_exact(&mut fields)?;
+        let field = |i: usize| <[u8; 8]>::try_from(&fields[i * 8..][..8]).unwrap();
+        let (diff, extra) = (u64::from_be_bytes(field(0)), u64::from_be_bytes(field(1)));
+        let seek = i64::from_be_bytes(field(2));
+        Ok((!Self::matches(diff, extra, seek)).then_some((diff, extra, seek)))
+    }
+}
+
+/// Where and why a patch doesn't match the grammar.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpecError {
+    pub offset: usize,
+    pub kind: SpecErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpecErrorKind {
+    UnexpectedEof,
+    /// The patch ends where an entry or the terminator of a chunk should start.
+    MissingTerminator,
+    BadMagic,
+    SizeMismatch {
+        declared: u64,
+        actual: u64,
+    },
+    InvalidCopy {
+        distance: u64,
+    },
+}
+
+impl fmt::Display for SpecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid patch at byte {}: {}", self.offset, self.kind)
+    }
+}
+
+impl std::error::Error for SpecError {}
+
+impl fmt::Display for SpecErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpecErrorKind::UnexpectedEof => f.write_str("unexpected end of patch"),
+            SpecErrorKind::MissingTerminator => f.write_str("patch ends before the terminator"),
+            SpecErrorKind::BadMagic => f.write_str("invalid magic number"),
+            SpecErrorKind::SizeMismatch { declared, actual } => write!(
+                f,
+                "terminator after {actual} bytes of entries, but the header declares {declared}"
+            ),
+            SpecErrorKind::InvalidCopy { distance } => write!(
+                f,
+                "copy from {distance} bytes back is outside of the new file"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SpecErrorKind {}
+
+/// A chunk of a patch, borrowing its data from the input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk<'a> {
+    pub new_file_size: u64,
+    /// Whether the diff data is added in 32-bit words, which is the `header-words` header.
+    pub words: bool,
+    pub entries: Vec<Entry<'a>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry<'a> {
+    pub diff: &'a [u8],
+    pub extra: &'a [u8],
+    pub seek: i64,
+    /// For copies, the `len` and `distance`. `diff` and `extra` are empty then.
+    pub copy: Option<(u64, u64)>,
+}
+
+/// The failure case only keeps how much input was left, which is turned into an offset at the top.
+type PResult<'a, T> = Result<(&'a [u8], T), (usize, SpecErrorKind)>;
+
+fn take(n: u64) -> impl Fn(&[u8]) -> PResult<&[u8]> {
+    move |input| match usize::try_from(n) {
+        Ok(n) if n <= input.len() => Ok((&input[n..], &input[..n])),
+        _ => Err((input.len(), SpecErrorKind::UnexpectedEof)),
+    }
+}
+
+fn tag(expected: &'static [u8], kind: SpecErrorKind) -> impl Fn(&[u8]) -> PResult<()> {
+    move |input| {
+        let (rest, bytes) = take(expected.len() as u64)(input)?;
+        if bytes == expected {
+            Ok((rest, ()))
+        } else {
+            Err((input.len(), kind.clone()))
+        }
+    }
+}
+
+fn be_u64(input: &[u8]) -> PResult<'_, u64> {
+    let (rest, bytes) = take(8)(input)?;
+    Ok((rest, u64::from_be_bytes(bytes.try_into().unwrap())))
+}
+
+fn be_i64(input: &[u8]) -> PResult<'_, i64> {
+    let (rest, bytes) = take(8)(input)?;
+    Ok((rest, i64::from_be_bytes(bytes.try_into().unwrap())))
+}
+
+/// Parses a header, returning the new size, whether copies are allowed, and whether the diff data
+/// is added in words.
+fn header(input: &[u8]) -> PResult<'_, (u64, bool, bool)> {
+    let (input, copies, words) = match tag(DDELTA_MAGIC, SpecErrorKind::BadMagic)(input) {
+        Ok((input, ())) => (input, false, false),
+        Err(_) => match tag(DDELTA_MAGIC_V2, SpecErrorKind::BadMagic)(input) {
+            Ok((input, ())) => (input, true, false),
+            Err(_) => (
+                tag(DDELTA_MAGIC_WORDS, SpecErrorKind::BadMagic)(input)?.0,
+                false,
+                true,
+            ),
+        },
+    };
+    let (input, new_file_size) = be_u64(input)?;
+    Ok((input, (new_file_size, copies, words)))
+}
+
+/// Skips the extended header, if there is one.
+fn ext_header(input: &[u8]) -> PResult<'_, ()> {
+    match ExtendedHeader::matches(input) {
+        true => Ok((take(PATCH_HEADER_SIZE as u64)(input)?.0, ())),
+        false => Ok((input, ())),
+    }
+}
+
+/// Parses either an entry, or the terminator as `None`. Copies are checked against the `written`
+/// bytes of the chunk so far.
+fn entry(copies: bool, written: u64) -> impl Fn(&[u8]) -> PResult<Option<Entry>> {
+    move |start| {
+        if start.is_empty() {
+            return Err((0, SpecErrorKind::MissingTerminator));
+        }
+        let (input, diff) = be_u64(start)?;
+        let (input, extra) = be_u64(input)?;
+        let (input, seek) = be_i64(input)?;
+        if Terminator::matches(diff, extra, seek) {
+            return Ok((input, None));
+        }
+        if copies && diff & COPY_FLAG != 0 {
+            let distance = extra;
+            if distance == 0 || distance > written || distance > COPY_WINDOW {
+                return Err((start.len(), SpecErrorKind::InvalidCopy { distance }));
+            }
+            let entry = Entry {
+                diff: &[],
+                extra: &[],
+                seek,
+                copy: Some((diff & !COPY_FLAG, distance)),
+            };
+            return Ok((input, Some(entry)));
+        }
+        let (input, diff) = take(diff)(input)?;
+        let (input, extra) = take(extra)(input)?;
+        let entry = Entry {
+            diff,
+            extra,
+            seek,
+            copy: None,
+        };
+        Ok((input, Some(entry)))
+    }
+}
+
+fn chunk(input: &[u8]) -> PResult<'_, Chunk<'_>> {
+    let (mut input, (new_file_size, copies, words)) = header(input)?;
+    let mut entries = Vec::new();
+    let mut actual: u64 = 0;
+    loop {
+        let (rest, entry) = entry(copies, actual)(input)?;
+        let Some(entry) = entry else {
+            if actual != new_file_size {
+                return Err((
+                    input.len(),
+                    SpecErrorKind::SizeMismatch {
+                        declared: new_file_size,
+                        actual,
+                    },
+                ));
+            }
+            return Ok((
+                rest,
+                Chunk {
+                    new_file_size,
+                    words,
+                    entries,
+                },
+            ));
+        };
+        let len = match entry.copy {
+            Some((len, _)) => len,
+            None => entry.diff.len() as u64 + entry.extra.len() as u64,
+        };
+        actual = actual.saturating_add(len);
+        entries.push(entry);
+        input = rest;
+    }
+}
+
+fn located<'a, T>(input: &[u8], result: PResult<'a, T>) -> Result<(&'a [u8], T), SpecError> {
+    result.map_err(|(remaining, kind)| SpecError {
+        offset: input.len() - remaining,
+        kind,
+    })
+}
+
+/// Parses a plain patch, as read by [`apply`][crate::apply]. Anything after the terminator is
+/// ignored.
+pub fn parse_patch(input: &[u8]) -> Result<Chunk<'_>, SpecError> {
+    let rest = located(input, ext_header(input))?.0;
+    located(input, chunk(rest)).map(|(_, chunk)| chunk)
+}
+
+/// Parses a chunked patch, as read by [`apply_chunked`][crate::apply_chunked].
+pub fn parse_chunked(input: &[u8]) -> Result<Vec<Chunk<'_>>, SpecError> {
+    let mut chunks = Vec::new();
+    let mut rest = located(input, ext_header(input))?.0;
+    while !rest.is_empty() {
+        let (r, chunk) = located(input, chunk(rest))?;
+        chunks.push(chunk);
+        rest = r;
+    }
+    Ok(chunks)
+}
+
+/// A test case for implementations of the format.
+#[derive(Debug, Clone)]
+pub struct Vector {
+    pub name: &'static str,
+    /// Whether this is a chunked patch, or a plain one.
+    pub chunked: bool,
+    pub old: Vec<u8>,
+    pub patch: Vec<u8>,
+    /// The new file, or [`None`] if the patch must be rejected.
+    pub expected: Option<Vec<u8>>,
+}
+
+pub(crate) fn encode_header(new_file_size: u64) -> Vec<u8> {
+    let mut out = DDELTA_MAGIC.to_vec();
+    out.extend_from_slice(&new_file_size.to_be_bytes());
+    out
+}
+
+pub(crate) fn encode_header_v2(new_file_size: u64) -> Vec<u8> {
+    let mut out = DDELTA_MAGIC_V2.to_vec();
+    out.extend_from_slice(&new_file_size.to_be_bytes());
+    out
+}
+
+pub(crate) fn encode_header_words(new_file_size: u64) -> Vec<u8> {
+    let mut out = DDELTA_MAGIC_WORDS.to_vec();
+    out.extend_from_slice(&new_file_size.to_be_bytes());
+    out
+}
+
+pub(crate) fn encode_ext_header(tag: [u8; 4], flags: u32) -> Vec<u8> {
+    let mut out = DDELTA_MAGIC_EXT.to_vec();
+    out.extend_from_slice(&tag);
+    out.extend_from_slice(&flags.to_be_bytes());
+    out
+}
+
+pub(crate) fn encode_copy(len: u64, distance: u64, seek: i64) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&(COPY_FLAG | len).to_be_bytes());
+    out.extend_from_slice(&distance.to_be_bytes());
+    out.extend_from_slice(&seek.to_be_bytes());
+    out
+}
+
+pub(crate) fn encode_entry(diff: &[u8], extra: &[u8], seek: i64) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&(diff.len() as u64).to_be_bytes());
+    out.extend_from_slice(&(extra.len() as u64).to_be_bytes());
+    out.extend_from_slice(&seek.to_be_bytes());
+    out.extend_from_slice(diff);
+    out.extend_from_slice(extra);
+    out
+}
+
+/// Valid and invalid patches, together with what applying them has to result in.
+pub fn conformance_vectors() -> Vec<Vector> {
+    let valid = |name, chunked, old: &[u8], patch: Vec<Vec<u8>>, new: &[u8]| Vector {
+        name,
+        chunked,
+        old: old.to_vec(),
+        patch: patch.concat(),
+        expected: Some(new.to_vec()),
+    };
+    let invalid = |name, chunked, old: &[u8], patch: Vec<Vec<u8>>| Vector {
+        name,
+        chunked,
+        old: old.to_vec(),
+        patch: patch.concat(),
+        expected: None,
+    };
+    vec![
+        valid(
+            "empty",
+            false,
+            b"old",
+            vec![encode_header(0), Terminator::BYTES.to_vec()],
+            b"",
+        ),
+        valid(
+            "extra only",
+            false,
+            b"",
+            vec![
+                encode_header(3),
+                encode_entry(b"", b"xyz", 0),
+                Terminator::BYTES.to_vec(),
+            ],
+            b"xyz",
+        ),
+        valid(
+            "diff adds and wraps",
+            false,
+            &[b'a', b'b', 0xff],
+            vec![
+                encode_header(3),
+                encode_entry(&[1, 0, 2], b"", 0),
+                Terminator::BYTES.to_vec(),
+            ],
+            &[b'b', b'b', 1],
+        ),
+        valid(
+            "negative seek",
+            false,
+            b"abcdef",
+            vec![
+                encode_header(4),
+                encode_entry(&[0, 0], b"", -2),
+                encode_entry(&[0, 0], b"", 0),
+                Terminator::BYTES.to_vec(),
+            ],
+            b"abab",
+        ),
+        valid(
+            "seek only entry",
+            false,
+            b"abcdef",
+            vec![
+                encode_header(3),
+                encode_entry(b"", b"", 3),
+                encode_entry(&[0, 0, 0], b"", 0),
+                Terminator::BYTES.to_vec(),
+            ],
+            b"def",
+        ),
+        valid(
+            "trailing data after plain patch",
+            false,
+            b"",
+            vec![
+                encode_header(1),
+                encode_entry(b"", b"x", 0),
+                Terminator::BYTES.to_vec(),
+                b"garbage".to_vec(),
+            ],
+            b"x",
+        ),
+        valid("no chunks", true, b"old", vec![], b""),
+        valid(
+            "chunks restart at the new offset",
+            true,
+            b"abcdef",
+            vec![
+                encode_header(2),
+                encode_entry(&[0, 0], b"", 2),
+                Terminator::BYTES.to_vec(),
+                encode_header(3),
+                encode_entry(&[0, 0], b"!", 0),
+                Terminator::BYTES.to_vec(),
+            ],
+            b"abcd!",
+        ),
+        valid(
+            "overlapping copy",
+            false,
+            b"abc",
+            vec![
+                encode_header_v2(9),
+                encode_entry(&[0, 0], b"", 0),
+                encode_copy(5, 2, -1),
+                encode_entry(&[0, 0], b"", 0),
+                Terminator::BYTES.to_vec(),
+            ],
+            b"ababababc",
+        ),
+        valid(
+            "copies only apply within their chunk",
+            true,
+            b"ab",
+            vec![
+                encode_header_v2(2),
+                encode_entry(&[0, 0], b"", 0),
+                Terminator::BYTES.to_vec(),
+                encode_header_v2(3),
+                encode_entry(b"", b"x", 0),
+                encode_copy(2, 1, 0),
+                Terminator::BYTES.to_vec(),
+            ],
+            b"abxxx",
+        ),
+        valid(
+            "word diffs carry within a word, but not within the rest",
+            false,
+            &[0xff, 0, 0, 0, 0xff, b'x'],
+            vec![
+                encode_header_words(7),
+                encode_entry(&[1, 0, 0, 0, 1, 0], b"!", 0),
+                Terminator::BYTES.to_vec(),
+            ],
+            &[0, 1, 0, 0, 0, b'x', b'!'],
+        ),
+        invalid(
+            "bad magic",
+            false,
+            b"",
+            vec![
+                b"DDELTA39".to_vec(),
+                0u64.to_be_bytes().to_vec(),
+                Terminator::BYTES.to_vec(),
+            ],
+        ),
+        invalid(
+            "truncated header",
+            false,
+            b"",
+            vec![encode_header(0)[..10].to_vec()],
+        ),
+        invalid("missing terminator", false, b"", vec![encode_header(0)]),
+        invalid(
+            "truncated entry data",
+            false,
+            b"abcd",
+            vec![
+                encode_header(4),
+                encode_entry(&[0, 0, 0, 0], b"", 0)[..26].to_vec(),
+            ],
+        ),
+        invalid(
+            "entries shorter than header",
+            false,
+            b"",
+            vec![
+                encode_header(5),
+                encode_entry(b"", b"abc", 0),
+                Terminator::BYTES.to_vec(),
+            ],
+        ),
+        invalid(
+            "entries longer than header",
+            false,
+            b"",
+            vec![
+                encode_header(2),
+                encode_entry(b"", b"abc", 0),
+                Terminator::BYTES.to_vec(),
+            ],
+        ),
+        invalid(
+            "copy before the start",
+            false,
+            b"",
+            vec![
+                encode_header_v2(3),
+                encode_entry(b"", b"x", 0),
+                encode_copy(2, 2, 0),
+                Terminator::BYTES.to_vec(),
+            ],
+        ),
+        invalid(
+            "copy past the end of the chunk",
+            false,
+            b"",
+            vec![
+                encode_header_v2(3),
+                encode_entry(b"", b"x", 0),
+                encode_copy(1 << 62, 1, 0),
+                Terminator::BYTES.to_vec(),
+            ],
+        ),
+        invalid(
+            "copy in a version 1 patch",
+            false,
+            b"",
+            vec![
+                encode_header(3),
+                encode_entry(b"", b"x", 0),
+                encode_copy(2, 1, 0),
+                Terminator::BYTES.to_vec(),
+            ],
+        ),
+        valid(
+            "extended header",
+            false,
+            b"ab",
+            vec![
+                encode_ext_header(*b"TAG!", 7),
+                encode_header(2),
+                encode_entry(&[0, 1], b"", 0),
+                Terminator::BYTES.to_vec(),
+            ],
+            b"ac",
+        ),
+        invalid(
+            "extended header with an unknown filter",
+            false,
+            b"",
+            vec![
+                [&b"DDELTAF9"[..], b"TAG!", &7u32.to_be_bytes()].concat(),
+                encode_header(0),
+                Terminator::BYTES.to_vec(),
+            ],
+        ),
+        invalid(
+            "extended header after the first chunk",
+            true,
+            b"",
+            vec![
+                encode_header(0),
+                Terminator::BYTES.to_vec(),
+                encode_ext_header(*b"TAG!", 7),
+                encode_header(0),
+                Terminator::BYTES.to_vec(),
+            ],
+        ),
+        invalid(
+            "copy in a word diff patch",
+            false,
+            b"",
+            vec![
+                encode_header_words(3),
+                encode_entry(b"", b"x", 0),
+                encode_copy(2, 1, 0),
+                Terminator::BYTES.to_vec(),
+            ],
+        ),
+        invalid(
+            "partial chunk header",
+            true,
+            b"",
+            vec![
+                encode_header(0),
+                Terminator::BYTES.to_vec(),
+                b"DDEL".to_vec(),
+            ],
+        ),
+    ]
+}
+
+#[cfg(all(test, feature = "apply"))]
+mod test {
+    use std::io::Cursor;
+
+    use crate::be::{I64, U64};
+    use zerocopy::AsBytes;
+
+    use super::{
+        conformance_vectors, parse_chunked, parse_patch, SpecErrorKind, Terminator, Vector,
+    };
+    use crate::{
+        apply, apply_chunked, apply_slice, EntryHeader, OldSource, PatchError, SliceSource,
+    };
+
+    fn apply_vector(vector: &Vector, old: &mut impl OldSource) -> Result<Vec<u8>, PatchError> {
+        let mut new = Vec::new();
+        if vector.chunked {
+            apply_chunked(old, &mut new, &mut &vector.patch[..])?;
+        } else {
+            apply(old, &mut new, &mut &vector.patch[..])?;
+        }
+        Ok(new)
+    }
+
+    #[test]
+    fn conformance() {
+        for vector in conformance_vectors() {
+            let parsed = if vector.chunked {
+                parse_chunked(&vector.patch).map(drop)
+            } else {
+                parse_patch(&vector.patch).map(drop)
+            };
+            let applied = apply_vector(&vector, &mut Cursor::new(&vector.old));
+            let from_slice = apply_vector(&vector, &mut SliceSource::new(&vector.old));
+            let mut in_memory = Vec::new();
+            let in_memory =
+                apply_slice(&vector.old, &vector.patch, &mut in_memory).map(|()| in_memory);
+            match &vector.expected {
+                Some(expected) => {
+                    assert_eq!(parsed, Ok(()), "{}", vector.name);
+                    assert_eq!(applied.as_ref().ok(), Some(expected), "{}", vector.name);
+                    assert_eq!(from_slice.as_ref().ok(), Some(expected), "{}", vector.name);
+                    // Plain patches are only valid chunked patches without trailing data
+                    if vector.chunked || parse_chunked(&vector.patch).is_ok() {
+                        assert_eq!(in_memory.as_ref().ok(), Some(expected), "{}", vector.name);
+                    }
+                }
+                None => {
+                    assert!(parsed.is_err(), "{}", vector.name);
+                    assert!(applied.is_err(), "{}", vector.name);
+                    assert!(from_slice.is_err(), "{}", vector.name);
+                    assert!(in_memory.is_err(), "{}", vector.name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn byte_order() {
+        // Written out byte by byte, as any target produces it
+        let patch = [
+            *b"DDELTA40",
+            [0, 0, 0, 0, 0, 0, 0, 3],
+            [0, 0, 0, 0, 0, 0, 0, 2],
+            [0, 0, 0, 0, 0, 0, 0, 1],
+            [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe],
+        ]
+        .concat();
+        let patch = [&patch[..], &[1, 1], b"!", &[0; 24]].concat();
+        let mut new = Vec::new();
+        apply(&mut Cursor::new(b"ab"), &mut new, &mut &patch[..]).unwrap();
+        assert_eq!(new, b"bc!");
+        let header = EntryHeader {
+            diff: U64::new(2),
+            extra: U64::new(1),
+            seek: I64::new(-2),
+        };
+        assert_eq!(header.as_bytes(), &patch[16..40]);
+    }
+
+    #[test]
+    fn terminator() {
+        let entry = [0u64, 0, 1].map(u64::to_be_bytes).concat();
+        let mut patch = [&entry[..], &Terminator::BYTES].concat();
+        let mut read = &patch[..];
+        assert_eq!(Terminator::read(&mut read).unwrap(), Some((0, 0, 1)));
+        assert_eq!(Terminator::read(&mut read).unwrap(), None);
+        assert!(Terminator::read(&mut read).is_err());
+
+        // Cut off right before the terminator, or in the middle of it
+        patch.splice(..0, super::encode_header(0));
+        let kind = |patch| parse_patch(patch).unwrap_err().kind;
+        assert_eq!(kind(&patch[..40]), SpecErrorKind::MissingTerminator);
+        assert_eq!(kind(&patch[..50]), SpecErrorKind::UnexpectedEof);
+        assert!(parse_patch(&patch).is_ok());
+    }
+}
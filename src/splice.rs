@@ -0,0 +1,176 @@
+//! Replacing a part of the new file of a chunked patch, regenerating only the chunks around it.
+
+use std::io::Write;
+use std::ops::Range;
+
+use crate::diff::{DiffError, Result};
+use crate::rechunk::patch_error;
+use crate::spec::{self, ENTRY_HEADER_SIZE, PATCH_HEADER_SIZE};
+use crate::{ChunkedPatchReader, ChunkedPatchWriter, DiffOptions, ExtendedHeader, SliceSource};
+
+/// Changes `patch_in`, a (chunked or plain) patch from `old`, to make a new file in which `range`
+/// is replaced by `new_segment`, and writes the result to `patch_out` as a chunked patch.
+///
+/// When one small file inside a large image changes, regenerating the whole patch takes as long as
+/// the first time. This only applies and diffs the chunks of `patch_in` that overlap `range`,
+/// against the part of `old` they were made from, in pieces as large as the largest of them. The
+/// chunks before are copied as they are, and so are the ones after, with a seek in front if
+/// `new_segment` has a different length than `range`. The extended header of `patch_in` is kept. A
+/// plain patch is a single chunk, so all of it is regenerated.
+///
+/// Errors in `patch_in` are returned as [`DiffError::Internal`], or [`DiffError::Io`] for I/O
+/// errors.
+pub fn splice(
+    patch_in: &[u8],
+    range: Range<u64>,
+    old: &[u8],
+    new_segment: &[u8],
+    patch_out: &mut impl Write,
+) -> Result<()> {
+    let chunks =
+        spec::parse_chunked(patch_in).map_err(|e| DiffError::Internal(e.to_string().into()))?;
+    // Where each chunk is in the patch and in the new file
+    let mut pos = match ExtendedHeader::matches(patch_in) {
+        true => PATCH_HEADER_SIZE,
+        false => 0,
+    };
+    let mut new_pos = 0;
+    let mut layout = Vec::with_capacity(chunks.len());
+    for chunk in &chunks {
+        let data: usize = chunk
+            .entries
+            .iter()
+            .map(|entry| ENTRY_HEADER_SIZE + entry.diff.len() + entry.extra.len())
+            .sum();
+        let len = PATCH_HEADER_SIZE + data + ENTRY_HEADER_SIZE;
+        layout.push((pos..pos + len, new_pos..new_pos + chunk.new_file_size));
+        pos += len;
+        new_pos += chunk.new_file_size;
+    }
+    if range.start > range.end || range.end > new_pos {
+        return Err(DiffError::Internal(
+            format!("{range:?} is outside of the new file of {new_pos} bytes").into(),
+        ));
+    }
+
+    // The chunks that change, which for an insertion is the one it's inserted into
+    let affected = |new: &Range<u64>| match range.is_empty() {
+        true => new.contains(&range.start),
+        false => new.start < range.end && range.start < new.end,
+    };
+    let first = layout.iter().position(|(_, new)| affected(new));
+    let last = layout.iter().rposition(|(_, new)| affected(new));
+    let (region, after) = match (first, last) {
+        (Some(first), Some(last)) => (first..last + 1, last + 1),
+        // Inserted at the end
+        _ => (layout.len()..layout.len(), layout.len()),
+    };
+    let region_new = match region.is_empty() {
+        true => range.clone(),
+        false => layout[region.start].1.start..layout[region.end - 1].1.end,
+    };
+
+    // The new file of the region, with `range` replaced
+    let mut applied = Vec::new();
+    let mut reader = ChunkedPatchReader::new(patch_in);
+    let mut index = 0;
+    while let Some(chunk) = reader.next_chunk().map_err(patch_error)? {
+        if region.contains(&index) {
+            chunk
+                .apply(&mut SliceSource::new(old), &mut applied)
+                .map_err(patch_error)?;
+        } else if index >= region.end {
+            break;
+        }
+        index += 1;
+    }
+    let (start, end) = (
+        (range.start - region_new.start) as usize,
+        (range.end - region_new.start) as usize,
+    );
+    applied.splice(start..end, new_segment.iter().copied());
+
+    patch_out.write_all(&patch_in[..layout.first().map_or(0, |(raw, _)| raw.start)])?;
+    let mut writer = ChunkedPatchWriter::new(patch_out, DiffOptions::new());
+    for (raw, new) in &layout[..region.start] {
+        writer.push_raw(&patch_in[raw.clone()], new.end - new.start)?;
+    }
+    let piece = layout[region.clone()]
+        .iter()
+        .map(|(_, new)| (new.end - new.start) as usize)
+        .max()
+        .unwrap_or(applied.len())
+        .max(1);
+    let old_window =
+        (region_new.start as usize).min(old.len())..(region_new.end as usize).min(old.len());
+    for data in applied.chunks(piece) {
+        writer.push(&old[old_window.clone()], old_window.start as u64, data)?;
+    }
+    // The chunks after start reading the old file where they now start, so they seek back to
+    // where they were made to start
+    let shift = new_segment.len() as i64 - (range.end - range.start) as i64;
+    for (raw, new) in &layout[after..] {
+        let chunk = &patch_in[raw.clone()];
+        let seek = [
+            0u64.to_be_bytes(),
+            0u64.to_be_bytes(),
+            (-shift).to_be_bytes(),
+        ]
+        .concat();
+        let chunk = match shift {
+            0 => chunk.to_vec(),
+            _ => [
+                &chunk[..PATCH_HEADER_SIZE],
+                &seek,
+                &chunk[PATCH_HEADER_SIZE..],
+            ]
+            .concat(),
+        };
+        writer.push_raw(&chunk, new.end - new.start)?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::splice;
+    use crate::{apply_chunked, generate_chunked};
+
+    #[test]
+    fn spliced() {
+        let old: Vec<u8> = (0..10_000u32).map(|i| (i * 31 % 253) as u8).collect();
+        let mut new = old.clone();
+        new[200..300].fill(b'x');
+        let mut patch = Vec::new();
+        generate_chunked(&mut &old[..], &mut &new[..], &mut patch, 1000, |_| {}).unwrap();
+
+        for (range, segment) in [
+            (4500..4600, &[b'y'; 300][..]),
+            (4500..4600, &[b'z'; 100][..]),
+            (3000..6500, b""),
+            (10_000..10_000, b"appended"),
+            (0..10_000, b"everything"),
+        ] {
+            let mut spliced = Vec::new();
+            splice(&patch, range.clone(), &old, segment, &mut spliced).unwrap();
+            let mut patched = Vec::new();
+            apply_chunked(&mut Cursor::new(&old), &mut patched, &mut &spliced[..]).unwrap();
+            let mut expected = new.clone();
+            expected.splice(
+                range.start as usize..range.end as usize,
+                segment.iter().copied(),
+            );
+            assert_eq!(patched, expected, "{range:?}");
+        }
+
+        // The chunks before the change are kept as they are
+        let mut spliced = Vec::new();
+        splice(&patch, 4500..4600, &old, &[b'y'; 300], &mut spliced).unwrap();
+        assert!(spliced.starts_with(&patch[..4 * (16 + 24 + 1000 + 24)]));
+
+        assert!(splice(&patch, 9000..10_001, &old, b"", &mut Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+//! Splitting entries with long runs of diff or extra data, for
+//! [`DiffOptions::max_run`][crate::DiffOptions::max_run].
+//!
+//! This works on the finished patch as it's written, so it applies to every way entries are
+//! generated. An entry is split into entries of at most the maximum of diff data first, then one
+//! that has the rest of the diff data and the start of the extra data, then entries of at most the
+//! maximum of extra data. Only the last one seeks. Copies are split into copies from the same
+//! distance back, which copy the same bytes. Word diffs are split at a multiple of 4 bytes, and at
+//! least 4, so their words stay whole.
+
+use std::io::{self, Write};
+use std::mem::size_of;
+
+use zerocopy::{AsBytes, FromBytes};
+
+use crate::be::{I64, U64};
+use crate::spec::Terminator;
+use crate::{EntryHeader, PatchHeader, COPY_FLAG, DDELTA_MAGIC_V2, DDELTA_MAGIC_WORDS};
+
+/// What's left of the entry being split.
+#[derive(Default)]
+struct Remaining {
+    diff: u64,
+    extra: u64,
+    seek: i64,
+    /// How far back the entry copies from, if it's a copy.
+    copy: Option<u64>,
+    /// The data of the current piece that is yet to be written.
+    data: u64,
+}
+
+/// Passes a patch through to `inner`, splitting entries with more than `max` bytes of diff or
+/// extra data.
+pub(crate) struct Splitter<W> {
+    pub(crate) inner: W,
+    max: Option<u64>,
+    /// The header being written, which is a chunk header outside of chunks and an entry header
+    /// inside of them.
+    header: Vec<u8>,
+    /// Whether the current chunk may contain copies, if there is one.
+    chunk: Option<bool>,
+    /// Whether the current chunk has word diffs.
+    words: bool,
+    entry: Remaining,
+}
+
+impl<W: Write> Splitter<W> {
+    pub(crate) fn new(inner: W, max: Option<u64>) -> Self {
+        Splitter {
+            inner,
+            max,
+            header: Vec::with_capacity(size_of::<EntryHeader>()),
+            chunk: None,
+            words: false,
+            entry: Remaining::default(),
+        }
+    }
+
+    /// Writes the header of the next piece of the current entry, if there is one.
+    fn next_piece(&mut self, max: u64) -> io::Result<()> {
+        let entry = &mut self.entry;
+        let max_diff = match self.words {
+            true => (max & !3).max(4),
+            false => max,
+        };
+        let (diff, extra) = match entry.copy {
+            Some(distance) => (entry.diff.min(max), distance),
+            None if entry.diff > max_diff => (max_diff, 0),
+            None => (entry.diff, entry.extra.min(max)),
+        };
+        entry.diff -= diff;
+        if entry.copy.is_none() {
+            entry.extra -= extra;
+            entry.data = diff + extra;
+        }
+        let last = entry.diff == 0 && (entry.copy.is_some() || entry.extra == 0);
+        let seek = match last {
+            true => std::mem::take(&mut entry.seek),
+            false => 0,
+        };
+        let flag = match entry.copy {
+            Some(_) => COPY_FLAG,
+            None => 0,
+        };
+        self.inner.write_all(
+            EntryHeader {
+                diff: U64::new(diff | flag),
+                extra: U64::new(extra),
+                seek: I64::new(seek),
+            }
+            .as_bytes(),
+        )?;
+        if last {
+            entry.copy = None;
+        }
+        Ok(())
+    }
+
+    /// Whether the current entry has pieces left to write.
+    fn splitting(&self) -> bool {
+        self.entry.diff > 0 || self.entry.extra > 0
+    }
+}
+
+impl<W: Write> Write for Splitter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(max) = self.max else {
+            return self.inner.write(buf);
+        };
+        let mut rest = buf;
+        while !rest.is_empty() {
+            if self.entry.data > 0 {
+                let n = self.entry.data.min(rest.len() as u64) as usize;
+                self.inner.write_all(&rest[..n])?;
+                self.entry.data -= n as u64;
+                rest = &rest[n..];
+                continue;
+            }
+            if self.splitting() {
+                self.next_piece(max)?;
+                continue;
+            }
+            let len = match self.chunk {
+                None => size_of::<PatchHeader>(),
+                Some(_) => size_of::<EntryHeader>(),
+            };
+            let take = (len - self.header.len()).min(rest.len());
+            self.header.extend_from_slice(&rest[..take]);
+            rest = &rest[take..];
+            if self.header.len() < len {
+                break;
+            }
+            match self.chunk {
+                None => {
+                    self.chunk = Some(self.header.starts_with(DDELTA_MAGIC_V2));
+                    self.words = self.header.starts_with(DDELTA_MAGIC_WORDS);
+                    self.inner.write_all(&self.header)?;
+                }
+                Some(copies) => {
+                    let header = EntryHeader::read_from(&self.header[..]).unwrap();
+                    let (diff, extra, seek) =
+                        (header.diff.get(), header.extra.get(), header.seek.get());
+                    let copy = copies && diff & COPY_FLAG != 0;
+                    if Terminator::matches(diff, extra, seek) {
+                        self.chunk = None;
+                        self.inner.write_all(&self.header)?;
+                    } else {
+                        self.entry = Remaining {
+                            diff: diff & !(COPY_FLAG * u64::from(copy)),
+                            extra: if copy { 0 } else { extra },
+                            seek,
+                            copy: copy.then_some(extra),
+                            data: 0,
+                        };
+                        self.next_piece(max)?;
+                    }
+                }
+            }
+            self.header.clear();
+        }
+        // Pieces without data of their own are written right away
+        while self.entry.data == 0 && self.splitting() {
+            self.next_piece(max)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,147 @@
+//! Storing patches by the checksums of the old and new file they're made for.
+//!
+//! An update server usually makes the same patches over and over: every client on the same old
+//! version asks for the same patch to the latest one. A [`PatchStore`] keeps each patch under the
+//! pair of checksums, so it's made once and looked up after that, and
+//! [`get_or_insert_with`][PatchStore::get_or_insert_with] does both in one step. [`FsStore`] keeps
+//! them in a directory; other backends, e.g. an object store, implement the trait.
+
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::Checksum;
+
+/// Patches, keyed by the checksums of their old and new file.
+///
+/// Both checksums should use the same algorithm for every patch, as the same file under two
+/// algorithms is two different keys.
+pub trait PatchStore {
+    /// The patch from `old` to `new`, if there is one.
+    fn get(&self, old: &Checksum, new: &Checksum) -> io::Result<Option<Vec<u8>>>;
+
+    /// Stores the patch from `old` to `new`. If there already is one, it's kept instead, as any
+    /// patch between the same files will do.
+    fn put(&self, old: &Checksum, new: &Checksum, patch: &[u8]) -> io::Result<()>;
+
+    /// Whether there is a patch from `old` to `new`.
+    fn contains(&self, old: &Checksum, new: &Checksum) -> io::Result<bool> {
+        Ok(self.get(old, new)?.is_some())
+    }
+
+    /// The patch from `old` to `new`, which is made with `make` and stored if there isn't one yet.
+    fn get_or_insert_with<E: From<io::Error>>(
+        &self,
+        old: &Checksum,
+        new: &Checksum,
+        make: impl FnOnce() -> Result<Vec<u8>, E>,
+    ) -> Result<Vec<u8>, E> {
+        if let Some(patch) = self.get(old, new)? {
+            return Ok(patch);
+        }
+        let patch = make()?;
+        self.put(old, new, &patch)?;
+        Ok(patch)
+    }
+}
+
+/// A [`PatchStore`] in a directory, with a subdirectory per old file, and a file per new one.
+///
+/// Patches are written to a temporary file first and then renamed, so several processes can share
+/// a store, and readers never see half of a patch.
+#[derive(Clone, Debug)]
+pub struct FsStore {
+    root: PathBuf,
+}
+
+impl FsStore {
+    /// Creates a store in `root`, which is created when the first patch is stored.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        FsStore { root: root.into() }
+    }
+
+    /// Where the patch from `old` to `new` is stored.
+    pub fn path(&self, old: &Checksum, new: &Checksum) -> PathBuf {
+        // Colons aren't allowed in file names on Windows
+        let name = |checksum: &Checksum| checksum.to_string().replace(':', "-");
+        self.root
+            .join(name(old))
+            .join(format!("{}.ddelta", name(new)))
+    }
+}
+
+impl PatchStore for FsStore {
+    fn get(&self, old: &Checksum, new: &Checksum) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.path(old, new)) {
+            Ok(patch) => Ok(Some(patch)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn put(&self, old: &Checksum, new: &Checksum, patch: &[u8]) -> io::Result<()> {
+        static TEMP: AtomicU64 = AtomicU64::new(0);
+        let path = self.path(old, new);
+        if path.exists() {
+            return Ok(());
+        }
+        fs::create_dir_all(path.parent().unwrap())?;
+        let temp = path.with_extension(format!(
+            "tmp-{}-{}",
+            std::process::id(),
+            TEMP.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&temp, patch)?;
+        fs::rename(&temp, &path).inspect_err(|_| {
+            let _ = fs::remove_file(&temp);
+        })
+    }
+
+    fn contains(&self, old: &Checksum, new: &Checksum) -> io::Result<bool> {
+        self.path(old, new).try_exists()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io;
+
+    use super::{FsStore, PatchStore};
+    use crate::{Checksum, ChecksumAlgorithm};
+
+    #[test]
+    fn fs_store() {
+        let root = std::env::temp_dir().join(format!("ddelta-store-{}", std::process::id()));
+        let store = FsStore::new(&root);
+        let checksum = |digest: u8| Checksum {
+            algorithm: ChecksumAlgorithm::Crc32,
+            digest: vec![digest; 4].into(),
+        };
+        let (old, new) = (checksum(1), checksum(2));
+        assert_eq!(store.get(&old, &new).unwrap(), None);
+        assert!(!store.contains(&old, &new).unwrap());
+
+        let mut made = 0;
+        for _ in 0..2 {
+            let patch = store
+                .get_or_insert_with(&old, &new, || {
+                    made += 1;
+                    Ok::<_, io::Error>(b"patch".to_vec())
+                })
+                .unwrap();
+            assert_eq!(patch, b"patch");
+        }
+        assert_eq!(made, 1);
+        assert!(store.contains(&old, &new).unwrap());
+        assert!(!store.contains(&new, &old).unwrap());
+        // The first patch is kept
+        store.put(&old, &new, b"other").unwrap();
+        assert_eq!(store.get(&old, &new).unwrap().unwrap(), b"patch");
+        assert!(store
+            .path(&old, &new)
+            .ends_with("crc32-01010101/crc32-02020202.ddelta"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,144 @@
+//! Timing the phases of generating a patch, for [`State::Done`].
+
+use std::cell::Cell;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+use crate::State;
+
+/// How long generating a patch took, split up by phase, and how much data was involved.
+///
+/// Passed to the progress callback as [`State::Done`] once the patch is complete.
+#[derive(Eq, PartialEq, Copy, Clone, Hash, Debug, Default)]
+pub struct Summary {
+    /// Time spent reading the old and new file. Zero if they were passed as slices.
+    pub reading: Duration,
+    /// Time spent building suffix arrays of the old file.
+    pub sorting: Duration,
+    /// Time spent looking for matches, and everything else that isn't covered by the other phases.
+    pub scanning: Duration,
+    /// Time spent writing the patch.
+    pub writing: Duration,
+    pub old_bytes: u64,
+    pub new_bytes: u64,
+    pub patch_bytes: u64,
+}
+
+/// Collects a [`Summary`] from the progress reports and the data passing through [`Timed`].
+pub(crate) struct Recorder {
+    start: Instant,
+    reading: Cell<Duration>,
+    writing: Cell<Duration>,
+    sorting: Cell<Duration>,
+    /// When the current sort started, if one is running.
+    sort_start: Cell<Option<Instant>>,
+    old_bytes: Cell<u64>,
+    new_bytes: Cell<u64>,
+    patch_bytes: Cell<u64>,
+}
+
+impl Recorder {
+    pub(crate) fn new() -> Self {
+        Recorder {
+            start: Instant::now(),
+            reading: Cell::default(),
+            writing: Cell::default(),
+            sorting: Cell::default(),
+            sort_start: Cell::default(),
+            old_bytes: Cell::default(),
+            new_bytes: Cell::default(),
+            patch_bytes: Cell::default(),
+        }
+    }
+
+    /// Records the sizes of inputs that are passed as slices, and so aren't read through
+    /// [`Recorder::old_reader`] and [`Recorder::new_reader`]. They're added to the ones recorded
+    /// before.
+    pub(crate) fn inputs(&self, old: &[u8], new: &[u8]) {
+        self.old_bytes.set(self.old_bytes.get() + old.len() as u64);
+        self.new_bytes.set(self.new_bytes.get() + new.len() as u64);
+    }
+
+    pub(crate) fn old_reader<R>(&self, inner: R) -> Timed<'_, R> {
+        Timed::new(inner, &self.reading, &self.old_bytes)
+    }
+
+    pub(crate) fn new_reader<R>(&self, inner: R) -> Timed<'_, R> {
+        Timed::new(inner, &self.reading, &self.new_bytes)
+    }
+
+    pub(crate) fn patch_writer<W>(&self, inner: W) -> Timed<'_, W> {
+        Timed::new(inner, &self.writing, &self.patch_bytes)
+    }
+
+    /// Tracks sorting, which starts with a report of nothing done, and ends with one of everything
+    /// done.
+    pub(crate) fn observe(&self, state: State) {
+        if let State::Sorting { done, total } = state {
+            if done == total {
+                if let Some(start) = self.sort_start.take() {
+                    self.sorting.set(self.sorting.get() + start.elapsed());
+                }
+            } else if done == 0 {
+                self.sort_start.set(Some(Instant::now()));
+            }
+        }
+    }
+
+    pub(crate) fn finish(&self) -> Summary {
+        let (reading, sorting, writing) =
+            (self.reading.get(), self.sorting.get(), self.writing.get());
+        Summary {
+            reading,
+            sorting,
+            scanning: self
+                .start
+                .elapsed()
+                .saturating_sub(reading + sorting + writing),
+            writing,
+            old_bytes: self.old_bytes.get(),
+            new_bytes: self.new_bytes.get(),
+            patch_bytes: self.patch_bytes.get(),
+        }
+    }
+}
+
+/// A reader or writer that adds the time spent in it and the bytes passing through to a
+/// [`Recorder`].
+pub(crate) struct Timed<'a, T> {
+    inner: T,
+    time: &'a Cell<Duration>,
+    bytes: &'a Cell<u64>,
+}
+
+impl<'a, T> Timed<'a, T> {
+    fn new(inner: T, time: &'a Cell<Duration>, bytes: &'a Cell<u64>) -> Self {
+        Timed { inner, time, bytes }
+    }
+
+    fn timed(&mut self, f: impl FnOnce(&mut T) -> io::Result<usize>) -> io::Result<usize> {
+        let start = Instant::now();
+        let result = f(&mut self.inner);
+        self.time.set(self.time.get() + start.elapsed());
+        if let Ok(n) = result {
+            self.bytes.set(self.bytes.get() + n as u64);
+        }
+        result
+    }
+}
+
+impl<T: Read> Read for Timed<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.timed(|inner| inner.read(buf))
+    }
+}
+
+impl<T: Write> Write for Timed<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.timed(|inner| inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.timed(|inner| inner.flush().map(|()| 0)).map(drop)
+    }
+}
@@ -0,0 +1,224 @@
+//! The only unsafe code in the crate: the system calls behind [`apply_mmap_out`],
+//! [`apply_reflink`] and [`preallocate`].
+//!
+//! The rest of the crate is compiled with `#![deny(unsafe_code)]`. Patch headers are read and
+//! written in place through zerocopy's derives, which check at compile time that every bit pattern
+//! is a valid value and that there's no padding, so reinterpreting bytes needs no unsafe code of
+//! our own. What's left is kept here, behind safe interfaces whose invariants are:
+//!
+//! - A [`Mapping`] owns its memory: it's created by `mmap` with exactly `len` bytes, the pointer
+//!   can't be changed or copied out, and it's unmapped exactly once, on drop. The mapping is shared
+//!   with the file, so other processes and handles may change it at any time. It's only written
+//!   through the pointer, with bounds checked, and never read or borrowed as a slice, so such
+//!   changes only end up mixed into the new file. Touching it past the end of a file that was
+//!   truncated meanwhile raises `SIGBUS`, which kills the process, as [`apply_mmap_out`] documents.
+//! - [`clone_range`] only passes file descriptors that are borrowed for the duration of the call,
+//!   and an argument of the type `FICLONERANGE` expects. The kernel checks the ranges.
+//! - [`allocate`] likewise only passes a borrowed file descriptor, and on Apple targets a
+//!   `fstore_t` that lives on its stack for the duration of the call.
+//!
+//! Miri can't run these calls, so their tests are skipped under it. The tests of the byte
+//! reinterpretation can be run with `cargo +nightly miri test --no-default-features --lib layout`.
+//!
+//! [`apply_mmap_out`]: crate::apply_mmap_out
+//! [`apply_reflink`]: crate::apply_reflink
+//! [`preallocate`]: crate::preallocate
+
+#![allow(unsafe_code)]
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+#[cfg(feature = "mmap")]
+use std::ptr;
+
+/// A shared, writable mapping of a whole file.
+#[cfg(feature = "mmap")]
+pub(crate) struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+#[cfg(feature = "mmap")]
+impl Mapping {
+    pub(crate) fn new(file: &File) -> io::Result<Self> {
+        let len = usize::try_from(file.metadata()?.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "file too large to map"))?;
+        if len == 0 {
+            // Empty mappings aren't allowed
+            return Ok(Mapping {
+                ptr: ptr::NonNull::dangling().as_ptr(),
+                len,
+            });
+        }
+        // SAFETY: a new mapping is created, which doesn't alias any memory of the program
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the range is the mapping that was just created. This is only a hint, so errors
+        // don't matter.
+        unsafe { libc::madvise(ptr, len, libc::MADV_SEQUENTIAL) };
+        Ok(Mapping {
+            ptr: ptr.cast(),
+            len,
+        })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Copies `data` into the mapping at `at`.
+    ///
+    /// # Panics
+    ///
+    /// If `data` doesn't fit the mapping there.
+    pub(crate) fn write(&mut self, at: usize, data: &[u8]) {
+        assert!(
+            at.checked_add(data.len())
+                .is_some_and(|end| end <= self.len),
+            "write past the end of the mapping"
+        );
+        // SAFETY: `at..at + data.len()` is within the mapping, which is valid for as long as
+        // `self` is alive. No reference to the mapping exists, and `ptr::copy` allows `data` to
+        // overlap with it. For an empty mapping, nothing is copied.
+        unsafe { ptr::copy(data.as_ptr(), self.ptr.add(at), data.len()) };
+    }
+
+    /// Starts writing back the pages in `start..end`, without waiting for them.
+    pub(crate) fn writeback(&self, start: usize, end: usize) -> io::Result<()> {
+        // msync needs a page aligned start
+        // SAFETY: sysconf has no preconditions
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1) as usize;
+        let start = start / page * page;
+        let end = end.min(self.len);
+        if end <= start {
+            return Ok(());
+        }
+        // SAFETY: `start..end` is within the mapping, which is checked above
+        let result =
+            unsafe { libc::msync(self.ptr.add(start).cast(), end - start, libc::MS_ASYNC) };
+        match result {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            // SAFETY: the mapping was created in `Mapping::new`, and isn't used after this
+            unsafe { libc::munmap(self.ptr.cast(), self.len) };
+        }
+    }
+}
+
+/// Makes `len` bytes at `new_pos` in `new` share the storage of the bytes at `old_pos` in `old`.
+/// This fails if the filesystem can't, or the files are on different ones.
+#[cfg(all(feature = "reflink", target_os = "linux"))]
+pub(crate) fn clone_range(
+    old: &File,
+    new: &File,
+    old_pos: u64,
+    new_pos: u64,
+    len: u64,
+) -> io::Result<()> {
+    let range = libc::file_clone_range {
+        src_fd: old.as_raw_fd().into(),
+        src_offset: old_pos,
+        src_length: len,
+        dest_offset: new_pos,
+    };
+    // SAFETY: both file descriptors are open for the duration of the call, and `range` is the
+    // argument FICLONERANGE expects
+    match unsafe { libc::ioctl(new.as_raw_fd(), libc::FICLONERANGE as _, &range) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Reserves disk space for the first `len` bytes of `file`, without changing its size.
+#[cfg(all(feature = "preallocate", target_os = "linux"))]
+pub(crate) fn allocate(file: &File, len: u64) -> io::Result<()> {
+    let len = libc::off_t::try_from(len)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "file too large"))?;
+    // SAFETY: the file descriptor is open for the duration of the call, and there's no memory
+    // passed
+    match unsafe { libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, len) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Reserves disk space for the first `len` bytes of `file`, contiguous if possible, without
+/// changing its size.
+#[cfg(all(feature = "preallocate", target_vendor = "apple"))]
+pub(crate) fn allocate(file: &File, len: u64) -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    // Space is added after what's allocated already
+    let allocated = file.metadata()?.blocks() * 512;
+    let Some(missing) = len.checked_sub(allocated).filter(|&missing| missing > 0) else {
+        return Ok(());
+    };
+    let mut store = libc::fstore_t {
+        fst_flags: libc::F_ALLOCATECONTIG,
+        fst_posmode: libc::F_PEOFPOSMODE,
+        fst_offset: 0,
+        fst_length: libc::off_t::try_from(missing)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "file too large"))?,
+        fst_bytesalloc: 0,
+    };
+    for flags in [libc::F_ALLOCATECONTIG, libc::F_ALLOCATEALL] {
+        store.fst_flags = flags;
+        // SAFETY: the file descriptor is open for the duration of the call, and `store` is the
+        // argument F_PREALLOCATE expects
+        if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_PREALLOCATE, &mut store) } != -1 {
+            return Ok(());
+        }
+    }
+    Err(io::Error::last_os_error())
+}
+
+#[cfg(all(test, feature = "mmap"))]
+mod test {
+    use std::fs::{self, OpenOptions};
+
+    use super::Mapping;
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn mapping() {
+        let path = std::env::temp_dir().join(format!("ddelta-sys-{}", std::process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        for len in [0, 10] {
+            file.set_len(len).unwrap();
+            let mut mapping = Mapping::new(&file).unwrap();
+            assert_eq!(mapping.len(), len as usize);
+            mapping.write(0, &vec![b'x'; len as usize]);
+            // Past the end is cut off rather than passed on
+            mapping.writeback(0, 100).unwrap();
+            drop(mapping);
+            assert_eq!(fs::read(&path).unwrap(), vec![b'x'; len as usize]);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
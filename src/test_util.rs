@@ -0,0 +1,178 @@
+//! Synthetic old and new files, for regression tests of pipelines built on this crate.
+//!
+//! A test describes how the new file differs from the old one as a list of [`Op`]s, and [`pair`]
+//! makes both files from it, with random bytes wherever the list doesn't say what they are. The
+//! randomness comes from a seed, so the files are the same on every run and target, and a test can
+//! e.g. compare patch sizes against recorded ones.
+//!
+//! ```
+//! use ddelta::test_util::{pair, Op};
+//!
+//! // A 4 KiB block inserted in the middle, and a few bytes changed near the end
+//! let (old, new) = pair(
+//!     1,
+//!     &[
+//!         Op::Copy(64 * 1024),
+//!         Op::Insert(4096),
+//!         Op::Copy(60 * 1024),
+//!         Op::Noise { len: 4096, percent: 2 },
+//!     ],
+//! );
+//! assert_eq!(old.len(), 128 * 1024);
+//! assert_eq!(new.len(), 132 * 1024);
+//! ```
+
+/// How a part of the new file is made from the old one, see [`pair`].
+///
+/// The ops are applied in order, each starting in the old file where the one before stopped.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Op {
+    /// The next `len` bytes of the old file, unchanged.
+    Copy(usize),
+    /// `len` random bytes that aren't in the old file.
+    Insert(usize),
+    /// Skips the next `len` bytes of the old file.
+    Delete(usize),
+    /// The next `len` bytes of the old file, cut into blocks of `block` bytes (the last one may be
+    /// shorter), in random order.
+    Shuffle { len: usize, block: usize },
+    /// The next `len` bytes of the old file, with about `percent` of them changed to random bytes.
+    Noise { len: usize, percent: u8 },
+}
+
+impl Op {
+    /// How many bytes of the old file this reads.
+    fn old_len(self) -> usize {
+        match self {
+            Op::Insert(_) => 0,
+            Op::Copy(len) | Op::Delete(len) | Op::Shuffle { len, .. } | Op::Noise { len, .. } => {
+                len
+            }
+        }
+    }
+}
+
+/// A small, seeded random number generator, which is good enough to make test data, e.g. for
+/// inputs that [`Op`]s can't describe.
+///
+/// The same seed always gives the same numbers, on every target.
+#[derive(Clone, Debug)]
+pub struct Rng(u64);
+
+impl Rng {
+    /// Creates a generator that starts from `seed`.
+    pub fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    /// The next random number, of 31 bits.
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        self.0 >> 33
+    }
+
+    /// A random number below `n`.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// `len` random bytes.
+    pub fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.next_u64() as u8).collect()
+    }
+}
+
+/// Makes an old file of random bytes, as long as `ops` read, and the new file they make from it.
+///
+/// The same `seed` and `ops` always make the same files.
+pub fn pair(seed: u64, ops: &[Op]) -> (Vec<u8>, Vec<u8>) {
+    let old = Rng::new(seed).bytes(ops.iter().map(|op| op.old_len()).sum());
+    // A different stream, so the inserted bytes aren't copies of the old file
+    let new = new_from(!seed, &old, ops);
+    (old, new)
+}
+
+/// Makes the new file that `ops` make from `old`, e.g. from a real file that's used as a starting
+/// point. Bytes of `old` after the ones `ops` read are left out, as if deleted.
+///
+/// # Panics
+///
+/// If `ops` read more than `old` has, or a [`Op::Shuffle`] has a block size of zero.
+pub fn new_from(seed: u64, old: &[u8], ops: &[Op]) -> Vec<u8> {
+    let needed: usize = ops.iter().map(|op| op.old_len()).sum();
+    assert!(
+        needed <= old.len(),
+        "ops read {needed} bytes of the old file, which only has {}",
+        old.len()
+    );
+    let mut rng = Rng::new(seed);
+    let mut new = Vec::new();
+    let mut pos = 0;
+    for &op in ops {
+        let from = &old[pos..pos + op.old_len()];
+        pos += op.old_len();
+        match op {
+            Op::Copy(_) => new.extend(from),
+            Op::Insert(len) => new.extend(rng.bytes(len)),
+            Op::Delete(_) => {}
+            Op::Shuffle { block, .. } => {
+                assert!(block > 0, "shuffle with blocks of 0 bytes");
+                let mut blocks: Vec<&[u8]> = from.chunks(block).collect();
+                for i in (1..blocks.len()).rev() {
+                    blocks.swap(i, rng.below(i + 1));
+                }
+                new.extend(blocks.concat());
+            }
+            Op::Noise { percent, .. } => {
+                new.extend(
+                    from.iter()
+                        .map(|&byte| match rng.below(100) < percent as usize {
+                            true => rng.next_u64() as u8,
+                            false => byte,
+                        }),
+                )
+            }
+        }
+    }
+    new
+}
+
+#[cfg(test)]
+mod test {
+    use super::{new_from, pair, Op};
+
+    #[test]
+    fn ops() {
+        let ops = [
+            Op::Copy(100),
+            Op::Delete(50),
+            Op::Insert(10),
+            Op::Shuffle {
+                len: 100,
+                block: 30,
+            },
+            Op::Noise {
+                len: 1000,
+                percent: 10,
+            },
+        ];
+        let (old, new) = pair(7, &ops);
+        assert_eq!((old.len(), new.len()), (1250, 1210));
+        assert_eq!(pair(7, &ops), (old.clone(), new.clone()));
+        assert_ne!(pair(8, &ops).1, new);
+
+        assert_eq!(new[..100], old[..100]);
+        let mut shuffled = new[110..210].to_vec();
+        shuffled.sort();
+        let mut original = old[150..250].to_vec();
+        original.sort();
+        assert_eq!(shuffled, original);
+        let changed = (0..1000).filter(|&i| new[210 + i] != old[250 + i]).count();
+        assert!((50..150).contains(&changed), "{changed}");
+
+        assert_eq!(new_from(0, b"abcdef", &[Op::Delete(2), Op::Copy(2)]), b"cd");
+    }
+}
@@ -0,0 +1,138 @@
+//! Offsets into the old and new file, and lengths, as types of their own.
+//!
+//! Applying a patch juggles offsets into both files, lengths of data, and seeks that may go
+//! backwards, which are all plain integers in the format. Keeping them apart in the types catches
+//! mixing them up, and the conversions between them check for overflow and negative positions
+//! instead of wrapping. Offsets are limited to [`i64::MAX`], which is what [`Seek`] can reach.
+//!
+//! [`Seek`]: std::io::Seek
+
+use std::fmt;
+use std::io::{self, ErrorKind};
+
+/// The length of some data, e.g. of the diff data of an entry, or of a whole file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Len(u64);
+
+impl Len {
+    pub const fn new(len: u64) -> Self {
+        Len(len)
+    }
+
+    pub const fn get(self) -> u64 {
+        self.0
+    }
+
+    /// The length as a [`usize`], if it fits, e.g. to index into memory.
+    pub fn to_usize(self) -> Option<usize> {
+        usize::try_from(self.0).ok()
+    }
+}
+
+/// A position in the new file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NewOffset(u64);
+
+impl NewOffset {
+    pub const fn new(offset: u64) -> Self {
+        NewOffset(offset)
+    }
+
+    pub const fn get(self) -> u64 {
+        self.0
+    }
+
+    /// The offset `len` bytes further, if that's still a valid file offset.
+    pub fn checked_add(self, len: Len) -> Option<Self> {
+        self.0
+            .checked_add(len.0)
+            .filter(|&offset| offset <= i64::MAX as u64)
+            .map(NewOffset)
+    }
+
+    /// How far this is past `start`, or [`None`] if it's before it.
+    pub fn since(self, start: NewOffset) -> Option<Len> {
+        self.0.checked_sub(start.0).map(Len)
+    }
+
+    /// The same offset in the old file. Each chunk of a chunked patch starts reading the old file
+    /// where the chunk starts in the new file.
+    pub fn in_old(self) -> OldOffset {
+        OldOffset(self.0)
+    }
+}
+
+/// A position in the old file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct OldOffset(u64);
+
+impl OldOffset {
+    pub const fn new(offset: u64) -> Self {
+        OldOffset(offset)
+    }
+
+    pub const fn get(self) -> u64 {
+        self.0
+    }
+
+    /// The offset `len` bytes further, if that's still a valid file offset.
+    pub fn checked_add(self, len: Len) -> Option<Self> {
+        self.0
+            .checked_add(len.0)
+            .filter(|&offset| offset <= i64::MAX as u64)
+            .map(OldOffset)
+    }
+
+    /// Moves by the seek of an entry, which fails if that ends up before the start of the file.
+    pub fn seek(self, by: i64) -> io::Result<Self> {
+        self.0
+            .checked_add_signed(by)
+            .map(OldOffset)
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "seek to a negative position"))
+    }
+
+    /// The seek from here to `to`, if it fits in one.
+    pub fn seek_to(self, to: OldOffset) -> Option<i64> {
+        i64::try_from(to.0)
+            .ok()?
+            .checked_sub(i64::try_from(self.0).ok()?)
+    }
+}
+
+impl fmt::Display for Len {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bytes", self.0)
+    }
+}
+
+impl fmt::Display for NewOffset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "new file offset {}", self.0)
+    }
+}
+
+impl fmt::Display for OldOffset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "old file offset {}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Len, NewOffset, OldOffset};
+
+    #[test]
+    fn offsets() {
+        let start = NewOffset::new(10);
+        let end = start.checked_add(Len::new(5)).unwrap();
+        assert_eq!(end.since(start), Some(Len::new(5)));
+        assert_eq!(start.since(end), None);
+        assert_eq!(start.checked_add(Len::new(i64::MAX as u64)), None);
+
+        let old = end.in_old();
+        assert_eq!(old.seek(-15).unwrap(), OldOffset::default());
+        assert!(old.seek(-16).is_err());
+        assert_eq!(old.seek_to(OldOffset::new(3)), Some(-12));
+        assert_eq!(old.to_string(), "old file offset 15");
+    }
+}
@@ -0,0 +1,141 @@
+//! Small patches that pin down how entries are read, the same way the original ddelta tool reads
+//! them.
+//!
+//! All fields are big-endian. The seek is a two's complement `int64_t` (unlike bsdiff, which stores
+//! a sign and a magnitude), and is added to the position in the old file with
+//! `fseek(old, seek, SEEK_CUR)`, so seeking before the start fails, while seeking past the end only
+//! fails once the old file is read there. Diff bytes are added to the old bytes modulo 256. An entry
+//! is only the end of the patch if all three fields are zero.
+
+use std::io::Cursor;
+
+use ddelta::spec::{ENTRY_HEADER_SIZE, PATCH_HEADER_SIZE};
+use ddelta::{apply, apply_chunked, Patch, PatchEntry, SliceSource};
+
+const OLD: &[u8] = b"abcdef";
+
+fn patch(entries: Vec<PatchEntry>) -> Vec<u8> {
+    let mut patch = Vec::new();
+    Patch {
+        entries,
+        ..Patch::new()
+    }
+    .write_to(&mut patch)
+    .unwrap();
+    patch
+}
+
+fn entry(diff: &[u8], extra: &[u8], seek: i64) -> PatchEntry {
+    PatchEntry::Data {
+        diff: diff.to_vec(),
+        extra: extra.to_vec(),
+        seek,
+    }
+}
+
+/// `patch` with the new file size and the fields of its first entry overwritten, for values that
+/// don't match the data, which [`Patch`] can't write.
+fn overwritten(mut patch: Vec<u8>, new_size: u64, fields: [u64; 3]) -> Vec<u8> {
+    patch[8..PATCH_HEADER_SIZE].copy_from_slice(&new_size.to_be_bytes());
+    let header = &mut patch[PATCH_HEADER_SIZE..PATCH_HEADER_SIZE + ENTRY_HEADER_SIZE];
+    header.copy_from_slice(&fields.map(u64::to_be_bytes).concat());
+    patch
+}
+
+/// Applies `patch` to [`OLD`] from a seekable file and from memory, and as a chunked patch, which
+/// must all agree.
+fn run(patch: &[u8]) -> Option<Vec<u8>> {
+    let mut from_file = Vec::new();
+    let file = apply(&mut Cursor::new(OLD), &mut from_file, &mut &patch[..]);
+    let mut from_slice = Vec::new();
+    let slice = apply(&mut SliceSource::new(OLD), &mut from_slice, &mut &patch[..]);
+    let mut chunked = Vec::new();
+    let as_chunk = apply_chunked(&mut Cursor::new(OLD), &mut chunked, &mut &patch[..]);
+    assert!(
+        file.is_ok() == slice.is_ok() && file.is_ok() == as_chunk.is_ok(),
+        "appliers disagree: {file:?}, {slice:?}, {as_chunk:?}"
+    );
+    file.ok().map(|()| {
+        assert_eq!(from_file, from_slice);
+        assert_eq!(from_file, chunked);
+        from_file
+    })
+}
+
+#[test]
+fn negative_seek() {
+    // "abc", back to the start, "ab"
+    let p = patch(vec![entry(&[0; 3], b"", -3), entry(&[0; 2], b"", 0)]);
+    assert_eq!(run(&p).unwrap(), b"abcab");
+
+    // -1 is all ones, not a sign bit and a magnitude of 1
+    let p = patch(vec![entry(&[0; 2], b"", -1), entry(&[0], b"", 0)]);
+    assert_eq!(p[32..40], [0xff; 8]);
+    assert_eq!(run(&p).unwrap(), b"abb");
+    let p = patch(vec![entry(&[0; 2], b"", i64::MIN + 1), entry(&[0], b"", 0)]);
+    assert_eq!(p[32..40], [0x80, 0, 0, 0, 0, 0, 0, 1]);
+    assert_eq!(run(&p), None);
+}
+
+#[test]
+fn seek_out_of_range() {
+    // Before the start of the old file
+    let p = patch(vec![entry(&[0], b"", -2), entry(b"", b"x", 0)]);
+    assert_eq!(run(&p), None);
+    for seek in [i64::MIN, i64::MAX] {
+        let p = patch(vec![entry(&[0], b"", seek), entry(b"", b"x", 0)]);
+        assert_eq!(run(&p), None, "seek {seek}");
+    }
+
+    // Past the end is fine as long as nothing is read there
+    let p = patch(vec![entry(&[0], b"", 100), entry(b"", b"x", 0)]);
+    assert_eq!(run(&p).unwrap(), b"ax");
+    let p = patch(vec![entry(&[0], b"", 100), entry(&[0], b"", 0)]);
+    assert_eq!(run(&p), None);
+    // And back again
+    let p = patch(vec![
+        entry(&[0], b"", 100),
+        entry(b"", b"", -100),
+        entry(&[0], b"", 0),
+    ]);
+    assert_eq!(run(&p).unwrap(), b"ab");
+}
+
+#[test]
+fn zero_fields() {
+    // Only a seek, or only extra data, doesn't end the patch
+    let p = patch(vec![entry(b"", b"", 4), entry(&[0], b"!", 0)]);
+    assert_eq!(run(&p).unwrap(), b"e!");
+    let p = patch(vec![entry(b"", b"xyz", 0)]);
+    assert_eq!(run(&p).unwrap(), b"xyz");
+    // An empty patch
+    assert_eq!(run(&patch(vec![])).unwrap(), b"");
+}
+
+#[test]
+fn diff_wraps() {
+    let p = patch(vec![entry(&[0xff, 1, 0x80], b"", 0)]);
+    assert_eq!(run(&p).unwrap(), [b'a' - 1, b'c', b'c' + 0x80]);
+}
+
+#[test]
+fn maximum_lengths() {
+    // Fail without trying to allocate or read that much
+    let six = patch(vec![entry(&[0; 6], b"", 0)]);
+    for fields in [
+        [u64::MAX, 0, 0],
+        [0, u64::MAX, 0],
+        [1 << 63, 0, 0],
+        [u64::MAX, u64::MAX, u64::MAX],
+        [i64::MAX as u64, i64::MAX as u64, 0],
+    ] {
+        let p = overwritten(six.clone(), u64::MAX, fields);
+        assert_eq!(run(&p), None, "{fields:?}");
+    }
+    // A new size that isn't what the entries add up to
+    let one = patch(vec![entry(&[0], b"", 0)]);
+    let p = overwritten(one.clone(), u64::MAX, [1, 0, 0]);
+    assert_eq!(run(&p), None);
+    let p = overwritten(one, 0, [1, 0, 0]);
+    assert_eq!(run(&p), None);
+}
@@ -0,0 +1,184 @@
+//! Checks that patches for a small corpus stay about as small as they were, to catch changes to
+//! the matcher that still produce correct patches, just worse ones.
+//!
+//! The inputs are generated from fixed seeds, so they're the same on every run and target. Run
+//! with `cargo test --features slow-tests --test ratio`. After a change that makes patches smaller
+//! (or larger on purpose), run it with `DDELTA_BLESS=1` and `--nocapture`, and copy the printed
+//! costs into [`BASELINES`].
+
+use std::io::Cursor;
+
+use ddelta::spec::parse_chunked;
+use ddelta::test_util::Rng;
+use ddelta::{apply_chunked, generate, generate_chunked, generate_lowmem};
+
+/// How much larger than its baseline a cost may get, in percent.
+const TOLERANCE: u64 = 5;
+
+/// The recorded cost of each pair and generator.
+const BASELINES: &[(&str, &str, u64)] = &[
+    ("shifted", "generate", 15_039),
+    ("shifted", "chunked", 27_051),
+    ("shifted", "lowmem", 15_335),
+    ("text", "generate", 16_464),
+    ("text", "chunked", 17_193),
+    ("text", "lowmem", 46_828),
+    ("reordered", "generate", 1512),
+    ("reordered", "chunked", 188_920),
+    ("reordered", "lowmem", 1512),
+    ("appended", "generate", 47_785),
+    ("appended", "chunked", 47_833),
+    ("appended", "lowmem", 47_761),
+    ("unrelated", "generate", 65_560),
+    ("unrelated", "chunked", 65_560),
+    ("unrelated", "lowmem", 65_560),
+];
+
+/// Something like machine code: instructions with absolute addresses in them, and 4 KiB inserted
+/// in the middle of the new one, which moves every address after it.
+fn shifted() -> (Vec<u8>, Vec<u8>) {
+    let mut rng = Rng::new(1);
+    let (len, at, inserted) = (256 * 1024, 100 * 1024, 4096);
+    let mut targets = Vec::new();
+    let mut code = Vec::new();
+    while code.len() < len {
+        code.extend(rng.bytes(12));
+        let target = rng.next_u64() as u32 % len as u32;
+        targets.push((code.len(), target));
+        code.extend(target.to_le_bytes());
+    }
+    let mut new = code.clone();
+    for &(pos, target) in &targets {
+        if target >= at as u32 {
+            new[pos..pos + 4].copy_from_slice(&(target + inserted as u32).to_le_bytes());
+        }
+    }
+    new.splice(at..at, rng.bytes(inserted));
+    (code, new)
+}
+
+/// Lines of text, with some of them edited, and some added and removed.
+fn text() -> (Vec<u8>, Vec<u8>) {
+    let mut rng = Rng::new(2);
+    let words = [
+        "delta", "patch", "chunk", "entry", "seek", "old", "new", "file", "data",
+    ];
+    let mut line = || -> Vec<u8> {
+        let count = 3 + rng.next_u64() % 8;
+        let line: Vec<&str> = (0..count)
+            .map(|_| words[rng.next_u64() as usize % words.len()])
+            .collect();
+        format!("{}\n", line.join(" ")).into_bytes()
+    };
+    let old: Vec<Vec<u8>> = (0..5000).map(|_| line()).collect();
+    let mut new = Vec::new();
+    for (i, old_line) in old.iter().enumerate() {
+        match i % 50 {
+            0 => new.push(line()),
+            17 => {}
+            23 => {
+                new.push(old_line.clone());
+                new.push(line());
+            }
+            _ => new.push(old_line.clone()),
+        }
+    }
+    (old.concat(), new.concat())
+}
+
+/// Blocks of 4 KiB, in a different order.
+fn reordered() -> (Vec<u8>, Vec<u8>) {
+    let mut rng = Rng::new(3);
+    let blocks: Vec<Vec<u8>> = (0..64).map(|_| rng.bytes(4096)).collect();
+    let mut order: Vec<usize> = (0..blocks.len()).collect();
+    for i in (1..order.len()).rev() {
+        order.swap(i, rng.next_u64() as usize % (i + 1));
+    }
+    let new: Vec<u8> = order.iter().flat_map(|&i| blocks[i].clone()).collect();
+    (blocks.concat(), new)
+}
+
+/// A log, with more lines at the end.
+fn appended() -> (Vec<u8>, Vec<u8>) {
+    let mut rng = Rng::new(4);
+    let mut log = Vec::new();
+    for i in 0..8000 {
+        log.extend(format!("{i:08} event {:x}\n", rng.next_u64()).into_bytes());
+    }
+    let old = log[..log.len() * 3 / 4].to_vec();
+    (old, log)
+}
+
+/// Nothing in common.
+fn unrelated() -> (Vec<u8>, Vec<u8>) {
+    let mut rng = Rng::new(5);
+    (rng.bytes(64 * 1024), rng.bytes(64 * 1024))
+}
+
+/// Roughly what the patch costs after compression: the bytes that aren't zero diff data, plus the
+/// entry headers, which compress poorly.
+fn cost(patch: &[u8]) -> u64 {
+    let mut cost = 0;
+    for chunk in parse_chunked(patch).unwrap() {
+        for entry in chunk.entries {
+            cost += 24 + entry.extra.len() as u64;
+            cost += entry.diff.iter().filter(|&&b| b != 0).count() as u64;
+        }
+    }
+    cost
+}
+
+#[test]
+fn ratio() {
+    type Pair = fn() -> (Vec<u8>, Vec<u8>);
+    let pairs: [(&str, Pair); 5] = [
+        ("shifted", shifted),
+        ("text", text),
+        ("reordered", reordered),
+        ("appended", appended),
+        ("unrelated", unrelated),
+    ];
+    let bless = std::env::var_os("DDELTA_BLESS").is_some();
+    let mut failures = Vec::new();
+    for (name, pair) in pairs {
+        let (old, new) = pair();
+        let mut patches = Vec::new();
+        let mut patch = Vec::new();
+        generate(&old, &new, &mut patch, |_| {}).unwrap();
+        patches.push(("generate", patch));
+        let mut patch = Vec::new();
+        generate_chunked(
+            &mut &old[..],
+            &mut &new[..],
+            &mut patch,
+            Some(64 * 1024),
+            |_| {},
+        )
+        .unwrap();
+        patches.push(("chunked", patch));
+        let mut patch = Vec::new();
+        generate_lowmem(&old, &new, &mut patch, 64 * 1024, |_| {}).unwrap();
+        patches.push(("lowmem", patch));
+
+        for (generator, patch) in patches {
+            let mut patched = Vec::new();
+            apply_chunked(&mut Cursor::new(&old), &mut patched, &mut &patch[..]).unwrap();
+            assert!(patched == new, "{name} with {generator} doesn't apply");
+            let cost = cost(&patch);
+            let baseline = BASELINES
+                .iter()
+                .find(|&&(n, g, _)| n == name && g == generator)
+                .map_or(0, |&(_, _, baseline)| baseline);
+            if bless {
+                println!("    ({name:?}, {generator:?}, {cost}),");
+            } else if cost > baseline + baseline * TOLERANCE / 100 {
+                failures.push(format!("{name} with {generator}: {cost}, was {baseline}"));
+            }
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "patches got larger:\n{}",
+        failures.join("\n")
+    );
+}